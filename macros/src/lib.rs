@@ -0,0 +1,127 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// #[proxy_wasm_test] turns a function taking the Tester into a #[test] run by
+// proxy_wasm_test_framework::tester::TestSetup, e.g.
+//
+//   #[proxy_wasm_test("target/wasm32-wasip1/release/filter.wasm", plugin_config = "{}")]
+//   fn denies_anonymous(tester: &mut Tester) -> Result<()> { ... }
+//
+// The module path is relative to the package (where cargo test runs), PROXY_WASM_TEST_MODULE
+// is used when it is left out. Other arguments: vm_config = "..", quiet, allow_unexpected and
+// fixture = path::to::suite, a function returning the TestSetup (with the setup and teardown
+// hooks) that the other arguments then adjust.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse::Parser;
+use syn::{parse_macro_input, ItemFn, LitStr, Path};
+
+#[derive(Default)]
+struct Args {
+    fixture: Option<Path>,
+    wasm: Option<LitStr>,
+    vm_config: Option<LitStr>,
+    plugin_config: Option<LitStr>,
+    quiet: bool,
+    allow_unexpected: bool,
+}
+
+#[proc_macro_attribute]
+pub fn proxy_wasm_test(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut args = Args::default();
+    // the module path may come first, without `wasm =`
+    let attr = proc_macro2::TokenStream::from(attr);
+    let mut tokens = attr.clone().into_iter().peekable();
+    let named = match tokens.peek() {
+        Some(proc_macro2::TokenTree::Literal(_)) => {
+            let literal: proc_macro2::TokenStream = tokens.next().into_iter().collect();
+            args.wasm = Some(match syn::parse2(literal) {
+                Ok(wasm) => wasm,
+                Err(error) => return error.to_compile_error().into(),
+            });
+            // skip the comma before the named arguments
+            tokens.next();
+            tokens.collect()
+        }
+        _ => attr,
+    };
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("fixture") {
+            args.fixture = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("wasm") {
+            args.wasm = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("vm_config") {
+            args.vm_config = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("plugin_config") {
+            args.plugin_config = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("quiet") {
+            args.quiet = true;
+        } else if meta.path.is_ident("allow_unexpected") {
+            args.allow_unexpected = true;
+        } else {
+            return Err(meta.error(
+                "expected fixture, wasm, vm_config, plugin_config, quiet or allow_unexpected",
+            ));
+        }
+        Ok(())
+    });
+    if let Err(error) = parser.parse2(named) {
+        return error.to_compile_error().into();
+    }
+
+    let mut test = parse_macro_input!(item as ItemFn);
+    // the attributes (e.g. #[ignore]) go to the generated #[test]
+    let attrs = std::mem::take(&mut test.attrs);
+    let vis = &test.vis;
+    let name = &test.sig.ident;
+    if test.sig.inputs.len() != 1 {
+        let message = "#[proxy_wasm_test] functions take a single `&mut Tester` argument";
+        return syn::Error::new_spanned(&test.sig, message)
+            .to_compile_error()
+            .into();
+    }
+
+    let framework = quote!(::proxy_wasm_test_framework::tester);
+    let mut setup = match (&args.fixture, &args.wasm) {
+        (Some(fixture), Some(wasm)) => quote!(#fixture().wasm_path(#wasm)),
+        (Some(fixture), None) => quote!(#fixture()),
+        (None, Some(wasm)) => quote!(#framework::TestSetup::new(#wasm)),
+        (None, None) => quote!(#framework::TestSetup::from_env().unwrap()),
+    };
+    if let Some(vm_config) = &args.vm_config {
+        setup = quote!(#setup.vm_config(#vm_config));
+    }
+    if let Some(plugin_config) = &args.plugin_config {
+        setup = quote!(#setup.plugin_config(#plugin_config));
+    }
+    if args.quiet {
+        setup = quote!(#setup.quiet());
+    }
+    if args.allow_unexpected {
+        setup = quote!(#setup.allow_unexpected());
+    }
+
+    let expanded = quote! {
+        #[test]
+        #(#attrs)*
+        #vis fn #name() {
+            #test
+            if let Err(error) = #setup.run(#name) {
+                panic!("{:?}", error);
+            }
+        }
+    };
+    expanded.into()
+}
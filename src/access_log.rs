@@ -0,0 +1,522 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Envoy access log lines of the emulated streams, to assert on what a logging filter contributes
+// (headers it adds, filter state and dynamic metadata it sets), e.g.
+//
+//   tester.set_access_log_format(AccessLogFormat::parse(
+//       "%REQ(:METHOD)% %REQ(:PATH)% %RESPONSE_CODE% %FILTER_STATE(wasm.user:PLAIN)%",
+//   )?);
+//   tester.send_request(2, HttpRequest::get("/"))?.execute_all()?;
+//   tester.send_response(2, HttpResponse::ok())?.execute_all()?;
+//   tester.call_proxy_on_log(2).execute_and_expect(ReturnType::None)?;
+//   tester.assert_access_log(2, "GET / 200 alice");
+//
+// Lines are rendered when proxy_on_log returns: request headers as forwarded upstream (or as
+// the module left them when it answered), response as sent downstream, attributes as read on the
+// stream (see Tester::set_default_property), and times from the host clock. Missing values are
+// rendered as "-", as in Envoy.
+
+use crate::tester::Tester;
+use crate::trace::json_bytes;
+
+use anyhow::{bail, Result};
+use std::convert::TryInto;
+
+// Envoy's format when none is configured
+pub const DEFAULT_FORMAT: &str = "[%START_TIME%] \"%REQ(:METHOD)% %REQ(X-ENVOY-ORIGINAL-PATH?:PATH)% %PROTOCOL%\" %RESPONSE_CODE% %RESPONSE_FLAGS% %BYTES_RECEIVED% %BYTES_SENT% %DURATION% %RESP(X-ENVOY-UPSTREAM-SERVICE-TIME)% \"%REQ(X-FORWARDED-FOR)%\" \"%REQ(USER-AGENT)%\" \"%REQ(X-REQUEST-ID)%\" \"%REQ(:AUTHORITY)%\" \"%UPSTREAM_HOST%\"\n";
+
+// Attributes rendered by the commands of the same name, integers marked, and those rendered
+// without their port
+const ATTRIBUTES: [(&str, &[&str], bool); 14] = [
+    ("PROTOCOL", &["request", "protocol"], false),
+    (
+        "RESPONSE_CODE_DETAILS",
+        &["response", "code_details"],
+        false,
+    ),
+    (
+        "CONNECTION_TERMINATION_DETAILS",
+        &["connection", "termination_details"],
+        false,
+    ),
+    ("CONNECTION_ID", &["connection", "id"], true),
+    ("UPSTREAM_HOST", &["upstream", "address"], false),
+    ("UPSTREAM_CLUSTER", &["cluster_name"], false),
+    (
+        "UPSTREAM_LOCAL_ADDRESS",
+        &["upstream", "local_address"],
+        false,
+    ),
+    (
+        "UPSTREAM_TRANSPORT_FAILURE_REASON",
+        &["upstream", "transport_failure_reason"],
+        false,
+    ),
+    ("DOWNSTREAM_REMOTE_ADDRESS", &["source", "address"], false),
+    (
+        "DOWNSTREAM_DIRECT_REMOTE_ADDRESS",
+        &["source", "address"],
+        false,
+    ),
+    (
+        "DOWNSTREAM_LOCAL_ADDRESS",
+        &["destination", "address"],
+        false,
+    ),
+    (
+        "DOWNSTREAM_TLS_VERSION",
+        &["connection", "tls_version"],
+        false,
+    ),
+    (
+        "REQUESTED_SERVER_NAME",
+        &["connection", "requested_server_name"],
+        false,
+    ),
+    ("ROUTE_NAME", &["route_name"], false),
+];
+
+// Short names of the bits of response.flags, in Envoy's order
+const RESPONSE_FLAGS: [&str; 26] = [
+    "LH", "UH", "UT", "LR", "UR", "UF", "UC", "UO", "NR", "DI", "FI", "RL", "UAEX", "RLSE", "DC",
+    "URX", "SI", "IH", "DPE", "UMSDR", "RFCF", "NFCF", "DT", "UPE", "NC", "OM",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessLogFormat {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    // command and its length limit (:Z)
+    Command(Command, Option<usize>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    StartTime(String),
+    // header, alternative header
+    Request(String, Option<String>),
+    RequestWithoutQuery(String, Option<String>),
+    Response(String, Option<String>),
+    Trailer(String, Option<String>),
+    ResponseCode,
+    ResponseFlags,
+    BytesReceived,
+    BytesSent,
+    Duration,
+    GrpcStatusNumber,
+    // path, integer, without port
+    Attribute(&'static [&'static str], bool, bool),
+    // key, plain (rather than typed, i.e. JSON)
+    FilterState(String, bool),
+    DynamicMetadata(Vec<String>),
+    Environment(String),
+}
+
+impl AccessLogFormat {
+    // Envoy's format string, %COMMAND(ARGUMENTS):LENGTH% operators among plain text
+    pub fn parse(format: &str) -> Result<AccessLogFormat> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = format;
+        while let Some(start) = rest.find('%') {
+            text.push_str(&rest[..start]);
+            rest = &rest[start + 1..];
+            if let Some(after) = rest.strip_prefix('%') {
+                text.push('%');
+                rest = after;
+                continue;
+            }
+            let name_length = rest
+                .find(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+                .unwrap_or(rest.len());
+            let name = &rest[..name_length];
+            rest = &rest[name_length..];
+            // arguments may hold '%', e.g. START_TIME(%s)
+            let mut arguments = None;
+            if let Some(after) = rest.strip_prefix('(') {
+                let end = match after.find(')') {
+                    Some(end) => end,
+                    None => bail!("unterminated arguments of %{}% in {:?}", name, format),
+                };
+                arguments = Some(&after[..end]);
+                rest = &after[end + 1..];
+            }
+            let mut length = None;
+            if let Some(after) = rest.strip_prefix(':') {
+                let digits = after
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(after.len());
+                length = Some(after[..digits].parse()?);
+                rest = &after[digits..];
+            }
+            rest = match rest.strip_prefix('%') {
+                Some(after) => after,
+                None => bail!("unterminated %{} in {:?}", name, format),
+            };
+            if !text.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut text)));
+            }
+            parts.push(Part::Command(command(name, arguments)?, length));
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(AccessLogFormat { parts })
+    }
+
+    pub fn envoy_default() -> AccessLogFormat {
+        AccessLogFormat::parse(DEFAULT_FORMAT).unwrap()
+    }
+
+    // Line of the stream as it is now, see Tester::set_access_log_format for lines rendered at
+    // proxy_on_log
+    pub fn render(&self, tester: &Tester, context_id: i32) -> String {
+        let stream = Stream::new(tester, context_id);
+        let mut line = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => line.push_str(text),
+                Part::Command(command, length) => {
+                    let value = stream.render(command).filter(|value| !value.is_empty());
+                    let value = value.unwrap_or_else(|| "-".to_string());
+                    match length {
+                        Some(length) => line.extend(value.chars().take(*length)),
+                        None => line.push_str(&value),
+                    }
+                }
+            }
+        }
+        line
+    }
+}
+
+fn command(name: &str, arguments: Option<&str>) -> Result<Command> {
+    let headers = || -> Result<(String, Option<String>)> {
+        let arguments = match arguments {
+            Some(arguments) if !arguments.is_empty() => arguments.to_lowercase(),
+            _ => bail!("%{}% needs a header name", name),
+        };
+        Ok(match arguments.split_once('?') {
+            Some((header, alternative)) => (header.to_string(), Some(alternative.to_string())),
+            None => (arguments, None),
+        })
+    };
+    if let Some((_, path, integer)) = ATTRIBUTES.iter().find(|(command, ..)| *command == name) {
+        return Ok(Command::Attribute(path, *integer, false));
+    }
+    if let Some(name) = name.strip_suffix("_WITHOUT_PORT") {
+        if let Some((_, path, _)) = ATTRIBUTES.iter().find(|(command, ..)| *command == name) {
+            return Ok(Command::Attribute(path, false, true));
+        }
+    }
+    Ok(match name {
+        "START_TIME" => Command::StartTime(
+            arguments
+                .filter(|format| !format.is_empty())
+                .unwrap_or("%Y-%m-%dT%H:%M:%E3SZ")
+                .to_string(),
+        ),
+        "REQ" => {
+            let (header, alternative) = headers()?;
+            Command::Request(header, alternative)
+        }
+        "REQ_WITHOUT_QUERY" => {
+            let (header, alternative) = headers()?;
+            Command::RequestWithoutQuery(header, alternative)
+        }
+        "RESP" => {
+            let (header, alternative) = headers()?;
+            Command::Response(header, alternative)
+        }
+        "TRAILER" => {
+            let (header, alternative) = headers()?;
+            Command::Trailer(header, alternative)
+        }
+        "RESPONSE_CODE" => Command::ResponseCode,
+        "RESPONSE_FLAGS" => Command::ResponseFlags,
+        "BYTES_RECEIVED" => Command::BytesReceived,
+        "BYTES_SENT" => Command::BytesSent,
+        "DURATION" => Command::Duration,
+        "GRPC_STATUS_NUMBER" => Command::GrpcStatusNumber,
+        "FILTER_STATE" => match arguments {
+            Some(arguments) if !arguments.is_empty() => match arguments.rsplit_once(':') {
+                Some((key, "PLAIN")) => Command::FilterState(key.to_string(), true),
+                Some((key, "TYPED")) => Command::FilterState(key.to_string(), false),
+                _ => Command::FilterState(arguments.to_string(), false),
+            },
+            _ => bail!("%FILTER_STATE% needs a key"),
+        },
+        "DYNAMIC_METADATA" => match arguments.map(|arguments| arguments.split(':')) {
+            Some(path) if arguments.is_some_and(|arguments| arguments.contains(':')) => {
+                Command::DynamicMetadata(path.map(str::to_string).collect())
+            }
+            _ => bail!("%DYNAMIC_METADATA% needs a namespace and a key, e.g. (envoy.lb:canary)"),
+        },
+        "ENVIRONMENT" => match arguments {
+            Some(variable) if !variable.is_empty() => Command::Environment(variable.to_string()),
+            _ => bail!("%ENVIRONMENT% needs a variable"),
+        },
+        name => bail!("unsupported command %{}% in the access log format", name),
+    })
+}
+
+// What the commands render of a stream
+struct Stream<'a> {
+    tester: &'a Tester,
+    context_id: i32,
+    request: Vec<(String, String)>,
+    response: Vec<(String, String)>,
+    trailers: Vec<(String, String)>,
+    bytes_received: usize,
+    bytes_sent: usize,
+}
+
+impl<'a> Stream<'a> {
+    fn new(tester: &'a Tester, context_id: i32) -> Stream<'a> {
+        let request = tester.final_request(context_id);
+        let response = tester.final_response(context_id);
+        let request_headers = match &request {
+            Some(request) => request.headers.clone().into_pairs(),
+            None => tester
+                .get_settings_handle()
+                .staged
+                .get_local_reply(context_id)
+                .map(|local_reply| {
+                    let pairs = local_reply.request_headers.into_iter();
+                    pairs
+                        .map(|(name, value)| (name, String::from_utf8_lossy(&value).into_owned()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        let body_length = |body: Option<&crate::http::HttpBody>| match body {
+            Some(crate::http::HttpBody::Full(body)) => body.len(),
+            Some(crate::http::HttpBody::Chunked(chunks)) => chunks.iter().map(Vec::len).sum(),
+            None => 0,
+        };
+        Stream {
+            tester,
+            context_id,
+            bytes_received: body_length(request.as_ref().and_then(|r| r.body.as_ref())),
+            bytes_sent: body_length(response.as_ref().and_then(|r| r.body.as_ref())),
+            request: request_headers,
+            trailers: response
+                .as_ref()
+                .and_then(|response| response.trailers.clone())
+                .map(|trailers| trailers.into_pairs())
+                .unwrap_or_default(),
+            response: response
+                .map(|response| response.headers.into_pairs())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn render(&self, command: &Command) -> Option<String> {
+        let host = self.tester.get_settings_handle();
+        let host = &host.staged;
+        let (started, logged) = host.get_stream_times_nanos(self.context_id);
+        match command {
+            Command::StartTime(format) => Some(format_time(started?, format)),
+            Command::Request(header, alternative) => {
+                header_value(&self.request, header, alternative)
+            }
+            Command::RequestWithoutQuery(header, alternative) => {
+                let value = header_value(&self.request, header, alternative)?;
+                Some(value.split('?').next().unwrap_or_default().to_string())
+            }
+            Command::Response(header, alternative) => {
+                header_value(&self.response, header, alternative)
+            }
+            Command::Trailer(header, alternative) => {
+                header_value(&self.trailers, header, alternative)
+            }
+            Command::ResponseCode => Some(
+                header_value(&self.response, ":status", &None).unwrap_or_else(|| "0".to_string()),
+            ),
+            Command::ResponseFlags => {
+                let flags =
+                    integer(&host.get_context_property(self.context_id, &["response", "flags"])?)?;
+                let names: Vec<&str> = RESPONSE_FLAGS
+                    .iter()
+                    .enumerate()
+                    .filter(|(bit, _)| flags >> bit & 1 == 1)
+                    .map(|(_, name)| *name)
+                    .collect();
+                Some(names.join(","))
+            }
+            Command::BytesReceived => Some(self.bytes_received.to_string()),
+            Command::BytesSent => Some(self.bytes_sent.to_string()),
+            Command::Duration => {
+                let ended = logged.unwrap_or_else(|| host.get_current_time_nanos());
+                Some((ended.saturating_sub(started?) / 1_000_000).to_string())
+            }
+            Command::GrpcStatusNumber => header_value(&self.trailers, "grpc-status", &None)
+                .or_else(|| header_value(&self.response, "grpc-status", &None)),
+            Command::Attribute(path, is_integer, without_port) => {
+                let value = host.get_context_property(self.context_id, path)?;
+                if *is_integer {
+                    return integer(&value).map(|value| value.to_string());
+                }
+                let value = String::from_utf8_lossy(&value).into_owned();
+                match without_port {
+                    true => Some(strip_port(&value).to_string()),
+                    false => Some(value),
+                }
+            }
+            Command::FilterState(key, plain) => {
+                let value = host.get_filter_state(key)?;
+                match plain {
+                    true => Some(String::from_utf8_lossy(&value).into_owned()),
+                    false => {
+                        let mut json = String::new();
+                        json_bytes(&mut json, &value);
+                        Some(json)
+                    }
+                }
+            }
+            Command::DynamicMetadata(path) => {
+                let mut property = vec!["metadata", "filter_metadata"];
+                property.extend(path.iter().map(String::as_str));
+                let value = host.get_context_property(self.context_id, &property)?;
+                Some(metadata_value(&value))
+            }
+            Command::Environment(variable) => std::env::var(variable).ok(),
+        }
+    }
+}
+
+fn header_value(
+    headers: &[(String, String)],
+    header: &str,
+    alternative: &Option<String>,
+) -> Option<String> {
+    let find = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    };
+    find(header).or_else(|| find(alternative.as_deref()?))
+}
+
+fn integer(value: &[u8]) -> Option<i64> {
+    Some(i64::from_le_bytes(value.try_into().ok()?))
+}
+
+// Metadata values as set by Tester::set_dynamic_metadata: text, numbers (8 bytes) or booleans
+fn metadata_value(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(text) if text.chars().all(|c| !c.is_control()) => text.to_string(),
+        _ => match value.len() {
+            8 => f64::from_le_bytes(value.try_into().unwrap()).to_string(),
+            1 => (value[0] != 0).to_string(),
+            _ => String::from_utf8_lossy(value).into_owned(),
+        },
+    }
+}
+
+fn strip_port(address: &str) -> &str {
+    match address.rsplit_once(':') {
+        // [::1]:443
+        Some((host, _)) if host.starts_with('[') => {
+            host.trim_start_matches('[').trim_end_matches(']')
+        }
+        Some((host, _)) if !host.contains(':') => host,
+        _ => address,
+    }
+}
+
+// strftime-like formats of Envoy: %Y %m %d %H %M %S %j %s %z %Z, and %E<n>S or %<n>f for
+// fractions of seconds
+fn format_time(nanos: u64, format: &str) -> String {
+    let seconds = (nanos / 1_000_000_000) as i64;
+    let fraction = nanos % 1_000_000_000;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let of_day = seconds.rem_euclid(86400);
+    let day_of_year = seconds.div_euclid(86400) - days_before_year(year) + 1;
+    let mut formatted = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+        let mut digits = String::new();
+        let extended = chars.next_if_eq(&'E').is_some();
+        while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+            digits.push(digit);
+        }
+        let precision = digits.parse::<usize>().unwrap_or(9).min(9);
+        let fractional = format!("{:09}", fraction)[..precision].to_string();
+        match chars.next() {
+            Some('Y') => formatted.push_str(&format!("{:04}", year)),
+            Some('m') => formatted.push_str(&format!("{:02}", month)),
+            Some('d') => formatted.push_str(&format!("{:02}", day)),
+            Some('H') => formatted.push_str(&format!("{:02}", of_day / 3600)),
+            Some('M') => formatted.push_str(&format!("{:02}", of_day / 60 % 60)),
+            Some('S') if extended => {
+                formatted.push_str(&format!("{:02}", of_day % 60));
+                if precision > 0 {
+                    formatted.push('.');
+                    formatted.push_str(&fractional);
+                }
+            }
+            Some('S') => formatted.push_str(&format!("{:02}", of_day % 60)),
+            Some('f') => formatted.push_str(&fractional),
+            Some('j') => formatted.push_str(&format!("{:03}", day_of_year)),
+            Some('s') => formatted.push_str(&seconds.to_string()),
+            Some('z') => formatted.push_str("+0000"),
+            Some('Z') => formatted.push_str("UTC"),
+            Some('%') => formatted.push('%'),
+            Some(other) => {
+                formatted.push('%');
+                formatted.push(other);
+            }
+            None => formatted.push('%'),
+        }
+    }
+    formatted
+}
+
+// Date of the proleptic Gregorian calendar of a number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+fn days_before_year(year: i64) -> i64 {
+    let previous = year - 1;
+    365 * (year - 1970) + previous.div_euclid(4) - previous.div_euclid(100)
+        + previous.div_euclid(400)
+        - (1969 / 4 - 1969 / 100 + 1969 / 400)
+}
@@ -0,0 +1,194 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Failure bundles, to diagnose failures of remote or batch runs without rerunning them: with
+// PROXY_WASM_TEST_ARTIFACTS=<dir>, TestSetup::run captures the interaction of each test and,
+// when it fails, writes into <dir>/<test>/
+//
+//   manifest.json     test, module, vm_id, engine, seed and the files of the bundle
+//   failure.txt       error or panic message
+//   events.jsonl      callbacks, returns and host calls (see EventLog for the format)
+//   pending.txt       expectations left pending
+//   logs.jsonl        messages logged by the module, {"level":"warn","message":"..."} lines
+//   plugin_config     plugin and VM configurations, as given to the module
+//   vm_config
+//   envoy.yaml        Envoy configuration running the module the same way (see EnvoyConfig)
+//
+// Tests driving a Tester directly do the same with FailureArtifacts, e.g.
+//
+//   let artifacts = FailureArtifacts::capture(&mut tester);
+//   ...
+//   artifacts.write(&tester, "target/artifacts/my_test", &format!("{:#}", error))?;
+
+use crate::envoy::EnvoyConfig;
+use crate::runtime::{Engine, Runtime};
+use crate::tester::Tester;
+use crate::trace::json_bytes;
+use crate::types::{BufferType, LogLevel};
+
+use anyhow::{Context, Result};
+use std::any::Any;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Event log of a Tester kept in memory until a failure is written
+pub struct FailureArtifacts {
+    events: Arc<Mutex<Vec<u8>>>,
+}
+
+impl FailureArtifacts {
+    // Logs the events of the Tester from now on (replacing its event log, if any)
+    pub fn capture(tester: &mut Tester) -> FailureArtifacts {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        tester.set_event_log(SharedBuffer(events.clone()));
+        FailureArtifacts { events }
+    }
+
+    // Writes the bundle of the Tester as it is now into the directory, returns its path
+    pub fn write(
+        &self,
+        tester: &Tester,
+        directory: impl AsRef<Path>,
+        failure: &str,
+    ) -> Result<PathBuf> {
+        let directory = directory.as_ref();
+        fs::create_dir_all(directory)
+            .with_context(|| format!("cannot create {}", directory.display()))?;
+        let write = |name: &str, contents: &[u8]| {
+            let path = directory.join(name);
+            fs::write(&path, contents).with_context(|| format!("cannot write {}", path.display()))
+        };
+        let mut files = vec!["failure.txt", "events.jsonl", "pending.txt", "logs.jsonl"];
+        write("failure.txt", failure.as_bytes())?;
+        write("events.jsonl", &self.events.lock().unwrap())?;
+        write(
+            "pending.txt",
+            tester.get_expect_handle().staged.summary().as_bytes(),
+        )?;
+        let mut logs = String::new();
+        for entry in tester.logs().entries {
+            logs.push_str(r#"{"level":"#);
+            json_bytes(&mut logs, level_name(entry.level).as_bytes());
+            logs.push_str(r#","message":"#);
+            json_bytes(&mut logs, entry.message.as_bytes());
+            logs.push_str("}\n");
+        }
+        write("logs.jsonl", logs.as_bytes())?;
+        let (plugin_config, vm_config) = {
+            let host = tester.get_settings_handle();
+            (
+                host.staged
+                    .get_buffer_bytes(BufferType::PluginConfiguration as i32),
+                host.staged
+                    .get_buffer_bytes(BufferType::VmConfiguration as i32),
+            )
+        };
+        write("plugin_config", &plugin_config)?;
+        write("vm_config", &vm_config)?;
+        files.extend(["plugin_config", "vm_config"]);
+        // inline modules have no Envoy counterpart
+        if let Ok(config) = EnvoyConfig::from_tester(tester) {
+            config.save(directory.join("envoy.yaml"))?;
+            files.push("envoy.yaml");
+        }
+        let mut manifest = String::from(r#"{"test":"#);
+        json_bytes(&mut manifest, test_name().as_bytes());
+        manifest.push_str(r#","module":"#);
+        match tester.wasm_path() {
+            Some(wasm_path) => json_bytes(&mut manifest, wasm_path.as_bytes()),
+            None => manifest.push_str("null"),
+        }
+        manifest.push_str(r#","vm_id":"#);
+        json_bytes(&mut manifest, tester.vm_id().as_bytes());
+        manifest.push_str(r#","engine":"#);
+        json_bytes(&mut manifest, Engine::name().as_bytes());
+        manifest.push_str(&format!(r#","seed":{},"files":["#, tester.seed()));
+        for (index, file) in files.iter().chain(["manifest.json"].iter()).enumerate() {
+            if index > 0 {
+                manifest.push(',');
+            }
+            json_bytes(&mut manifest, file.as_bytes());
+        }
+        manifest.push_str("]}\n");
+        write("manifest.json", manifest.as_bytes())?;
+        Ok(directory.to_path_buf())
+    }
+}
+
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Captures the Tester of a TestSetup::run with PROXY_WASM_TEST_ARTIFACTS set
+pub(crate) fn capture_from_env(tester: &mut Tester) -> Option<FailureArtifacts> {
+    std::env::var_os("PROXY_WASM_TEST_ARTIFACTS")?;
+    Some(FailureArtifacts::capture(tester))
+}
+
+// Writes the bundle of a failed TestSetup::run into PROXY_WASM_TEST_ARTIFACTS, a failure to
+// write it is logged rather than hiding the failure of the test
+pub(crate) fn write_to_env(artifacts: &FailureArtifacts, tester: &Tester, failure: &str) -> String {
+    let directory = match std::env::var("PROXY_WASM_TEST_ARTIFACTS") {
+        Ok(directory) => Path::new(&directory).join(test_name()),
+        Err(_) => return String::new(),
+    };
+    // the host state may be left poisoned by the panic of the test
+    let written = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        artifacts.write(tester, &directory, failure)
+    }));
+    match written {
+        Ok(Ok(directory)) => format!("wrote the failure artifacts to {}", directory.display()),
+        Ok(Err(error)) => format!("cannot write the failure artifacts: {:#}", error),
+        Err(_) => "cannot write the failure artifacts: the host state is poisoned".to_string(),
+    }
+}
+
+// Message of a panic caught in a test
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "panicked".to_string(),
+    }
+}
+
+fn test_name() -> String {
+    match std::thread::current().name() {
+        Some("main") | None => "test".to_string(),
+        Some(name) => name.replace("::", "-"),
+    }
+}
+
+fn level_name(level: i32) -> &'static str {
+    match level {
+        level if level == LogLevel::Trace as i32 => "trace",
+        level if level == LogLevel::Debug as i32 => "debug",
+        level if level == LogLevel::Info as i32 => "info",
+        level if level == LogLevel::Warn as i32 => "warn",
+        level if level == LogLevel::Error as i32 => "error",
+        level if level == LogLevel::Critical as i32 => "critical",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Benchmarking of a module: one instance started and configured up front, then streams driven
+// through it over and over with every host call allowed and nothing checked, e.g. in a
+// criterion bench
+//
+//   let mut bench = TestSetup::new("filter.wasm").plugin_config("{}").bench()?;
+//   let request = HttpRequest::get("/").header("authorization", "token");
+//   c.bench_function("request", |b| b.iter(|| bench.request(&request).unwrap()));
+//
+// Each iteration runs on the same HTTP context id, created anew and deleted at the end (with
+// the logs of the module dropped), so that the host state does not grow with the iterations.
+
+use crate::http::{HttpRequest, HttpResponse};
+use crate::tester::{TestSetup, Tester, ROOT_CONTEXT};
+use crate::types::StreamOutcome;
+
+use anyhow::Result;
+
+pub struct Bench {
+    tester: Tester,
+    context_id: i32,
+}
+
+impl TestSetup {
+    // Started module for Bench, quiet and allowing every host call
+    pub fn bench(&self) -> Result<Bench> {
+        let mut setup = self.clone();
+        setup.mock_settings.quiet = true;
+        setup.mock_settings.allow_unexpected = true;
+        Ok(Bench::new(setup.start()?))
+    }
+}
+
+impl Bench {
+    pub(crate) fn new(tester: Tester) -> Bench {
+        Bench {
+            tester,
+            context_id: ROOT_CONTEXT + 1,
+        }
+    }
+
+    // The request through a fresh HTTP context, up to proxy_on_log
+    pub fn request(&mut self, request: &HttpRequest) -> Result<()> {
+        self.callback(|tester, context_id| {
+            tester.send_request(context_id, request.clone())?;
+            Ok(())
+        })
+    }
+
+    // Same as request(), with the response when the module let the request through
+    pub fn exchange(&mut self, request: &HttpRequest, response: &HttpResponse) -> Result<()> {
+        self.callback(|tester, context_id| {
+            tester
+                .send_request(context_id, request.clone())?
+                .execute_all()?;
+            if tester.stream_outcome(context_id) == StreamOutcome::Forwarded {
+                tester.send_response(context_id, response.clone())?;
+            }
+            Ok(())
+        })
+    }
+
+    // Callbacks staged by the closure (e.g. call_proxy_on_request_headers) on a fresh context,
+    // followed by proxy_on_log, proxy_on_done and proxy_on_delete
+    pub fn callback(&mut self, stage: impl FnOnce(&mut Tester, i32) -> Result<()>) -> Result<()> {
+        let context_id = self.context_id;
+        self.tester
+            .call_proxy_on_context_create(context_id, ROOT_CONTEXT);
+        stage(&mut self.tester, context_id)?;
+        self.tester
+            .call_proxy_on_log(context_id)
+            .call_proxy_on_done(context_id)
+            .call_proxy_on_delete(context_id)
+            .execute_all()?;
+        self.tester.get_settings_handle().staged.reset_logs();
+        Ok(())
+    }
+
+    // The started module, e.g. to stage host defaults or read metrics between runs
+    pub fn tester(&mut self) -> &mut Tester {
+        &mut self.tester
+    }
+}
@@ -0,0 +1,389 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Runs YAML/JSON scenario files (see src/scenario.rs) against a module, outside of any Rust
+// project, e.g.
+//
+//   proxy-wasm-test my_filter.wasm scenarios/
+//   proxy-wasm-test my_filter.wasm scenarios/ --filter deny --fail-fast
+//   proxy-wasm-test my_filter.wasm scenarios/ --list
+//   proxy-wasm-test my_filter.wasm scenarios/ --junit target/scenarios.xml
+//   proxy-wasm-test my_filter.wasm scenarios/ --format tap
+//   proxy-wasm-test my_filter.wasm scenarios/ --baseline my_filter-1.2.wasm
+//   proxy-wasm-test my_filter.wasm scenarios/ --envoy
+
+use anyhow::{bail, Context, Result};
+use proxy_wasm_test_framework::conformance::DockerEnvoy;
+use proxy_wasm_test_framework::junit::{catch, Outcome, Report, TestSuite};
+use proxy_wasm_test_framework::scenario::{Divergence, Scenario};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "proxy-wasm-test",
+    about = "Runs YAML/JSON test scenarios against a Proxy-Wasm module",
+    rename_all = "kebab-case"
+)]
+struct Args {
+    // module to run the scenarios against (the `wasm` of the files is ignored)
+    wasm_path: PathBuf,
+    // scenario files, or directories searched (recursively) for .yaml, .yml and .json files
+    #[structopt(required = true)]
+    scenarios: Vec<PathBuf>,
+    // only run the requests whose name (`<file>::<request>`) contains this
+    #[structopt(short = "f", long)]
+    filter: Option<String>,
+    // list the requests (after filtering) instead of running them
+    #[structopt(short = "l", long)]
+    list: bool,
+    // stop after the scenario of the first failing request
+    #[structopt(short = "x", long)]
+    fail_fast: bool,
+    // write the results as JUnit XML (a <testsuite> per scenario file)
+    #[structopt(long)]
+    junit: Option<PathBuf>,
+    // output format: pretty (like cargo test) or tap
+    #[structopt(long, default_value = "pretty")]
+    format: Format,
+    // compare the module with another build of it (e.g. the previous release) rather than
+    // checking the expectations: a request fails when the host calls it makes differ
+    #[structopt(long)]
+    baseline: Option<PathBuf>,
+    // compare the emulator with a real Envoy running the module (in docker, the image given by
+    // PROXY_WASM_TEST_ENVOY_IMAGE): a request fails when what reaches the upstream or the
+    // client differs
+    #[structopt(long, conflicts_with = "baseline")]
+    envoy: bool,
+    // silence the module's own logs
+    #[structopt(short = "q", long)]
+    quiet: bool,
+}
+
+fn main() {
+    let args = Args::from_args();
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(error) => {
+            eprintln!("Error: {:#}", error);
+            process::exit(2);
+        }
+    }
+}
+
+// Returns whether every scenario that ran has passed
+fn run(args: &Args) -> Result<bool> {
+    let wasm_path = fs::canonicalize(&args.wasm_path)
+        .with_context(|| format!("cannot find module {}", args.wasm_path.display()))?;
+    let baseline = match &args.baseline {
+        Some(baseline) => Some(
+            fs::canonicalize(baseline)
+                .with_context(|| format!("cannot find module {}", baseline.display()))?,
+        ),
+        None => None,
+    };
+    let mut files = Vec::new();
+    for path in &args.scenarios {
+        if path.is_dir() {
+            find_scenarios(path, path, &mut files)?;
+        } else if path.is_file() {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            files.push((name, path.clone()));
+        } else {
+            bail!("no such scenario file or directory: {}", path.display());
+        }
+    }
+
+    let mut filtered_out = 0;
+    let mut scenarios = Vec::new();
+    for (name, path) in files {
+        let mut scenario = Scenario::load(&path)?;
+        scenario.wasm = Some(wasm_path.to_string_lossy().into_owned());
+        scenario.quiet |= args.quiet;
+        let total = scenario.requests.len();
+        if let Some(filter) = &args.filter {
+            scenario
+                .requests
+                .retain(|request| request_name(&name, &request.name).contains(filter.as_str()));
+        }
+        filtered_out += total - scenario.requests.len();
+        if !scenario.requests.is_empty() {
+            scenarios.push((name, scenario));
+        }
+    }
+
+    if args.list {
+        for (name, scenario) in &scenarios {
+            for request in &scenario.requests {
+                println!("{}", request_name(name, &request.name));
+            }
+        }
+        return Ok(true);
+    }
+
+    let tests: usize = scenarios
+        .iter()
+        .map(|(_, scenario)| scenario.requests.len())
+        .sum();
+    let mut console = Console::new(args.format);
+    console.start(tests);
+    let mut report = Report::new("proxy-wasm-test");
+    for (name, scenario) in &scenarios {
+        let suite = match &baseline {
+            Some(baseline) => compare(
+                &mut console,
+                name,
+                scenario,
+                "host calls differ (- baseline, + module)",
+                || scenario.diff(&baseline.to_string_lossy(), &wasm_path.to_string_lossy()),
+            ),
+            None if args.envoy => compare(
+                &mut console,
+                name,
+                scenario,
+                "streams differ (- emulator, + Envoy)",
+                || scenario.conformance(&DockerEnvoy::from_env()),
+            ),
+            None => check(&mut console, name, scenario),
+        };
+        report.add_suite(suite);
+        if args.fail_fast && console.failed() {
+            break;
+        }
+    }
+
+    if let Some(path) = &args.junit {
+        report.save(path)?;
+    }
+    console.finish(filtered_out);
+    Ok(!console.failed())
+}
+
+// Runs the requests of the scenario, checking their expectations
+fn check(console: &mut Console, name: &str, scenario: &Scenario) -> TestSuite {
+    let mut suite = TestSuite::new(name);
+    let started = Instant::now();
+    let mut tester = match catch(|| scenario.start()) {
+        Ok(tester) => Some(tester),
+        Err(reason) => {
+            let outcome = Outcome::Failed(reason);
+            console.result(&request_name(name, "(setup)"), &outcome);
+            suite.add("(setup)", started.elapsed(), outcome);
+            None
+        }
+    };
+    for (index, request) in scenario.requests.iter().enumerate() {
+        let test = request_name(name, &request.name);
+        // a failure leaves the module mid-request, the following ones cannot be trusted
+        let (time, outcome) = match tester.as_mut() {
+            Some(current) => {
+                let started = Instant::now();
+                match catch(|| scenario.run_request(current, index)) {
+                    Ok(()) => (started.elapsed(), Outcome::Passed),
+                    Err(reason) => {
+                        tester = None;
+                        (started.elapsed(), Outcome::Failed(reason))
+                    }
+                }
+            }
+            None => {
+                let reason = "not run after an earlier failure in the scenario";
+                (Duration::ZERO, Outcome::Skipped(reason.into()))
+            }
+        };
+        console.result(&test, &outcome);
+        suite.add(&request.name, time, outcome);
+    }
+    suite
+}
+
+// Runs the requests of the scenario both ways (two builds, or the emulator and Envoy), failing
+// those that diverge
+fn compare(
+    console: &mut Console,
+    name: &str,
+    scenario: &Scenario,
+    differ: &str,
+    divergences: impl FnOnce() -> Result<Vec<Divergence>>,
+) -> TestSuite {
+    let mut suite = TestSuite::new(name);
+    let started = Instant::now();
+    let divergences = match catch(divergences) {
+        Ok(divergences) => divergences,
+        Err(reason) => {
+            let outcome = Outcome::Failed(reason);
+            console.result(&request_name(name, "(setup)"), &outcome);
+            suite.add("(setup)", started.elapsed(), outcome);
+            return suite;
+        }
+    };
+    let diverged = |request: &str| -> Option<&Divergence> {
+        divergences
+            .iter()
+            .find(|divergence| divergence.request == request)
+    };
+    let failed =
+        |divergence: &Divergence| Outcome::Failed(format!("{}:\n{}", differ, divergence.diff));
+    if let Some(divergence) = diverged("(setup)") {
+        let outcome = failed(divergence);
+        console.result(&request_name(name, "(setup)"), &outcome);
+        suite.add("(setup)", started.elapsed(), outcome);
+    }
+    for request in &scenario.requests {
+        let outcome = match diverged(&request.name) {
+            Some(divergence) => failed(divergence),
+            None => Outcome::Passed,
+        };
+        console.result(&request_name(name, &request.name), &outcome);
+        suite.add(&request.name, Duration::ZERO, outcome);
+    }
+    suite
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Pretty,
+    Tap,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Format, String> {
+        match format {
+            "pretty" => Ok(Format::Pretty),
+            "tap" => Ok(Format::Tap),
+            _ => Err(format!("unknown format {:?} (pretty or tap)", format)),
+        }
+    }
+}
+
+// Results as they come on stdout, either like cargo test or as TAP (version 13, with the
+// plan at the end since setup failures add test points); the host logs interleaved with the
+// TAP lines are ignored by TAP consumers
+struct Console {
+    format: Format,
+    count: usize,
+    passed: usize,
+    skipped: usize,
+    failures: Vec<(String, String)>,
+}
+
+impl Console {
+    fn new(format: Format) -> Console {
+        Console {
+            format,
+            count: 0,
+            passed: 0,
+            skipped: 0,
+            failures: Vec::new(),
+        }
+    }
+
+    fn start(&self, tests: usize) {
+        match self.format {
+            Format::Pretty => println!("running {} tests", tests),
+            Format::Tap => println!("TAP version 13"),
+        }
+    }
+
+    fn result(&mut self, test: &str, outcome: &Outcome) {
+        self.count += 1;
+        match (self.format, outcome) {
+            (Format::Pretty, Outcome::Passed) => println!("test {} ... ok", test),
+            (Format::Pretty, Outcome::Failed(_)) => println!("test {} ... FAILED", test),
+            (Format::Pretty, Outcome::Skipped(_)) => println!("test {} ... skipped", test),
+            (Format::Tap, Outcome::Passed) => println!("ok {} - {}", self.count, tap_escape(test)),
+            (Format::Tap, Outcome::Failed(reason)) => {
+                println!("not ok {} - {}", self.count, tap_escape(test));
+                println!("  ---");
+                println!("  message: |");
+                for line in reason.lines() {
+                    println!("    {}", line);
+                }
+                println!("  ...");
+            }
+            (Format::Tap, Outcome::Skipped(reason)) => {
+                println!("ok {} - {} # SKIP {}", self.count, tap_escape(test), reason)
+            }
+        }
+        match outcome {
+            Outcome::Passed => self.passed += 1,
+            Outcome::Failed(reason) => self.failures.push((test.to_string(), reason.clone())),
+            Outcome::Skipped(_) => self.skipped += 1,
+        }
+    }
+
+    fn failed(&self) -> bool {
+        !self.failures.is_empty()
+    }
+
+    fn finish(&self, filtered_out: usize) {
+        let summary = format!(
+            "{} passed; {} failed; {} skipped; {} filtered out",
+            self.passed,
+            self.failures.len(),
+            self.skipped,
+            filtered_out
+        );
+        match self.format {
+            Format::Pretty => {
+                if self.failed() {
+                    println!("\nfailures:");
+                    for (test, reason) in &self.failures {
+                        println!("    {}: {}", test, reason);
+                    }
+                }
+                let result = if self.failed() { "FAILED" } else { "ok" };
+                println!("\nresult: {}. {}", result, summary);
+            }
+            Format::Tap => {
+                println!("1..{}", self.count);
+                println!("# {}", summary);
+            }
+        }
+    }
+}
+
+// `#` starts a directive in a TAP description
+fn tap_escape(test: &str) -> String {
+    test.replace('\\', "\\\\").replace('#', "\\#")
+}
+
+fn request_name(scenario: &str, request: &str) -> String {
+    format!("{}::{}", scenario, request)
+}
+
+// Collects the scenario files under dir, named by their path relative to root, in order
+fn find_scenarios(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            find_scenarios(root, &path, files)?;
+        } else if let Some("yaml" | "yml" | "json") = path.extension().and_then(|ext| ext.to_str())
+        {
+            let name = path.strip_prefix(root).unwrap_or(&path);
+            files.push((name.to_string_lossy().into_owned(), path.clone()));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,204 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Builds the plugin crate before the tests run against it, so that they never test a stale
+// .wasm file, e.g.
+//
+//   let wasm = build::build_wasm("../my-filter")?;
+//   let tester = Tester::builder().wasm_file(wasm.to_str().unwrap()).build()?;
+//
+// cargo is run once per crate, target, profile and features in a test binary (the tests
+// running in parallel wait for it), later calls return the artifact of that build.
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    static ref BUILDS: Mutex<HashMap<WasmBuild, Arc<Mutex<Option<PathBuf>>>>> =
+        Mutex::new(HashMap::new());
+}
+
+// cargo build --target wasm32-wasip1 --release of the crate in the directory
+pub fn build_wasm(crate_dir: impl AsRef<Path>) -> Result<PathBuf> {
+    WasmBuild::new(crate_dir).build()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WasmBuild {
+    pub crate_dir: PathBuf,
+    pub target: String,
+    pub release: bool,
+    pub features: Vec<String>,
+    // package of a workspace, the only cdylib of the crate otherwise
+    pub package: Option<String>,
+    // instrumented for source coverage (see the coverage module)
+    pub coverage: bool,
+}
+
+impl WasmBuild {
+    pub fn new(crate_dir: impl AsRef<Path>) -> WasmBuild {
+        WasmBuild {
+            crate_dir: crate_dir.as_ref().to_path_buf(),
+            target: "wasm32-wasip1".to_string(),
+            release: true,
+            features: Vec::new(),
+            package: None,
+            coverage: false,
+        }
+    }
+
+    pub fn target(mut self, target: &str) -> WasmBuild {
+        self.target = target.to_string();
+        self
+    }
+
+    pub fn release(mut self, release: bool) -> WasmBuild {
+        self.release = release;
+        self
+    }
+
+    pub fn feature(mut self, feature: &str) -> WasmBuild {
+        self.features.push(feature.to_string());
+        self
+    }
+
+    pub fn package(mut self, package: &str) -> WasmBuild {
+        self.package = Some(package.to_string());
+        self
+    }
+
+    // Built with -Cinstrument-coverage (unstable flags allowed on stable with RUSTC_BOOTSTRAP)
+    // into target/coverage, so that it does not rebuild the uninstrumented module; the plugin
+    // must link minicov and export its counters
+    pub fn coverage(mut self, coverage: bool) -> WasmBuild {
+        self.coverage = coverage;
+        self
+    }
+
+    // Path of the built .wasm file
+    pub fn build(&self) -> Result<PathBuf> {
+        let build = BUILDS
+            .lock()
+            .unwrap()
+            .entry(self.clone())
+            .or_default()
+            .clone();
+        let mut artifact = build.lock().unwrap();
+        if let Some(path) = artifact.as_ref() {
+            return Ok(path.clone());
+        }
+        let path = self.run_cargo()?;
+        *artifact = Some(path.clone());
+        Ok(path)
+    }
+
+    fn run_cargo(&self) -> Result<PathBuf> {
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+        let mut command = Command::new(cargo);
+        command.current_dir(&self.crate_dir).args([
+            "build",
+            "--message-format=json-render-diagnostics",
+            "--target",
+            &self.target,
+        ]);
+        if self.release {
+            command.arg("--release");
+        }
+        if !self.features.is_empty() {
+            command.args(["--features", &self.features.join(",")]);
+        }
+        if let Some(package) = &self.package {
+            command.args(["--package", package]);
+        }
+        if self.coverage {
+            let rustflags = std::env::var("RUSTFLAGS").unwrap_or_default();
+            command
+                .args(["--target-dir", "target/coverage"])
+                .env("RUSTC_BOOTSTRAP", "1")
+                .env(
+                    "RUSTFLAGS",
+                    format!("{} -Cinstrument-coverage -Zno-profiler-runtime", rustflags)
+                        .trim_start(),
+                );
+        }
+        let output = command
+            .output()
+            .with_context(|| format!("cannot run cargo in {}", self.crate_dir.display()))?;
+        if !output.status.success() {
+            bail!(
+                "cargo build --target {} failed in {}:\n{}",
+                self.target,
+                self.crate_dir.display(),
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let artifacts: Vec<String> = stdout
+            .lines()
+            .filter(|line| line.contains(r#""reason":"compiler-artifact""#))
+            .filter(|line| match &self.package {
+                Some(package) => line.contains(&format!(r#""name":"{}""#, package)),
+                None => true,
+            })
+            .flat_map(wasm_filenames)
+            .collect();
+        match artifacts.as_slice() {
+            [artifact] => Ok(PathBuf::from(artifact)),
+            [] => bail!(
+                "cargo built no .wasm file in {} (is the crate a cdylib?)",
+                self.crate_dir.display()
+            ),
+            _ => bail!(
+                "cargo built several .wasm files in {}, select one with package(): {:?}",
+                self.crate_dir.display(),
+                artifacts
+            ),
+        }
+    }
+}
+
+// .wasm files among the "filenames" of a compiler-artifact message
+fn wasm_filenames(message: &str) -> Vec<String> {
+    let filenames = match message.find(r#""filenames":["#) {
+        Some(start) => &message[start + r#""filenames":["#.len()..],
+        None => return Vec::new(),
+    };
+    let mut names = Vec::new();
+    let mut chars = filenames.chars();
+    let mut name = String::new();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (false, ']') => break,
+            (false, '"') => quoted = true,
+            (true, '"') => {
+                quoted = false;
+                names.push(std::mem::take(&mut name));
+            }
+            (true, '\\') => {
+                if let Some(escaped) = chars.next() {
+                    name.push(escaped);
+                }
+            }
+            (true, c) => name.push(c),
+            _ => {}
+        }
+    }
+    names.retain(|name| name.ends_with(".wasm"));
+    names
+}
@@ -0,0 +1,159 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Table-driven tests: requests run against one started module, each on a fresh HTTP context,
+// with the outcome of every case reported together, e.g.
+//
+//   TestSetup::new("filter.wasm").run_cases(vec![
+//       Case::new("anonymous", HttpRequest::get("/"))
+//           .returns(vec![ReturnType::Action(Action::Pause)])
+//           .expect(|tester| { tester.expect_send_local_response(403, Any, Any, Any); }),
+//       Case::new("authenticated", HttpRequest::get("/").header("authorization", "t")),
+//   ])?
+
+use crate::http::{HttpBody, HttpRequest};
+use crate::junit::{catch, Outcome, TestSuite};
+use crate::tester::{default_verbosity, log_dispatch, with_log, TestSetup, Tester, ROOT_CONTEXT};
+use crate::types::{Action, ReturnType};
+
+use anyhow::{bail, Result};
+use std::time::Instant;
+use tracing::info;
+
+pub struct Case {
+    pub name: String,
+    pub request: HttpRequest,
+    // one per request callback, Continue for every callback when not given
+    pub returns: Option<Vec<ReturnType>>,
+    pub expect: Option<Staging>,
+}
+
+type Staging = Box<dyn Fn(&mut Tester)>;
+
+impl Case {
+    pub fn new(name: &str, request: HttpRequest) -> Case {
+        Case {
+            name: name.to_string(),
+            request,
+            returns: None,
+            expect: None,
+        }
+    }
+
+    pub fn returns(mut self, returns: Vec<ReturnType>) -> Case {
+        self.returns = Some(returns);
+        self
+    }
+
+    // Stages the host calls expected while the request goes through the module
+    pub fn expect(mut self, expect: impl Fn(&mut Tester) + 'static) -> Case {
+        self.expect = Some(Box::new(expect));
+        self
+    }
+
+    fn run(&self, tester: &mut Tester, context_id: i32) -> Result<()> {
+        tester
+            .call_proxy_on_context_create(context_id, ROOT_CONTEXT)
+            .execute_and_expect(ReturnType::None)?;
+        tester.send_request(context_id, self.request.clone())?;
+        if let Some(expect) = &self.expect {
+            expect(tester);
+        }
+        let returns = match &self.returns {
+            Some(returns) => returns.clone(),
+            None => (0..callbacks(&self.request))
+                .map(|_| ReturnType::Action(Action::Continue))
+                .collect(),
+        };
+        tester.execute_and_expect_n(returns)?;
+        tester.assert_finished();
+        Ok(())
+    }
+}
+
+impl TestSetup {
+    // Runs every case, failing with the list of the failed ones
+    pub fn run_cases(&self, cases: Vec<Case>) -> Result<()> {
+        let suite = self.run_cases_report(&cases);
+        let failures: Vec<String> = suite
+            .cases
+            .iter()
+            .filter_map(|case| match &case.outcome {
+                Outcome::Failed(reason) => Some(format!("  {}: {}", case.name, reason)),
+                _ => None,
+            })
+            .collect();
+        if !failures.is_empty() {
+            bail!(
+                "{} of {} cases failed:\n{}",
+                failures.len(),
+                suite.cases.len(),
+                failures.join("\n")
+            );
+        }
+        Ok(())
+    }
+
+    // Outcome of every case (e.g. for Report), a failed case leaves the module mid-request so
+    // the next ones run on a newly started module
+    pub fn run_cases_report(&self, cases: &[Case]) -> TestSuite {
+        let mut suite = TestSuite::new(&self.mock_settings.wasm_path);
+        let mut tester = None;
+        for (index, case) in cases.iter().enumerate() {
+            let started = Instant::now();
+            let result = catch(|| {
+                let current = match tester.as_mut() {
+                    Some(current) => current,
+                    None => tester.insert(self.start()?),
+                };
+                case.run(current, ROOT_CONTEXT + 1 + index as i32)
+            });
+            // output of the tester the case ran on, at the default verbosity when none started
+            let log = match tester.as_ref() {
+                Some(current) => current.log().clone(),
+                None => log_dispatch(default_verbosity(self.mock_settings.quiet)),
+            };
+            let outcome = match result {
+                Ok(()) => Outcome::Passed,
+                Err(reason) => {
+                    tester = None;
+                    Outcome::Failed(reason)
+                }
+            };
+            with_log(&log, || {
+                info!(
+                    "[case] {} ... {}",
+                    case.name,
+                    match outcome {
+                        Outcome::Passed => "ok",
+                        _ => "FAILED",
+                    }
+                )
+            });
+            suite.add(&case.name, started.elapsed(), outcome);
+        }
+        suite
+    }
+}
+
+// Callbacks the combination calls run for a request
+fn callbacks(request: &HttpRequest) -> usize {
+    let body = match &request.body {
+        Some(HttpBody::Full(_)) => 1,
+        // stage_body_chunks delivers a lone end of stream chunk for an empty body
+        Some(HttpBody::Chunked(chunks)) => chunks.len().max(1),
+        None => 0,
+    };
+    1 + body + request.trailers.is_some() as usize
+}
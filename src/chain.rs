@@ -0,0 +1,389 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Several filters on one stream, the way the proxy chains them, e.g. an auth filter in front of
+// a header-rewrite filter:
+//
+//   let auth = TestSetup::new("auth.wasm");
+//   let rewrite = TestSetup::new("rewrite.wasm");
+//   let mut chain = FilterChain::start(vec![auth, rewrite])?;
+//   chain.create_http_context(2)?;
+//   let request = HttpRequest::get("/api").header("authorization", "Bearer ...");
+//   let upstream = chain.send_request(2, request)?;
+//   assert_eq!(upstream.headers.unwrap().get("x-user"), Some("alice"));
+//
+// Each filter is a Tester of its own, what a callback leaves in its host settings (headers added,
+// body replaced...) is what the next filter receives. Requests go through the filters in order,
+// responses in reverse order. A local response ends the stream; a filter pausing it, without
+// resuming it before the callback returns (e.g. from the response to its http_call), holds the
+// later callbacks: they reach that filter but none after it. Bodies go through in one piece.
+
+use crate::host_settings::StreamAction;
+use crate::http::{HeaderMap, HttpBody, HttpRequest, HttpResponse};
+use crate::tester::{TestSetup, Tester, ROOT_CONTEXT};
+use crate::types::{Action, BufferType, Bytes, MapType, ReturnType};
+
+use anyhow::Result;
+
+pub struct FilterChain {
+    filters: Vec<Tester>,
+}
+
+// What came out of the chain: to the upstream for a request, to the downstream for a response
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChainOutcome {
+    pub headers: Option<HeaderMap>,
+    pub body: Option<Bytes>,
+    pub trailers: Option<HeaderMap>,
+    // index of the filter holding the stream
+    pub paused_at: Option<usize>,
+    pub local_response: Option<LocalResponse>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalResponse {
+    pub filter: usize,
+    pub status_code: u32,
+    pub body: Bytes,
+    pub headers: HeaderMap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Direction {
+    Request = 0,
+    Response = 1,
+}
+
+enum Step {
+    Continue,
+    Paused,
+    LocalResponse(LocalResponse),
+}
+
+// How far one callback went down the chain
+enum Passed {
+    Through,
+    // position (in the chain order) of the filter that paused the stream
+    PausedAt(usize),
+    LocalResponse(LocalResponse),
+}
+
+impl FilterChain {
+    // Filters in request order, already started (see TestSetup::start)
+    pub fn new(filters: Vec<Tester>) -> FilterChain {
+        FilterChain { filters }
+    }
+
+    pub fn start(setups: Vec<TestSetup>) -> Result<FilterChain> {
+        let filters = setups
+            .iter()
+            .map(TestSetup::start)
+            .collect::<Result<Vec<Tester>>>()?;
+        Ok(FilterChain::new(filters))
+    }
+
+    // The Tester running the filter, e.g. to stage expectations or mock upstreams on it
+    pub fn filter(&mut self, index: usize) -> &mut Tester {
+        &mut self.filters[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub fn create_http_context(&mut self, context_id: i32) -> Result<&mut Self> {
+        for tester in &mut self.filters {
+            tester
+                .call_proxy_on_context_create(context_id, ROOT_CONTEXT)
+                .execute_and_expect(ReturnType::None)?;
+        }
+        Ok(self)
+    }
+
+    pub fn send_request(&mut self, context_id: i32, request: HttpRequest) -> Result<ChainOutcome> {
+        self.send(
+            context_id,
+            Direction::Request,
+            request.headers,
+            request.body,
+            request.trailers,
+        )
+    }
+
+    pub fn send_response(
+        &mut self,
+        context_id: i32,
+        response: HttpResponse,
+    ) -> Result<ChainOutcome> {
+        self.send(
+            context_id,
+            Direction::Response,
+            response.headers,
+            response.body,
+            response.trailers,
+        )
+    }
+
+    fn send(
+        &mut self,
+        context_id: i32,
+        direction: Direction,
+        headers: HeaderMap,
+        body: Option<HttpBody>,
+        trailers: Option<HeaderMap>,
+    ) -> Result<ChainOutcome> {
+        let mut order: Vec<usize> = (0..self.filters.len()).collect();
+        if direction == Direction::Response {
+            order.reverse();
+        }
+        let body = body.map(|body| match body {
+            HttpBody::Full(body) => body,
+            HttpBody::Chunked(chunks) => chunks.concat(),
+        });
+        let mut outcome = ChainOutcome::default();
+        let first = match order.first() {
+            Some(first) => &self.filters[*first],
+            None => return Ok(outcome),
+        };
+        let mut headers = to_data(&first.with_content_length(headers.pairs(), body.as_deref()));
+        let trailers = trailers.map(|trailers| to_data(&trailers.into_pairs()));
+        // filters the stream still reaches, cut short by a pause
+        let mut reach = order.len();
+
+        let end_of_stream = body.is_none() && trailers.is_none();
+        let stage = Stage::Headers(end_of_stream);
+        match self.pass(&order[..reach], context_id, direction, stage, &mut headers)? {
+            Passed::Through => outcome.headers = Some(from_data(&headers)),
+            Passed::PausedAt(position) => {
+                reach = position + 1;
+                outcome.paused_at = Some(order[position]);
+            }
+            Passed::LocalResponse(local_response) => {
+                outcome.local_response = Some(local_response);
+                return Ok(outcome);
+            }
+        }
+
+        if let Some(mut body_data) = body {
+            let stage = Stage::Body(trailers.is_none());
+            match self.pass_body(
+                &order[..reach],
+                context_id,
+                direction,
+                stage,
+                &mut body_data,
+            )? {
+                Passed::Through if outcome.paused_at.is_none() => outcome.body = Some(body_data),
+                Passed::Through => {}
+                Passed::PausedAt(position) => {
+                    reach = position + 1;
+                    outcome.paused_at.get_or_insert(order[position]);
+                }
+                Passed::LocalResponse(local_response) => {
+                    outcome.local_response = Some(local_response);
+                    return Ok(outcome);
+                }
+            }
+        }
+
+        if let Some(mut trailers_data) = trailers {
+            let stage = Stage::Trailers;
+            match self.pass(
+                &order[..reach],
+                context_id,
+                direction,
+                stage,
+                &mut trailers_data,
+            )? {
+                Passed::Through if outcome.paused_at.is_none() => {
+                    outcome.trailers = Some(from_data(&trailers_data))
+                }
+                Passed::Through => {}
+                Passed::PausedAt(position) => {
+                    outcome.paused_at.get_or_insert(order[position]);
+                }
+                Passed::LocalResponse(local_response) => {
+                    outcome.local_response = Some(local_response);
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    // Runs the headers (or trailers) through the filters, as each filter leaves them
+    fn pass(
+        &mut self,
+        order: &[usize],
+        context_id: i32,
+        direction: Direction,
+        stage: Stage,
+        header_map: &mut Vec<(String, Bytes)>,
+    ) -> Result<Passed> {
+        let map_type = stage.map_type(direction);
+        for (position, index) in order.iter().enumerate() {
+            let tester = &mut self.filters[*index];
+            tester
+                .get_settings_handle()
+                .staged
+                .set_header_map_data(map_type as i32, header_map.clone());
+            let num_headers = header_map.len() as i32;
+            stage.call(tester, context_id, direction, num_headers);
+            let step = self.step(*index, context_id, direction)?;
+            *header_map = self.filters[*index]
+                .get_settings_handle()
+                .staged
+                .get_header_map_data(map_type as i32);
+            match step {
+                Step::Continue => {}
+                Step::Paused => return Ok(Passed::PausedAt(position)),
+                Step::LocalResponse(local_response) => {
+                    return Ok(Passed::LocalResponse(local_response))
+                }
+            }
+        }
+        Ok(Passed::Through)
+    }
+
+    fn pass_body(
+        &mut self,
+        order: &[usize],
+        context_id: i32,
+        direction: Direction,
+        stage: Stage,
+        body: &mut Bytes,
+    ) -> Result<Passed> {
+        let buffer_type = match direction {
+            Direction::Request => BufferType::HttpRequestBody,
+            Direction::Response => BufferType::HttpResponseBody,
+        };
+        for (position, index) in order.iter().enumerate() {
+            let tester = &mut self.filters[*index];
+            tester
+                .get_settings_handle()
+                .staged
+                .set_buffer_data(buffer_type as i32, body.clone());
+            let body_size = body.len() as i32;
+            stage.call(tester, context_id, direction, body_size);
+            let step = self.step(*index, context_id, direction)?;
+            *body = self.filters[*index]
+                .get_settings_handle()
+                .staged
+                .get_buffer_bytes(buffer_type as i32);
+            match step {
+                Step::Continue => {}
+                Step::Paused => return Ok(Passed::PausedAt(position)),
+                Step::LocalResponse(local_response) => {
+                    return Ok(Passed::LocalResponse(local_response))
+                }
+            }
+        }
+        Ok(Passed::Through)
+    }
+
+    // Executes the callback staged on the filter and tells what became of the stream
+    fn step(&mut self, index: usize, context_id: i32, direction: Direction) -> Result<Step> {
+        let tester = &mut self.filters[index];
+        tester.toggle_strict_mode(false);
+        let returned = tester.execute_all()?.last().copied().flatten();
+        let actions = tester.get_settings_handle().staged.take_stream_actions();
+        let mut resumed = false;
+        for action in actions {
+            match action {
+                StreamAction::LocalResponse {
+                    context_id: sent_on,
+                    status_code,
+                    body,
+                    headers,
+                } if sent_on == context_id => {
+                    return Ok(Step::LocalResponse(LocalResponse {
+                        filter: index,
+                        status_code: status_code as u32,
+                        body,
+                        headers: from_data(&headers),
+                    }))
+                }
+                StreamAction::Continue {
+                    context_id: resumed_on,
+                    stream_type,
+                } if resumed_on == context_id && stream_type == direction as i32 => resumed = true,
+                _ => {}
+            }
+        }
+        match returned {
+            Some(action) if action == Action::Pause as i32 && !resumed => Ok(Step::Paused),
+            _ => Ok(Step::Continue),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Stage {
+    // end_of_stream
+    Headers(bool),
+    Body(bool),
+    Trailers,
+}
+
+impl Stage {
+    fn map_type(self, direction: Direction) -> MapType {
+        match (direction, self) {
+            (Direction::Request, Stage::Trailers) => MapType::HttpRequestTrailers,
+            (Direction::Request, _) => MapType::HttpRequestHeaders,
+            (Direction::Response, Stage::Trailers) => MapType::HttpResponseTrailers,
+            (Direction::Response, _) => MapType::HttpResponseHeaders,
+        }
+    }
+
+    // Stages the callback of this stage, size being the number of headers or the body size
+    fn call(self, tester: &mut Tester, context_id: i32, direction: Direction, size: i32) {
+        match (direction, self) {
+            (Direction::Request, Stage::Headers(end_of_stream)) => {
+                tester.call_proxy_on_request_headers(context_id, size, end_of_stream)
+            }
+            (Direction::Request, Stage::Body(end_of_stream)) => {
+                tester.call_proxy_on_request_body(context_id, size, end_of_stream)
+            }
+            (Direction::Request, Stage::Trailers) => {
+                tester.call_proxy_on_request_trailers(context_id, size)
+            }
+            (Direction::Response, Stage::Headers(end_of_stream)) => {
+                tester.call_proxy_on_response_headers(context_id, size, end_of_stream)
+            }
+            (Direction::Response, Stage::Body(end_of_stream)) => {
+                tester.call_proxy_on_response_body(context_id, size, end_of_stream)
+            }
+            (Direction::Response, Stage::Trailers) => {
+                tester.call_proxy_on_response_trailers(context_id, size)
+            }
+        };
+    }
+}
+
+fn to_data(header_map_pairs: &[(String, String)]) -> Vec<(String, Bytes)> {
+    header_map_pairs
+        .iter()
+        .map(|(key, value)| (key.clone(), value.as_bytes().to_vec()))
+        .collect()
+}
+
+fn from_data(header_map_data: &[(String, Bytes)]) -> HeaderMap {
+    header_map_data
+        .iter()
+        .map(|(key, value)| (key.as_str(), String::from_utf8_lossy(value)))
+        .collect()
+}
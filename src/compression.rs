@@ -0,0 +1,112 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::matchers::{Matcher, Matches};
+use crate::types::Bytes;
+
+use anyhow::Result;
+use std::io::{Read, Write};
+
+// Content codings supported by the body helpers
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    // Value of the content-encoding header
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "br" => Some(Encoding::Brotli),
+            _ => None,
+        }
+    }
+}
+
+pub fn encode(encoding: Encoding, data: &[u8]) -> Bytes {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+        // "deflate" in HTTP is the zlib format
+        Encoding::Deflate => {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+        Encoding::Brotli => {
+            let mut encoded = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                encoder.write_all(data).unwrap();
+            }
+            encoded
+        }
+    }
+}
+
+pub fn decode(encoding: Encoding, data: &[u8]) -> Result<Bytes> {
+    let mut decoded = Vec::new();
+    match encoding {
+        Encoding::Gzip => flate2::read::GzDecoder::new(data).read_to_end(&mut decoded)?,
+        Encoding::Deflate => flate2::read::ZlibDecoder::new(data).read_to_end(&mut decoded)?,
+        Encoding::Brotli => brotli::Decompressor::new(data, 4096).read_to_end(&mut decoded)?,
+    };
+    Ok(decoded)
+}
+
+// Matches compressed bodies whose decoded content matches the inner matcher, e.g.
+// expect_set_buffer_bytes(Some(BufferType::HttpResponseBody), Decompressed::new(Encoding::Gzip, "hello"))
+#[derive(Debug, Clone)]
+pub struct Decompressed {
+    encoding: Encoding,
+    matcher: Matches<[u8]>,
+}
+
+impl Decompressed {
+    pub fn new(encoding: Encoding, matcher: impl Into<Matches<[u8]>>) -> Decompressed {
+        Decompressed {
+            encoding,
+            matcher: matcher.into(),
+        }
+    }
+}
+
+impl Matcher<[u8]> for Decompressed {
+    fn matches(&self, value: &[u8]) -> bool {
+        decode(self.encoding, value).is_ok_and(|decoded| self.matcher.matches(&decoded))
+    }
+}
+
+impl From<Decompressed> for Matches<[u8]> {
+    fn from(matcher: Decompressed) -> Self {
+        Matches::new(matcher)
+    }
+}
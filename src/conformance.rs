@@ -0,0 +1,575 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Conformance of the emulator with a real Envoy: the requests of a scenario are run through the
+// module both in a Tester and in Envoy (started with docker, see EnvoyConfig), and what comes
+// out of each is compared, e.g.
+//
+//   let divergences = scenario.conformance(&DockerEnvoy::from_env())?;
+//
+// or `proxy-wasm-test my_filter.wasm scenarios/ --envoy`. What is compared is what the module
+// makes of a stream: the request as it reached the upstream (served by the harness, answering
+// with the response of the scenario) and the response as it reached the client, local replies
+// included. The headers Envoy sets itself (x-request-id, date, ...) are left out, and so are
+// trailers, which HTTP/1.1 does not carry.
+
+use crate::envoy::EnvoyConfig;
+use crate::http::HttpBody;
+use crate::scenario::{Divergence, Headers, Response, Scenario};
+use crate::trace::diff_lines;
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ROOT_CONTEXT: i32 = 1;
+
+const DEFAULT_IMAGE: &str = "envoyproxy/envoy:v1.31-latest";
+
+// Headers of the request added by Envoy (or by the HTTP/1.1 framing)
+const ENVOY_REQUEST_HEADERS: &[&str] = &[
+    ":scheme",
+    "content-length",
+    "transfer-encoding",
+    "x-envoy-expected-rq-timeout-ms",
+    "x-envoy-internal",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-request-id",
+];
+
+// Same for the response
+const ENVOY_RESPONSE_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "date",
+    "server",
+    "transfer-encoding",
+    "x-envoy-upstream-service-time",
+];
+
+// Envoy run in a docker container (on the host network), from the image given by
+// PROXY_WASM_TEST_ENVOY_IMAGE or a recent release
+pub struct DockerEnvoy {
+    image: String,
+    listener_port: u16,
+    admin_port: u16,
+    timeout: Duration,
+}
+
+impl DockerEnvoy {
+    pub fn new(image: &str) -> DockerEnvoy {
+        DockerEnvoy {
+            image: image.to_string(),
+            listener_port: 10000,
+            admin_port: 9901,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub fn from_env() -> DockerEnvoy {
+        match std::env::var("PROXY_WASM_TEST_ENVOY_IMAGE") {
+            Ok(image) => DockerEnvoy::new(&image),
+            Err(_) => DockerEnvoy::new(DEFAULT_IMAGE),
+        }
+    }
+
+    // Port of the listener with the wasm filter, the mock upstreams listen on the next ones
+    pub fn set_listener_port(&mut self, port: u16) -> &mut Self {
+        self.listener_port = port;
+        self
+    }
+
+    pub fn set_admin_port(&mut self, port: u16) -> &mut Self {
+        self.admin_port = port;
+        self
+    }
+
+    // Time given to Envoy to answer each request (10s by default)
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Starts Envoy with the configuration, once it is ready to take requests; the module and
+    // the configuration are mounted at the same paths in the container
+    fn start(&self, config: &EnvoyConfig) -> Result<Container> {
+        let directory =
+            std::env::temp_dir().join(format!("proxy-wasm-conformance-{}", std::process::id()));
+        fs::create_dir_all(&directory)?;
+        let config_path = directory.join("envoy.yaml");
+        config.save(&config_path)?;
+        let mut docker = Command::new("docker");
+        docker.args(["run", "--detach", "--network", "host"]);
+        for mounted in [
+            Path::new(config.wasm_path()).parent(),
+            Some(directory.as_path()),
+        ]
+        .iter()
+        .flatten()
+        {
+            docker
+                .arg("--volume")
+                .arg(format!("{}:{}:ro", mounted.display(), mounted.display()));
+        }
+        let output = docker
+            .arg(&self.image)
+            .arg("--config-path")
+            .arg(&config_path)
+            .args(["--log-level", "warn"])
+            .output()
+            .context("cannot run docker")?;
+        if !output.status.success() {
+            bail!(
+                "cannot start Envoy ({}): {}",
+                self.image,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let container = Container {
+            id: String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        };
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(60) {
+            let ready = exchange(
+                self.admin_port,
+                b"GET /ready HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+                Duration::from_secs(1),
+            );
+            if let Ok(ready) = ready {
+                if ready.start_line.split(' ').nth(1) == Some("200") {
+                    return Ok(container);
+                }
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+        bail!(
+            "Envoy ({}) was not ready after 60s:\n{}",
+            self.image,
+            container.logs()
+        )
+    }
+}
+
+struct Container {
+    id: String,
+}
+
+impl Container {
+    fn logs(&self) -> String {
+        match Command::new("docker").args(["logs", &self.id]).output() {
+            Ok(output) => {
+                String::from_utf8_lossy(&[output.stdout, output.stderr].concat()).into_owned()
+            }
+            Err(error) => error.to_string(),
+        }
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        let _ = Command::new("docker")
+            .args(["rm", "--force", &self.id])
+            .output();
+    }
+}
+
+impl Scenario {
+    // Runs the requests through the module in the emulator and in Envoy, and returns the ones
+    // (in order) whose upstream request or response differ
+    pub fn conformance(&self, envoy: &DockerEnvoy) -> Result<Vec<Divergence>> {
+        let scenario = self.comparable();
+        let mut tester = scenario
+            .start_with(true)
+            .context("cannot start the module in the emulator")?;
+        let upstream = Upstream::start()?;
+        let mut config = EnvoyConfig::from_tester(&tester)?;
+        config
+            .set_listener_port(envoy.listener_port)
+            .set_admin_port(envoy.admin_port)
+            .set_upstream_port(upstream.port);
+        let _container = envoy.start(&config)?;
+
+        let mut divergences = Vec::new();
+        for (index, request) in scenario.requests.iter().enumerate() {
+            let context_id = ROOT_CONTEXT + 1 + index as i32;
+            let emulated = match request.run(&mut tester, context_id, true) {
+                Ok(()) => {
+                    let mut text = String::new();
+                    let forwarded = tester
+                        .final_request(context_id)
+                        .map(|request| (pairs(request.headers.iter()), body_bytes(request.body)));
+                    describe(
+                        &mut text,
+                        "upstream request",
+                        forwarded,
+                        ENVOY_REQUEST_HEADERS,
+                    );
+                    let answered = tester.final_response(context_id).map(|response| {
+                        (pairs(response.headers.iter()), body_bytes(response.body))
+                    });
+                    describe(&mut text, "response", answered, ENVOY_RESPONSE_HEADERS);
+                    text
+                }
+                Err(error) => format!("error: {:#}\n", error),
+            };
+            let proxied = upstream.serve(request.response.as_ref().unwrap(), || {
+                send(envoy, &request.headers, request.body.as_deref())
+            });
+            let mut text = String::new();
+            let forwarded = proxied
+                .upstream_request
+                .map(|request| request.pseudo_headers());
+            describe(
+                &mut text,
+                "upstream request",
+                forwarded,
+                ENVOY_REQUEST_HEADERS,
+            );
+            let answered = proxied.response.map(|response| response.pseudo_headers());
+            describe(&mut text, "response", answered, ENVOY_RESPONSE_HEADERS);
+            if emulated != text {
+                divergences.push(Divergence {
+                    request: request.name.clone(),
+                    diff: diff_lines(&emulated, &text),
+                });
+            }
+        }
+        Ok(divergences)
+    }
+
+    // Same requests, sent the same way to both: complete pseudo-headers, an upstream answering
+    // 200 when the scenario gives no response, and no trailers
+    fn comparable(&self) -> Scenario {
+        let mut scenario = self.clone();
+        for request in &mut scenario.requests {
+            for (name, default) in [
+                (":method", "GET"),
+                (":path", "/"),
+                (":authority", "localhost"),
+            ] {
+                if !request.headers.0.iter().any(|(key, _)| key == name) {
+                    request
+                        .headers
+                        .0
+                        .push((name.to_string(), default.to_string()));
+                }
+            }
+            request.trailers = None;
+            let response = request.response.get_or_insert_with(|| Response {
+                headers: Headers(vec![(":status".to_string(), "200".to_string())]),
+                ..Response::default()
+            });
+            response.trailers = None;
+        }
+        scenario
+    }
+}
+
+// What came out of Envoy for a request
+struct Proxied {
+    upstream_request: Option<Message>,
+    response: Option<Message>,
+}
+
+// Server of the filtered streams, answering each request with the response of the scenario
+// being run
+struct Upstream {
+    port: u16,
+    state: Arc<Mutex<UpstreamState>>,
+    stopped: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct UpstreamState {
+    response: Option<Response>,
+    received: Option<Message>,
+}
+
+impl Upstream {
+    fn start() -> Result<Upstream> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let state = Arc::new(Mutex::new(UpstreamState::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let upstream = Upstream {
+            port,
+            state: state.clone(),
+            stopped: stopped.clone(),
+        };
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let state = state.clone();
+                    thread::spawn(move || serve_connection(stream, &state));
+                }
+            }
+        });
+        Ok(upstream)
+    }
+
+    fn serve(&self, response: &Response, send: impl FnOnce() -> Option<Message>) -> Proxied {
+        *self.state.lock().unwrap() = UpstreamState {
+            response: Some(response.clone()),
+            received: None,
+        };
+        let response = send();
+        Proxied {
+            upstream_request: self.state.lock().unwrap().received.take(),
+            response,
+        }
+    }
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wakes up the accepting thread
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+    }
+}
+
+// Requests of a (kept alive) connection from Envoy
+fn serve_connection(stream: TcpStream, state: &Mutex<UpstreamState>) {
+    let mut reader = BufReader::new(match stream.try_clone() {
+        Ok(stream) => stream,
+        Err(_) => return,
+    });
+    let mut writer = stream;
+    while let Ok(Some(request)) = read_message(&mut reader, false) {
+        let response = {
+            let mut state = state.lock().unwrap();
+            state.received = Some(request);
+            state.response.clone().unwrap_or_default()
+        };
+        let mut status = "200";
+        let mut head = String::new();
+        for (name, value) in &response.headers.0 {
+            match name.as_str() {
+                ":status" => status = value,
+                name if name.starts_with(':') || name.eq_ignore_ascii_case("content-length") => {}
+                name => head.push_str(&format!("{}: {}\r\n", name, value)),
+            }
+        }
+        let body = response.body.unwrap_or_default();
+        let message = format!(
+            "HTTP/1.1 {} {}\r\n{}content-length: {}\r\n\r\n{}",
+            status,
+            reason(status),
+            head,
+            body.len(),
+            body
+        );
+        if writer.write_all(message.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+// Sends the request to the listener with the wasm filter, None if Envoy did not answer
+fn send(envoy: &DockerEnvoy, headers: &Headers, body: Option<&str>) -> Option<Message> {
+    let header = |name: &str| {
+        headers
+            .0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .unwrap_or_default()
+    };
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nhost: {}\r\n",
+        header(":method"),
+        header(":path"),
+        header(":authority")
+    );
+    for (name, value) in &headers.0 {
+        if !name.starts_with(':') && !name.eq_ignore_ascii_case("content-length") {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    if let Some(body) = body {
+        request.push_str(&format!("content-length: {}\r\n", body.len()));
+    }
+    request.push_str("connection: close\r\n\r\n");
+    request.push_str(body.unwrap_or_default());
+    exchange(envoy.listener_port, request.as_bytes(), envoy.timeout).ok()
+}
+
+fn exchange(port: u16, request: &[u8], timeout: Duration) -> io::Result<Message> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(request)?;
+    read_message(&mut BufReader::new(stream), true)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"))
+}
+
+// Headers (lowercased) and body of a message, as compared
+type HeadersAndBody = (Vec<(String, String)>, Vec<u8>);
+
+// HTTP/1.1 request or response
+struct Message {
+    start_line: String,
+    // names lowercased
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Message {
+    // Headers with the pseudo-headers of the start line, the way the module sees them
+    fn pseudo_headers(self) -> HeadersAndBody {
+        let parts: Vec<&str> = self.start_line.splitn(3, ' ').collect();
+        let mut headers = match parts[..] {
+            ["HTTP/1.1", status, ..] | ["HTTP/1.0", status, ..] => {
+                vec![(":status".to_string(), status.to_string())]
+            }
+            [method, path, ..] => vec![
+                (":method".to_string(), method.to_string()),
+                (":path".to_string(), path.to_string()),
+            ],
+            _ => vec![],
+        };
+        for (name, value) in self.headers {
+            match name.as_str() {
+                "host" => headers.push((":authority".to_string(), value)),
+                _ => headers.push((name, value)),
+            }
+        }
+        (headers, self.body)
+    }
+}
+
+// None on a connection closed before the message; responses without a length run until the
+// connection is closed
+fn read_message(reader: &mut impl BufRead, response: bool) -> io::Result<Option<Message>> {
+    let mut start_line = String::new();
+    if reader.read_line(&mut start_line)? == 0 {
+        return Ok(None);
+    }
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let invalid = |_| io::Error::from(io::ErrorKind::InvalidData);
+    let mut body = Vec::new();
+    if header("transfer-encoding").is_some_and(|value| value.eq_ignore_ascii_case("chunked")) {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size)?;
+            let size = size.trim().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16).map_err(invalid)?;
+            if size == 0 {
+                // trailers, up to the empty line
+                let mut line = String::new();
+                while reader.read_line(&mut line)? > 0 && !line.trim().is_empty() {
+                    line.clear();
+                }
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            reader.read_line(&mut String::new())?;
+        }
+    } else if let Some(length) = header("content-length") {
+        body.resize(length.parse().map_err(invalid)?, 0);
+        reader.read_exact(&mut body)?;
+    } else if response {
+        reader.read_to_end(&mut body)?;
+    }
+    Ok(Some(Message {
+        start_line: start_line.trim_end().to_string(),
+        headers,
+        body,
+    }))
+}
+
+// Headers sorted by name (values of a name kept in order), then the body
+fn describe(text: &mut String, title: &str, message: Option<HeadersAndBody>, ignored: &[&str]) {
+    let (mut headers, body) = match message {
+        Some(message) => message,
+        None => {
+            text.push_str(&format!("{}: none\n", title));
+            return;
+        }
+    };
+    text.push_str(&format!("{}:\n", title));
+    headers.retain(|(name, _)| !ignored.contains(&name.as_str()));
+    headers.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (name, value) in headers {
+        text.push_str(&format!("  {}: {}\n", name, value));
+    }
+    if !body.is_empty() {
+        text.push_str(&format!("  body: {:?}\n", String::from_utf8_lossy(&body)));
+    }
+}
+
+fn pairs<'a>(headers: impl Iterator<Item = (&'a str, &'a str)>) -> Vec<(String, String)> {
+    headers
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+        .collect()
+}
+
+fn body_bytes(body: Option<HttpBody>) -> Vec<u8> {
+    match body {
+        Some(HttpBody::Full(body)) => body,
+        Some(HttpBody::Chunked(chunks)) => chunks.concat(),
+        None => Vec::new(),
+    }
+}
+
+fn reason(status: &str) -> &'static str {
+    match status {
+        "200" => "OK",
+        "201" => "Created",
+        "204" => "No Content",
+        "301" => "Moved Permanently",
+        "302" => "Found",
+        "304" => "Not Modified",
+        "400" => "Bad Request",
+        "401" => "Unauthorized",
+        "403" => "Forbidden",
+        "404" => "Not Found",
+        "429" => "Too Many Requests",
+        "500" => "Internal Server Error",
+        "502" => "Bad Gateway",
+        "503" => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Source coverage of the plugin under test. wasm32 has no profiler runtime, so the plugin links
+// minicov and exports its counters for the host to collect:
+//
+//   #[no_mangle]
+//   pub extern "C" fn proxy_wasm_test_coverage() -> u64 {
+//       let mut data = Vec::new();
+//       unsafe { minicov::capture_coverage(&mut data).unwrap() };
+//       let data = data.leak();
+//       (data.as_ptr() as u64) << 32 | data.len() as u64
+//   }
+//
+// and is built with -Cinstrument-coverage (see WasmBuild::coverage). With
+// PROXY_WASM_TEST_COVERAGE=<dir>, each Tester writes the counters of its instance into a .profraw
+// file of the directory when dropped, which are then merged and reported on, e.g.
+//
+//   PROXY_WASM_TEST_COVERAGE=target/coverage cargo test
+//   let profile = coverage::merge_profiles("target/coverage")?;
+//   println!("{}", coverage::report(&profile, &["target/.../filter.wasm"])?);
+//
+// llvm-cov reads the coverage mapping of .wasm files from LLVM 19 on (the module must not be
+// stripped); older ones need native objects compiled with clang from the LLVM IR of the plugin.
+
+use crate::tester::Tester;
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Export of the instrumented module returning the address (high 32 bits) and size (low 32 bits)
+// of its profile data
+pub const COVERAGE_EXPORT: &str = "proxy_wasm_test_coverage";
+
+static PROFILES: AtomicUsize = AtomicUsize::new(0);
+
+// Writes the coverage of the Tester's instance into PROXY_WASM_TEST_COVERAGE, named after the test
+// (thread), the process and a counter, nothing when it is unset or the module not instrumented
+pub(crate) fn write_profile(tester: &mut Tester) {
+    let directory = match std::env::var("PROXY_WASM_TEST_COVERAGE") {
+        Ok(directory) => directory,
+        Err(_) => return,
+    };
+    let data = match tester.coverage_data() {
+        Ok(Some(data)) => data,
+        Ok(None) => return,
+        Err(error) => {
+            eprintln!("cannot collect the coverage of the module: {:#}", error);
+            return;
+        }
+    };
+    let test_name = match std::thread::current().name() {
+        Some("main") | None => "coverage".to_string(),
+        Some(name) => name.replace("::", "-"),
+    };
+    let path = Path::new(&directory).join(format!(
+        "{}-{}-{}.profraw",
+        test_name,
+        std::process::id(),
+        PROFILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    if let Err(error) = fs::create_dir_all(&directory).and_then(|_| fs::write(&path, data)) {
+        eprintln!("cannot write {}: {}", path.display(), error);
+    }
+}
+
+// Merges the .profraw files of the directory into <dir>/coverage.profdata (llvm-profdata)
+pub fn merge_profiles(directory: impl AsRef<Path>) -> Result<PathBuf> {
+    let directory = directory.as_ref();
+    let profiles: Vec<PathBuf> = fs::read_dir(directory)
+        .with_context(|| format!("cannot read {}", directory.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "profraw")
+        })
+        .collect();
+    if profiles.is_empty() {
+        bail!(
+            "no .profraw file in {} (is the module instrumented and exporting {}?)",
+            directory.display(),
+            COVERAGE_EXPORT
+        );
+    }
+    let profile = directory.join("coverage.profdata");
+    let mut command = Command::new(llvm_tool("llvm-profdata"));
+    command
+        .args(["merge", "-sparse", "-o"])
+        .arg(&profile)
+        .args(&profiles);
+    run_tool(&mut command)?;
+    Ok(profile)
+}
+
+// Summary of the coverage per source file (llvm-cov report) of the given modules or objects
+pub fn report(profile: impl AsRef<Path>, objects: &[impl AsRef<Path>]) -> Result<String> {
+    let mut command = llvm_cov("report", profile.as_ref(), objects)?;
+    run_tool(&mut command)
+}
+
+// Annotated sources in HTML (llvm-cov show), index.html in the output directory
+pub fn html_report(
+    profile: impl AsRef<Path>,
+    objects: &[impl AsRef<Path>],
+    output_dir: impl AsRef<Path>,
+) -> Result<PathBuf> {
+    let output_dir = output_dir.as_ref();
+    let mut command = llvm_cov("show", profile.as_ref(), objects)?;
+    command.args([
+        "--format=html",
+        "--show-line-counts-or-regions",
+        "--output-dir",
+    ]);
+    command.arg(output_dir);
+    run_tool(&mut command)?;
+    Ok(output_dir.join("index.html"))
+}
+
+// Sources of the Rust standard library and dependencies are left out
+fn llvm_cov(subcommand: &str, profile: &Path, objects: &[impl AsRef<Path>]) -> Result<Command> {
+    let (first, rest) = match objects.split_first() {
+        Some(objects) => objects,
+        None => bail!("no module to report the coverage of"),
+    };
+    let mut command = Command::new(llvm_tool("llvm-cov"));
+    command
+        .arg(subcommand)
+        .arg("--instr-profile")
+        .arg(profile)
+        .arg(first.as_ref())
+        .args([
+            "--ignore-filename-regex",
+            r"/rustc/|\.cargo/registry|\.rustup/",
+        ]);
+    for object in rest {
+        command.arg("--object").arg(object.as_ref());
+    }
+    Ok(command)
+}
+
+fn run_tool(command: &mut Command) -> Result<String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command.output().with_context(|| {
+        format!(
+            "cannot run {} (install llvm-tools or set its path)",
+            program
+        )
+    })?;
+    if !output.status.success() {
+        bail!(
+            "{} failed:\n{}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// LLVM_PROFDATA or LLVM_COV when set, then the tools of the llvm-tools rustup component (of
+// the same LLVM as rustc), then the ones on the PATH
+fn llvm_tool(name: &str) -> PathBuf {
+    let variable = name.to_uppercase().replace('-', "_");
+    if let Ok(path) = std::env::var(variable) {
+        return PathBuf::from(path);
+    }
+    let sysroot = Command::new(std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()))
+        .args(["--print", "sysroot"])
+        .output()
+        .ok()
+        .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()));
+    let rustlib = sysroot.map(|sysroot| sysroot.join("lib").join("rustlib"));
+    if let Some(Ok(hosts)) = rustlib.as_ref().map(fs::read_dir) {
+        for host in hosts.filter_map(|host| host.ok()) {
+            let tool = host.path().join("bin").join(name);
+            if tool.exists() {
+                return tool;
+            }
+        }
+    }
+    PathBuf::from(name)
+}
@@ -0,0 +1,312 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Given/When/Then tests, written the way filter behavior is specified, e.g.
+//
+//   given().plugin_config(r#"{"deny": "/admin"}"#)
+//       .when().request(HttpRequest::get("/admin"))
+//       .then().expect_local_response(403);
+//
+// Rather than staging expectations ahead of each callback, then() runs the request (and the
+// response, if any) through the module with every host call allowed, and the assertions check
+// what the module did: the host calls it made, the actions it returned and what it logged.
+
+use crate::http::{HttpBody, HttpRequest, HttpResponse};
+use crate::tester::{TestSetup, Tester, ROOT_CONTEXT};
+use crate::trace::TracedCall;
+use crate::types::{Action, LogLevel, MapType, ReturnType};
+
+use anyhow::Result;
+
+// Module given by PROXY_WASM_TEST_MODULE, unless set with module()
+pub fn given() -> Given {
+    Given {
+        setup: TestSetup::from_env().unwrap_or_else(|_| TestSetup::new("")),
+    }
+}
+
+pub struct Given {
+    setup: TestSetup,
+}
+
+pub struct When {
+    setup: TestSetup,
+    request: Option<HttpRequest>,
+    response: Option<HttpResponse>,
+}
+
+pub struct Then {
+    tester: Tester,
+    context_id: i32,
+    // what each callback returned, request callbacks first
+    returned: Vec<Option<i32>>,
+    calls: Vec<TracedCall>,
+}
+
+impl TestSetup {
+    pub fn given(self) -> Given {
+        Given { setup: self }
+    }
+}
+
+impl Given {
+    pub fn module(mut self, wasm_path: &str) -> Given {
+        self.setup = self.setup.wasm_path(wasm_path);
+        self
+    }
+
+    pub fn vm_config(mut self, vm_config: &str) -> Given {
+        self.setup = self.setup.vm_config(vm_config);
+        self
+    }
+
+    pub fn plugin_config(mut self, plugin_config: &str) -> Given {
+        self.setup = self.setup.plugin_config(plugin_config);
+        self
+    }
+
+    // e.g. property(vec!["source", "address"], "10.0.0.1:4242")
+    pub fn property(mut self, path: Vec<&str>, value: impl AsRef<[u8]>) -> Given {
+        let path: Vec<String> = path.into_iter().map(str::to_string).collect();
+        let value = value.as_ref().to_vec();
+        self.setup = self.setup.before_start(move |tester: &mut Tester| {
+            tester.set_default_property(path.iter().map(String::as_str).collect(), &value);
+        });
+        self
+    }
+
+    // Upstream (cluster or authority) answering the module's http_calls with the response
+    pub fn upstream(mut self, upstream: &str, response: HttpResponse) -> Given {
+        let upstream = upstream.to_string();
+        self.setup = self
+            .setup
+            .before_start(move |tester: &mut Tester| -> Result<()> {
+                let mut headers = response.headers.clone();
+                let status = match headers.remove(":status") {
+                    Some(status) => status.parse()?,
+                    None => 200,
+                };
+                let body = match &response.body {
+                    Some(HttpBody::Full(body)) => body.clone(),
+                    Some(HttpBody::Chunked(chunks)) => chunks.concat(),
+                    None => Vec::new(),
+                };
+                let trailers = response.trailers.clone().unwrap_or_default();
+                tester.set_mock_upstream(&upstream).returning_bytes(
+                    status,
+                    headers,
+                    Some(&body),
+                    trailers,
+                );
+                Ok(())
+            });
+        self
+    }
+
+    // Any other preparation of the Tester before the module starts
+    pub fn tester(mut self, hook: impl Fn(&mut Tester) + 'static) -> Given {
+        self.setup = self.setup.before_start(hook);
+        self
+    }
+
+    pub fn when(self) -> When {
+        When {
+            setup: self.setup.allow_unexpected(),
+            request: None,
+            response: None,
+        }
+    }
+}
+
+impl When {
+    pub fn request(mut self, request: HttpRequest) -> When {
+        self.request = Some(request);
+        self
+    }
+
+    pub fn response(mut self, response: HttpResponse) -> When {
+        self.response = Some(response);
+        self
+    }
+
+    // Runs the module on a new HTTP context, panics if it fails (e.g. traps)
+    #[track_caller]
+    pub fn then(self) -> Then {
+        match self.run() {
+            Ok(then) => then,
+            Err(error) => panic!(
+                "Error: the module failed to handle the request: {:?}",
+                error
+            ),
+        }
+    }
+
+    fn run(self) -> Result<Then> {
+        if self.setup.mock_settings.wasm_path.is_empty() {
+            anyhow::bail!("no module to test: set PROXY_WASM_TEST_MODULE or call module()");
+        }
+        let mut tester = self.setup.start()?;
+        let context_id = ROOT_CONTEXT + 1;
+        tester
+            .call_proxy_on_context_create(context_id, ROOT_CONTEXT)
+            .execute_and_expect(ReturnType::None)?;
+        tester.record_trace();
+        let mut returned = Vec::new();
+        if let Some(request) = self.request {
+            tester.send_request(context_id, request)?;
+            returned.extend(tester.execute_all()?);
+        }
+        if let Some(response) = self.response {
+            tester.send_response(context_id, response)?;
+            returned.extend(tester.execute_all()?);
+        }
+        let calls = tester.take_trace().calls().cloned().collect();
+        Ok(Then {
+            tester,
+            context_id,
+            returned,
+            calls,
+        })
+    }
+}
+
+impl Then {
+    #[track_caller]
+    pub fn expect_local_response(&mut self, status_code: u32) -> &mut Self {
+        let sent = self.local_responses();
+        assert!(
+            sent.contains(&(status_code as i32)),
+            "Error: expected a local response with status {}, sent: {:?}",
+            status_code,
+            sent
+        );
+        self
+    }
+
+    // The request (and response) went through: no local response, and every callback continued
+    #[track_caller]
+    pub fn expect_forwarded(&mut self) -> &mut Self {
+        let sent = self.local_responses();
+        assert!(
+            sent.is_empty(),
+            "Error: expected the request to be forwarded, local responses sent: {:?}",
+            sent
+        );
+        let continued = self
+            .returned
+            .iter()
+            .all(|returned| *returned == Some(Action::Continue as i32));
+        assert!(
+            continued,
+            "Error: expected every callback to continue, returned: {:?}",
+            self.returned
+        );
+        self
+    }
+
+    // A callback paused the stream (e.g. waiting on an http_call)
+    #[track_caller]
+    pub fn expect_paused(&mut self) -> &mut Self {
+        assert!(
+            self.returned.contains(&Some(Action::Pause as i32)),
+            "Error: expected a callback to pause, returned: {:?}",
+            self.returned
+        );
+        self
+    }
+
+    // The module added the header, or replaced its value
+    #[track_caller]
+    pub fn expect_header(&mut self, map_type: MapType, name: &str, value: &str) -> &mut Self {
+        let written: Vec<(&str, String)> = self
+            .calls
+            .iter()
+            .filter_map(|call| match call {
+                TracedCall::AddHeaderMapValue {
+                    map_type: written_type,
+                    key,
+                    value,
+                }
+                | TracedCall::ReplaceHeaderMapValue {
+                    map_type: written_type,
+                    key,
+                    value,
+                } if *written_type == map_type as i32 => {
+                    Some((key.as_str(), String::from_utf8_lossy(value).into_owned()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert!(
+            written
+                .iter()
+                .any(|(key, written)| key.eq_ignore_ascii_case(name) && written == value),
+            "Error: expected {}: {} to be set on {:?}, set: {:?}",
+            name,
+            value,
+            map_type,
+            written
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn expect_http_call(&mut self, upstream: &str) -> &mut Self {
+        let upstreams: Vec<&str> = self
+            .calls
+            .iter()
+            .filter_map(|call| match call {
+                TracedCall::HttpCall { upstream, .. } => Some(upstream.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert!(
+            upstreams.contains(&upstream),
+            "Error: expected an http_call to {}, called: {:?}",
+            upstream,
+            upstreams
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn expect_log(&mut self, level: LogLevel, substring: &str) -> &mut Self {
+        self.tester.assert_logged(level, substring);
+        self
+    }
+
+    // Host calls made by the module while handling the request (and response)
+    pub fn calls(&self) -> &[TracedCall] {
+        &self.calls
+    }
+
+    // The Tester, to go on with the HTTP context (e.g. call_proxy_on_log(context_id()))
+    pub fn tester(&mut self) -> &mut Tester {
+        &mut self.tester
+    }
+
+    pub fn context_id(&self) -> i32 {
+        self.context_id
+    }
+
+    fn local_responses(&self) -> Vec<i32> {
+        self.calls
+            .iter()
+            .filter_map(|call| match call {
+                TracedCall::SendLocalResponse { status_code, .. } => Some(*status_code),
+                _ => None,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Envoy configuration running the module the way a test did, to check a failing test against
+// the real proxy, e.g.
+//
+//   let command = EnvoyConfig::from_tester(&tester)?.save("target/envoy.yaml")?;
+//   // envoy -c target/envoy.yaml, then curl localhost:10000
+//
+// or for every failing test run by TestSetup, with PROXY_WASM_TEST_ENVOY_CONFIG=<directory>.
+//
+// The bootstrap has a listener with the wasm filter (same module, vm and plugin configuration,
+// vm id and root id), routing to a static cluster answering 200. Each mock upstream becomes a
+// static cluster of the same name, served by a listener with its canned response (status,
+// headers, body and delay, unavailable faults through the fault filter). Resets, timeouts and
+// trailers have no equivalent there and are left out.
+
+use crate::host_settings::MockResponse;
+use crate::tester::Tester;
+use crate::trace::{encode_base64, json_bytes};
+use crate::types::{BufferType, Bytes, UpstreamFault};
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::Path;
+
+pub struct EnvoyConfig {
+    wasm_path: String,
+    vm_id: String,
+    root_id: String,
+    plugin_name: String,
+    vm_config: Bytes,
+    plugin_config: Bytes,
+    upstreams: Vec<(String, MockResponse)>,
+    listener_port: u16,
+    admin_port: u16,
+    // server of the filtered streams outside of Envoy, instead of the listener answering 200
+    upstream_port: Option<u16>,
+}
+
+impl EnvoyConfig {
+    // Configuration of the Tester as it is now (e.g. at the end of a test), its module must
+    // have been loaded from a file
+    pub fn from_tester(tester: &Tester) -> Result<EnvoyConfig> {
+        let wasm_path = match tester.wasm_path() {
+            Some(wasm_path) => fs::canonicalize(wasm_path)
+                .with_context(|| format!("cannot find the module {}", wasm_path))?,
+            None => bail!("the module was given inline, Envoy can only load it from a file"),
+        };
+        let host = tester.get_settings_handle();
+        let property = |name: &str| {
+            host.staged
+                .get_property(&[name])
+                .map(|value| String::from_utf8_lossy(&value).into_owned())
+                .unwrap_or_default()
+        };
+        Ok(EnvoyConfig {
+            wasm_path: wasm_path.to_string_lossy().into_owned(),
+            vm_id: tester.vm_id().to_string(),
+            root_id: property("plugin_root_id"),
+            plugin_name: property("plugin_name"),
+            vm_config: host
+                .staged
+                .get_buffer_bytes(BufferType::VmConfiguration as i32),
+            plugin_config: host
+                .staged
+                .get_buffer_bytes(BufferType::PluginConfiguration as i32),
+            upstreams: host.staged.get_mock_upstreams(),
+            listener_port: 10000,
+            admin_port: 9901,
+            upstream_port: None,
+        })
+    }
+
+    // Port of the listener with the wasm filter (10000 by default), the clusters listen on the
+    // next ones
+    pub fn set_listener_port(&mut self, port: u16) -> &mut Self {
+        self.listener_port = port;
+        self
+    }
+
+    pub fn set_admin_port(&mut self, port: u16) -> &mut Self {
+        self.admin_port = port;
+        self
+    }
+
+    // Sends the filtered streams to a server listening on this (local) port, e.g. to look at
+    // the requests as they left Envoy
+    pub fn set_upstream_port(&mut self, port: u16) -> &mut Self {
+        self.upstream_port = Some(port);
+        self
+    }
+
+    #[cfg(feature = "scenario")]
+    pub(crate) fn wasm_path(&self) -> &str {
+        &self.wasm_path
+    }
+
+    // Writes the configuration, and returns the command running Envoy with it
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<String> {
+        let path = path.as_ref();
+        fs::write(path, self.to_yaml())
+            .with_context(|| format!("cannot write the Envoy configuration {}", path.display()))?;
+        Ok(format!("envoy -c {}", path.display()))
+    }
+
+    pub fn to_yaml(&self) -> String {
+        let mut yaml = String::new();
+        yaml.push_str(&format!(
+            "admin:\n  address:\n    socket_address: {}\nstatic_resources:\n  listeners:\n",
+            socket_address(self.admin_port)
+        ));
+        self.filter_listener(&mut yaml);
+        let mut port = self.listener_port;
+        let mut clusters: Vec<(String, u16)> = self
+            .upstream_port
+            .map(|port| ("upstream".to_string(), port))
+            .into_iter()
+            .collect();
+        for (name, response) in self.upstreams() {
+            port += 1;
+            upstream_listener(&mut yaml, &name, port, &response);
+            clusters.push((name, port));
+        }
+        yaml.push_str("  clusters:\n");
+        for (name, port) in clusters {
+            yaml.push_str(&format!(
+                "  - name: {}\n    type: STATIC\n    connect_timeout: 1s\n    load_assignment:\n      cluster_name: {}\n      endpoints:\n      - lb_endpoints:\n        - endpoint:\n            address:\n              socket_address: {}\n",
+                quoted(&name),
+                quoted(&name),
+                socket_address(port)
+            ));
+        }
+        yaml
+    }
+
+    // The mock upstreams, and the upstream of the filtered streams unless mocked too (or served
+    // outside of Envoy)
+    fn upstreams(&self) -> Vec<(String, MockResponse)> {
+        let mut upstreams = self.upstreams.clone();
+        if self.upstream_port.is_some() {
+            upstreams.retain(|(name, _)| name != "upstream");
+        } else if !upstreams.iter().any(|(name, _)| name == "upstream") {
+            let upstream = MockResponse {
+                headers: vec![(":status".to_string(), "200".to_string())],
+                body: Bytes::new(),
+                trailers: vec![],
+                delay_millis: 0,
+                faults: vec![],
+            };
+            upstreams.insert(0, ("upstream".to_string(), upstream));
+        }
+        upstreams
+    }
+
+    fn filter_listener(&self, yaml: &mut String) {
+        listener_head(yaml, "main", self.listener_port);
+        yaml.push_str("              routes:\n              - match: { prefix: \"/\" }\n                route: { cluster: upstream }\n          http_filters:\n");
+        yaml.push_str("          - name: envoy.filters.http.wasm\n            typed_config:\n              \"@type\": type.googleapis.com/envoy.extensions.filters.http.wasm.v3.Wasm\n              config:\n");
+        yaml.push_str(&format!(
+            "                name: {}\n                root_id: {}\n",
+            quoted(&self.plugin_name),
+            quoted(&self.root_id)
+        ));
+        any_value(yaml, "                ", &self.plugin_config);
+        yaml.push_str(&format!(
+            "                vm_config:\n                  vm_id: {}\n                  runtime: envoy.wasm.runtime.v8\n",
+            quoted(&self.vm_id)
+        ));
+        any_value(yaml, "                  ", &self.vm_config);
+        yaml.push_str(&format!(
+            "                  code:\n                    local:\n                      filename: {}\n",
+            quoted(&self.wasm_path)
+        ));
+        router(yaml);
+    }
+}
+
+fn listener_head(yaml: &mut String, name: &str, port: u16) {
+    yaml.push_str(&format!(
+        "  - name: {}\n    address:\n      socket_address: {}\n    filter_chains:\n    - filters:\n",
+        quoted(name),
+        socket_address(port)
+    ));
+    yaml.push_str("      - name: envoy.filters.network.http_connection_manager\n        typed_config:\n          \"@type\": type.googleapis.com/envoy.extensions.filters.network.http_connection_manager.v3.HttpConnectionManager\n");
+    yaml.push_str(&format!(
+        "          stat_prefix: {}\n          route_config:\n            virtual_hosts:\n            - name: {}\n              domains: [\"*\"]\n",
+        quoted(name),
+        quoted(name)
+    ));
+}
+
+// Listener answering every request with the canned response
+fn upstream_listener(yaml: &mut String, name: &str, port: u16, response: &MockResponse) {
+    listener_head(yaml, name, port);
+    let status = response
+        .headers
+        .iter()
+        .find(|(key, _)| key == ":status")
+        .and_then(|(_, value)| value.parse::<u32>().ok())
+        .unwrap_or(200);
+    yaml.push_str(&format!(
+        "              routes:\n              - match: {{ prefix: \"/\" }}\n                direct_response:\n                  status: {}\n",
+        status
+    ));
+    if !response.body.is_empty() {
+        match std::str::from_utf8(&response.body) {
+            Ok(body) => yaml.push_str(&format!(
+                "                  body: {{ inline_string: {} }}\n",
+                quoted(body)
+            )),
+            Err(_) => yaml.push_str(&format!(
+                "                  body: {{ inline_bytes: {} }}\n",
+                quoted(&encode_base64(&response.body))
+            )),
+        }
+    }
+    let headers: Vec<&(String, String)> = response
+        .headers
+        .iter()
+        .filter(|(key, _)| !key.starts_with(':') && !key.eq_ignore_ascii_case("content-length"))
+        .collect();
+    if !headers.is_empty() {
+        yaml.push_str("                response_headers_to_add:\n");
+        for (key, value) in headers {
+            yaml.push_str(&format!(
+                "                - header: {{ key: {}, value: {} }}\n                  append_action: APPEND_IF_EXISTS_OR_ADD\n",
+                quoted(key),
+                quoted(value)
+            ));
+        }
+    }
+    yaml.push_str("          http_filters:\n");
+    let unavailable: u32 = response
+        .faults
+        .iter()
+        .filter(|(fault, _)| *fault == UpstreamFault::Unavailable)
+        .map(|(_, percent)| percent)
+        .sum();
+    let delayed = response.delay_millis > 0 && response.delay_millis != u64::MAX;
+    if delayed || unavailable > 0 {
+        yaml.push_str("          - name: envoy.filters.http.fault\n            typed_config:\n              \"@type\": type.googleapis.com/envoy.extensions.filters.http.fault.v3.HTTPFault\n");
+        if delayed {
+            yaml.push_str(&format!(
+                "              delay:\n                fixed_delay: {}.{:03}s\n                percentage: {{ numerator: 100 }}\n",
+                response.delay_millis / 1000,
+                response.delay_millis % 1000
+            ));
+        }
+        if unavailable > 0 {
+            yaml.push_str(&format!(
+                "              abort:\n                http_status: 503\n                percentage: {{ numerator: {} }}\n",
+                unavailable.min(100)
+            ));
+        }
+    }
+    router(yaml);
+}
+
+fn router(yaml: &mut String) {
+    yaml.push_str("          - name: envoy.filters.http.router\n            typed_config:\n              \"@type\": type.googleapis.com/envoy.extensions.filters.http.router.v3.Router\n");
+}
+
+// Configuration of the plugin or the vm, as a StringValue (or BytesValue if not UTF-8)
+fn any_value(yaml: &mut String, indent: &str, value: &[u8]) {
+    if value.is_empty() {
+        return;
+    }
+    let (type_url, value) = match std::str::from_utf8(value) {
+        Ok(text) => ("StringValue", quoted(text)),
+        Err(_) => ("BytesValue", quoted(&encode_base64(value))),
+    };
+    yaml.push_str(&format!(
+        "{indent}configuration:\n{indent}  \"@type\": type.googleapis.com/google.protobuf.{}\n{indent}  value: {}\n",
+        type_url,
+        value,
+        indent = indent
+    ));
+}
+
+fn socket_address(port: u16) -> String {
+    format!("{{ address: 127.0.0.1, port_value: {} }}", port)
+}
+
+// JSON strings are YAML double-quoted scalars
+fn quoted(text: &str) -> String {
+    let mut quoted = String::new();
+    json_bytes(&mut quoted, text.as_bytes());
+    quoted
+}
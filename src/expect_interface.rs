@@ -12,7 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::tester::Tester;
+use crate::expectations::{ExpectHandle, Response};
+use crate::http::HeaderMap;
+use crate::matchers::Matches;
+use crate::tester::{with_log, Tester};
+
+use std::sync::{Arc, Mutex};
+use tracing::{warn, Dispatch};
 
 // As of now, the following expectations do not require "fn returning()" implementations and hence
 // no structure is provided for them. Setting of these expectations are built directly into tester.rs:
@@ -29,6 +35,7 @@ impl<'a> ExpectGetCurrentTimeNanos<'a> {
         ExpectGetCurrentTimeNanos { tester: tester }
     }
 
+    #[track_caller]
     pub fn returning(&mut self, current_time_nanos: Option<u64>) -> &mut Tester {
         self.tester
             .get_expect_handle()
@@ -36,6 +43,19 @@ impl<'a> ExpectGetCurrentTimeNanos<'a> {
             .set_expect_get_current_time_nanos(current_time_nanos);
         self.tester
     }
+
+    // Stages one expectation per value, consumed call by call in the given order
+    #[track_caller]
+    pub fn returning_each(&mut self, current_time_nanos: Vec<Option<u64>>) -> &mut Tester {
+        assert!(
+            !current_time_nanos.is_empty(),
+            "Error: returning_each() requires at least one value"
+        );
+        for current_time_nanos in current_time_nanos {
+            self.returning(current_time_nanos);
+        }
+        self.tester
+    }
 }
 
 pub struct ExpectGetBufferBytes<'a> {
@@ -51,13 +71,33 @@ impl<'a> ExpectGetBufferBytes<'a> {
         }
     }
 
+    #[track_caller]
     pub fn returning(&mut self, buffer_data: Option<&str>) -> &mut Tester {
+        self.returning_bytes(buffer_data.map(str::as_bytes))
+    }
+
+    // Same as returning() for binary (non-UTF-8) data, e.g. protobuf or compressed payloads
+    #[track_caller]
+    pub fn returning_bytes(&mut self, buffer_data: Option<&[u8]>) -> &mut Tester {
         self.tester
             .get_expect_handle()
             .staged
             .set_expect_get_buffer_bytes(self.buffer_type, buffer_data);
         self.tester
     }
+
+    // Stages one expectation per value, consumed call by call in the given order
+    #[track_caller]
+    pub fn returning_each(&mut self, buffer_data: Vec<Option<&str>>) -> &mut Tester {
+        assert!(
+            !buffer_data.is_empty(),
+            "Error: returning_each() requires at least one value"
+        );
+        for buffer_data in buffer_data {
+            self.returning(buffer_data);
+        }
+        self.tester
+    }
 }
 
 pub struct ExpectGetHeaderMapPairs<'a> {
@@ -73,6 +113,7 @@ impl<'a> ExpectGetHeaderMapPairs<'a> {
         }
     }
 
+    #[track_caller]
     pub fn returning(&mut self, header_map_pairs: Option<Vec<(&str, &str)>>) -> &mut Tester {
         self.tester
             .get_expect_handle()
@@ -80,19 +121,51 @@ impl<'a> ExpectGetHeaderMapPairs<'a> {
             .set_expect_get_header_map_pairs(self.map_type, header_map_pairs);
         self.tester
     }
+
+    // Same as returning() with the pairs of a HeaderMap, in its order
+    #[track_caller]
+    pub fn returning_map(&mut self, header_map: Option<HeaderMap>) -> &mut Tester {
+        self.returning(header_map.as_ref().map(HeaderMap::pairs))
+    }
+
+    // Same as returning() with header values given as raw bytes
+    #[track_caller]
+    pub fn returning_bytes(&mut self, header_map_pairs: Option<Vec<(&str, &[u8])>>) -> &mut Tester {
+        self.tester
+            .get_expect_handle()
+            .staged
+            .set_expect_get_header_map_pairs(self.map_type, header_map_pairs);
+        self.tester
+    }
+
+    // Stages one expectation per value, consumed call by call in the given order
+    #[track_caller]
+    pub fn returning_each(
+        &mut self,
+        header_map_pairs: Vec<Option<Vec<(&str, &str)>>>,
+    ) -> &mut Tester {
+        assert!(
+            !header_map_pairs.is_empty(),
+            "Error: returning_each() requires at least one value"
+        );
+        for header_map_pairs in header_map_pairs {
+            self.returning(header_map_pairs);
+        }
+        self.tester
+    }
 }
 
 pub struct ExpectGetHeaderMapValue<'a> {
     tester: &'a mut Tester,
     map_type: Option<i32>,
-    header_map_key: Option<&'static str>,
+    header_map_key: Matches<str>,
 }
 
 impl<'a> ExpectGetHeaderMapValue<'a> {
     pub fn expecting(
         tester: &'a mut Tester,
         map_type: Option<i32>,
-        header_map_key: Option<&'static str>,
+        header_map_key: Matches<str>,
     ) -> ExpectGetHeaderMapValue<'a> {
         ExpectGetHeaderMapValue {
             tester: tester,
@@ -101,52 +174,145 @@ impl<'a> ExpectGetHeaderMapValue<'a> {
         }
     }
 
+    #[track_caller]
     pub fn returning(&mut self, header_map_value: Option<&str>) -> &mut Tester {
+        self.returning_bytes(header_map_value.map(str::as_bytes))
+    }
+
+    // Same as returning() for header values which are not valid UTF-8
+    #[track_caller]
+    pub fn returning_bytes(&mut self, header_map_value: Option<&[u8]>) -> &mut Tester {
         self.tester
             .get_expect_handle()
             .staged
-            .set_expect_get_header_map_value(self.map_type, self.header_map_key, header_map_value);
+            .set_expect_get_header_map_value(
+                self.map_type,
+                self.header_map_key.clone(),
+                Response::Fixed(header_map_value.map(|value| value.to_vec())),
+            );
+        self.tester
+    }
+
+    // Answers with a value computed from the requested key, e.g. for lookup-table filters
+    #[track_caller]
+    pub fn responding_with(
+        &mut self,
+        responder: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> &mut Tester {
+        self.tester
+            .get_expect_handle()
+            .staged
+            .set_expect_get_header_map_value(
+                self.map_type,
+                self.header_map_key.clone(),
+                Response::Computed(Arc::new(move |header_map_key: &str| {
+                    responder(header_map_key).map(String::into_bytes)
+                })),
+            );
+        self.tester
+    }
+
+    // Stages one expectation per value, consumed call by call in the given order
+    #[track_caller]
+    pub fn returning_each(&mut self, header_map_value: Vec<Option<&str>>) -> &mut Tester {
+        assert!(
+            !header_map_value.is_empty(),
+            "Error: returning_each() requires at least one value"
+        );
+        for header_map_value in header_map_value {
+            self.returning(header_map_value);
+        }
         self.tester
     }
 }
 
 pub struct ExpectHttpCall<'a> {
     tester: &'a mut Tester,
-    upstream: Option<&'a str>,
-    headers: Option<Option<Vec<(&'a str, &'a str)>>>,
-    body: Option<&'a str>,
-    trailers: Option<Option<Vec<(&'a str, &'a str)>>>,
-    timeout: Option<u64>,
+    upstream: Matches<str>,
+    headers: Matches<[(String, String)]>,
+    body: Matches<[u8]>,
+    trailers: Matches<[(String, String)]>,
+    timeout: Matches<u64>,
 }
 
 impl<'a> ExpectHttpCall<'a> {
     pub fn expecting(
         tester: &'a mut Tester,
-        upstream: Option<&'a str>,
-        headers: Option<Vec<(&'a str, &'a str)>>,
-        body: Option<&'a str>,
-        trailers: Option<Vec<(&'a str, &'a str)>>,
-        timeout: Option<u64>,
+        upstream: Matches<str>,
+        headers: Matches<[(String, String)]>,
+        body: Matches<[u8]>,
+        trailers: Matches<[(String, String)]>,
+        timeout: Matches<u64>,
     ) -> ExpectHttpCall<'a> {
         ExpectHttpCall {
-            tester: tester,
-            upstream: upstream,
-            headers: Some(headers),
-            body: body,
-            trailers: Some(trailers),
-            timeout: timeout,
+            tester,
+            upstream,
+            headers,
+            body,
+            trailers,
+            timeout,
         }
     }
 
+    #[track_caller]
     pub fn returning(&mut self, token_id: Option<u32>) -> &mut Tester {
         self.tester.get_expect_handle().staged.set_expect_http_call(
-            self.upstream,
-            self.headers.take().unwrap(),
-            self.body,
-            self.trailers.take().unwrap(),
-            self.timeout,
+            self.upstream.clone(),
+            self.headers.clone(),
+            self.body.clone(),
+            self.trailers.clone(),
+            self.timeout.clone(),
             token_id,
         );
         self.tester
     }
+
+    // Stages one expectation per value, consumed call by call in the given order
+    #[track_caller]
+    pub fn returning_each(&mut self, token_id: Vec<Option<u32>>) -> &mut Tester {
+        assert!(
+            !token_id.is_empty(),
+            "Error: returning_each() requires at least one value"
+        );
+        for token_id in token_id {
+            self.returning(token_id);
+        }
+        self.tester
+    }
+}
+
+// Guard over the current expectation stage: verify() asserts that every staged expectation was
+// consumed, dropping the guard without verifying warns and then verifies all the same
+pub struct Verifier {
+    expect: Arc<Mutex<ExpectHandle>>,
+    log: Dispatch,
+    verified: bool,
+}
+
+impl Verifier {
+    pub fn new(expect: Arc<Mutex<ExpectHandle>>, log: Dispatch) -> Verifier {
+        Verifier {
+            expect,
+            log,
+            verified: false,
+        }
+    }
+
+    pub fn verify(mut self) {
+        self.verified = true;
+        with_log(&self.log, || self.expect.lock().unwrap().assert_stage());
+    }
+}
+
+impl Drop for Verifier {
+    fn drop(&mut self) {
+        // avoid a double panic when the test is already failing
+        if self.verified || std::thread::panicking() {
+            return;
+        }
+        with_log(&self.log, || {
+            warn!("Warning: expectations were never verified, call verify() before returning");
+            self.expect.lock().unwrap().assert_stage();
+        });
+    }
 }
@@ -12,16 +12,199 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::hostcalls::{serial_utils::serialize_map, set_status};
+use crate::hostcalls::serial_utils::serialize_map;
+use crate::matchers::Matches;
 use crate::types::*;
 
+use std::fmt;
+use std::panic::Location;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error};
+
+impl HostCall {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HostCall::Log => "proxy_log",
+            HostCall::SetTickPeriodMillis => "proxy_set_tick_period_milliseconds",
+            HostCall::GetCurrentTimeNanos => "proxy_get_current_time_nanoseconds",
+            HostCall::GetBufferBytes => "proxy_get_buffer_bytes",
+            HostCall::SetBufferBytes => "proxy_set_buffer_bytes",
+            HostCall::GetHeaderMapPairs => "proxy_get_header_map_pairs",
+            HostCall::SetHeaderMapPairs => "proxy_set_header_map_pairs",
+            HostCall::GetHeaderMapValue => "proxy_get_header_map_value",
+            HostCall::ReplaceHeaderMapValue => "proxy_replace_header_map_value",
+            HostCall::RemoveHeaderMapValue => "proxy_remove_header_map_value",
+            HostCall::AddHeaderMapValue => "proxy_add_header_map_value",
+            HostCall::SendLocalResponse => "proxy_send_local_response",
+            HostCall::HttpCall => "proxy_http_call",
+            HostCall::MetricCreate => "proxy_define_metric",
+            HostCall::MetricIncrement => "proxy_increment_metric",
+            HostCall::MetricRecord => "proxy_record_metric",
+            HostCall::MetricGet => "proxy_get_metric",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HostCall> {
+        match name {
+            "proxy_log" => Some(HostCall::Log),
+            "proxy_set_tick_period_milliseconds" => Some(HostCall::SetTickPeriodMillis),
+            "proxy_get_current_time_nanoseconds" => Some(HostCall::GetCurrentTimeNanos),
+            "proxy_get_buffer_bytes" => Some(HostCall::GetBufferBytes),
+            "proxy_set_buffer_bytes" => Some(HostCall::SetBufferBytes),
+            "proxy_get_header_map_pairs" => Some(HostCall::GetHeaderMapPairs),
+            "proxy_set_header_map_pairs" => Some(HostCall::SetHeaderMapPairs),
+            "proxy_get_header_map_value" => Some(HostCall::GetHeaderMapValue),
+            "proxy_replace_header_map_value" => Some(HostCall::ReplaceHeaderMapValue),
+            "proxy_remove_header_map_value" => Some(HostCall::RemoveHeaderMapValue),
+            "proxy_add_header_map_value" => Some(HostCall::AddHeaderMapValue),
+            "proxy_send_local_response" => Some(HostCall::SendLocalResponse),
+            "proxy_http_call" => Some(HostCall::HttpCall),
+            "proxy_define_metric" => Some(HostCall::MetricCreate),
+            "proxy_increment_metric" => Some(HostCall::MetricIncrement),
+            "proxy_record_metric" => Some(HostCall::MetricRecord),
+            "proxy_get_metric" => Some(HostCall::MetricGet),
+            _ => None,
+        }
+    }
+}
+
+type Pairs = [(String, String)];
+type BufferBytes = (Matches<i32>, Matches<[u8]>);
+type HeaderMapLookup = (Matches<i32>, Matches<str>, Response<str, Option<Bytes>>);
+type HeaderMapValue = (Matches<i32>, Matches<str>, Matches<[u8]>);
+type LocalResponse = (Matches<i32>, Matches<[u8]>, Matches<Pairs>, Matches<i32>);
+type HttpCall = (
+    Matches<str>,
+    Matches<Pairs>,
+    Matches<[u8]>,
+    Matches<Pairs>,
+    Matches<u64>,
+    Option<u32>,
+);
+
+// Value handed back to the module by a staged expectation: fixed, or computed from the call
+pub enum Response<A: ?Sized, R> {
+    Fixed(R),
+    Computed(Arc<dyn Fn(&A) -> R + Send + Sync>),
+}
 
-fn set_expect_status(checks: bool) {
-    if checks {
-        set_status(ExpectStatus::Expected)
-    } else {
-        set_status(ExpectStatus::Failed);
+impl<A: ?Sized, R: Clone> Response<A, R> {
+    fn respond(&self, argument: &A) -> R {
+        match self {
+            Response::Fixed(value) => value.clone(),
+            Response::Computed(responder) => responder(argument),
+        }
+    }
+}
+
+impl<A: ?Sized, R: Clone> Clone for Response<A, R> {
+    fn clone(&self) -> Self {
+        match self {
+            Response::Fixed(value) => Response::Fixed(value.clone()),
+            Response::Computed(responder) => Response::Computed(responder.clone()),
+        }
+    }
+}
+
+impl<A: ?Sized, R: fmt::Debug> fmt::Debug for Response<A, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Fixed(value) => value.fmt(f),
+            Response::Computed(_) => write!(f, "Computed"),
+        }
+    }
+}
+
+// A single staged expectation, sticky expectations are never consumed
+#[derive(Debug)]
+struct Staged<T> {
+    expected: T,
+    sticky: bool,
+    hits: u32,
+    location: &'static Location<'static>,
+}
+
+impl<T> Staged<T> {
+    // Records the location of the (tracked) test code staging the expectation
+    #[track_caller]
+    fn new(expected: T) -> Staged<T> {
+        Staged {
+            expected,
+            sticky: false,
+            hits: 0,
+            location: Location::caller(),
+        }
+    }
+}
+
+// Returns the next expectation to match against: staged (one-shot) expectations are consumed in
+// order and take precedence over sticky ones, which can be hit any number of times
+fn pop_staged<T: Clone>(
+    staged: &mut [Staged<T>],
+    expect_count: &mut i32,
+) -> Option<(T, &'static Location<'static>)> {
+    let entry = match staged
+        .iter()
+        .position(|entry| !entry.sticky && entry.hits == 0)
+    {
+        Some(index) => {
+            *expect_count -= 1;
+            &mut staged[index]
+        }
+        None => staged.iter_mut().find(|entry| entry.sticky)?,
+    };
+    entry.hits += 1;
+    Some((entry.expected.clone(), entry.location))
+}
+
+// e.g. "content-TYPE" -> "Content-Type"
+fn title_case(name: &str) -> String {
+    name.split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first
+                    .to_uppercase()
+                    .chain(chars.flat_map(char::to_lowercase))
+                    .collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+// Appends one line per staged expectation of the given host call to the summary report
+fn summarize<T: fmt::Debug>(report: &mut Vec<String>, host_call: HostCall, staged: &[Staged<T>]) {
+    for entry in staged {
+        let hits = match entry.hits {
+            0 => "unmatched".to_string(),
+            1 => "matched 1 time".to_string(),
+            hits => format!("matched {} times", hits),
+        };
+        // tuples already print their own parentheses
+        let mut expected = format!("{:?}", entry.expected);
+        if !expected.starts_with('(') {
+            expected = format!("({})", expected);
+        }
+        report.push(format!(
+            "  {}{}{} - {} (staged at {})",
+            host_call.name(),
+            expected,
+            if entry.sticky { " [always]" } else { "" },
+            hits,
+            entry.location
+        ));
+    }
+}
+
+fn mark_sticky<T>(staged: &mut [Staged<T>], expect_count: &mut i32) {
+    if let Some(entry) = staged.last_mut() {
+        if !entry.sticky && entry.hits == 0 {
+            entry.sticky = true;
+            *expect_count -= 1;
+        }
     }
 }
 
@@ -37,22 +220,29 @@ impl ExpectHandle {
         }
     }
 
+    // Starts a fresh stage, carrying over the options that persist across stages
     pub fn update_stage(&mut self, allow_unexpected: bool) {
-        self.staged = Expect::new(allow_unexpected);
+        let mut staged = Expect::new(allow_unexpected);
+        staged.allow_unexpected_calls = std::mem::take(&mut self.staged.allow_unexpected_calls);
+        staged.canonical_header_names = self.staged.canonical_header_names;
+        self.staged = staged;
     }
 
     pub fn assert_stage(&self) {
+        let summary = self.staged.summary();
         if self.staged.expect_count > 0 {
             panic!(
-                "Error: failed to consume all expectations - total remaining: {}\n{:?}",
-                self.staged.expect_count, self.staged
+                "Error: failed to consume all expectations - total remaining: {}\n{}",
+                self.staged.expect_count, summary
             );
         } else if self.staged.expect_count < 0 {
             panic!(
                 "Error: expectations failed to account for all host calls by {} \n\
-            if this is intended, please use --allow-unexpected (-a) mode",
-                -1 * self.staged.expect_count
+            if this is intended, please use --allow-unexpected (-a) mode\n{}",
+                -self.staged.expect_count, summary
             );
+        } else if !summary.is_empty() {
+            debug!("{}", summary);
         }
     }
 
@@ -64,39 +254,42 @@ impl ExpectHandle {
 // Structure for setting low-level expectations over specific host functions
 #[derive(Debug)]
 pub struct Expect {
+    status: ExpectStatus,
     allow_unexpected: bool,
+    allow_unexpected_calls: Vec<HostCall>,
+    canonical_header_names: bool,
     pub expect_count: i32,
-    log_message: Vec<(Option<i32>, Option<String>)>,
-    tick_period_millis: Vec<Option<Duration>>,
-    current_time_nanos: Vec<Option<SystemTime>>,
-    get_buffer_bytes: Vec<(Option<i32>, Option<Bytes>)>,
-    set_buffer_bytes: Vec<(Option<i32>, Option<Bytes>)>,
-    get_header_map_pairs: Vec<(Option<i32>, Option<Bytes>)>,
-    set_header_map_pairs: Vec<(Option<i32>, Option<Bytes>)>,
-    get_header_map_value: Vec<(Option<i32>, Option<String>, Option<String>)>,
-    replace_header_map_value: Vec<(Option<i32>, Option<String>, Option<String>)>,
-    remove_header_map_value: Vec<(Option<i32>, Option<String>)>,
-    add_header_map_value: Vec<(Option<i32>, Option<String>, Option<String>)>,
-    send_local_response: Vec<(Option<i32>, Option<String>, Option<Bytes>, Option<i32>)>,
-    http_call: Vec<(
-        Option<String>,
-        Option<Bytes>,
-        Option<String>,
-        Option<Bytes>,
-        Option<Duration>,
-        Option<u32>,
-    )>,
-    metrics_create: Vec<(i32, String)>,
-    metrics_increment: Vec<(i32, i64)>,
-    metrics_record: Vec<(i32, u64)>,
-    metrics_get: Vec<(i32, u64)>,
+    last_staged: Option<HostCall>,
+    unexpected_calls: Vec<HostCall>,
+    log_message: Vec<Staged<(Matches<i32>, Matches<str>)>>,
+    tick_period_millis: Vec<Staged<Matches<u64>>>,
+    current_time_nanos: Vec<Staged<Option<SystemTime>>>,
+    get_buffer_bytes: Vec<Staged<(Matches<i32>, Option<Bytes>)>>,
+    set_buffer_bytes: Vec<Staged<BufferBytes>>,
+    get_header_map_pairs: Vec<Staged<(Matches<i32>, Option<Bytes>)>>,
+    set_header_map_pairs: Vec<Staged<(Matches<i32>, Matches<Pairs>)>>,
+    get_header_map_value: Vec<Staged<HeaderMapLookup>>,
+    replace_header_map_value: Vec<Staged<HeaderMapValue>>,
+    remove_header_map_value: Vec<Staged<(Matches<i32>, Matches<str>)>>,
+    add_header_map_value: Vec<Staged<HeaderMapValue>>,
+    send_local_response: Vec<Staged<LocalResponse>>,
+    http_call: Vec<Staged<HttpCall>>,
+    metrics_create: Vec<Staged<(Matches<i32>, Matches<str>)>>,
+    metrics_increment: Vec<Staged<(Matches<i32>, Matches<i64>)>>,
+    metrics_record: Vec<Staged<(Matches<i32>, Matches<u64>)>>,
+    metrics_get: Vec<Staged<(Matches<i32>, Matches<u64>)>>,
 }
 
 impl Expect {
     pub fn new(allow_unexpected: bool) -> Expect {
         Expect {
+            status: ExpectStatus::Unexpected,
             allow_unexpected: allow_unexpected,
+            allow_unexpected_calls: vec![],
+            canonical_header_names: false,
             expect_count: 0,
+            last_staged: None,
+            unexpected_calls: vec![],
             log_message: vec![],
             tick_period_millis: vec![],
             current_time_nanos: vec![],
@@ -117,553 +310,694 @@ impl Expect {
         }
     }
 
-    pub fn set_expect_log(&mut self, log_level: Option<i32>, log_string: Option<&str>) {
+    pub fn set_allow_unexpected_call(&mut self, host_call: HostCall, allow: bool) {
+        self.allow_unexpected_calls
+            .retain(|allowed_call| *allowed_call != host_call);
+        if allow {
+            self.allow_unexpected_calls.push(host_call);
+        }
+    }
+
+    pub fn set_canonical_header_names(&mut self, canonical_header_names: bool) {
+        self.canonical_header_names = canonical_header_names;
+    }
+
+    // With canonicalization on, header names match in any case: the name as sent by the module,
+    // its lowercase and its Title-Case forms are all tried against the expectation
+    fn matches_header_name(&self, expected: &Matches<str>, name: &str) -> bool {
+        if !self.canonical_header_names {
+            return expected.matches(name);
+        }
+        expected.matches(name)
+            || expected.matches(&name.to_lowercase())
+            || expected.matches(&title_case(name))
+    }
+
+    fn matches_header_pairs(&self, expected: &Matches<Pairs>, pairs: &Pairs) -> bool {
+        if !self.canonical_header_names {
+            return expected.matches(pairs);
+        }
+        let with_names = |canonical: fn(&str) -> String| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(name, value)| (canonical(name), value.clone()))
+                .collect()
+        };
+        expected.matches(pairs)
+            || expected.matches(&with_names(str::to_lowercase))
+            || expected.matches(&with_names(title_case))
+    }
+
+    // Outcome of the last host call checked against the staged expectations
+    pub fn status(&self) -> ExpectStatus {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: ExpectStatus) {
+        self.status = status;
+    }
+
+    fn set_expect_status(&mut self, location: &Location, checks: bool) {
+        if checks {
+            self.status = ExpectStatus::Expected;
+        } else {
+            error!(
+                "Error: host call does not match the expectation staged at {}",
+                location
+            );
+            self.status = ExpectStatus::Failed;
+        }
+    }
+
+    fn unexpected(&mut self, host_call: HostCall) {
+        self.unexpected_calls.push(host_call);
+        if !self.allow_unexpected && !self.allow_unexpected_calls.contains(&host_call) {
+            self.expect_count -= 1;
+        }
+        self.status = ExpectStatus::Unexpected;
+    }
+
+    // Report of every staged expectation (and how often it matched) along with the host calls
+    // that no expectation accounted for, empty if nothing was staged or called
+    pub fn summary(&self) -> String {
+        let mut report = Vec::new();
+        summarize(&mut report, HostCall::Log, &self.log_message);
+        summarize(
+            &mut report,
+            HostCall::SetTickPeriodMillis,
+            &self.tick_period_millis,
+        );
+        summarize(
+            &mut report,
+            HostCall::GetCurrentTimeNanos,
+            &self.current_time_nanos,
+        );
+        summarize(
+            &mut report,
+            HostCall::GetBufferBytes,
+            &self.get_buffer_bytes,
+        );
+        summarize(
+            &mut report,
+            HostCall::SetBufferBytes,
+            &self.set_buffer_bytes,
+        );
+        summarize(
+            &mut report,
+            HostCall::GetHeaderMapPairs,
+            &self.get_header_map_pairs,
+        );
+        summarize(
+            &mut report,
+            HostCall::SetHeaderMapPairs,
+            &self.set_header_map_pairs,
+        );
+        summarize(
+            &mut report,
+            HostCall::GetHeaderMapValue,
+            &self.get_header_map_value,
+        );
+        summarize(
+            &mut report,
+            HostCall::ReplaceHeaderMapValue,
+            &self.replace_header_map_value,
+        );
+        summarize(
+            &mut report,
+            HostCall::RemoveHeaderMapValue,
+            &self.remove_header_map_value,
+        );
+        summarize(
+            &mut report,
+            HostCall::AddHeaderMapValue,
+            &self.add_header_map_value,
+        );
+        summarize(
+            &mut report,
+            HostCall::SendLocalResponse,
+            &self.send_local_response,
+        );
+        summarize(&mut report, HostCall::HttpCall, &self.http_call);
+        summarize(&mut report, HostCall::MetricCreate, &self.metrics_create);
+        summarize(
+            &mut report,
+            HostCall::MetricIncrement,
+            &self.metrics_increment,
+        );
+        summarize(&mut report, HostCall::MetricRecord, &self.metrics_record);
+        summarize(&mut report, HostCall::MetricGet, &self.metrics_get);
+        if !report.is_empty() {
+            report.insert(0, "Expectation summary:".to_string());
+        }
+        if !self.unexpected_calls.is_empty() {
+            report.push("Unexpected host calls:".to_string());
+            for host_call in &self.unexpected_calls {
+                report.push(format!("  {}", host_call.name()));
+            }
+        }
+        report.join("\n")
+    }
+
+    // Marks the most recently staged expectation as sticky so that it matches any number of calls
+    pub fn set_expect_always(&mut self) {
+        let expect_count = &mut self.expect_count;
+        match self.last_staged {
+            Some(HostCall::Log) => mark_sticky(&mut self.log_message, expect_count),
+            Some(HostCall::SetTickPeriodMillis) => {
+                mark_sticky(&mut self.tick_period_millis, expect_count)
+            }
+            Some(HostCall::GetCurrentTimeNanos) => {
+                mark_sticky(&mut self.current_time_nanos, expect_count)
+            }
+            Some(HostCall::GetBufferBytes) => mark_sticky(&mut self.get_buffer_bytes, expect_count),
+            Some(HostCall::SetBufferBytes) => mark_sticky(&mut self.set_buffer_bytes, expect_count),
+            Some(HostCall::GetHeaderMapPairs) => {
+                mark_sticky(&mut self.get_header_map_pairs, expect_count)
+            }
+            Some(HostCall::SetHeaderMapPairs) => {
+                mark_sticky(&mut self.set_header_map_pairs, expect_count)
+            }
+            Some(HostCall::GetHeaderMapValue) => {
+                mark_sticky(&mut self.get_header_map_value, expect_count)
+            }
+            Some(HostCall::ReplaceHeaderMapValue) => {
+                mark_sticky(&mut self.replace_header_map_value, expect_count)
+            }
+            Some(HostCall::RemoveHeaderMapValue) => {
+                mark_sticky(&mut self.remove_header_map_value, expect_count)
+            }
+            Some(HostCall::AddHeaderMapValue) => {
+                mark_sticky(&mut self.add_header_map_value, expect_count)
+            }
+            Some(HostCall::SendLocalResponse) => {
+                mark_sticky(&mut self.send_local_response, expect_count)
+            }
+            Some(HostCall::HttpCall) => mark_sticky(&mut self.http_call, expect_count),
+            Some(HostCall::MetricCreate) => mark_sticky(&mut self.metrics_create, expect_count),
+            Some(HostCall::MetricIncrement) => {
+                mark_sticky(&mut self.metrics_increment, expect_count)
+            }
+            Some(HostCall::MetricRecord) => mark_sticky(&mut self.metrics_record, expect_count),
+            Some(HostCall::MetricGet) => mark_sticky(&mut self.metrics_get, expect_count),
+            None => panic!("Error: always() must follow the expectation it applies to"),
+        }
+    }
+
+    #[track_caller]
+    pub fn set_expect_log(
+        &mut self,
+        log_level: impl Into<Matches<i32>>,
+        log_string: impl Into<Matches<str>>,
+    ) {
         self.expect_count += 1;
+        self.last_staged = Some(HostCall::Log);
         self.log_message
-            .push((log_level, log_string.map(|s| s.to_string())));
+            .push(Staged::new((log_level.into(), log_string.into())));
     }
 
     pub fn get_expect_log(&mut self, log_level: i32, log_string: &str) {
-        match self.log_message.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let log_tuple = self.log_message.remove(0);
-                let mut expect_status = log_level == log_tuple.0.unwrap_or(log_level);
-                expect_status =
-                    expect_status && log_string == log_tuple.1.unwrap_or(log_string.to_string());
-                set_expect_status(expect_status);
+        match pop_staged(&mut self.log_message, &mut self.expect_count) {
+            None => self.unexpected(HostCall::Log),
+            Some(((expect_level, expect_string), location)) => {
+                self.set_expect_status(
+                    location,
+                    expect_level.matches(&log_level) && expect_string.matches(log_string),
+                );
             }
         }
     }
 
-    pub fn set_expect_set_tick_period_millis(&mut self, tick_period_millis: Option<u64>) {
+    #[track_caller]
+    pub fn set_expect_set_tick_period_millis(
+        &mut self,
+        tick_period_millis: impl Into<Matches<u64>>,
+    ) {
         self.expect_count += 1;
+        self.last_staged = Some(HostCall::SetTickPeriodMillis);
         self.tick_period_millis
-            .push(tick_period_millis.map(|period| Duration::from_millis(period)));
+            .push(Staged::new(tick_period_millis.into()));
     }
 
-    pub fn get_expect_set_tick_period_millis(&mut self, tick_period_millis: u128) {
-        match self.tick_period_millis.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let expect_status = tick_period_millis
-                    == self
-                        .tick_period_millis
-                        .remove(0)
-                        .map(|period| period.as_millis())
-                        .unwrap_or(tick_period_millis);
-                set_expect_status(expect_status);
+    pub fn get_expect_set_tick_period_millis(&mut self, tick_period_millis: u64) {
+        match pop_staged(&mut self.tick_period_millis, &mut self.expect_count) {
+            None => self.unexpected(HostCall::SetTickPeriodMillis),
+            Some((expect_period, location)) => {
+                self.set_expect_status(location, expect_period.matches(&tick_period_millis));
             }
         }
     }
 
+    #[track_caller]
     pub fn set_expect_get_current_time_nanos(&mut self, current_time_nanos: Option<u64>) {
         self.expect_count += 1;
-        self.current_time_nanos.push(
+        self.last_staged = Some(HostCall::GetCurrentTimeNanos);
+        self.current_time_nanos.push(Staged::new(
             current_time_nanos.map(|time_nanos| UNIX_EPOCH + Duration::from_nanos(time_nanos)),
-        );
+        ));
     }
 
     pub fn get_expect_get_current_time_nanos(&mut self) -> Option<u128> {
-        match self.current_time_nanos.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
+        match pop_staged(&mut self.current_time_nanos, &mut self.expect_count) {
+            None => {
+                self.unexpected(HostCall::GetCurrentTimeNanos);
                 None
             }
-            _ => {
-                self.expect_count -= 1;
-                set_status(ExpectStatus::Expected);
-                self.current_time_nanos
-                    .remove(0)
+            Some((current_time_nanos, _)) => {
+                self.status = ExpectStatus::Expected;
+                current_time_nanos
                     .map(|time_nanos| time_nanos.duration_since(UNIX_EPOCH).unwrap().as_nanos())
             }
         }
     }
 
+    #[track_caller]
     pub fn set_expect_get_buffer_bytes(
         &mut self,
-        buffer_type: Option<i32>,
-        buffer_data: Option<&str>,
+        buffer_type: impl Into<Matches<i32>>,
+        buffer_data: Option<&[u8]>,
     ) {
         self.expect_count += 1;
-        self.get_buffer_bytes.push((
-            buffer_type,
-            buffer_data.map(|data| data.as_bytes().to_vec()),
-        ));
+        self.last_staged = Some(HostCall::GetBufferBytes);
+        self.get_buffer_bytes.push(Staged::new((
+            buffer_type.into(),
+            buffer_data.map(|data| data.to_vec()),
+        )));
     }
 
     pub fn get_expect_get_buffer_bytes(&mut self, buffer_type: i32) -> Option<Bytes> {
-        match self.get_buffer_bytes.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
+        match pop_staged(&mut self.get_buffer_bytes, &mut self.expect_count) {
+            None => {
+                self.unexpected(HostCall::GetBufferBytes);
                 None
             }
-            _ => {
-                self.expect_count -= 1;
-                let expect_status =
-                    buffer_type == self.get_buffer_bytes[0].0.unwrap_or(buffer_type);
-                set_expect_status(expect_status);
-                self.get_buffer_bytes.remove(0).1
+            Some(((expect_type, buffer_data), location)) => {
+                self.set_expect_status(location, expect_type.matches(&buffer_type));
+                buffer_data
             }
         }
     }
 
+    #[track_caller]
     pub fn set_expect_set_buffer_bytes(
         &mut self,
-        buffer_type: Option<i32>,
-        buffer_data: Option<&str>,
+        buffer_type: impl Into<Matches<i32>>,
+        buffer_data: impl Into<Matches<[u8]>>,
     ) {
         self.expect_count += 1;
-        self.set_buffer_bytes.push((
-            buffer_type,
-            buffer_data.map(|data| data.as_bytes().to_vec()),
-        ));
+        self.last_staged = Some(HostCall::SetBufferBytes);
+        self.set_buffer_bytes
+            .push(Staged::new((buffer_type.into(), buffer_data.into())));
     }
 
     pub fn get_expect_set_buffer_bytes(&mut self, buffer_type: i32, buffer_data: &[u8]) {
-        match self.set_buffer_bytes.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let expect_buffer = self.set_buffer_bytes.remove(0);
-                let mut expect_status = buffer_type == expect_buffer.0.unwrap_or(buffer_type);
-                expect_status = expect_status
-                    && &buffer_data == &&expect_buffer.1.unwrap_or(buffer_data.to_vec())[..];
-                set_expect_status(expect_status);
+        match pop_staged(&mut self.set_buffer_bytes, &mut self.expect_count) {
+            None => self.unexpected(HostCall::SetBufferBytes),
+            Some(((expect_type, expect_data), location)) => {
+                self.set_expect_status(
+                    location,
+                    expect_type.matches(&buffer_type) && expect_data.matches(buffer_data),
+                );
             }
         }
     }
 
-    pub fn set_expect_get_header_map_pairs(
+    #[track_caller]
+    pub fn set_expect_get_header_map_pairs<K: AsRef<[u8]>, V: AsRef<[u8]>>(
         &mut self,
-        map_type: Option<i32>,
-        header_map_pairs: Option<Vec<(&str, &str)>>,
+        map_type: impl Into<Matches<i32>>,
+        header_map_pairs: Option<Vec<(K, V)>>,
     ) {
         self.expect_count += 1;
-        self.get_header_map_pairs
-            .push((map_type, header_map_pairs.map(|map| serialize_map(map))));
+        self.last_staged = Some(HostCall::GetHeaderMapPairs);
+        self.get_header_map_pairs.push(Staged::new((
+            map_type.into(),
+            header_map_pairs.map(serialize_map),
+        )));
     }
 
     pub fn get_expect_get_header_map_pairs(&mut self, map_type: i32) -> Option<Bytes> {
-        match self.get_header_map_pairs.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
+        match pop_staged(&mut self.get_header_map_pairs, &mut self.expect_count) {
+            None => {
+                self.unexpected(HostCall::GetHeaderMapPairs);
                 None
             }
-            _ => {
-                self.expect_count -= 1;
-                let expect_status = map_type == self.get_header_map_pairs[0].0.unwrap_or(map_type);
-                set_expect_status(expect_status);
-                self.get_header_map_pairs.remove(0).1
+            Some(((expect_type, header_map_pairs), location)) => {
+                self.set_expect_status(location, expect_type.matches(&map_type));
+                header_map_pairs
             }
         }
     }
 
+    #[track_caller]
     pub fn set_expect_set_header_map_pairs(
         &mut self,
-        map_type: Option<i32>,
-        header_map_pairs: Option<Vec<(&str, &str)>>,
+        map_type: impl Into<Matches<i32>>,
+        header_map_pairs: impl Into<Matches<Pairs>>,
     ) {
         self.expect_count += 1;
+        self.last_staged = Some(HostCall::SetHeaderMapPairs);
         self.set_header_map_pairs
-            .push((map_type, header_map_pairs.map(|map| serialize_map(map))));
+            .push(Staged::new((map_type.into(), header_map_pairs.into())));
     }
 
-    pub fn get_expect_set_header_map_pairs(&mut self, map_type: i32, header_map_pairs: &[u8]) {
-        match self.set_header_map_pairs.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let mut expect_status =
-                    map_type == self.set_header_map_pairs[0].0.unwrap_or(map_type);
-                expect_status = expect_status
-                    && &header_map_pairs
-                        == &&self
-                            .set_header_map_pairs
-                            .remove(0)
-                            .1
-                            .unwrap_or(header_map_pairs.to_vec())[..];
-                set_expect_status(expect_status);
+    pub fn get_expect_set_header_map_pairs(&mut self, map_type: i32, header_map_pairs: &Pairs) {
+        match pop_staged(&mut self.set_header_map_pairs, &mut self.expect_count) {
+            None => self.unexpected(HostCall::SetHeaderMapPairs),
+            Some(((expect_type, expect_pairs), location)) => {
+                self.set_expect_status(
+                    location,
+                    expect_type.matches(&map_type)
+                        && self.matches_header_pairs(&expect_pairs, header_map_pairs),
+                );
             }
         }
     }
 
+    #[track_caller]
     pub fn set_expect_get_header_map_value(
         &mut self,
-        map_type: Option<i32>,
-        header_map_key: Option<&str>,
-        header_map_value: Option<&str>,
+        map_type: impl Into<Matches<i32>>,
+        header_map_key: impl Into<Matches<str>>,
+        header_map_value: Response<str, Option<Bytes>>,
     ) {
         self.expect_count += 1;
-        self.get_header_map_value.push((
-            map_type,
-            header_map_key.map(|key| key.to_string()),
-            header_map_value.map(|value| value.to_string()),
-        ));
+        self.last_staged = Some(HostCall::GetHeaderMapValue);
+        self.get_header_map_value.push(Staged::new((
+            map_type.into(),
+            header_map_key.into(),
+            header_map_value,
+        )));
     }
 
     pub fn get_expect_get_header_map_value(
         &mut self,
         map_type: i32,
         header_map_key: &str,
-    ) -> Option<String> {
-        match self.get_header_map_value.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
+    ) -> Option<Bytes> {
+        match pop_staged(&mut self.get_header_map_value, &mut self.expect_count) {
+            None => {
+                self.unexpected(HostCall::GetHeaderMapValue);
                 None
             }
-            _ => {
-                self.expect_count -= 1;
-                let header_map_tuple = self.get_header_map_value.remove(0);
-                let mut expect_status = map_type == header_map_tuple.0.unwrap_or(map_type);
-                expect_status = expect_status
-                    && header_map_key == &header_map_tuple.1.unwrap_or(header_map_key.to_string());
-                set_expect_status(expect_status);
-                header_map_tuple.2
+            Some(((expect_type, expect_key, header_map_value), location)) => {
+                self.set_expect_status(
+                    location,
+                    expect_type.matches(&map_type)
+                        && self.matches_header_name(&expect_key, header_map_key),
+                );
+                header_map_value.respond(header_map_key)
             }
         }
     }
 
+    #[track_caller]
     pub fn set_expect_replace_header_map_value(
         &mut self,
-        map_type: Option<i32>,
-        header_map_key: Option<&str>,
-        header_map_value: Option<&str>,
+        map_type: impl Into<Matches<i32>>,
+        header_map_key: impl Into<Matches<str>>,
+        header_map_value: impl Into<Matches<[u8]>>,
     ) {
         self.expect_count += 1;
-        self.replace_header_map_value.push((
-            map_type,
-            header_map_key.map(|key| key.to_string()),
-            header_map_value.map(|value| value.to_string()),
-        ));
+        self.last_staged = Some(HostCall::ReplaceHeaderMapValue);
+        self.replace_header_map_value.push(Staged::new((
+            map_type.into(),
+            header_map_key.into(),
+            header_map_value.into(),
+        )));
     }
 
     pub fn get_expect_replace_header_map_value(
         &mut self,
         map_type: i32,
         header_map_key: &str,
-        header_map_value: &str,
+        header_map_value: &[u8],
     ) {
-        match self.replace_header_map_value.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let header_map_tuple = self.replace_header_map_value.remove(0);
-                let mut expect_status = map_type == header_map_tuple.0.unwrap_or(map_type);
-                expect_status = expect_status
-                    && header_map_key == &header_map_tuple.1.unwrap_or(header_map_key.to_string());
-                expect_status = expect_status
-                    && header_map_value
-                        == &header_map_tuple.2.unwrap_or(header_map_value.to_string());
-                set_expect_status(expect_status);
+        match pop_staged(&mut self.replace_header_map_value, &mut self.expect_count) {
+            None => self.unexpected(HostCall::ReplaceHeaderMapValue),
+            Some(((expect_type, expect_key, expect_value), location)) => {
+                self.set_expect_status(
+                    location,
+                    expect_type.matches(&map_type)
+                        && self.matches_header_name(&expect_key, header_map_key)
+                        && expect_value.matches(header_map_value),
+                );
             }
         }
     }
 
+    #[track_caller]
     pub fn set_expect_remove_header_map_value(
         &mut self,
-        map_type: Option<i32>,
-        header_map_key: Option<&str>,
+        map_type: impl Into<Matches<i32>>,
+        header_map_key: impl Into<Matches<str>>,
     ) {
         self.expect_count += 1;
+        self.last_staged = Some(HostCall::RemoveHeaderMapValue);
         self.remove_header_map_value
-            .push((map_type, header_map_key.map(|key| key.to_string())));
+            .push(Staged::new((map_type.into(), header_map_key.into())));
     }
 
     pub fn get_expect_remove_header_map_value(&mut self, map_type: i32, header_map_key: &str) {
-        match self.remove_header_map_value.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let header_map_tuple = self.remove_header_map_value.remove(0);
-                let mut expect_status = map_type == header_map_tuple.0.unwrap_or(map_type);
-                expect_status = expect_status
-                    && header_map_key == &header_map_tuple.1.unwrap_or(header_map_key.to_string());
-                set_expect_status(expect_status);
+        match pop_staged(&mut self.remove_header_map_value, &mut self.expect_count) {
+            None => self.unexpected(HostCall::RemoveHeaderMapValue),
+            Some(((expect_type, expect_key), location)) => {
+                self.set_expect_status(
+                    location,
+                    expect_type.matches(&map_type)
+                        && self.matches_header_name(&expect_key, header_map_key),
+                );
             }
         }
     }
 
+    #[track_caller]
     pub fn set_expect_add_header_map_value(
         &mut self,
-        map_type: Option<i32>,
-        header_map_key: Option<&str>,
-        header_map_value: Option<&str>,
+        map_type: impl Into<Matches<i32>>,
+        header_map_key: impl Into<Matches<str>>,
+        header_map_value: impl Into<Matches<[u8]>>,
     ) {
         self.expect_count += 1;
-        self.add_header_map_value.push((
-            map_type,
-            header_map_key.map(|key| key.to_string()),
-            header_map_value.map(|value| value.to_string()),
-        ));
+        self.last_staged = Some(HostCall::AddHeaderMapValue);
+        self.add_header_map_value.push(Staged::new((
+            map_type.into(),
+            header_map_key.into(),
+            header_map_value.into(),
+        )));
     }
 
     pub fn get_expect_add_header_map_value(
         &mut self,
         map_type: i32,
         header_map_key: &str,
-        header_map_value: &str,
+        header_map_value: &[u8],
     ) {
-        match self.add_header_map_value.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let header_map_tuple = self.add_header_map_value.remove(0);
-                let mut expect_status = map_type == header_map_tuple.0.unwrap_or(map_type);
-                expect_status = expect_status
-                    && header_map_key == &header_map_tuple.1.unwrap_or(header_map_key.to_string());
-                expect_status = expect_status
-                    && header_map_value
-                        == &header_map_tuple.2.unwrap_or(header_map_value.to_string());
-                set_expect_status(expect_status);
+        match pop_staged(&mut self.add_header_map_value, &mut self.expect_count) {
+            None => self.unexpected(HostCall::AddHeaderMapValue),
+            Some(((expect_type, expect_key, expect_value), location)) => {
+                self.set_expect_status(
+                    location,
+                    expect_type.matches(&map_type)
+                        && self.matches_header_name(&expect_key, header_map_key)
+                        && expect_value.matches(header_map_value),
+                );
             }
         }
     }
 
+    #[track_caller]
     pub fn set_expect_send_local_response(
         &mut self,
-        status_code: Option<i32>,
-        body: Option<&str>,
-        headers: Option<Vec<(&str, &str)>>,
-        grpc_status: Option<i32>,
+        status_code: impl Into<Matches<i32>>,
+        body: impl Into<Matches<[u8]>>,
+        headers: impl Into<Matches<Pairs>>,
+        grpc_status: impl Into<Matches<i32>>,
     ) {
         self.expect_count += 1;
-        self.send_local_response.push((
-            status_code,
-            body.map(|data| data.to_string()),
-            headers.map(|data| serialize_map(data)),
-            grpc_status,
-        ))
+        self.last_staged = Some(HostCall::SendLocalResponse);
+        self.send_local_response.push(Staged::new((
+            status_code.into(),
+            body.into(),
+            headers.into(),
+            grpc_status.into(),
+        )))
     }
 
     pub fn get_expect_send_local_response(
         &mut self,
         status_code: i32,
-        body: Option<&str>,
-        headers: &[u8],
+        body: &[u8],
+        headers: &Pairs,
         grpc_status: i32,
     ) {
-        match self.send_local_response.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let local_response_tuple = self.send_local_response.remove(0);
-                let mut expect_status =
-                    status_code == local_response_tuple.0.unwrap_or(status_code);
-                expect_status = expect_status
-                    && body.unwrap_or("default")
-                        == &local_response_tuple
-                            .1
-                            .unwrap_or(body.unwrap_or("default").to_string());
-                expect_status = expect_status
-                    && &headers == &&local_response_tuple.2.unwrap_or(headers.to_vec())[..];
-                expect_status =
-                    expect_status && grpc_status == local_response_tuple.3.unwrap_or(grpc_status);
-                set_expect_status(expect_status);
+        match pop_staged(&mut self.send_local_response, &mut self.expect_count) {
+            None => self.unexpected(HostCall::SendLocalResponse),
+            Some((
+                (expect_status_code, expect_body, expect_headers, expect_grpc_status),
+                location,
+            )) => {
+                self.set_expect_status(
+                    location,
+                    expect_status_code.matches(&status_code)
+                        && expect_body.matches(body)
+                        && self.matches_header_pairs(&expect_headers, headers)
+                        && expect_grpc_status.matches(&grpc_status),
+                );
             }
         }
     }
 
+    #[track_caller]
     pub fn set_expect_http_call(
         &mut self,
-        upstream: Option<&str>,
-        headers: Option<Vec<(&str, &str)>>,
-        body: Option<&str>,
-        trailers: Option<Vec<(&str, &str)>>,
-        timeout: Option<u64>,
+        upstream: impl Into<Matches<str>>,
+        headers: impl Into<Matches<Pairs>>,
+        body: impl Into<Matches<[u8]>>,
+        trailers: impl Into<Matches<Pairs>>,
+        timeout: impl Into<Matches<u64>>,
         token_id: Option<u32>,
     ) {
         self.expect_count += 1;
-        self.http_call.push((
-            upstream.map(|data| data.to_string()),
-            headers.map(|data| serialize_map(data)),
-            body.map(|data| data.to_string()),
-            trailers.map(|data| serialize_map(data)),
-            timeout.map(|data| Duration::from_millis(data)),
+        self.last_staged = Some(HostCall::HttpCall);
+        self.http_call.push(Staged::new((
+            upstream.into(),
+            headers.into(),
+            body.into(),
+            trailers.into(),
+            timeout.into(),
             token_id,
-        ));
+        )));
     }
 
     pub fn get_expect_http_call(
         &mut self,
         upstream: &str,
-        headers: &[u8],
-        body: Option<&str>,
-        trailers: &[u8],
-        timeout: i32,
+        headers: &Pairs,
+        body: &[u8],
+        trailers: &Pairs,
+        timeout: u64,
     ) -> Option<u32> {
-        match self.http_call.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
+        match pop_staged(&mut self.http_call, &mut self.expect_count) {
+            None => {
+                self.unexpected(HostCall::HttpCall);
                 None
             }
-            _ => {
-                self.expect_count -= 1;
-                let http_call_tuple = self.http_call.remove(0);
-                let mut expect_status =
-                    upstream == &http_call_tuple.0.unwrap_or(upstream.to_string());
-                expect_status = expect_status
-                    && &headers == &&http_call_tuple.1.unwrap_or(headers.to_vec())[..];
-                expect_status = expect_status
-                    && body.unwrap_or("default")
-                        == &http_call_tuple
-                            .2
-                            .unwrap_or(body.unwrap_or("default").to_string());
-                expect_status = expect_status
-                    && &trailers == &&http_call_tuple.3.unwrap_or(trailers.to_vec())[..];
-                expect_status = expect_status
-                    && timeout
-                        == http_call_tuple
-                            .4
-                            .map(|data| data.as_millis() as i32)
-                            .unwrap_or(timeout);
-                set_expect_status(expect_status);
-                http_call_tuple.5
-            }
-        }
-    }
-
-    pub fn set_expect_metric_create(&mut self, metric_type: i32, name: &str) {
+            Some((
+                (
+                    expect_upstream,
+                    expect_headers,
+                    expect_body,
+                    expect_trailers,
+                    expect_timeout,
+                    token_id,
+                ),
+                location,
+            )) => {
+                self.set_expect_status(
+                    location,
+                    expect_upstream.matches(upstream)
+                        && self.matches_header_pairs(&expect_headers, headers)
+                        && expect_body.matches(body)
+                        && self.matches_header_pairs(&expect_trailers, trailers)
+                        && expect_timeout.matches(&timeout),
+                );
+                token_id
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn set_expect_metric_create(
+        &mut self,
+        metric_type: impl Into<Matches<i32>>,
+        name: impl Into<Matches<str>>,
+    ) {
         self.expect_count += 1;
-        self.metrics_create.push((metric_type, name.to_string()));
+        self.last_staged = Some(HostCall::MetricCreate);
+        self.metrics_create
+            .push(Staged::new((metric_type.into(), name.into())));
     }
 
     pub fn get_expect_metric_create(&mut self, metric_type: i32, name: &str) {
-        match self.metrics_create.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let expected_metric_type = self.metrics_create.remove(0);
-                let expect_status = expected_metric_type == (metric_type, name.to_string());
-                set_expect_status(expect_status);
+        match pop_staged(&mut self.metrics_create, &mut self.expect_count) {
+            None => self.unexpected(HostCall::MetricCreate),
+            Some(((expect_type, expect_name), location)) => {
+                self.set_expect_status(
+                    location,
+                    expect_type.matches(&metric_type) && expect_name.matches(name),
+                );
             }
         }
     }
 
-    pub fn set_expect_metric_increment(&mut self, metric_id: i32, offset: i64) {
+    #[track_caller]
+    pub fn set_expect_metric_increment(
+        &mut self,
+        metric_id: impl Into<Matches<i32>>,
+        offset: impl Into<Matches<i64>>,
+    ) {
         self.expect_count += 1;
-        self.metrics_increment.push((metric_id, offset));
+        self.last_staged = Some(HostCall::MetricIncrement);
+        self.metrics_increment
+            .push(Staged::new((metric_id.into(), offset.into())));
     }
 
     pub fn get_expect_metric_increment(&mut self, metric_id: i32, offset: i64) {
-        match self.metrics_increment.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let expected_metric_increment_tuple = self.metrics_increment.remove(0);
-                let expect_status = expected_metric_increment_tuple == (metric_id, offset);
-                set_expect_status(expect_status);
+        match pop_staged(&mut self.metrics_increment, &mut self.expect_count) {
+            None => self.unexpected(HostCall::MetricIncrement),
+            Some(((expect_id, expect_offset), location)) => {
+                self.set_expect_status(
+                    location,
+                    expect_id.matches(&metric_id) && expect_offset.matches(&offset),
+                );
             }
         }
     }
 
-    pub fn set_expect_metric_record(&mut self, metric_id: i32, value: u64) {
+    #[track_caller]
+    pub fn set_expect_metric_record(
+        &mut self,
+        metric_id: impl Into<Matches<i32>>,
+        value: impl Into<Matches<u64>>,
+    ) {
         self.expect_count += 1;
-        self.metrics_record.push((metric_id, value));
+        self.last_staged = Some(HostCall::MetricRecord);
+        self.metrics_record
+            .push(Staged::new((metric_id.into(), value.into())));
     }
 
     pub fn get_expect_metric_record(&mut self, metric_id: i32, value: u64) {
-        match self.metrics_record.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let expected_metric_record_tuple = self.metrics_record.remove(0);
-                let expect_status = expected_metric_record_tuple == (metric_id, value);
-                set_expect_status(expect_status);
+        match pop_staged(&mut self.metrics_record, &mut self.expect_count) {
+            None => self.unexpected(HostCall::MetricRecord),
+            Some(((expect_id, expect_value), location)) => {
+                self.set_expect_status(
+                    location,
+                    expect_id.matches(&metric_id) && expect_value.matches(&value),
+                );
             }
         }
     }
 
-    pub fn set_expect_metric_get(&mut self, metric_id: i32, value: u64) {
+    #[track_caller]
+    pub fn set_expect_metric_get(
+        &mut self,
+        metric_id: impl Into<Matches<i32>>,
+        value: impl Into<Matches<u64>>,
+    ) {
         self.expect_count += 1;
-        self.metrics_get.push((metric_id, value));
+        self.last_staged = Some(HostCall::MetricGet);
+        self.metrics_get
+            .push(Staged::new((metric_id.into(), value.into())));
     }
 
     pub fn get_expect_metric_get(&mut self, metric_id: i32, value: u64) {
-        match self.metrics_get.len() {
-            0 => {
-                if !self.allow_unexpected {
-                    self.expect_count -= 1;
-                }
-                set_status(ExpectStatus::Unexpected);
-            }
-            _ => {
-                self.expect_count -= 1;
-                let expected_get_metric_tuple = self.metrics_get.remove(0);
-                let expect_status = expected_get_metric_tuple == (metric_id, value);
-                set_expect_status(expect_status);
+        match pop_staged(&mut self.metrics_get, &mut self.expect_count) {
+            None => self.unexpected(HostCall::MetricGet),
+            Some(((expect_id, expect_value), location)) => {
+                self.set_expect_status(
+                    location,
+                    expect_id.matches(&metric_id) && expect_value.matches(&value),
+                );
             }
         }
     }
@@ -0,0 +1,150 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Sampling profiler of the module (wasmtime only): while sampling, the stack of the module is
+// captured at every epoch (each millisecond of execution in the module), with the names of the
+// functions from the name section (demangled), e.g.
+//
+//   tester.record_stack_samples();
+//   for _ in 0..1000 {
+//       tester.send_request(2, request.clone())?.execute_all()?;
+//   }
+//   tester.take_stack_samples().write_folded("request.folded")?;
+//
+// The folded stacks ("outer;inner count" lines) are read by flamegraph.pl, inferno or speedscope,
+// and rendered to SVG by write_svg with the `flamegraph` feature. With
+// PROXY_WASM_TEST_FLAMEGRAPH=<dir>, every Tester samples and writes its stacks (and flamegraph)
+// into the directory when dropped. Callbacks returning within a millisecond are rarely sampled,
+// so slow paths are best profiled over many iterations.
+
+use crate::tester::Tester;
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PROFILES: AtomicUsize = AtomicUsize::new(0);
+
+// Number of samples of each stack, outermost function (the callback) first
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StackSamples {
+    stacks: BTreeMap<Vec<String>, u64>,
+}
+
+impl StackSamples {
+    // stacks are only sampled on wasmtime, see runtime::sample_stack
+    #[cfg(not(feature = "wasmi"))]
+    pub(crate) fn record(&mut self, stack: Vec<String>) {
+        if !stack.is_empty() {
+            *self.stacks.entry(stack).or_default() += 1;
+        }
+    }
+
+    pub fn stacks(&self) -> impl Iterator<Item = (&[String], u64)> {
+        self.stacks
+            .iter()
+            .map(|(stack, count)| (stack.as_slice(), *count))
+    }
+
+    pub fn total(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stacks.is_empty()
+    }
+
+    // Samples whose stack goes through the function (named as in the stacks), at any depth
+    pub fn count(&self, function: &str) -> u64 {
+        self.stacks
+            .iter()
+            .filter(|(stack, _)| stack.iter().any(|frame| frame == function))
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    // One "outer;inner count" line per stack
+    pub fn folded(&self) -> String {
+        self.stacks
+            .iter()
+            .map(|(stack, count)| {
+                let frames: Vec<String> =
+                    stack.iter().map(|frame| frame.replace(';', ":")).collect();
+                format!("{} {}\n", frames.join(";"), count)
+            })
+            .collect()
+    }
+
+    pub fn write_folded(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.folded()).with_context(|| format!("cannot write {}", path.display()))
+    }
+
+    #[cfg(feature = "flamegraph")]
+    pub fn write_svg(&self, path: impl AsRef<Path>, title: &str) -> Result<()> {
+        let path = path.as_ref();
+        let folded = self.folded();
+        let mut options = inferno::flamegraph::Options::default();
+        options.title = title.to_string();
+        options.count_name = "samples".to_string();
+        let file =
+            fs::File::create(path).with_context(|| format!("cannot write {}", path.display()))?;
+        inferno::flamegraph::from_lines(&mut options, folded.lines(), std::io::BufWriter::new(file))
+            .with_context(|| format!("cannot render {}", path.display()))
+    }
+}
+
+// Whether Testers sample from the start, see write_samples
+pub(crate) fn sampling_from_env() -> bool {
+    std::env::var_os("PROXY_WASM_TEST_FLAMEGRAPH").is_some()
+}
+
+// Writes the samples of the Tester into PROXY_WASM_TEST_FLAMEGRAPH as <test>-<pid>-<n>.folded
+// (and .svg with the flamegraph feature), named as the coverage profiles
+pub(crate) fn write_samples(tester: &Tester) {
+    let directory = match std::env::var("PROXY_WASM_TEST_FLAMEGRAPH") {
+        Ok(directory) => directory,
+        Err(_) => return,
+    };
+    let samples = tester.stack_samples();
+    if samples.is_empty() {
+        return;
+    }
+    let test_name = match std::thread::current().name() {
+        Some("main") | None => "flamegraph".to_string(),
+        Some(name) => name.replace("::", "-"),
+    };
+    let name = format!(
+        "{}-{}-{}",
+        test_name,
+        std::process::id(),
+        PROFILES.fetch_add(1, Ordering::Relaxed)
+    );
+    let path = Path::new(&directory).join(format!("{}.folded", name));
+    let written = fs::create_dir_all(&directory)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| samples.write_folded(&path));
+    #[cfg(feature = "flamegraph")]
+    let written = written.and_then(|_| {
+        samples.write_svg(
+            Path::new(&directory).join(format!("{}.svg", name)),
+            &test_name,
+        )
+    });
+    if let Err(error) = written {
+        eprintln!("cannot write the flamegraph of the module: {:#}", error);
+    }
+}
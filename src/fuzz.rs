@@ -0,0 +1,320 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Fuzzing of a module with cargo fuzz (libFuzzer): each input is decoded into a request and a
+// response (and optionally the plugin configuration), driven through the module with every
+// host call allowed; a trap or a failing callback is the crash libFuzzer reports, e.g.
+//
+//   thread_local! {
+//       static TARGET: RefCell<FuzzTarget> =
+//           RefCell::new(FuzzTarget::new(TestSetup::new("filter.wasm")));
+//   }
+//   fuzz_target!(|data: &[u8]| TARGET.with(|target| target.borrow_mut().run(data)));
+//
+// which generate_harness writes into a cargo fuzz project. The clock and seed are pinned, but
+// the module is started once (unless the configuration is fuzzed), so what it keeps across
+// streams can make a crash depend on the inputs run before. Seed corpora can be written from
+// the requests of existing tests with FuzzInput::encode.
+
+use crate::bench::Bench;
+use crate::http::{HeaderMap, HttpBody, HttpRequest, HttpResponse};
+use crate::tester::{TestSetup, Tester};
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+const FUZZ_TIME_NANOS: u64 = 1_600_000_000_000_000_000;
+const FUZZ_SEED: u64 = 0;
+
+const METHODS: &[&str] = &[
+    "GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+// flags of the first byte of an input
+const REQUEST_BODY: u8 = 1;
+const REQUEST_TRAILERS: u8 = 2;
+const RESPONSE_BODY: u8 = 4;
+const RESPONSE_TRAILERS: u8 = 8;
+
+pub struct FuzzTarget {
+    setup: TestSetup,
+    fuzz_plugin_config: bool,
+    // started module, kept across inputs unless the plugin configuration is fuzzed
+    bench: Option<Bench>,
+}
+
+impl FuzzTarget {
+    pub fn new(setup: TestSetup) -> FuzzTarget {
+        let mut setup = setup.before_start(|tester: &mut Tester| {
+            tester
+                .set_seed(FUZZ_SEED)
+                .set_default_current_time_nanos(FUZZ_TIME_NANOS);
+        });
+        setup.mock_settings.quiet = true;
+        setup.mock_settings.allow_unexpected = true;
+        FuzzTarget {
+            setup,
+            fuzz_plugin_config: false,
+            bench: None,
+        }
+    }
+
+    // Off by default: the plugin configuration comes from the input too, and the module is
+    // started anew for each input (configurations it rejects are not failures)
+    pub fn toggle_plugin_config(&mut self, on: bool) {
+        self.fuzz_plugin_config = on;
+        self.bench = None;
+    }
+
+    // Runs one input, panicking when the module fails on it
+    pub fn run(&mut self, data: &[u8]) {
+        let input = FuzzInput::decode(data, self.fuzz_plugin_config);
+        if let Err(error) = self.drive(&input) {
+            panic!("Error: the module failed on {:?}: {:#}", input, error);
+        }
+    }
+
+    fn drive(&mut self, input: &FuzzInput) -> Result<()> {
+        if let Some(plugin_config) = &input.plugin_config {
+            let setup = self.setup.clone().plugin_config(plugin_config);
+            self.bench = match setup.try_start()? {
+                Some(tester) => Some(Bench::new(tester)),
+                None => return Ok(()),
+            };
+        }
+        let bench = match self.bench.as_mut() {
+            Some(bench) => bench,
+            None => self.bench.insert(Bench::new(self.setup.start()?)),
+        };
+        bench.exchange(&input.request, &input.response)
+    }
+}
+
+// Input of a fuzz target. In the encoding, a byte of flags (bodies and trailers present) is
+// followed by the plugin configuration (when fuzzed), the method (an index in METHODS, or 255
+// and its name), path, authority, headers, body and trailers of the request, then the status
+// (from 100), headers, body and trailers of the response. Bytes are length-prefixed (u16, little
+// endian), header maps count-prefixed (u8), and a truncated input reads as zeros.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzInput {
+    pub plugin_config: Option<String>,
+    pub request: HttpRequest,
+    pub response: HttpResponse,
+}
+
+impl FuzzInput {
+    pub fn decode(data: &[u8], plugin_config: bool) -> FuzzInput {
+        let mut reader = Reader { data };
+        let flags = reader.byte();
+        let plugin_config = match plugin_config {
+            true => Some(reader.text()),
+            false => None,
+        };
+        let method = match METHODS.get(reader.byte() as usize) {
+            Some(method) => method.to_string(),
+            None => reader.text(),
+        };
+        let path = match reader.text() {
+            path if path.is_empty() => "/".to_string(),
+            path => path,
+        };
+        let mut request = HttpRequest::new(&method, &path);
+        let authority = reader.text();
+        if !authority.is_empty() {
+            request = request.authority(&authority);
+        }
+        reader.headers(&mut request.headers);
+        if flags & REQUEST_BODY != 0 {
+            request.body = Some(HttpBody::Full(reader.bytes()));
+        }
+        if flags & REQUEST_TRAILERS != 0 {
+            reader.headers(request.trailers.get_or_insert_with(HeaderMap::new));
+        }
+        let status = reader.byte() as u32 | (reader.byte() as u32) << 8;
+        let mut response = HttpResponse::new(100 + status % 500);
+        reader.headers(&mut response.headers);
+        if flags & RESPONSE_BODY != 0 {
+            response.body = Some(HttpBody::Full(reader.bytes()));
+        }
+        if flags & RESPONSE_TRAILERS != 0 {
+            reader.headers(response.trailers.get_or_insert_with(HeaderMap::new));
+        }
+        FuzzInput {
+            plugin_config,
+            request,
+            response,
+        }
+    }
+
+    // Input decoding (back) to this one, e.g. to seed a corpus with the requests of the tests
+    pub fn encode(&self) -> Vec<u8> {
+        let flags = [
+            (self.request.body.is_some(), REQUEST_BODY),
+            (self.request.trailers.is_some(), REQUEST_TRAILERS),
+            (self.response.body.is_some(), RESPONSE_BODY),
+            (self.response.trailers.is_some(), RESPONSE_TRAILERS),
+        ]
+        .iter()
+        .filter(|(present, _)| *present)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        let mut data = vec![flags];
+        if let Some(plugin_config) = &self.plugin_config {
+            put_bytes(&mut data, plugin_config.as_bytes());
+        }
+        let header = |name: &str| self.request.headers.get(name).unwrap_or_default();
+        match METHODS
+            .iter()
+            .position(|method| *method == header(":method"))
+        {
+            Some(index) => data.push(index as u8),
+            None => {
+                data.push(u8::MAX);
+                put_bytes(&mut data, header(":method").as_bytes());
+            }
+        }
+        put_bytes(&mut data, header(":path").as_bytes());
+        put_bytes(&mut data, header(":authority").as_bytes());
+        put_headers(&mut data, &self.request.headers);
+        if let Some(body) = &self.request.body {
+            put_bytes(&mut data, &body_bytes(body));
+        }
+        if let Some(trailers) = &self.request.trailers {
+            put_headers(&mut data, trailers);
+        }
+        let status = self
+            .response
+            .headers
+            .get(":status")
+            .and_then(|status| status.parse::<u32>().ok())
+            .unwrap_or(200);
+        data.extend_from_slice(&(status.saturating_sub(100) as u16).to_le_bytes());
+        put_headers(&mut data, &self.response.headers);
+        if let Some(body) = &self.response.body {
+            put_bytes(&mut data, &body_bytes(body));
+        }
+        if let Some(trailers) = &self.response.trailers {
+            put_headers(&mut data, trailers);
+        }
+        data
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> u8 {
+        match self.data.split_first() {
+            Some((byte, rest)) => {
+                self.data = rest;
+                *byte
+            }
+            None => 0,
+        }
+    }
+
+    fn bytes(&mut self) -> Vec<u8> {
+        let length = self.byte() as usize | (self.byte() as usize) << 8;
+        let (bytes, rest) = self.data.split_at(length.min(self.data.len()));
+        self.data = rest;
+        bytes.to_vec()
+    }
+
+    fn text(&mut self) -> String {
+        String::from_utf8_lossy(&self.bytes()).into_owned()
+    }
+
+    // Lowercased names, empty names and pseudo-headers dropped
+    fn headers(&mut self, headers: &mut HeaderMap) {
+        for _ in 0..self.byte() {
+            let name = self.text().to_ascii_lowercase();
+            let value = self.text();
+            if !name.is_empty() && !name.starts_with(':') {
+                headers.append(&name, &value);
+            }
+        }
+    }
+}
+
+fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    let bytes = &bytes[..bytes.len().min(u16::MAX as usize)];
+    data.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+    data.extend_from_slice(bytes);
+}
+
+fn put_headers(data: &mut Vec<u8>, headers: &HeaderMap) {
+    let headers: Vec<(&str, &str)> = headers
+        .iter()
+        .filter(|(name, _)| !name.starts_with(':'))
+        .take(u8::MAX as usize)
+        .collect();
+    data.push(headers.len() as u8);
+    for (name, value) in headers {
+        put_bytes(data, name.as_bytes());
+        put_bytes(data, value.as_bytes());
+    }
+}
+
+fn body_bytes(body: &HttpBody) -> Vec<u8> {
+    match body {
+        HttpBody::Full(body) => body.clone(),
+        HttpBody::Chunked(chunks) => chunks.concat(),
+    }
+}
+
+// Writes a cargo fuzz project (or adds a target to it) fuzzing the module, run with
+// `cargo fuzz run <target>` from the directory containing fuzz_dir
+pub fn generate_harness(fuzz_dir: impl AsRef<Path>, target: &str, wasm_path: &str) -> Result<()> {
+    let fuzz_dir = fuzz_dir.as_ref();
+    let wasm_path = fs::canonicalize(wasm_path)
+        .with_context(|| format!("cannot find the module {}", wasm_path))?;
+    fs::create_dir_all(fuzz_dir.join("fuzz_targets"))?;
+    let manifest_path = fuzz_dir.join("Cargo.toml");
+    let mut manifest = match fs::read_to_string(&manifest_path) {
+        Ok(manifest) => manifest,
+        Err(_) => format!(
+            "[package]\nname = \"proxy-wasm-fuzz\"\nversion = \"0.0.0\"\npublish = false\nedition = \"2018\"\n\n\
+             [package.metadata]\ncargo-fuzz = true\n\n\
+             [dependencies]\nlibfuzzer-sys = \"0.4\"\nproxy-wasm-test-framework = {{ path = {:?} }}\n",
+            env!("CARGO_MANIFEST_DIR")
+        ),
+    };
+    if !manifest.contains(&format!("name = {:?}\n", target)) {
+        manifest.push_str(&format!(
+            "\n[[bin]]\nname = {:?}\npath = \"fuzz_targets/{}.rs\"\ntest = false\ndoc = false\nbench = false\n",
+            target, target
+        ));
+    }
+    fs::write(&manifest_path, manifest)
+        .with_context(|| format!("cannot write {}", manifest_path.display()))?;
+    let target_path = fuzz_dir.join("fuzz_targets").join(format!("{}.rs", target));
+    fs::write(
+        &target_path,
+        format!(
+            "#![no_main]\n\n\
+             use libfuzzer_sys::fuzz_target;\n\
+             use proxy_wasm_test_framework::fuzz::FuzzTarget;\n\
+             use proxy_wasm_test_framework::tester::TestSetup;\n\
+             use std::cell::RefCell;\n\n\
+             thread_local! {{\n    static TARGET: RefCell<FuzzTarget> =\n        \
+             RefCell::new(FuzzTarget::new(TestSetup::new({:?})));\n}}\n\n\
+             fuzz_target!(|data: &[u8]| TARGET.with(|target| target.borrow_mut().run(data)));\n",
+            wasm_path.to_string_lossy()
+        ),
+    )
+    .with_context(|| format!("cannot write {}", target_path.display()))?;
+    Ok(())
+}
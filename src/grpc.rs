@@ -0,0 +1,625 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// gRPC messages built from JSON (`grpc` feature), with the descriptors of the services instead
+// of prost code generated for each of them. The descriptors come from protoc:
+//
+//   protoc --include_imports --descriptor_set_out=api.pb api.proto
+//
+// and messages are written in the canonical JSON mapping of protobuf (field or JSON names, enums
+// by name, bytes in base64, maps as objects, well-known types such as Timestamp as strings), e.g.
+//
+//   let descriptors = Descriptors::load("tests/api.pb")?;
+//   let request = descriptors.grpc_request("/helloworld.Greeter/SayHello", r#"{"name": "x"}"#)?;
+//   tester.send_request(2, request.authority("greeter"))?.execute_all()?;
+//   let message = descriptors.encode("helloworld.HelloReply", r#"{"message": "hi"}"#)?;
+
+use crate::har::decode_base64;
+use crate::http::{HttpRequest, HttpResponse};
+
+use anyhow::{bail, Context, Result};
+use prost::encoding::{encode_key, encode_varint, WireType};
+use prost::Message;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{
+    DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FileDescriptorSet,
+    MethodDescriptorProto,
+};
+use serde_yaml::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Messages, enums and methods of a FileDescriptorSet, by full name (without the leading dot)
+pub struct Descriptors {
+    messages: HashMap<String, MessageType>,
+    enums: HashMap<String, EnumDescriptorProto>,
+    // keyed by service/method, e.g. helloworld.Greeter/SayHello
+    methods: HashMap<String, MethodDescriptorProto>,
+}
+
+struct MessageType {
+    descriptor: DescriptorProto,
+    // repeated scalars are packed by default
+    proto3: bool,
+}
+
+impl Descriptors {
+    pub fn load(path: impl AsRef<Path>) -> Result<Descriptors> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)
+            .with_context(|| format!("cannot read descriptor set {}", path.display()))?;
+        Descriptors::from_bytes(&bytes)
+            .with_context(|| format!("invalid descriptor set {}", path.display()))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Descriptors> {
+        let set = FileDescriptorSet::decode(bytes)?;
+        let mut descriptors = Descriptors {
+            messages: HashMap::new(),
+            enums: HashMap::new(),
+            methods: HashMap::new(),
+        };
+        for file in set.file {
+            let proto3 = file.syntax() == "proto3";
+            let package = match file.package() {
+                "" => String::new(),
+                package => format!("{}.", package),
+            };
+            for message in file.message_type {
+                descriptors.add_message(&package, message, proto3);
+            }
+            for enumeration in file.enum_type {
+                let name = format!("{}{}", package, enumeration.name());
+                descriptors.enums.insert(name, enumeration);
+            }
+            for service in file.service {
+                let prefix = format!("{}{}/", package, service.name());
+                for method in service.method {
+                    let name = format!("{}{}", prefix, method.name());
+                    descriptors.methods.insert(name, method);
+                }
+            }
+        }
+        Ok(descriptors)
+    }
+
+    fn add_message(&mut self, prefix: &str, mut message: DescriptorProto, proto3: bool) {
+        let name = format!("{}{}", prefix, message.name());
+        let nested = format!("{}.", name);
+        for inner in std::mem::take(&mut message.nested_type) {
+            self.add_message(&nested, inner, proto3);
+        }
+        for enumeration in std::mem::take(&mut message.enum_type) {
+            let name = format!("{}{}", nested, enumeration.name());
+            self.enums.insert(name, enumeration);
+        }
+        self.messages.insert(
+            name,
+            MessageType {
+                descriptor: message,
+                proto3,
+            },
+        );
+    }
+
+    // Full names of the messages, sorted
+    pub fn messages(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.messages.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    // Input and output messages of a method, given as /package.Service/Method (the gRPC path)
+    // or package.Service/Method
+    pub fn method_types(&self, method: &str) -> Result<(&str, &str)> {
+        let method = match self.methods.get(method.trim_start_matches('/')) {
+            Some(method) => method,
+            None => bail!("no method {} in the descriptors", method),
+        };
+        Ok((
+            method.input_type().trim_start_matches('.'),
+            method.output_type().trim_start_matches('.'),
+        ))
+    }
+
+    // Protobuf encoding of the message given in JSON
+    pub fn encode(&self, message: &str, json: &str) -> Result<Vec<u8>> {
+        let value: Value =
+            serde_yaml::from_str(json).with_context(|| format!("invalid JSON for {}", message))?;
+        let mut encoded = Vec::new();
+        self.encode_message(message, &value, &mut encoded)
+            .with_context(|| format!("cannot encode {}", message))?;
+        Ok(encoded)
+    }
+
+    // POST to the method, its request message as a length-prefixed gRPC frame
+    pub fn grpc_request(&self, method: &str, json: &str) -> Result<HttpRequest> {
+        let (input, _) = self.method_types(method)?;
+        let message = self.encode(input, json)?;
+        Ok(
+            HttpRequest::post(&format!("/{}", method.trim_start_matches('/')))
+                .header("content-type", "application/grpc")
+                .header("te", "trailers")
+                .body(grpc_frame(&message)),
+        )
+    }
+
+    // Successful reply of the method (e.g. for a mock upstream), grpc-status in the trailers
+    pub fn grpc_response(&self, method: &str, json: &str) -> Result<HttpResponse> {
+        let (_, output) = self.method_types(method)?;
+        let message = self.encode(output, json)?;
+        Ok(HttpResponse::ok()
+            .header("content-type", "application/grpc")
+            .body(grpc_frame(&message))
+            .trailer("grpc-status", "0"))
+    }
+
+    fn encode_message(&self, name: &str, value: &Value, out: &mut Vec<u8>) -> Result<()> {
+        if name.starts_with("google.protobuf.") && self.encode_well_known(name, value, out)? {
+            return Ok(());
+        }
+        let message = match self.messages.get(name) {
+            Some(message) => message,
+            None => bail!("no message {} in the descriptors", name),
+        };
+        let object = match value {
+            Value::Mapping(object) => object,
+            value => bail!("expected an object, found {}", kind(value)),
+        };
+        for (key, value) in object {
+            let key = key.as_str().unwrap_or_default();
+            let field = message
+                .descriptor
+                .field
+                .iter()
+                .find(|field| field.json_name() == key || field.name() == key);
+            let field = match field {
+                Some(field) => field,
+                None => bail!("no field {} in {}", key, name),
+            };
+            self.encode_field(field, message.proto3, value, out)
+                .with_context(|| format!("in field {}", key))?;
+        }
+        Ok(())
+    }
+
+    fn encode_field(
+        &self,
+        field: &FieldDescriptorProto,
+        proto3: bool,
+        value: &Value,
+        out: &mut Vec<u8>,
+    ) -> Result<()> {
+        let number = field.number() as u32;
+        if value.is_null() && field.type_name() != ".google.protobuf.Value" {
+            return Ok(());
+        }
+        if field.label() != Label::Repeated {
+            return self.encode_single(field, number, value, out);
+        }
+        if let Some(entry) = self.map_entry(field) {
+            let object = match value {
+                Value::Mapping(object) => object,
+                value => bail!("expected an object for a map, found {}", kind(value)),
+            };
+            let (key_field, value_field) = match entry.field.as_slice() {
+                [key, value] => (key, value),
+                _ => bail!("invalid map entry {}", entry.name()),
+            };
+            for (key, value) in object {
+                let mut encoded = Vec::new();
+                // keys are strings in JSON, whatever their type
+                let key = match key_field.r#type() {
+                    Type::String => key.clone(),
+                    _ => serde_yaml::from_str(key.as_str().unwrap_or_default())?,
+                };
+                self.encode_single(key_field, 1, &key, &mut encoded)?;
+                self.encode_single(value_field, 2, value, &mut encoded)?;
+                length_delimited(number, &encoded, out);
+            }
+            return Ok(());
+        }
+        let items = match value {
+            Value::Sequence(items) => items,
+            value => bail!("expected an array, found {}", kind(value)),
+        };
+        let packable = !matches!(
+            field.r#type(),
+            Type::String | Type::Bytes | Type::Message | Type::Group
+        );
+        let packed = field
+            .options
+            .as_ref()
+            .and_then(|options| options.packed)
+            .unwrap_or(proto3);
+        if packable && packed {
+            let mut encoded = Vec::new();
+            for item in items {
+                self.encode_scalar(field, item, &mut encoded)?;
+            }
+            length_delimited(number, &encoded, out);
+            return Ok(());
+        }
+        for item in items {
+            self.encode_single(field, number, item, out)?;
+        }
+        Ok(())
+    }
+
+    // Key and value of a field (or of one item of a repeated field)
+    fn encode_single(
+        &self,
+        field: &FieldDescriptorProto,
+        number: u32,
+        value: &Value,
+        out: &mut Vec<u8>,
+    ) -> Result<()> {
+        match field.r#type() {
+            Type::Message => {
+                let mut encoded = Vec::new();
+                self.encode_message(
+                    field.type_name().trim_start_matches('.'),
+                    value,
+                    &mut encoded,
+                )?;
+                length_delimited(number, &encoded, out);
+            }
+            Type::String | Type::Bytes => {
+                let mut encoded = Vec::new();
+                self.encode_scalar(field, value, &mut encoded)?;
+                length_delimited(number, &encoded, out);
+            }
+            Type::Group => bail!("groups are not supported"),
+            field_type => {
+                encode_key(number, wire_type(field_type), out);
+                self.encode_scalar(field, value, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Value of a scalar field, without its key
+    fn encode_scalar(
+        &self,
+        field: &FieldDescriptorProto,
+        value: &Value,
+        out: &mut Vec<u8>,
+    ) -> Result<()> {
+        match field.r#type() {
+            Type::Enum => {
+                let number = match value {
+                    Value::String(name) => {
+                        let enumeration = field.type_name().trim_start_matches('.');
+                        let values = self.enums.get(enumeration).map(|e| e.value.as_slice());
+                        let value = values
+                            .unwrap_or_default()
+                            .iter()
+                            .find(|value| value.name() == name);
+                        match value {
+                            Some(value) => value.number(),
+                            None => bail!("no value {} in enum {}", name, enumeration),
+                        }
+                    }
+                    value => integer(value)? as i32,
+                };
+                encode_varint(number as i64 as u64, out);
+            }
+            field_type => encode_scalar(field_type, value, out)?,
+        }
+        Ok(())
+    }
+
+    fn map_entry(&self, field: &FieldDescriptorProto) -> Option<&DescriptorProto> {
+        if field.r#type() != Type::Message {
+            return None;
+        }
+        let message = self
+            .messages
+            .get(field.type_name().trim_start_matches('.'))?;
+        let options = message.descriptor.options.as_ref()?;
+        options.map_entry().then_some(&message.descriptor)
+    }
+
+    // Well-known types with a JSON representation of their own, false for the other ones
+    fn encode_well_known(&self, name: &str, value: &Value, out: &mut Vec<u8>) -> Result<bool> {
+        let short = name.trim_start_matches("google.protobuf.");
+        match (short, value) {
+            ("Timestamp", Value::String(text)) => {
+                let (seconds, nanos) = timestamp(text)?;
+                encode_seconds_nanos(seconds, nanos, out);
+            }
+            ("Duration", Value::String(text)) => {
+                let (seconds, nanos) = duration(text)?;
+                encode_seconds_nanos(seconds, nanos, out);
+            }
+            ("FieldMask", Value::String(text)) => {
+                for path in text.split(',').filter(|path| !path.is_empty()) {
+                    length_delimited(1, snake_case(path).as_bytes(), out);
+                }
+            }
+            ("Struct", Value::Mapping(object)) => {
+                for (key, value) in object {
+                    let mut entry = Vec::new();
+                    length_delimited(1, key.as_str().unwrap_or_default().as_bytes(), &mut entry);
+                    let mut encoded = Vec::new();
+                    self.encode_well_known("google.protobuf.Value", value, &mut encoded)?;
+                    length_delimited(2, &encoded, &mut entry);
+                    length_delimited(1, &entry, out);
+                }
+            }
+            ("ListValue", Value::Sequence(items)) => {
+                for item in items {
+                    let mut encoded = Vec::new();
+                    self.encode_well_known("google.protobuf.Value", item, &mut encoded)?;
+                    length_delimited(1, &encoded, out);
+                }
+            }
+            ("Value", value) => match value {
+                Value::Null => {
+                    encode_key(1, WireType::Varint, out);
+                    encode_varint(0, out);
+                }
+                Value::Number(number) => {
+                    encode_key(2, WireType::SixtyFourBit, out);
+                    out.extend(number.as_f64().unwrap_or_default().to_le_bytes());
+                }
+                Value::String(text) => length_delimited(3, text.as_bytes(), out),
+                Value::Bool(flag) => {
+                    encode_key(4, WireType::Varint, out);
+                    encode_varint(*flag as u64, out);
+                }
+                Value::Mapping(_) => {
+                    let mut encoded = Vec::new();
+                    self.encode_well_known("google.protobuf.Struct", value, &mut encoded)?;
+                    length_delimited(5, &encoded, out);
+                }
+                Value::Sequence(_) => {
+                    let mut encoded = Vec::new();
+                    self.encode_well_known("google.protobuf.ListValue", value, &mut encoded)?;
+                    length_delimited(6, &encoded, out);
+                }
+                Value::Tagged(tagged) => return self.encode_well_known(name, &tagged.value, out),
+            },
+            (wrapper, value) if wrapper.ends_with("Value") && !value.is_mapping() => {
+                let field_type = match wrapper {
+                    "DoubleValue" => Type::Double,
+                    "FloatValue" => Type::Float,
+                    "Int64Value" => Type::Int64,
+                    "UInt64Value" => Type::Uint64,
+                    "Int32Value" => Type::Int32,
+                    "UInt32Value" => Type::Uint32,
+                    "BoolValue" => Type::Bool,
+                    "StringValue" => Type::String,
+                    "BytesValue" => Type::Bytes,
+                    _ => return Ok(false),
+                };
+                let mut encoded = Vec::new();
+                encode_scalar(field_type, value, &mut encoded)?;
+                match field_type {
+                    Type::String | Type::Bytes => length_delimited(1, &encoded, out),
+                    field_type => {
+                        encode_key(1, wire_type(field_type), out);
+                        out.extend(encoded);
+                    }
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+// Length-prefixed message of a gRPC body: compression flag (0) and big-endian length
+pub fn grpc_frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + message.len());
+    frame.push(0);
+    frame.extend((message.len() as u32).to_be_bytes());
+    frame.extend(message);
+    frame
+}
+
+fn length_delimited(number: u32, bytes: &[u8], out: &mut Vec<u8>) {
+    encode_key(number, WireType::LengthDelimited, out);
+    encode_varint(bytes.len() as u64, out);
+    out.extend(bytes);
+}
+
+fn wire_type(field_type: Type) -> WireType {
+    match field_type {
+        Type::Double | Type::Fixed64 | Type::Sfixed64 => WireType::SixtyFourBit,
+        Type::Float | Type::Fixed32 | Type::Sfixed32 => WireType::ThirtyTwoBit,
+        Type::String | Type::Bytes | Type::Message => WireType::LengthDelimited,
+        Type::Group => WireType::StartGroup,
+        _ => WireType::Varint,
+    }
+}
+
+fn encode_scalar(field_type: Type, value: &Value, out: &mut Vec<u8>) -> Result<()> {
+    match field_type {
+        Type::Double => out.extend(float(value)?.to_le_bytes()),
+        Type::Float => out.extend((float(value)? as f32).to_le_bytes()),
+        Type::Int64 | Type::Int32 => encode_varint(integer(value)? as u64, out),
+        Type::Uint64 | Type::Uint32 => encode_varint(unsigned(value)?, out),
+        Type::Sint64 | Type::Sint32 => {
+            let value = integer(value)?;
+            encode_varint(((value << 1) ^ (value >> 63)) as u64, out);
+        }
+        Type::Fixed64 => out.extend(unsigned(value)?.to_le_bytes()),
+        Type::Sfixed64 => out.extend(integer(value)?.to_le_bytes()),
+        Type::Fixed32 => out.extend((unsigned(value)? as u32).to_le_bytes()),
+        Type::Sfixed32 => out.extend((integer(value)? as i32).to_le_bytes()),
+        Type::Bool => match value {
+            Value::Bool(flag) => encode_varint(*flag as u64, out),
+            value => bail!("expected a boolean, found {}", kind(value)),
+        },
+        Type::String => match value {
+            Value::String(text) => out.extend(text.as_bytes()),
+            value => bail!("expected a string, found {}", kind(value)),
+        },
+        Type::Bytes => match value {
+            Value::String(text) => out.extend(decode_base64(text)?),
+            value => bail!("expected base64 bytes, found {}", kind(value)),
+        },
+        field_type => bail!("unexpected scalar type {:?}", field_type),
+    }
+    Ok(())
+}
+
+// 64-bit integers may be given as strings (as JavaScript loses their precision)
+fn integer(value: &Value) -> Result<i64> {
+    let integer = match value {
+        Value::Number(number) => number.as_i64(),
+        Value::String(text) => text.parse().ok(),
+        _ => None,
+    };
+    integer.with_context(|| format!("expected an integer, found {}", kind(value)))
+}
+
+fn unsigned(value: &Value) -> Result<u64> {
+    let unsigned = match value {
+        Value::Number(number) => number.as_u64(),
+        Value::String(text) => text.parse().ok(),
+        _ => None,
+    };
+    unsigned.with_context(|| format!("expected an unsigned integer, found {}", kind(value)))
+}
+
+fn float(value: &Value) -> Result<f64> {
+    let float = match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => match text.as_str() {
+            "NaN" => Some(f64::NAN),
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            text => text.parse().ok(),
+        },
+        _ => None,
+    };
+    float.with_context(|| format!("expected a number, found {}", kind(value)))
+}
+
+fn kind(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(flag) => flag.to_string(),
+        Value::Number(number) => number.to_string(),
+        Value::String(text) => format!("{:?}", text),
+        Value::Sequence(_) => "an array".to_string(),
+        Value::Mapping(_) => "an object".to_string(),
+        Value::Tagged(_) => "a tagged value".to_string(),
+    }
+}
+
+fn encode_seconds_nanos(seconds: i64, nanos: i32, out: &mut Vec<u8>) {
+    if seconds != 0 {
+        encode_key(1, WireType::Varint, out);
+        encode_varint(seconds as u64, out);
+    }
+    if nanos != 0 {
+        encode_key(2, WireType::Varint, out);
+        encode_varint(nanos as i64 as u64, out);
+    }
+}
+
+// RFC 3339 date and time, e.g. 2020-01-01T10:00:20.021Z or 2020-01-01T12:00:20+02:00
+fn timestamp(text: &str) -> Result<(i64, i32)> {
+    let invalid = || format!("invalid timestamp {:?}", text);
+    let (date, time) = text.split_once(['T', 't']).with_context(invalid)?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = match (date.next(), date.next(), date.next()) {
+        (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) => (year, month, day),
+        _ => bail!(invalid()),
+    };
+    let (time, offset) = match time.find(['Z', 'z', '+', '-']) {
+        Some(index) => time.split_at(index),
+        None => bail!(invalid()),
+    };
+    let offset = match offset {
+        "Z" | "z" => 0,
+        offset => {
+            let (hours, minutes) = offset[1..].split_once(':').with_context(invalid)?;
+            let minutes = hours.parse::<i64>()? * 60 + minutes.parse::<i64>()?;
+            if offset.starts_with('-') {
+                -minutes * 60
+            } else {
+                minutes * 60
+            }
+        }
+    };
+    let (time, nanos) = match time.split_once('.') {
+        Some((time, fraction)) => (time, fraction_nanos(fraction).with_context(invalid)?),
+        None => (time, 0),
+    };
+    let mut time = time.splitn(3, ':').map(str::parse::<i64>);
+    let (hours, minutes, seconds) = match (time.next(), time.next(), time.next()) {
+        (Some(Ok(hours)), Some(Ok(minutes)), Some(Ok(seconds))) => (hours, minutes, seconds),
+        _ => bail!(invalid()),
+    };
+    let days = days_from_civil(year, month, day);
+    Ok((
+        days * 86400 + hours * 3600 + minutes * 60 + seconds - offset,
+        nanos,
+    ))
+}
+
+// Seconds with up to 9 fractional digits and an "s" suffix, e.g. 1.5s or -0.001s
+fn duration(text: &str) -> Result<(i64, i32)> {
+    let invalid = || format!("invalid duration {:?}", text);
+    let number = text.strip_suffix('s').with_context(invalid)?;
+    let (negative, number) = match number.strip_prefix('-') {
+        Some(number) => (true, number),
+        None => (false, number),
+    };
+    let (seconds, nanos) = match number.split_once('.') {
+        Some((seconds, fraction)) => (seconds, fraction_nanos(fraction).with_context(invalid)?),
+        None => (number, 0),
+    };
+    let seconds: i64 = seconds.parse().with_context(invalid)?;
+    match negative {
+        true => Ok((-seconds, -nanos)),
+        false => Ok((seconds, nanos)),
+    }
+}
+
+fn fraction_nanos(fraction: &str) -> Option<i32> {
+    if fraction.is_empty() || fraction.len() > 9 || !fraction.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    format!("{:0<9}", fraction).parse().ok()
+}
+
+// Days since 1970-01-01 of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// Paths of FieldMasks are lowerCamelCase in JSON
+fn snake_case(path: &str) -> String {
+    let mut snake = String::new();
+    for c in path.chars() {
+        if c.is_ascii_uppercase() {
+            snake.push('_');
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
@@ -0,0 +1,486 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Traffic captured by a browser (DevTools "Save all as HAR") or a proxy, replayed through the
+// module as test input, e.g.
+//
+//   let har = Har::load("tests/login.har")?;
+//   let streams = har.replay(&mut tester)?;
+//   tester.assert_forwarded(streams[0]);
+//
+// Each entry becomes an HttpRequest (pseudo-headers from its URL) and, if the server answered,
+// an HttpResponse. HAR bodies are stored decoded, so content-encoding is left out of the
+// response headers, and the content-length of both is recomputed (see toggle_auto_content_length).
+//
+// The other way around, the streams as the module left them are written as HAR, to look at what
+// it would have sent (in DevTools, or any HAR viewer):
+//
+//   Har::capture(&tester).save("target/filtered.har")?;
+
+use crate::http::{HeaderMap, HttpBody, HttpRequest, HttpResponse};
+use crate::tester::{lossy_header_map, Tester, ROOT_CONTEXT};
+use crate::trace::{encode_base64, json_bytes};
+use crate::types::{Bytes, ReturnType, StreamOutcome};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct Har {
+    pub entries: Vec<HarEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HarEntry {
+    // e.g. 2024-01-01T00:00:00.000Z
+    pub started_date_time: String,
+    pub url: String,
+    pub request: HttpRequest,
+    // None when the request got no response (e.g. blocked or aborted, status 0 in the HAR)
+    pub response: Option<HttpResponse>,
+}
+
+#[derive(Deserialize)]
+struct HarFile {
+    log: HarLog,
+}
+
+#[derive(Deserialize)]
+struct HarLog {
+    #[serde(default)]
+    entries: Vec<RawEntry>,
+}
+
+#[derive(Deserialize)]
+struct RawEntry {
+    #[serde(rename = "startedDateTime", default)]
+    started_date_time: String,
+    request: RawRequest,
+    response: RawResponse,
+}
+
+#[derive(Deserialize)]
+struct RawRequest {
+    method: String,
+    url: String,
+    #[serde(default)]
+    headers: Vec<RawHeader>,
+    #[serde(rename = "postData")]
+    post_data: Option<RawContent>,
+}
+
+#[derive(Deserialize)]
+struct RawResponse {
+    status: u32,
+    #[serde(default)]
+    headers: Vec<RawHeader>,
+    #[serde(default)]
+    content: RawContent,
+}
+
+#[derive(Deserialize)]
+struct RawHeader {
+    name: String,
+    value: String,
+}
+
+// postData of a request, content of a response
+#[derive(Default, Deserialize)]
+struct RawContent {
+    text: Option<String>,
+    // "base64" for binary content
+    encoding: Option<String>,
+}
+
+impl Har {
+    pub fn load(path: impl AsRef<Path>) -> Result<Har> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("cannot read HAR file {}", path.display()))?;
+        Har::parse(&text).with_context(|| format!("invalid HAR file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Har> {
+        // JSON being valid YAML
+        let har: HarFile = serde_yaml::from_str(text)?;
+        let entries = har
+            .log
+            .entries
+            .into_iter()
+            .map(HarEntry::from_raw)
+            .collect::<Result<_>>()?;
+        Ok(Har { entries })
+    }
+
+    // Runs every entry on a new HTTP context of the started module (with every host call
+    // allowed, see TestSetup::allow_unexpected): the request, then the response if the module
+    // let the request through. Returns the context of each entry, e.g. for stream_outcome()
+    pub fn replay(&self, tester: &mut Tester) -> Result<Vec<i32>> {
+        let streams = self
+            .entries
+            .iter()
+            .map(|entry| (entry.url.as_str(), &entry.request, entry.response.as_ref()));
+        replay_streams(tester, streams)
+    }
+
+    // Streams of the tester as the module left them, in context order: the request as it went
+    // upstream (or as it was when the module answered it) and the response as it went downstream,
+    // see Tester::final_request and final_response. Entries are dated by the host clock
+    pub fn capture(tester: &Tester) -> Har {
+        let mut contexts: Vec<i32> = tester
+            .contexts()
+            .iter()
+            .map(|context| context.context_id)
+            .collect();
+        contexts.sort_unstable();
+        let started_date_time = iso_8601(tester.current_time_nanos());
+        let entries = contexts
+            .into_iter()
+            .filter_map(|context_id| {
+                let request = tester.final_request(context_id).or_else(|| {
+                    let local_reply = tester
+                        .get_settings_handle()
+                        .staged
+                        .get_local_reply(context_id)?;
+                    Some(HttpRequest {
+                        headers: lossy_header_map(local_reply.request_headers),
+                        body: None,
+                        trailers: None,
+                    })
+                })?;
+                Some(HarEntry {
+                    started_date_time: started_date_time.clone(),
+                    url: url_of(&request.headers),
+                    request,
+                    response: tester.final_response(context_id),
+                })
+            })
+            .collect();
+        Har { entries }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_json())
+            .with_context(|| format!("cannot write HAR file {}", path.display()))
+    }
+
+    // HAR 1.2, with the pseudo-headers as the method, URL and status of the entries, and bodies
+    // that are not UTF-8 in base64 (trailers have no place in HAR and are left out)
+    pub fn to_json(&self) -> String {
+        let mut json = String::from(
+            r#"{"log":{"version":"1.2","creator":{"name":"proxy-wasm-test-framework","version":"#,
+        );
+        json_bytes(&mut json, env!("CARGO_PKG_VERSION").as_bytes());
+        json.push_str(r#"},"entries":["#);
+        for (index, entry) in self.entries.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            entry.write_json(&mut json);
+        }
+        json.push_str("]}}\n");
+        json
+    }
+}
+
+impl HarEntry {
+    fn from_raw(entry: RawEntry) -> Result<HarEntry> {
+        let (scheme, authority, path) = split_url(&entry.request.url)?;
+        let mut request = HttpRequest::new(&entry.request.method, path)
+            .authority(authority)
+            .header(":scheme", scheme);
+        for header in &entry.request.headers {
+            if keep_header(&header.name, &["host", "content-length"]) {
+                request = request.header(&header.name.to_ascii_lowercase(), &header.value);
+            }
+        }
+        if let Some(body) = entry.request.post_data.unwrap_or_default().decode()? {
+            request = request.body(body);
+        }
+
+        let response = match entry.response.status {
+            0 => None,
+            status => {
+                let mut response = HttpResponse::new(status);
+                for header in &entry.response.headers {
+                    if keep_header(&header.name, &["content-length", "content-encoding"]) {
+                        response =
+                            response.header(&header.name.to_ascii_lowercase(), &header.value);
+                    }
+                }
+                if let Some(body) = entry.response.content.decode()? {
+                    response = response.body(body);
+                }
+                Some(response)
+            }
+        };
+        Ok(HarEntry {
+            started_date_time: entry.started_date_time,
+            url: entry.request.url,
+            request,
+            response,
+        })
+    }
+}
+
+// Each stream (named in errors) on a new HTTP context, see Har::replay
+pub(crate) fn replay_streams<'a>(
+    tester: &mut Tester,
+    streams: impl Iterator<Item = (&'a str, &'a HttpRequest, Option<&'a HttpResponse>)>,
+) -> Result<Vec<i32>> {
+    let mut context_id = tester
+        .contexts()
+        .iter()
+        .map(|context| context.context_id)
+        .max()
+        .unwrap_or(ROOT_CONTEXT);
+    let mut contexts = Vec::new();
+    for (name, request, response) in streams {
+        context_id += 1;
+        tester
+            .call_proxy_on_context_create(context_id, ROOT_CONTEXT)
+            .execute_and_expect(ReturnType::None)?;
+        tester.send_request(context_id, request.clone())?;
+        tester
+            .execute_all()
+            .with_context(|| format!("request to {} failed", name))?;
+        if let Some(response) = response {
+            if tester.stream_outcome(context_id) == StreamOutcome::Forwarded {
+                tester.send_response(context_id, response.clone())?;
+                tester
+                    .execute_all()
+                    .with_context(|| format!("response from {} failed", name))?;
+            }
+        }
+        contexts.push(context_id);
+    }
+    Ok(contexts)
+}
+
+impl HarEntry {
+    fn write_json(&self, json: &mut String) {
+        let headers = &self.request.headers;
+        let path = headers.get(":path").unwrap_or("/");
+        json.push_str(r#"{"startedDateTime":"#);
+        json_bytes(json, self.started_date_time.as_bytes());
+        json.push_str(r#","time":0,"request":{"method":"#);
+        json_bytes(json, headers.get(":method").unwrap_or("GET").as_bytes());
+        json.push_str(r#","url":"#);
+        json_bytes(json, self.url.as_bytes());
+        json.push_str(r#","httpVersion":"HTTP/1.1","cookies":[],"headers":"#);
+        write_headers(json, headers);
+        json.push_str(r#","queryString":["#);
+        let query = path.split_once('?').map(|(_, query)| query);
+        let parameters = query
+            .unwrap_or_default()
+            .split('&')
+            .filter(|p| !p.is_empty());
+        for (index, parameter) in parameters.enumerate() {
+            let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            if index > 0 {
+                json.push(',');
+            }
+            write_pair(json, name, value);
+        }
+        json.push(']');
+        let body = joined_body(self.request.body.as_ref());
+        if !body.is_empty() {
+            json.push_str(r#","postData":{"mimeType":"#);
+            json_bytes(
+                json,
+                headers.get("content-type").unwrap_or_default().as_bytes(),
+            );
+            write_text(json, &body);
+            json.push('}');
+        }
+        json.push_str(&format!(
+            r#","headersSize":-1,"bodySize":{}}},"response":"#,
+            body.len()
+        ));
+        match &self.response {
+            Some(response) => {
+                let headers = &response.headers;
+                let status = headers.get(":status").unwrap_or("0");
+                let body = joined_body(response.body.as_ref());
+                json.push_str(&format!(
+                    r#"{{"status":{},"statusText":"","httpVersion":"HTTP/1.1","cookies":[],"headers":"#,
+                    status.parse::<u32>().unwrap_or_default()
+                ));
+                write_headers(json, headers);
+                json.push_str(&format!(r#","content":{{"size":{},"mimeType":"#, body.len()));
+                json_bytes(json, headers.get("content-type").unwrap_or_default().as_bytes());
+                if !body.is_empty() {
+                    write_text(json, &body);
+                }
+                json.push_str(r#"},"redirectURL":"#);
+                json_bytes(json, headers.get("location").unwrap_or_default().as_bytes());
+                json.push_str(&format!(r#","headersSize":-1,"bodySize":{}}}"#, body.len()));
+            }
+            // as browsers record requests that got no response
+            None => json.push_str(
+                r#"{"status":0,"statusText":"","httpVersion":"","cookies":[],"headers":[],"content":{"size":0,"mimeType":""},"redirectURL":"","headersSize":-1,"bodySize":-1}"#,
+            ),
+        }
+        json.push_str(r#","cache":{},"timings":{"send":0,"wait":0,"receive":0}}"#);
+    }
+}
+
+impl RawContent {
+    // None for an empty (or left out) body
+    fn decode(self) -> Result<Option<Vec<u8>>> {
+        let body = match (self.text, self.encoding.as_deref()) {
+            (None, _) => return Ok(None),
+            (Some(text), Some("base64")) => decode_base64(&text)?,
+            (Some(text), _) => text.into_bytes(),
+        };
+        Ok(Some(body).filter(|body| !body.is_empty()))
+    }
+}
+
+// The pseudo-headers (of HTTP/2 captures) come from the URL, and the others left out are
+// replaced by what the framework computes
+fn keep_header(name: &str, left_out: &[&str]) -> bool {
+    !name.starts_with(':')
+        && !left_out
+            .iter()
+            .any(|other| name.eq_ignore_ascii_case(other))
+}
+
+// Absolute URL of a request from its pseudo-headers
+fn url_of(headers: &HeaderMap) -> String {
+    format!(
+        "{}://{}{}",
+        headers.get(":scheme").unwrap_or("http"),
+        headers
+            .get(":authority")
+            .or_else(|| headers.get("host"))
+            .unwrap_or("localhost"),
+        headers.get(":path").unwrap_or("/")
+    )
+}
+
+fn joined_body(body: Option<&HttpBody>) -> Bytes {
+    match body {
+        Some(HttpBody::Full(body)) => body.clone(),
+        Some(HttpBody::Chunked(chunks)) => chunks.concat(),
+        None => Bytes::new(),
+    }
+}
+
+fn write_headers(json: &mut String, headers: &HeaderMap) {
+    json.push('[');
+    let headers = headers.iter().filter(|(name, _)| !name.starts_with(':'));
+    for (index, (name, value)) in headers.enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        write_pair(json, name, value);
+    }
+    json.push(']');
+}
+
+fn write_pair(json: &mut String, name: &str, value: &str) {
+    json.push_str(r#"{"name":"#);
+    json_bytes(json, name.as_bytes());
+    json.push_str(r#","value":"#);
+    json_bytes(json, value.as_bytes());
+    json.push('}');
+}
+
+// "text" (and "encoding") fields of a body
+fn write_text(json: &mut String, body: &[u8]) {
+    json.push_str(r#","text":"#);
+    match std::str::from_utf8(body) {
+        Ok(text) => json_bytes(json, text.as_bytes()),
+        Err(_) => {
+            json_bytes(json, encode_base64(body).as_bytes());
+            json.push_str(r#","encoding":"base64""#);
+        }
+    }
+}
+
+// UTC, with milliseconds
+fn iso_8601(nanos: u64) -> String {
+    let seconds = nanos / 1_000_000_000;
+    let (days, time) = ((seconds / 86400) as i64, seconds % 86400);
+    // civil date of a day count since 1970-01-01 (from Howard Hinnant's date algorithms)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        nanos / 1_000_000 % 1000
+    )
+}
+
+// scheme, authority and path (with the query, without the fragment) of an absolute URL
+fn split_url(url: &str) -> Result<(&str, &str, &str)> {
+    let (scheme, rest) = match url.split_once("://") {
+        Some(split) => split,
+        None => bail!("not an absolute URL: {}", url),
+    };
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    let path = match path {
+        "" => "/",
+        path => path,
+    };
+    Ok((scheme, authority, path))
+}
+
+pub(crate) fn decode_base64(text: &str) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace() && *c != b'=')
+    {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => bail!("invalid base64 content: {:?}", c as char),
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Ok(decoded)
+}
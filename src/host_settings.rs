@@ -12,46 +12,696 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::hostcalls::serial_utils::serialize_map;
+use crate::hostcalls::serial_utils::{generate_random_string, serialize_map};
+use crate::phases::{HeaderPairs, LocalReply, StreamPhase, StreamPhases};
 use crate::types::*;
 
-use std::collections::HashMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::convert::TryFrom;
-use std::convert::TryInto;
-use std::time::Duration;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Virtual clock backing every time-based feature of the host: proxy_get_current_time_nanoseconds,
+// tick scheduling, shared-data TTLs and the delays and timeouts of http and grpc calls. It starts
+// at the system time and only moves when advanced, so that tests are reproducible.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now_nanos: u64,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            now_nanos: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+        }
+    }
+
+    pub fn now_nanos(&self) -> u64 {
+        self.now_nanos
+    }
+
+    pub fn set_nanos(&mut self, now_nanos: u64) {
+        self.now_nanos = now_nanos;
+    }
+
+    pub fn advance(&mut self, delta: Duration) {
+        let delta_nanos = u64::try_from(delta.as_nanos()).unwrap_or(u64::MAX);
+        self.now_nanos = self.now_nanos.saturating_add(delta_nanos);
+    }
+}
 
 // Global structure for handling default host behaviour (and high-level expectation setting)
 pub struct HostHandle {
     pub staged: HostSettings,
+    seed: u64,
+}
+
+// Seed of all the randomness of the host, taken from PROXY_WASM_TEST_SEED if set so that a
+// failing test can be replayed
+fn default_seed() -> u64 {
+    std::env::var("PROXY_WASM_TEST_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(rand::random)
 }
 
 impl HostHandle {
     pub fn new() -> HostHandle {
-        HostHandle {
+        let mut host = HostHandle {
             staged: HostSettings::new(AbiVersion::UnknownAbiVersion, false),
-        }
+            seed: default_seed(),
+        };
+        host.staged.set_seed(host.seed);
+        host
     }
 
+    // The seed and the clock persist across resets
     pub fn reset(&mut self, abi_version: AbiVersion, quiet: bool) {
+        let clock = self.staged.clock.clone();
         self.staged = HostSettings::new(abi_version, quiet);
+        self.staged.clock = clock;
+        self.staged.set_seed(self.seed);
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.staged.set_seed(seed);
     }
 
     pub fn print_staged(&self) {
         println!("{:?}", self.staged);
     }
+
+    pub fn snapshot(&self) -> HostSnapshot {
+        HostSnapshot {
+            staged: self.staged.clone(),
+            seed: self.seed,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &HostSnapshot) {
+        self.staged = snapshot.staged.clone();
+        self.seed = snapshot.seed;
+    }
+}
+
+// Copy of the host settings (headers, bodies, shared data, metrics, properties, clock and random
+// state) taken with Tester::snapshot, so that a test can branch from a common setup point
+#[derive(Debug, Clone)]
+pub struct HostSnapshot {
+    staged: HostSettings,
+    seed: u64,
+}
+
+// Canned response of a mock upstream, delivered through proxy_on_http_call_response
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+    pub trailers: Vec<(String, String)>,
+    pub delay_millis: u64,
+    pub faults: Vec<(UpstreamFault, u32)>,
+}
+
+impl MockResponse {
+    // Response of a failed call (e.g. reset or timed out), which carries nothing
+    fn failure(delay_millis: u64) -> MockResponse {
+        MockResponse {
+            headers: vec![],
+            body: vec![],
+            trailers: vec![],
+            delay_millis,
+            faults: vec![],
+        }
+    }
+}
+
+// Response to an http_call dispatched to a mock upstream, awaiting delivery to the module
+#[derive(Debug, Clone)]
+pub struct PendingHttpCall {
+    pub context_id: i32,
+    pub token_id: u32,
+    pub response: MockResponse,
+    pub due_nanos: u64,
+}
+
+// Route of the proxy configuration, matched by the :path of the request headers, with the
+// plugin configuration it overrides for the streams it matches (Envoy's per-route config)
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub name: String,
+    pub path_prefix: String,
+    pub plugin_config: Option<Bytes>,
+}
+
+// What a module did to the stream of an HTTP context beyond returning an action, read by filter
+// chains to tell whether the stream goes on to the next filter
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamAction {
+    // proxy_continue_stream (or proxy_continue_request/response), 0 request and 1 response
+    Continue {
+        context_id: i32,
+        stream_type: i32,
+    },
+    LocalResponse {
+        context_id: i32,
+        status_code: i32,
+        body: Bytes,
+        headers: Vec<(String, Bytes)>,
+    },
+}
+
+// Mock Redis clusters of the vNEXT redis_call, each answering every query with the same (RESP
+// encoded) reply, and the queries they received
+#[cfg(feature = "vnext")]
+#[derive(Debug, Clone, Default)]
+pub struct MockRedis {
+    replies: HashMap<String, Bytes>,
+    initialized: Vec<String>,
+    queries: Vec<(String, Bytes)>,
+    pending: Vec<PendingRedisCall>,
+}
+
+// Reply to a redis_call, awaiting delivery to the module after the current callback
+#[cfg(feature = "vnext")]
+#[derive(Debug, Clone)]
+pub struct PendingRedisCall {
+    pub context_id: i32,
+    pub token_id: u32,
+    pub reply: Bytes,
+}
+
+// Reply of a mock gRPC service, delivered through proxy_on_grpc_receive (Ok) or otherwise
+// through proxy_on_grpc_close. A trailers-only reply (carrying its grpc-message) has no
+// message, its headers frame goes to proxy_on_grpc_receive_initial_metadata before the close
+#[derive(Debug, Clone)]
+pub struct GrpcReply {
+    pub status: GrpcStatus,
+    pub message: Bytes,
+    pub delay_millis: u64,
+    pub trailers_only: Option<String>,
+}
+
+// Sets content-length to the size of the (fully buffered) body, chunked messages carry no
+// content-length so it is stripped from those instead
+pub fn set_content_length(headers: &mut Vec<(String, String)>, body_size: usize) {
+    let chunked = headers.iter().any(|(key, value)| {
+        key.eq_ignore_ascii_case("transfer-encoding")
+            && value.to_ascii_lowercase().contains("chunked")
+    });
+    headers.retain(|(key, _)| !key.eq_ignore_ascii_case("content-length"));
+    if !chunked {
+        headers.push(("content-length".to_string(), body_size.to_string()));
+    }
+}
+
+// Headers frame of a gRPC trailers-only response, grpc-status is sent without any trailers
+pub fn grpc_trailers_only_headers(status: GrpcStatus, message: &str) -> Vec<(String, String)> {
+    let mut headers = vec![
+        ("content-type".to_string(), "application/grpc".to_string()),
+        ("grpc-status".to_string(), (status as i32).to_string()),
+    ];
+    if !message.is_empty() {
+        headers.push(("grpc-message".to_string(), message.to_string()));
+    }
+    headers
+}
+
+pub type GrpcResponder = Arc<dyn Fn(&[u8]) -> Option<GrpcReply> + Send + Sync>;
+
+// Staged rule of a mock gRPC method, answers the (encoded) requests it matches
+#[derive(Clone)]
+pub struct MockGrpcRule(pub GrpcResponder);
+
+impl fmt::Debug for MockGrpcRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MockGrpcRule")
+    }
+}
+
+// Reply to a grpc_call dispatched to a mock gRPC service, awaiting delivery to the module
+#[derive(Debug, Clone)]
+pub struct PendingGrpcCall {
+    pub context_id: i32,
+    pub token_id: u32,
+    pub reply: GrpcReply,
+    pub due_nanos: u64,
+}
+
+// In-memory shared data store backing proxy_{get,set}_shared_data, entries expire after the
+// configured TTL (on the host clock) and the least recently used entry is evicted when full
+#[derive(Debug, Clone)]
+struct SharedData {
+    entries: HashMap<String, SharedDataEntry>,
+    ttl_millis: Option<u64>,
+    max_entries: Option<usize>,
+    next_cas: u32,
+    access_count: u64,
+}
+
+#[derive(Debug, Clone)]
+struct SharedDataEntry {
+    value: Bytes,
+    cas: u32,
+    expires_nanos: Option<u64>,
+    last_access: u64,
+}
+
+impl SharedData {
+    fn new() -> SharedData {
+        SharedData {
+            entries: HashMap::new(),
+            ttl_millis: None,
+            max_entries: None,
+            next_cas: 1,
+            access_count: 0,
+        }
+    }
+
+    fn expire(&mut self, now_nanos: u64) {
+        self.entries.retain(|_, entry| match entry.expires_nanos {
+            Some(expires_nanos) => expires_nanos > now_nanos,
+            None => true,
+        });
+    }
+
+    fn get(&mut self, key: &str, now_nanos: u64) -> Option<(Bytes, u32)> {
+        self.expire(now_nanos);
+        self.access_count += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_access = self.access_count;
+        Some((entry.value.clone(), entry.cas))
+    }
+
+    fn set(&mut self, key: &str, value: Bytes, cas: u32, now_nanos: u64) -> Status {
+        self.expire(now_nanos);
+        if let Some(entry) = self.entries.get(key) {
+            if cas != 0 && cas != entry.cas {
+                return Status::CasMismatch;
+            }
+        } else if let Some(max_entries) = self.max_entries {
+            while self.entries.len() >= max_entries.max(1) {
+                let lru_key = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_access)
+                    .map(|(key, _)| key.clone())
+                    .unwrap();
+                self.entries.remove(&lru_key);
+            }
+        }
+        self.access_count += 1;
+        self.entries.insert(
+            key.to_string(),
+            SharedDataEntry {
+                value,
+                cas: self.next_cas,
+                // a TTL past the end of the clock never expires
+                expires_nanos: self.ttl_millis.and_then(|ttl_millis| {
+                    ttl_millis
+                        .checked_mul(1_000_000)
+                        .and_then(|ttl_nanos| now_nanos.checked_add(ttl_nanos))
+                }),
+                last_access: self.access_count,
+            },
+        );
+        self.next_cas += 1;
+        Status::Ok
+    }
+}
+
+impl MetadataValue {
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            MetadataValue::String(value) => value.as_bytes().to_vec(),
+            MetadataValue::Number(value) => value.to_le_bytes().to_vec(),
+            MetadataValue::Bool(value) => vec![*value as u8],
+        }
+    }
+}
+
+impl From<&str> for MetadataValue {
+    fn from(value: &str) -> Self {
+        MetadataValue::String(value.to_string())
+    }
+}
+
+impl From<f64> for MetadataValue {
+    fn from(value: f64) -> Self {
+        MetadataValue::Number(value)
+    }
+}
+
+impl From<bool> for MetadataValue {
+    fn from(value: bool) -> Self {
+        MetadataValue::Bool(value)
+    }
+}
+
+impl FilterStateValue {
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            FilterStateValue::Bytes(value) => value.clone(),
+            FilterStateValue::String(value) => value.as_bytes().to_vec(),
+            FilterStateValue::Int(value) => value.to_le_bytes().to_vec(),
+            FilterStateValue::Bool(value) => vec![*value as u8],
+        }
+    }
+}
+
+impl From<&[u8]> for FilterStateValue {
+    fn from(value: &[u8]) -> Self {
+        FilterStateValue::Bytes(value.to_vec())
+    }
+}
+
+impl From<&str> for FilterStateValue {
+    fn from(value: &str) -> Self {
+        FilterStateValue::String(value.to_string())
+    }
+}
+
+impl From<i64> for FilterStateValue {
+    fn from(value: i64) -> Self {
+        FilterStateValue::Int(value)
+    }
+}
+
+impl From<bool> for FilterStateValue {
+    fn from(value: bool) -> Self {
+        FilterStateValue::Bool(value)
+    }
+}
+
+impl Histogram {
+    pub fn count(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn sum(&self) -> i64 {
+        self.samples.iter().sum()
+    }
+
+    pub fn min(&self) -> Option<i64> {
+        self.samples.iter().copied().min()
+    }
+
+    pub fn max(&self) -> Option<i64> {
+        self.samples.iter().copied().max()
+    }
+
+    // Nearest-rank percentile, e.g. percentile(99.0) for the p99 latency
+    pub fn percentile(&self, percentile: f64) -> Option<i64> {
+        assert!(
+            (0.0..=100.0).contains(&percentile),
+            "Error: percentile {} is out of range [0, 100]",
+            percentile
+        );
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.clamp(1, sorted.len()) - 1])
+    }
+}
+
+// Bucket bounds of Envoy's histograms, by default
+const HISTOGRAM_BUCKETS: [f64; 19] = [
+    0.5, 1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0, 30000.0,
+    60000.0, 300000.0, 600000.0, 1800000.0, 3600000.0,
+];
+
+impl Metrics {
+    pub fn get(&self, name: &str) -> Option<&Metric> {
+        self.entries.iter().find(|metric| metric.name == name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Prometheus text exposition format, as Envoy serves it on /stats/prometheus: metrics sorted
+    // by name, names sanitized (every character but [a-zA-Z0-9_] becomes '_'), histograms with
+    // Envoy's default buckets, e.g.
+    //
+    //   # TYPE auth_denied counter
+    //   auth_denied 3
+    //   # TYPE upstream_latency histogram
+    //   upstream_latency_bucket{le="0.5"} 0
+    //   ...
+    //   upstream_latency_bucket{le="+Inf"} 2
+    //   upstream_latency_sum 130
+    //   upstream_latency_count 2
+    pub fn to_prometheus_text(&self) -> String {
+        let mut metrics: Vec<(String, &Metric)> = self
+            .entries
+            .iter()
+            .map(|metric| (prometheus_name(&metric.name), metric))
+            .collect();
+        metrics.sort_by(|(name, _), (other, _)| name.cmp(other));
+        let mut text = String::new();
+        for (name, metric) in metrics {
+            match metric.metric_type {
+                metric_type if metric_type == MetricType::Counter as i32 => {
+                    text.push_str(&format!(
+                        "# TYPE {} counter\n{} {}\n",
+                        name, name, metric.value
+                    ));
+                }
+                metric_type if metric_type == MetricType::Gauge as i32 => {
+                    text.push_str(&format!(
+                        "# TYPE {} gauge\n{} {}\n",
+                        name, name, metric.value
+                    ));
+                }
+                _ => {
+                    text.push_str(&format!("# TYPE {} histogram\n", name));
+                    for bound in HISTOGRAM_BUCKETS.iter() {
+                        let count = metric
+                            .samples
+                            .iter()
+                            .filter(|sample| **sample as f64 <= *bound)
+                            .count();
+                        text.push_str(&format!("{}_bucket{{le=\"{}\"}} {}\n", name, bound, count));
+                    }
+                    let histogram = Histogram {
+                        samples: metric.samples.clone(),
+                    };
+                    text.push_str(&format!(
+                        "{}_bucket{{le=\"+Inf\"}} {}\n{}_sum {}\n{}_count {}\n",
+                        name,
+                        histogram.count(),
+                        name,
+                        histogram.sum(),
+                        name,
+                        histogram.count()
+                    ));
+                }
+            }
+        }
+        text
+    }
+}
+
+fn prometheus_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+impl Logs {
+    // Whether a message logged at this level contains the substring
+    pub fn contains(&self, level: LogLevel, substring: &str) -> bool {
+        self.count(level, substring) > 0
+    }
+
+    pub fn count(&self, level: LogLevel, substring: &str) -> usize {
+        self.at_level(level)
+            .filter(|message| message.contains(substring))
+            .count()
+    }
+
+    pub fn messages(&self, level: LogLevel) -> Vec<&str> {
+        self.at_level(level).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn at_level(&self, level: LogLevel) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.level == level as i32)
+            .map(|entry| entry.message.as_str())
+    }
+}
+
+// Message bus backing proxy_{register,resolve,enqueue,dequeue}_shared_queue, unlike the settings
+// above it can be shared by several Testers so that producer and consumer plugins can talk to
+// each other
+#[derive(Debug)]
+pub struct SharedQueues {
+    queues: HashMap<u32, SharedQueue>,
+    pending_ready: Vec<QueueReady>,
+    next_queue_id: u32,
 }
 
-// Global struct for host environment default settings
 #[derive(Debug)]
+struct SharedQueue {
+    vm_id: String,
+    name: String,
+    context_id: i32,
+    messages: VecDeque<Bytes>,
+}
+
+// proxy_on_queue_ready notification owed to the context that registered the queue
+#[derive(Debug, Clone)]
+pub struct QueueReady {
+    pub vm_id: String,
+    pub context_id: i32,
+    pub queue_id: u32,
+}
+
+impl SharedQueues {
+    pub fn new() -> SharedQueues {
+        SharedQueues {
+            queues: HashMap::new(),
+            pending_ready: Vec::new(),
+            next_queue_id: 1,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = SharedQueues::new();
+    }
+
+    pub fn register(&mut self, vm_id: &str, name: &str, context_id: i32) -> u32 {
+        if let Some(queue_id) = self.resolve(vm_id, name) {
+            self.queues.get_mut(&queue_id).unwrap().context_id = context_id;
+            return queue_id;
+        }
+        let queue_id = self.next_queue_id;
+        self.next_queue_id += 1;
+        self.queues.insert(
+            queue_id,
+            SharedQueue {
+                vm_id: vm_id.to_string(),
+                name: name.to_string(),
+                context_id,
+                messages: VecDeque::new(),
+            },
+        );
+        queue_id
+    }
+
+    pub fn resolve(&self, vm_id: &str, name: &str) -> Option<u32> {
+        self.queues
+            .iter()
+            .find(|(_, queue)| queue.vm_id == vm_id && queue.name == name)
+            .map(|(queue_id, _)| *queue_id)
+    }
+
+    pub fn enqueue(&mut self, queue_id: u32, value: Bytes) -> Status {
+        match self.queues.get_mut(&queue_id) {
+            Some(queue) => {
+                queue.messages.push_back(value);
+                self.pending_ready.push(QueueReady {
+                    vm_id: queue.vm_id.clone(),
+                    context_id: queue.context_id,
+                    queue_id,
+                });
+                Status::Ok
+            }
+            None => Status::NotFound,
+        }
+    }
+
+    pub fn dequeue(&mut self, queue_id: u32) -> Result<Bytes, Status> {
+        match self.queues.get_mut(&queue_id) {
+            Some(queue) => queue.messages.pop_front().ok_or(Status::Empty),
+            None => Err(Status::NotFound),
+        }
+    }
+
+    // Notifications for queues registered by the given VM, in enqueue order
+    pub fn take_pending_ready(&mut self, vm_id: &str) -> Vec<QueueReady> {
+        let (ready, pending) = self
+            .pending_ready
+            .drain(..)
+            .partition(|queue_ready| queue_ready.vm_id == vm_id);
+        self.pending_ready = pending;
+        ready
+    }
+}
+
+// Global struct for host environment default settings
+#[derive(Debug, Clone)]
 pub struct HostSettings {
     abi_version: AbiVersion,
     quiet: bool,
     effective_context_id: i32,
+    vm_id: String,
     tick_period_millis: Duration,
-    header_map_pairs: HashMap<i32, Vec<(String, String)>>,
+    // root context receiving the ticks and the time of the next one, if a period is set
+    tick_context_id: i32,
+    next_tick_nanos: Option<u64>,
+    clock: MockClock,
+    header_map_pairs: HashMap<i32, Vec<(String, Bytes)>>,
     buffer_bytes: HashMap<i32, Bytes>,
-    metrics_value: HashMap<i32, i64>,
-    metrics_ids: HashMap<String, i32>,
+    metrics: Vec<Metric>,
+    return_status: HashMap<String, Status>,
+    mock_upstreams: HashMap<String, MockResponse>,
+    pending_http_calls: Vec<PendingHttpCall>,
+    mock_grpc_services: HashMap<(String, String), Vec<MockGrpcRule>>,
+    pending_grpc_calls: Vec<PendingGrpcCall>,
+    next_token_id: u32,
+    fault_rng: StdRng,
+    rng: StdRng,
+    shared_data: SharedData,
+    properties: HashMap<Vec<String>, Bytes>,
+    context_properties: HashMap<i32, HashMap<Vec<String>, Bytes>>,
+    filter_state: HashMap<String, Bytes>,
+    logs: Vec<LogEntry>,
+    log_level: i32,
+    stream_actions: Vec<StreamAction>,
+    routes: Vec<Route>,
+    // route of each stream, matched on its request headers or set explicitly
+    stream_routes: HashMap<i32, String>,
+    contexts: BTreeMap<i32, ContextInfo>,
+    phase_checks: bool,
+    streams: HashMap<i32, StreamPhases>,
+    phase_violations: Vec<String>,
+    #[cfg(feature = "vnext")]
+    redis: MockRedis,
 }
 
 impl HostSettings {
@@ -60,11 +710,37 @@ impl HostSettings {
             abi_version: abi_version,
             quiet: quiet,
             effective_context_id: -1,
+            vm_id: String::new(),
             tick_period_millis: Duration::new(0, 0),
+            tick_context_id: -1,
+            next_tick_nanos: None,
+            clock: MockClock::new(),
             header_map_pairs: default_header_map_pairs(),
             buffer_bytes: default_buffer_bytes(),
-            metrics_value: HashMap::new(),
-            metrics_ids: HashMap::new(),
+            metrics: Vec::new(),
+            return_status: HashMap::new(),
+            mock_upstreams: HashMap::new(),
+            pending_http_calls: Vec::new(),
+            mock_grpc_services: HashMap::new(),
+            pending_grpc_calls: Vec::new(),
+            next_token_id: 1,
+            fault_rng: StdRng::seed_from_u64(0),
+            rng: StdRng::seed_from_u64(0),
+            shared_data: SharedData::new(),
+            properties: default_properties(),
+            context_properties: HashMap::new(),
+            filter_state: HashMap::new(),
+            logs: Vec::new(),
+            log_level: LogLevel::Info as i32,
+            stream_actions: Vec::new(),
+            routes: Vec::new(),
+            stream_routes: HashMap::new(),
+            contexts: BTreeMap::new(),
+            phase_checks: true,
+            streams: HashMap::new(),
+            phase_violations: Vec::new(),
+            #[cfg(feature = "vnext")]
+            redis: MockRedis::default(),
         }
     }
 
@@ -92,18 +768,88 @@ impl HostSettings {
         self.effective_context_id
     }
 
+    pub fn set_vm_id(&mut self, vm_id: &str) {
+        self.vm_id = vm_id.to_string();
+    }
+
+    pub fn get_vm_id(&self) -> &str {
+        &self.vm_id
+    }
+
     pub fn reset_tick_period_millis(&mut self) {
-        self.tick_period_millis = Duration::from_millis(0u64);
+        self.set_tick_period_millis(0);
     }
 
+    // Ticks are delivered to the calling (root) context every period as the clock advances,
+    // starting one period from now
     pub fn set_tick_period_millis(&mut self, tick_period_millis: u64) {
         self.tick_period_millis = Duration::from_millis(tick_period_millis);
+        self.tick_context_id = self.effective_context_id;
+        self.next_tick_nanos = match tick_period_millis {
+            0 => None,
+            _ => Some(
+                self.get_current_time_nanos()
+                    .saturating_add(tick_period_millis.saturating_mul(1_000_000)),
+            ),
+        };
     }
 
     pub fn get_tick_period_millis(&self) -> u128 {
         self.tick_period_millis.as_millis()
     }
 
+    // Context of the tick that is due by the current time (if any), scheduling the next one
+    pub fn take_due_tick(&mut self) -> Option<i32> {
+        let next_tick_nanos = self.next_tick_nanos.filter(|next_tick_nanos| {
+            *next_tick_nanos <= self.get_current_time_nanos() && self.tick_context_id >= 0
+        })?;
+        // no tick past the end of the clock, which would otherwise be due forever
+        let period_nanos = u64::try_from(self.tick_period_millis.as_nanos()).unwrap_or(u64::MAX);
+        self.next_tick_nanos = next_tick_nanos.checked_add(period_nanos);
+        Some(self.tick_context_id)
+    }
+
+    // Time of the next tick or mock reply, at which the clock stops while advancing
+    pub fn next_timer_nanos(&self) -> Option<u64> {
+        let next_tick_nanos = self.next_tick_nanos.filter(|_| self.tick_context_id >= 0);
+        let http_calls = self.pending_http_calls.iter().map(|call| call.due_nanos);
+        let grpc_calls = self.pending_grpc_calls.iter().map(|call| call.due_nanos);
+        next_tick_nanos
+            .into_iter()
+            .chain(http_calls)
+            .chain(grpc_calls)
+            .min()
+    }
+
+    // Back to the system time (frozen)
+    pub fn reset_current_time_nanos(&mut self) {
+        self.clock = MockClock::new();
+    }
+
+    pub fn set_current_time_nanos(&mut self, current_time_nanos: u64) {
+        self.clock.set_nanos(current_time_nanos);
+    }
+
+    pub fn advance_current_time_nanos(&mut self, delta_nanos: u64) {
+        self.clock.advance(Duration::from_nanos(delta_nanos));
+    }
+
+    pub fn get_current_time_nanos(&self) -> u64 {
+        self.clock.now_nanos()
+    }
+
+    pub fn reset_return_status(&mut self) {
+        self.return_status.clear();
+    }
+
+    pub fn set_return_status(&mut self, host_call: &str, status: Status) {
+        self.return_status.insert(host_call.to_string(), status);
+    }
+
+    pub fn get_return_status(&self, host_call: &str) -> Option<Status> {
+        self.return_status.get(host_call).copied()
+    }
+
     pub fn reset_buffer_bytes(&mut self) {
         self.buffer_bytes = default_buffer_bytes();
     }
@@ -113,9 +859,45 @@ impl HostSettings {
             .insert(buffer_type, buffer_data.as_bytes().to_vec());
     }
 
+    pub fn set_buffer_data(&mut self, buffer_type: i32, buffer_data: Bytes) {
+        self.buffer_bytes.insert(buffer_type, buffer_data);
+    }
+
+    // proxy_set_buffer_bytes as Envoy implements it: from the start, the data replaces the first
+    // size bytes; from the end (or beyond), it is appended; anywhere else is a bad argument
+    pub fn copy_into_buffer(
+        &mut self,
+        buffer_type: i32,
+        start: usize,
+        size: usize,
+        data: &[u8],
+    ) -> Status {
+        let buffer = self.buffer_bytes.entry(buffer_type).or_default();
+        if start == 0 {
+            let drained = size.min(buffer.len());
+            buffer.splice(..drained, data.iter().copied());
+        } else if start >= buffer.len() {
+            buffer.extend_from_slice(data);
+        } else {
+            return Status::BadArgument;
+        }
+        Status::Ok
+    }
+
     pub fn get_buffer_bytes(&self, buffer_type: i32) -> Bytes {
-        let buffer_data = self.buffer_bytes.get(&buffer_type).unwrap().clone();
-        buffer_data
+        if buffer_type == BufferType::PluginConfiguration as i32 {
+            if let Some(plugin_config) = self
+                .stream_route()
+                .and_then(|route| route.plugin_config.as_ref())
+            {
+                return plugin_config.clone();
+            }
+        }
+        // buffers without a default (e.g. configuration) are treated as empty
+        self.buffer_bytes
+            .get(&buffer_type)
+            .cloned()
+            .unwrap_or_default()
     }
 
     pub fn reset_header_map_pairs(&mut self) {
@@ -123,114 +905,774 @@ impl HostSettings {
     }
 
     pub fn set_header_map_pairs(&mut self, map_type: i32, header_map_pairs: Vec<(&str, &str)>) {
-        let mut header_map = Vec::new();
-        for (header_map_key, header_map_value) in header_map_pairs.into_iter() {
-            header_map.push((header_map_key.to_string(), header_map_value.to_string()));
-        }
-        self.header_map_pairs.insert(map_type, header_map);
+        self.set_header_map_data(
+            map_type,
+            header_map_pairs
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+        );
     }
 
-    pub fn get_header_map_pairs(&self, map_type: i32) -> Bytes {
-        let header_map_pairs = self.header_map_pairs.get(&map_type).unwrap();
-        let header_map_pairs = header_map_pairs
-            .iter()
-            .map(|(k, v)| (k as &str, v as &str))
-            .collect();
-        serialize_map(header_map_pairs)
+    // Header values are kept as raw bytes, they need not be valid UTF-8
+    pub fn set_header_map_data(&mut self, map_type: i32, header_map_pairs: Vec<(String, Bytes)>) {
+        self.header_map_pairs.insert(map_type, header_map_pairs);
     }
 
-    pub fn get_header_map_value(&self, map_type: i32, header_map_key: &str) -> Option<String> {
-        let mut header_map_value: Option<String> = None;
-        let header_map = self.header_map_pairs.get(&map_type).unwrap();
-        for (key, value) in header_map {
-            if key == header_map_key {
-                header_map_value = Some(value.to_string());
-            }
+    pub fn get_header_map_pairs(&self, map_type: i32) -> Bytes {
+        match self.header_map_pairs.get(&map_type) {
+            Some(header_map_pairs) => serialize_map(header_map_pairs.clone()),
+            None => serialize_map::<&str, &str>(vec![]),
         }
-        header_map_value
     }
 
+    pub fn get_header_map_data(&self, map_type: i32) -> Vec<(String, Bytes)> {
+        self.header_map_pairs
+            .get(&map_type)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // First value of the header, names are matched case-insensitively
+    pub fn get_header_map_value(&self, map_type: i32, header_map_key: &str) -> Option<Bytes> {
+        let header_map = self.header_map_pairs.get(&map_type)?;
+        header_map
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(header_map_key))
+            .map(|(_, value)| value.clone())
+    }
+
+    // Sets the first value of the header in place, dropping the others, or adds it if missing
     pub fn replace_header_map_value(
         &mut self,
         map_type: i32,
         header_map_key: &str,
-        header_map_value: &str,
+        header_map_value: &[u8],
     ) {
-        let mut new_header_map: Vec<(String, String)> = Vec::new();
-        let header_map = self.header_map_pairs.get(&map_type).unwrap();
-        for (key, value) in header_map {
-            if key != header_map_key {
-                new_header_map.push((key.to_string(), value.to_string()));
-            } else {
-                new_header_map.push((key.to_string(), header_map_value.to_string()));
+        let header_map = self.header_map_pairs.entry(map_type).or_default();
+        match header_map
+            .iter()
+            .position(|(key, _)| key.eq_ignore_ascii_case(header_map_key))
+        {
+            Some(index) => {
+                let (key, _) = header_map.remove(index);
+                header_map.retain(|(other, _)| !other.eq_ignore_ascii_case(header_map_key));
+                header_map.insert(index, (key, header_map_value.to_vec()));
             }
+            None => header_map.push((header_map_key.to_string(), header_map_value.to_vec())),
         }
-        self.header_map_pairs.insert(map_type, new_header_map);
     }
 
+    // Removes every value of the header
     pub fn remove_header_map_value(&mut self, map_type: i32, header_map_key: &str) {
-        let mut new_header_map: Vec<(String, String)> = Vec::new();
-        let header_map = self.header_map_pairs.get(&map_type).unwrap();
-        for (key, value) in header_map {
-            if key != header_map_key {
-                new_header_map.push((key.to_string(), value.to_string()));
-            }
+        if let Some(header_map) = self.header_map_pairs.get_mut(&map_type) {
+            header_map.retain(|(key, _)| !key.eq_ignore_ascii_case(header_map_key));
         }
-        self.header_map_pairs.insert(map_type, new_header_map);
     }
 
+    // Adds a value after the existing ones, e.g. a second set-cookie
     pub fn add_header_map_value(
         &mut self,
         map_type: i32,
         header_map_key: &str,
-        header_map_value: &str,
+        header_map_value: &[u8],
+    ) {
+        self.header_map_pairs
+            .entry(map_type)
+            .or_default()
+            .push((header_map_key.to_string(), header_map_value.to_vec()));
+    }
+
+    pub fn continue_stream(&mut self, stream_type: i32) {
+        let body_type = match stream_type {
+            0 => BufferType::HttpRequestBody,
+            _ => BufferType::HttpResponseBody,
+        };
+        let body = self.get_buffer_bytes(body_type as i32);
+        let (headers, trailers) = match stream_type {
+            0 => (MapType::HttpRequestHeaders, MapType::HttpRequestTrailers),
+            _ => (MapType::HttpResponseHeaders, MapType::HttpResponseTrailers),
+        };
+        let headers = self.get_header_map_data(headers as i32);
+        let trailers = self.get_header_map_data(trailers as i32);
+        if let Some(stream) = self.streams.get_mut(&self.effective_context_id) {
+            stream.resume(stream_type, &body, (&headers, &trailers));
+        }
+        self.stream_actions.push(StreamAction::Continue {
+            context_id: self.effective_context_id,
+            stream_type,
+        });
+    }
+
+    pub fn send_local_response(
+        &mut self,
+        status_code: i32,
+        body: &[u8],
+        headers: Vec<(String, Bytes)>,
+    ) {
+        let request_headers = self.get_header_map_data(MapType::HttpRequestHeaders as i32);
+        if let Some(stream) = self.streams.get_mut(&self.effective_context_id) {
+            stream.reply_locally(LocalReply {
+                status_code: status_code as u32,
+                headers: headers.clone(),
+                body: body.to_vec(),
+                request_headers,
+            });
+        }
+        self.stream_actions.push(StreamAction::LocalResponse {
+            context_id: self.effective_context_id,
+            status_code,
+            body: body.to_vec(),
+            headers,
+        });
+    }
+
+    pub fn take_stream_actions(&mut self) -> Vec<StreamAction> {
+        std::mem::take(&mut self.stream_actions)
+    }
+
+    pub fn reset_mock_upstreams(&mut self) {
+        self.mock_upstreams.clear();
+    }
+
+    pub fn set_mock_upstream(&mut self, upstream: &str, response: MockResponse) {
+        self.mock_upstreams.insert(upstream.to_string(), response);
+    }
+
+    pub fn get_mock_upstreams(&self) -> Vec<(String, MockResponse)> {
+        let mut upstreams: Vec<(String, MockResponse)> = self
+            .mock_upstreams
+            .iter()
+            .map(|(upstream, response)| (upstream.clone(), response.clone()))
+            .collect();
+        upstreams.sort_by(|(a, _), (b, _)| a.cmp(b));
+        upstreams
+    }
+
+    // Mock upstreams are keyed by cluster name (the http_call upstream) or by ":authority"
+    pub fn get_mock_upstream(
+        &self,
+        upstream: &str,
+        headers: &[(String, String)],
+    ) -> Option<MockResponse> {
+        if let Some(response) = self.mock_upstreams.get(upstream) {
+            return Some(response.clone());
+        }
+        headers
+            .iter()
+            .find(|(key, _)| key == ":authority")
+            .and_then(|(_, authority)| self.mock_upstreams.get(authority))
+            .cloned()
+    }
+
+    pub fn next_token_id(&mut self) -> u32 {
+        let token_id = self.next_token_id;
+        self.next_token_id += 1;
+        token_id
+    }
+
+    // Responses are due after the upstream's delay, a delay beyond the call's timeout (if any)
+    // turns into a failed call (no headers, body or trailers) due at the timeout instead
+    pub fn queue_http_call_response(
+        &mut self,
+        token_id: u32,
+        response: MockResponse,
+        timeout_millis: u64,
     ) {
-        let mut key_found = false;
-        let mut new_header_map: Vec<(String, String)> = Vec::new();
-        let header_map = self.header_map_pairs.get(&map_type).unwrap();
-        for (key, value) in header_map {
-            if key != header_map_key {
-                new_header_map.push((key.to_string(), value.to_string()));
+        let response = match self.roll_fault(&response.faults) {
+            None => response,
+            Some(UpstreamFault::Reset) => MockResponse::failure(response.delay_millis),
+            Some(UpstreamFault::Unavailable) => MockResponse {
+                headers: vec![(":status".to_string(), "503".to_string())],
+                body: b"no healthy upstream".to_vec(),
+                trailers: vec![],
+                delay_millis: response.delay_millis,
+                faults: vec![],
+            },
+            // never answers, only the timeout (if any) ends the call
+            Some(UpstreamFault::Timeout) => MockResponse::failure(u64::MAX),
+        };
+        if timeout_millis == 0 && response.delay_millis == u64::MAX {
+            return;
+        }
+        let (response, delay_millis) =
+            if timeout_millis > 0 && response.delay_millis > timeout_millis {
+                (MockResponse::failure(timeout_millis), timeout_millis)
             } else {
-                key_found = true;
+                let delay_millis = response.delay_millis;
+                (response, delay_millis)
+            };
+        self.pending_http_calls.push(PendingHttpCall {
+            context_id: self.effective_context_id,
+            token_id,
+            response,
+            // saturated, a huge delay leaves the response pending rather than overflowing
+            due_nanos: self
+                .get_current_time_nanos()
+                .saturating_add(delay_millis.saturating_mul(1_000_000)),
+        });
+    }
+
+    // Faults are rolled from their own stream, so that which calls they hit does not depend on
+    // how much other randomness the module asked for
+    pub fn set_seed(&mut self, seed: u64) {
+        self.fault_rng = StdRng::seed_from_u64(seed);
+        self.rng = StdRng::seed_from_u64(seed.wrapping_add(1));
+    }
+
+    pub fn set_fault_seed(&mut self, seed: u64) {
+        self.fault_rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn random_bytes(&mut self, len: usize) -> Bytes {
+        (0..len).map(|_| self.rng.gen()).collect()
+    }
+
+    pub fn random_string(&mut self, len: usize) -> String {
+        generate_random_string(&mut self.rng, len)
+    }
+
+    // Picks the fault (if any) hitting this call, given per-fault percentages of calls
+    fn roll_fault(&mut self, faults: &[(UpstreamFault, u32)]) -> Option<UpstreamFault> {
+        if faults.is_empty() {
+            return None;
+        }
+        let mut roll = self.fault_rng.gen_range(0..100);
+        for (fault, percent) in faults {
+            if roll < *percent {
+                return Some(*fault);
+            }
+            roll -= percent;
+        }
+        None
+    }
+
+    // Takes the pending responses that are due by the current (host) time, earliest first
+    pub fn take_pending_http_calls(&mut self) -> Vec<PendingHttpCall> {
+        let now = self.get_current_time_nanos();
+        let (mut due, pending) = std::mem::take(&mut self.pending_http_calls)
+            .into_iter()
+            .partition::<Vec<_>, _>(|http_call| http_call.due_nanos <= now);
+        self.pending_http_calls = pending;
+        due.sort_by_key(|http_call| http_call.due_nanos);
+        due
+    }
+
+    #[cfg(feature = "vnext")]
+    pub fn set_mock_redis(&mut self, cluster: &str, reply: Bytes) {
+        self.redis.replies.insert(cluster.to_string(), reply);
+    }
+
+    #[cfg(feature = "vnext")]
+    pub fn reset_mock_redis(&mut self) {
+        self.redis = MockRedis::default();
+    }
+
+    // Whether the cluster can be reached, i.e. has a mock
+    #[cfg(feature = "vnext")]
+    pub fn init_redis(&mut self, cluster: &str) -> bool {
+        if !self.redis.replies.contains_key(cluster) {
+            return false;
+        }
+        if !self.redis.initialized.iter().any(|name| name == cluster) {
+            self.redis.initialized.push(cluster.to_string());
+        }
+        true
+    }
+
+    // Token of the call, None if redis_init was not called for the cluster
+    #[cfg(feature = "vnext")]
+    pub fn queue_redis_call(&mut self, cluster: &str, query: Bytes) -> Option<u32> {
+        if !self.redis.initialized.iter().any(|name| name == cluster) {
+            return None;
+        }
+        let token_id = self.next_token_id();
+        let reply = self.redis.replies[cluster].clone();
+        self.redis.queries.push((cluster.to_string(), query));
+        self.redis.pending.push(PendingRedisCall {
+            context_id: self.effective_context_id,
+            token_id,
+            reply,
+        });
+        Some(token_id)
+    }
+
+    #[cfg(feature = "vnext")]
+    pub fn take_pending_redis_calls(&mut self) -> Vec<PendingRedisCall> {
+        std::mem::take(&mut self.redis.pending)
+    }
+
+    #[cfg(feature = "vnext")]
+    pub fn get_redis_queries(&self, cluster: &str) -> Vec<Bytes> {
+        self.redis
+            .queries
+            .iter()
+            .filter(|(name, _)| name == cluster)
+            .map(|(_, query)| query.clone())
+            .collect()
+    }
+
+    pub fn reset_mock_grpc_services(&mut self) {
+        self.mock_grpc_services.clear();
+        self.pending_grpc_calls.clear();
+    }
+
+    pub fn add_mock_grpc_rule(&mut self, service: &str, method: &str, rule: MockGrpcRule) {
+        self.mock_grpc_services
+            .entry((service.to_string(), method.to_string()))
+            .or_default()
+            .push(rule);
+    }
+
+    // First staged rule matching the request answers it, requests matching no rule of a mocked
+    // method fail with Unimplemented, None if the method is not mocked at all
+    pub fn get_mock_grpc_reply(
+        &self,
+        service: &str,
+        method: &str,
+        request: &[u8],
+    ) -> Option<GrpcReply> {
+        let rules = self
+            .mock_grpc_services
+            .get(&(service.to_string(), method.to_string()))?;
+        let reply = rules.iter().find_map(|rule| (rule.0)(request));
+        Some(reply.unwrap_or(GrpcReply {
+            status: GrpcStatus::Unimplemented,
+            message: vec![],
+            delay_millis: 0,
+            trailers_only: None,
+        }))
+    }
+
+    pub fn queue_grpc_call_reply(&mut self, token_id: u32, reply: GrpcReply, timeout_millis: u64) {
+        let reply = if timeout_millis > 0 && reply.delay_millis > timeout_millis {
+            GrpcReply {
+                status: GrpcStatus::DeadlineExceeded,
+                message: vec![],
+                delay_millis: timeout_millis,
+                trailers_only: None,
             }
+        } else {
+            reply
+        };
+        self.pending_grpc_calls.push(PendingGrpcCall {
+            context_id: self.effective_context_id,
+            token_id,
+            // saturated, a huge delay leaves the reply pending rather than overflowing
+            due_nanos: self
+                .get_current_time_nanos()
+                .saturating_add(reply.delay_millis.saturating_mul(1_000_000)),
+            reply,
+        });
+    }
+
+    pub fn take_pending_grpc_calls(&mut self) -> Vec<PendingGrpcCall> {
+        let now = self.get_current_time_nanos();
+        let (mut due, pending) = std::mem::take(&mut self.pending_grpc_calls)
+            .into_iter()
+            .partition::<Vec<_>, _>(|grpc_call| grpc_call.due_nanos <= now);
+        self.pending_grpc_calls = pending;
+        due.sort_by_key(|grpc_call| grpc_call.due_nanos);
+        due
+    }
+
+    // A context created again under the same id replaces the previous one
+    pub fn create_context(&mut self, context_id: i32, root_context_id: i32) {
+        let (context_type, root_context_id) = match root_context_id {
+            0 => (ContextType::Root, None),
+            root_context_id => (ContextType::Unknown, Some(root_context_id)),
+        };
+        self.contexts.insert(
+            context_id,
+            ContextInfo {
+                context_id,
+                context_type,
+                root_context_id,
+                state: ContextState::Active,
+            },
+        );
+        self.streams.remove(&context_id);
+    }
+
+    // Child contexts take the type of their first HTTP or network callback
+    pub fn set_context_type(&mut self, context_id: i32, context_type: ContextType) {
+        if let Some(context) = self.contexts.get_mut(&context_id) {
+            if context.context_type == ContextType::Unknown {
+                context.context_type = context_type;
+            }
+        }
+    }
+
+    pub fn set_context_state(&mut self, context_id: i32, state: ContextState) {
+        if let Some(context) = self.contexts.get_mut(&context_id) {
+            context.state = state;
         }
-        if !key_found {
-            new_header_map.push((header_map_key.to_string(), header_map_value.to_string()));
+    }
+
+    // In context id order
+    pub fn get_contexts(&self) -> Vec<ContextInfo> {
+        self.contexts.values().cloned().collect()
+    }
+
+    pub fn set_phase_checks(&mut self, on: bool) {
+        self.phase_checks = on;
+    }
+
+    // Starts a callback of the stream, unless it cannot come at this point of the stream (the
+    // streams are tracked for their outcome even with the checks off)
+    pub fn enter_phase(
+        &mut self,
+        context_id: i32,
+        phase: StreamPhase,
+        end_of_stream: bool,
+    ) -> Result<(), String> {
+        let now_nanos = self.get_current_time_nanos();
+        let entered =
+            self.streams
+                .entry(context_id)
+                .or_default()
+                .enter(phase, end_of_stream, now_nanos);
+        match self.phase_checks {
+            true => entered,
+            false => Ok(()),
         }
-        self.header_map_pairs.insert(map_type, new_header_map);
     }
 
-    pub fn create_metric(&mut self, name: &str) -> i32 {
-        let metric_id: i32 = self.metrics_value.len().try_into().unwrap();
-        self.metrics_value.insert(metric_id, 0);
-        self.metrics_ids.insert(name.to_string(), metric_id);
-        metric_id
+    pub fn leave_phase(&mut self, context_id: i32, phase: StreamPhase, returned: Option<i32>) {
+        let body = phase
+            .body_buffer()
+            .map(|buffer_type| self.get_buffer_bytes(buffer_type as i32));
+        let (headers, trailers) = match phase.header_maps() {
+            Some((headers, trailers)) => (
+                self.get_header_map_data(headers as i32),
+                self.get_header_map_data(trailers as i32),
+            ),
+            None => Default::default(),
+        };
+        if let Some(stream) = self.streams.get_mut(&context_id) {
+            stream.leave(returned, body.as_deref(), (&headers, &trailers));
+        }
+    }
+
+    // Whether the stream holds its request (or response) body, buffering the next chunks
+    pub fn is_body_held(&self, context_id: i32, request: bool) -> bool {
+        self.streams
+            .get(&context_id)
+            .is_some_and(|stream| stream.is_body_held(request))
+    }
+
+    // Request (or response) body let through by the module so far
+    pub fn get_forwarded_body(&self, context_id: i32, request: bool) -> Bytes {
+        self.streams
+            .get(&context_id)
+            .map(|stream| stream.forwarded_body(request).to_vec())
+            .unwrap_or_default()
+    }
+
+    // Request (or response) headers and trailers as the module let them through, if it did
+    pub fn get_forwarded_header_maps(
+        &self,
+        context_id: i32,
+        request: bool,
+    ) -> (Option<HeaderPairs>, Option<HeaderPairs>) {
+        match self.streams.get(&context_id) {
+            Some(stream) => {
+                let (headers, trailers) = stream.forwarded_header_maps(request);
+                (headers.cloned(), trailers.cloned())
+            }
+            None => (None, None),
+        }
+    }
+
+    pub fn get_stream_times_nanos(&self, context_id: i32) -> (Option<u64>, Option<u64>) {
+        self.streams
+            .get(&context_id)
+            .map(StreamPhases::times_nanos)
+            .unwrap_or_default()
+    }
+
+    pub fn get_local_reply(&self, context_id: i32) -> Option<LocalReply> {
+        self.streams
+            .get(&context_id)
+            .and_then(|stream| stream.local_reply().cloned())
+    }
+
+    // Header maps of a stream are only there for its callbacks, and only until forwarded
+    pub fn check_header_map_access(&mut self, host_call: &str, map_type: i32, modify: bool) {
+        if !self.phase_checks {
+            return;
+        }
+        let violation = self
+            .streams
+            .get(&self.effective_context_id)
+            .and_then(|stream| stream.check_header_map(map_type, modify));
+        if let Some(violation) = violation {
+            self.phase_violations
+                .push(format!("{}: {}", host_call, violation));
+        }
+    }
+
+    pub fn check_body_access(&mut self, host_call: &str, buffer_type: i32) {
+        if !self.phase_checks {
+            return;
+        }
+        let violation = self
+            .streams
+            .get(&self.effective_context_id)
+            .and_then(|stream| stream.check_body(buffer_type));
+        if let Some(violation) = violation {
+            self.phase_violations
+                .push(format!("{}: {}", host_call, violation));
+        }
+    }
+
+    pub fn get_stream_outcome(&self, context_id: i32) -> StreamOutcome {
+        match self.streams.get(&context_id) {
+            Some(stream) => stream.outcome(),
+            None => StreamOutcome::Pending,
+        }
+    }
+
+    pub fn take_phase_violations(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.phase_violations)
+    }
+
+    pub fn reset_routes(&mut self) {
+        self.routes.clear();
+        self.stream_routes.clear();
+    }
+
+    // Replaces the route of the same name, if any
+    pub fn set_route(&mut self, route: Route) {
+        match self
+            .routes
+            .iter_mut()
+            .find(|known| known.name == route.name)
+        {
+            Some(known) => *known = route,
+            None => self.routes.push(route),
+        }
+    }
+
+    pub fn set_stream_route(&mut self, context_id: i32, route_name: &str) {
+        self.stream_routes
+            .insert(context_id, route_name.to_string());
+    }
+
+    // Routes the stream by the :path of its request headers, the longest prefix winning, unless
+    // its route was set explicitly
+    pub fn match_route(&mut self, context_id: i32) {
+        if self.stream_routes.contains_key(&context_id) {
+            return;
+        }
+        let path = self
+            .get_header_map_value(MapType::HttpRequestHeaders as i32, ":path")
+            .unwrap_or_default();
+        let route = self
+            .routes
+            .iter()
+            .filter(|route| path.starts_with(route.path_prefix.as_bytes()))
+            .fold(None, |longest: Option<&Route>, route| match longest {
+                Some(longest) if longest.path_prefix.len() >= route.path_prefix.len() => {
+                    Some(longest)
+                }
+                _ => Some(route),
+            });
+        if let Some(route) = route {
+            self.stream_routes.insert(context_id, route.name.clone());
+        }
+    }
+
+    fn stream_route(&self) -> Option<&Route> {
+        let route_name = self.stream_routes.get(&self.effective_context_id)?;
+        self.routes.iter().find(|route| &route.name == route_name)
+    }
+
+    pub fn reset_properties(&mut self) {
+        self.properties = default_properties();
+        self.context_properties.clear();
+    }
+
+    // Overrides only seen while the given (HTTP or stream) context is the effective one
+    pub fn set_context_property(&mut self, context_id: i32, path: &[&str], value: Bytes) {
+        self.context_properties
+            .entry(context_id)
+            .or_default()
+            .insert(to_property_path(path), value);
+    }
+
+    pub fn reset_context_properties(&mut self, context_id: i32) {
+        self.context_properties.remove(&context_id);
+    }
+
+    // Restores the default value of a single property (or removes it if it has none)
+    pub fn reset_property(&mut self, path: &[&str]) {
+        let path = to_property_path(path);
+        match default_properties().remove(&path) {
+            Some(value) => self.properties.insert(path, value),
+            None => self.properties.remove(&path),
+        };
+    }
+
+    pub fn set_property(&mut self, path: &[&str], value: Bytes) {
+        self.properties.insert(to_property_path(path), value);
+    }
+
+    pub fn remove_property(&mut self, path: &[&str]) {
+        self.properties.remove(&to_property_path(path));
+    }
+
+    // Per-context overrides take precedence, then the route of the stream (route_name), then
+    // attributes, then filter state: either by its full name under ["filter_state", name] or,
+    // for state written by wasm, by the path it was written at
+    pub fn get_property(&self, path: &[&str]) -> Option<Bytes> {
+        self.get_context_property(self.effective_context_id, path)
+    }
+
+    // Property as read by the module on the given context
+    pub fn get_context_property(&self, context_id: i32, path: &[&str]) -> Option<Bytes> {
+        let property_path = to_property_path(path);
+        if let Some(value) = self
+            .context_properties
+            .get(&context_id)
+            .and_then(|properties| properties.get(&property_path))
+        {
+            return Some(value.clone());
+        }
+        if let (["route_name"], Some(route_name)) = (path, self.stream_routes.get(&context_id)) {
+            return Some(route_name.as_bytes().to_vec());
+        }
+        if let Some(value) = self.properties.get(&property_path) {
+            return Some(value.clone());
+        }
+        match path {
+            ["filter_state", name] => self.filter_state.get(*name).cloned(),
+            _ => self.filter_state.get(&filter_state_name(path)).cloned(),
+        }
+    }
+
+    // proxy_set_property writes filter state, as in Envoy, under "wasm.<path>"
+    pub fn write_property(&mut self, path: &[&str], value: Bytes) {
+        self.filter_state.insert(filter_state_name(path), value);
+    }
+
+    pub fn reset_filter_state(&mut self) {
+        self.filter_state.clear();
+    }
+
+    pub fn set_filter_state(&mut self, name: &str, value: Bytes) {
+        self.filter_state.insert(name.to_string(), value);
+    }
+
+    pub fn get_filter_state(&self, name: &str) -> Option<Bytes> {
+        self.filter_state.get(name).cloned()
+    }
+
+    pub fn log(&mut self, level: i32, message: &str) {
+        self.logs.push(LogEntry {
+            level,
+            message: message.to_string(),
+        });
+    }
+
+    pub fn get_logs(&self) -> Logs {
+        Logs {
+            entries: self.logs.clone(),
+        }
+    }
+
+    pub fn reset_logs(&mut self) {
+        self.logs.clear();
+    }
+
+    pub fn set_log_level(&mut self, log_level: i32) {
+        self.log_level = log_level;
+    }
+
+    pub fn get_log_level(&self) -> i32 {
+        self.log_level
+    }
+
+    pub fn reset_shared_data(&mut self) {
+        self.shared_data = SharedData::new();
+    }
+
+    pub fn set_shared_data_ttl_millis(&mut self, ttl_millis: Option<u64>) {
+        self.shared_data.ttl_millis = ttl_millis;
+    }
+
+    pub fn set_shared_data_max_entries(&mut self, max_entries: Option<usize>) {
+        self.shared_data.max_entries = max_entries;
+    }
+
+    pub fn get_shared_data(&mut self, key: &str) -> Option<(Bytes, u32)> {
+        let now_nanos = self.get_current_time_nanos();
+        self.shared_data.get(key, now_nanos)
+    }
+
+    pub fn set_shared_data(&mut self, key: &str, value: Bytes, cas: u32) -> Status {
+        let now_nanos = self.get_current_time_nanos();
+        self.shared_data.set(key, value, cas, now_nanos)
+    }
+
+    // Metrics are registered by name, so defining the same metric twice yields the same id
+    pub fn define_metric(&mut self, metric_type: i32, name: &str) -> i32 {
+        if let Some(metric_id) = self.metrics.iter().position(|metric| metric.name == name) {
+            return metric_id as i32;
+        }
+        self.metrics.push(Metric {
+            name: name.to_string(),
+            metric_type,
+            value: 0,
+            samples: Vec::new(),
+        });
+        (self.metrics.len() - 1) as i32
     }
 
     pub fn increment_metric(&mut self, metric_id: i32, offset: i64) {
-        let value = self.metrics_value.get_mut(&metric_id).unwrap();
-        *value += offset;
+        let metric = self.metrics.get_mut(metric_id as usize).unwrap();
+        metric.value += offset;
     }
 
+    // Histograms keep every recorded sample, counters and gauges only the latest value
     pub fn record_metric(&mut self, metric_id: i32, new_value: i64) {
-        let value = self.metrics_value.get_mut(&metric_id).unwrap();
-        *value = new_value;
+        let metric = self.metrics.get_mut(metric_id as usize).unwrap();
+        if metric.metric_type == MetricType::Histogram as i32 {
+            metric.samples.push(new_value);
+        }
+        metric.value = new_value;
     }
 
     pub fn get_metric(&self, metric_id: i32) -> u64 {
-        let value = *self.metrics_value.get(&metric_id).unwrap();
+        let value = self.metrics.get(metric_id as usize).unwrap().value;
         u64::try_from(value).unwrap()
     }
 
     pub fn get_metric_id(&self, name: &str) -> i32 {
-        *self.metrics_ids.get(name).unwrap()
+        self.metrics
+            .iter()
+            .position(|metric| metric.name == name)
+            .unwrap() as i32
+    }
+
+    pub fn get_metrics(&self) -> Metrics {
+        Metrics {
+            entries: self.metrics.clone(),
+        }
+    }
+
+    pub fn get_metric_by_id(&self, metric_id: i32) -> Option<&Metric> {
+        self.metrics.get(usize::try_from(metric_id).ok()?)
+    }
+
+    pub fn get_metric_by_name(&self, name: &str) -> Option<&Metric> {
+        self.metrics.iter().find(|metric| metric.name == name)
     }
 }
 
 // functions to retrieve default values
-pub fn default_header_map_pairs() -> HashMap<i32, Vec<(String, String)>> {
-    let mut default_header_maps = HashMap::new();
+pub fn default_header_map_pairs() -> HashMap<i32, Vec<(String, Bytes)>> {
+    let mut default_header_maps: HashMap<i32, Vec<(String, String)>> = HashMap::new();
 
     let mut http_on_request_headers = Vec::new();
     http_on_request_headers.push((":method".to_string(), "GET".to_string()));
@@ -302,6 +1744,15 @@ pub fn default_header_map_pairs() -> HashMap<i32, Vec<(String, String)>> {
     );
 
     default_header_maps
+        .into_iter()
+        .map(|(map_type, header_map)| {
+            let header_map = header_map
+                .into_iter()
+                .map(|(key, value)| (key, value.into_bytes()))
+                .collect();
+            (map_type, header_map)
+        })
+        .collect()
 }
 
 pub fn default_buffer_bytes() -> HashMap<i32, Bytes> {
@@ -328,3 +1779,93 @@ pub fn default_buffer_bytes() -> HashMap<i32, Bytes> {
     );
     default_bytes
 }
+
+fn filter_state_name(path: &[&str]) -> String {
+    format!("wasm.{}", path.join("."))
+}
+
+fn to_property_path(path: &[&str]) -> Vec<String> {
+    path.iter().map(|part| part.to_string()).collect()
+}
+
+// Envoy attributes as returned by proxy_get_property: strings as raw bytes, integers (and
+// durations / timestamps in nanoseconds) as 8-byte little-endian, booleans as a single byte
+pub fn default_properties() -> HashMap<Vec<String>, Bytes> {
+    let string = |value: &str| value.as_bytes().to_vec();
+    let int = |value: i64| value.to_le_bytes().to_vec();
+    let boolean = |value: bool| vec![value as u8];
+
+    let default_properties = vec![
+        // request attributes
+        (
+            vec!["request", "path"],
+            string("/default/request/headers/path"),
+        ),
+        (
+            vec!["request", "url_path"],
+            string("/default/request/headers/path"),
+        ),
+        (vec!["request", "host"], string("abi_test_harness")),
+        (vec!["request", "scheme"], string("http")),
+        (vec!["request", "method"], string("GET")),
+        (vec!["request", "referer"], string("")),
+        (vec!["request", "useragent"], string("abi_test_harness")),
+        (vec!["request", "time"], int(0)),
+        (
+            vec!["request", "id"],
+            string("00000000-0000-0000-0000-000000000000"),
+        ),
+        (vec!["request", "protocol"], string("HTTP/1.1")),
+        (vec!["request", "query"], string("")),
+        (vec!["request", "duration"], int(0)),
+        (vec!["request", "size"], int(0)),
+        (vec!["request", "total_size"], int(0)),
+        // response attributes
+        (vec!["response", "code"], int(200)),
+        (vec!["response", "code_details"], string("via_upstream")),
+        (vec!["response", "flags"], int(0)),
+        (vec!["response", "grpc_status"], int(0)),
+        (vec!["response", "size"], int(0)),
+        (vec!["response", "total_size"], int(0)),
+        // connection attributes
+        (vec!["source", "address"], string("127.0.0.1:54321")),
+        (vec!["source", "port"], int(54321)),
+        (vec!["destination", "address"], string("127.0.0.1:10000")),
+        (vec!["destination", "port"], int(10000)),
+        (vec!["connection", "id"], int(1)),
+        (vec!["connection", "mtls"], boolean(false)),
+        (vec!["connection", "requested_server_name"], string("")),
+        (vec!["connection", "tls_version"], string("")),
+        (vec!["connection", "subject_local_certificate"], string("")),
+        (vec!["connection", "subject_peer_certificate"], string("")),
+        (vec!["connection", "dns_san_local_certificate"], string("")),
+        (vec!["connection", "dns_san_peer_certificate"], string("")),
+        (vec!["connection", "uri_san_local_certificate"], string("")),
+        (vec!["connection", "uri_san_peer_certificate"], string("")),
+        (
+            vec!["connection", "sha256_peer_certificate_digest"],
+            string(""),
+        ),
+        (vec!["connection", "termination_details"], string("")),
+        // upstream attributes
+        (vec!["upstream", "address"], string("127.0.0.1:8080")),
+        (vec!["upstream", "port"], int(8080)),
+        (vec!["upstream", "local_address"], string("127.0.0.1:40000")),
+        (vec!["upstream", "tls_version"], string("")),
+        (vec!["upstream", "transport_failure_reason"], string("")),
+        // node and wasm attributes
+        (vec!["node", "id"], string("abi_test_harness")),
+        (vec!["node", "cluster"], string("abi_test_harness")),
+        (vec!["cluster_name"], string("default_cluster")),
+        (vec!["route_name"], string("default_route")),
+        (vec!["listener_direction"], int(1)),
+        (vec!["plugin_name"], string("")),
+        (vec!["plugin_root_id"], string("")),
+        (vec!["plugin_vm_id"], string("")),
+    ];
+
+    default_properties
+        .into_iter()
+        .map(|(path, value)| (to_property_path(&path), value))
+        .collect()
+}
@@ -12,115 +12,251 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+// wasmtime's Caller::get_export takes &mut self where wasmi's takes &self
+#![cfg_attr(feature = "wasmi", allow(unused_mut))]
+
 use crate::expectations::ExpectHandle;
-use crate::host_settings::HostHandle;
+use crate::flamegraph::StackSamples;
+use crate::host_settings::{HostHandle, QueueReady, SharedQueues};
+use crate::otlp::OtlpExport;
+use crate::profile::{HostCallReport, HostCallTimer};
+use crate::runtime::*;
+use crate::statsd::{MetricUpdate, StatsdSink};
+use crate::tester::HostExtensions;
+use crate::trace::{EventLog, Trace, TracedCall, TracedEvent};
 use crate::types::*;
 
-use lazy_static::lazy_static;
+use anyhow::Result;
 use more_asserts::*;
 use std::convert::TryInto;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
-use wasmtime::*;
-
-lazy_static! {
-    static ref HOST: Arc<Mutex<HostHandle>> = Arc::new(Mutex::new(HostHandle::new()));
-    static ref EXPECT: Arc<Mutex<ExpectHandle>> = Arc::new(Mutex::new(ExpectHandle::new()));
-    pub static ref STATUS: Arc<Mutex<ExpectStatus>> =
-        Arc::new(Mutex::new(ExpectStatus::Unexpected));
+use std::time::Instant;
+use tracing::{error, info, trace};
+
+// State of the host functions for one Tester, owned by its wasm store (rather than global) so
+// that tests can run in parallel
+#[derive(Clone)]
+pub struct HostState {
+    pub host: Arc<Mutex<HostHandle>>,
+    pub expect: Arc<Mutex<ExpectHandle>>,
+    // message bus backing the shared queues, which Testers can share with each other
+    pub queues: Arc<Mutex<SharedQueues>>,
+    // resources (e.g. linear memory) the module may use
+    pub limits: StoreLimits,
+    // host calls recorded while tracing is on
+    pub trace: Arc<Mutex<Option<Trace>>>,
+    // structured log of the host calls, written while set
+    pub event_log: Arc<Mutex<Option<EventLog>>>,
+    // OpenTelemetry spans of the callbacks and host calls, exported while set
+    pub otlp: Arc<Mutex<Option<OtlpExport>>>,
+    // stacks of the module sampled while set (see Tester::set_sampling)
+    pub samples: Arc<Mutex<Option<StackSamples>>>,
+    // metric updates forwarded while set (see Tester::set_statsd_sink)
+    pub statsd: Arc<Mutex<Option<StatsdSink>>>,
+    // calls to each host function and their handling time (see Tester::host_call_report)
+    pub host_calls: Arc<Mutex<HostCallReport>>,
+    // end of the current callback while sampling, checked by the sampler instead of the engine
+    pub deadline: Option<Instant>,
 }
 
-pub fn set_status(expect_status: ExpectStatus) {
-    *STATUS.lock().unwrap() = expect_status;
+impl HostState {
+    pub fn new(abi_version: AbiVersion) -> HostState {
+        let mut host = HostHandle::new();
+        host.staged.set_abi_version(abi_version);
+        HostState {
+            host: Arc::new(Mutex::new(host)),
+            expect: Arc::new(Mutex::new(ExpectHandle::new())),
+            queues: Arc::new(Mutex::new(SharedQueues::new())),
+            limits: StoreLimits::default(),
+            trace: Arc::new(Mutex::new(None)),
+            event_log: Arc::new(Mutex::new(None)),
+            otlp: Arc::new(Mutex::new(None)),
+            samples: Arc::new(Mutex::new(None)),
+            statsd: Arc::new(Mutex::new(None)),
+            host_calls: Arc::new(Mutex::new(HostCallReport::default())),
+            deadline: None,
+        }
+    }
+
+    pub fn get_status(&self) -> ExpectStatus {
+        self.expect.lock().unwrap().staged.status()
+    }
+
+    pub fn set_status(&self, expect_status: ExpectStatus) {
+        self.expect.lock().unwrap().staged.set_status(expect_status);
+    }
+
+    // Times the host call until the returned timer is dropped (at the end of the host function)
+    pub(crate) fn time_host_call(&self, host_call: &'static str) -> HostCallTimer {
+        // calls without a TracedCall of their own are traced by name (see trace::Trace)
+        if host_call.starts_with("proxy_") && HostCall::from_name(host_call).is_none() {
+            self.record(TracedCall::Untraced {
+                name: host_call.to_string(),
+            });
+        }
+        HostCallTimer::start(self.host_calls.clone(), host_call)
+    }
+
+    pub fn record(&self, call: TracedCall) {
+        let event = TracedEvent::HostCall(call);
+        if let Some(event_log) = self.event_log.lock().unwrap().as_mut() {
+            event_log.write(&event);
+        }
+        if let Some(otlp) = self.otlp.lock().unwrap().as_mut() {
+            otlp.write(&event);
+        }
+        if let Some(trace) = self.trace.lock().unwrap().as_mut() {
+            trace.record(event);
+        }
+    }
+
+    // Forwards the update of a metric to the statsd sink, if any, before the host applies it
+    pub(crate) fn forward_metric_update(&self, metric_id: i32, update: MetricUpdate) {
+        if let Some(sink) = self.statsd.lock().unwrap().as_ref() {
+            let host = self.host.lock().unwrap();
+            if let Some(metric) = host.staged.get_metric_by_id(metric_id) {
+                sink.send(metric, update);
+            }
+        }
+    }
+
+    pub fn take_queue_ready(&self, vm_id: &str) -> Vec<QueueReady> {
+        self.queues.lock().unwrap().take_pending_ready(vm_id)
+    }
 }
 
-pub fn get_status() -> ExpectStatus {
-    let status = *STATUS.lock().unwrap();
-    status
+// Status the test forces the given host function to return (skipping its default behaviour)
+fn get_forced_status(state: &HostState, name: &str) -> Option<i32> {
+    let status = state.host.lock().unwrap().staged.get_return_status(name)?;
+    trace!("[vm->host] {}(...) forced", name);
+    trace!("[vm<-host] {}(...) return: {:?}", name, status);
+    Some(status as i32)
 }
 
+// Detected from the marker function exported by the SDK, the latest version wins when a module
+// exports several
 pub fn get_abi_version(module: &Module) -> AbiVersion {
-    if module.get_export("proxy_abi_version_0_1_0").is_some() {
-        AbiVersion::ProxyAbiVersion0_1_0
-    } else if module.get_export("proxy_abi_version_0_2_0").is_some()
-        || module.get_export("proxy_abi_version_0_2_1").is_some()
-    {
+    if module.get_export("proxy_abi_version_0_2_1").is_some() {
+        AbiVersion::ProxyAbiVersion0_2_1
+    } else if module.get_export("proxy_abi_version_0_2_0").is_some() {
         AbiVersion::ProxyAbiVersion0_2_0
+    } else if module.get_export("proxy_abi_version_0_1_0").is_some() {
+        AbiVersion::ProxyAbiVersion0_1_0
     } else {
         panic!("Error: test-framework does not support proxy-wasm modules of this abi version");
     }
 }
 
-pub fn generate_import_list(
-    store: &mut Store<()>,
+// Defines the host functions imported by the module on the linker: the extensions registered
+// for the Tester first, then the built-in implementations
+pub fn link_host_functions(
+    linker: &mut Linker<HostState>,
     module: &Module,
-    func_vec: Arc<Mutex<Vec<Extern>>>,
-) -> (Arc<Mutex<HostHandle>>, Arc<Mutex<ExpectHandle>>) {
+    extensions: &mut HostExtensions,
+) -> Result<()> {
     let abi_version = get_abi_version(module);
-    HOST.lock().unwrap().staged.set_abi_version(abi_version);
-    let imports = module.imports();
-    for import in imports {
-        match get_hostfunc(store, abi_version, &import) {
-            Some(func) => (*func_vec).lock().unwrap().push(func.into()),
-            None => panic!("Error: failed to acquire \"{}\"", import.name()),
+    for import in module.imports() {
+        if extensions.link(linker, import.module(), import.name())? {
+            continue;
+        }
+        #[cfg(feature = "vnext")]
+        if crate::vnext::link_hostfunc(linker, &import)? {
+            continue;
+        }
+        if !link_hostfunc(linker, abi_version, &import)? {
+            panic!("Error: failed to acquire \"{}\"", import.name());
         }
     }
-    (HOST.clone(), EXPECT.clone())
+    Ok(())
 }
 
 cfg_if::cfg_if! {
     if #[cfg(all(target_arch = "wasm32", target_os = "unknown"))] {
-        fn get_allocator(caller: &mut Caller<'_, ()>) -> Option<Extern> { get_allocator(&mut caller) }
+        fn get_allocator(caller: &mut Caller<'_, HostState>) -> Option<Extern> { get_allocator(&mut caller) }
     } else {
-        fn get_allocator(caller: &mut Caller<'_, ()>) -> Option<Extern> { caller.get_export("proxy_on_memory_allocate")}
+        fn get_allocator(caller: &mut Caller<'_, HostState>) -> Option<Extern> { caller.get_export("proxy_on_memory_allocate")}
     }
 }
 
-fn get_hostfunc(
-    store: &mut Store<()>,
+// Allocates memory in the module for data returned by a host function, None if the allocation
+// failed (e.g. past the memory limit) in which case the host function returns
+// InvalidMemoryAccess, as Envoy does
+fn allocate(
+    caller: &mut Caller<'_, HostState>,
+    malloc: &Func,
+    mem: &Memory,
+    size: usize,
+) -> Option<usize> {
+    let mut result = [Val::I32(0)];
+    malloc
+        .call(&mut *caller, &[Val::I32(size as i32)], &mut result)
+        .ok()?;
+    let address = result[0].i32()? as u32 as usize;
+    if (address == 0 && size > 0) || address + size > mem.data(&*caller).len() {
+        return None;
+    }
+    Some(address)
+}
+
+// Returns whether a built-in implementation of the imported function exists
+fn link_hostfunc(
+    linker: &mut Linker<HostState>,
     _abi_version: AbiVersion,
     import: &ImportType,
-) -> Option<Func> {
-    match import.name() {
+) -> Result<bool> {
+    let (module, name) = (import.module(), import.name());
+    let linked = match name {
         /* ---------------------------------- Configuration and Status ---------------------------------- */
         "proxy_get_configuration" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>,
                  _return_buffer_data: i32,
                  _return_buffer_size: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_configuration");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_configuration") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
                     assert_eq!(
-                        HOST.lock().unwrap().staged.get_abi_version(),
+                        state.host.lock().unwrap().staged.get_abi_version(),
                         AbiVersion::ProxyAbiVersion0_1_0
                     );
-                    println!(
+                    trace!(
                         "[vm->host] proxy_get_configuration() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!("[vm<-host] proxy_get_configuration() -> (return_buffer_data, return_buffer_size) return: {:?}", Status::InternalFailure);
+                    trace!("[vm<-host] proxy_get_configuration() -> (return_buffer_data, return_buffer_size) return: {:?}", Status::InternalFailure);
                     return Status::InternalFailure as i32;
                 },
             ))
         }
 
         "proxy_get_status" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>,
                  _status_code_ptr: i32,
                  _message_ptr: i32,
                  _message_size: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_status");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_status") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    println!(
+                    trace!(
                         "[vm->host] proxy_get_status() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_get_status() -> (..) return: {:?}",
                         Status::InternalFailure
                     );
@@ -131,20 +267,26 @@ fn get_hostfunc(
 
         /* ---------------------------------- Logging ---------------------------------- */
         "proxy_log" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
                  level: i32,
                  message_data: i32,
                  message_size: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_log");
+                    if let Some(status) = get_forced_status(&state, "proxy_log") {
+                        return status;
+                    }
                     // Default Function: retrieve and display log message from proxy-wasm module
                     // Expectation: ensure the log level and the message data are as expected
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!("Error: proxy_log cannot get_export \"memory\"");
-                            println!(
+                            error!("Error: proxy_log cannot get_export \"memory\"");
+                            trace!(
                                 "[vm<-host] proxy_log(...) return: {:?}",
                                 Status::InternalFailure
                             );
@@ -164,72 +306,122 @@ fn get_hostfunc(
                         _ => "invalid utf-8 slice",
                     };
 
-                    EXPECT
+                    state
+                        .expect
                         .lock()
                         .unwrap()
                         .staged
                         .get_expect_log(level, string_msg);
-                    println!(
+                    state.host.lock().unwrap().staged.log(level, string_msg);
+                    state.record(TracedCall::Log {
+                        level,
+                        message: string_msg.to_string(),
+                    });
+                    info!(
                         "[vm->host] proxy_log(level={}, message_data=\"{}\") status: {:?}",
                         level,
                         string_msg,
-                        get_status()
+                        state.get_status()
                     );
                     // println!("[vm<-host] proxy_log(...) return: {:?}", Status::Ok)
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_get_log_level" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>, _level: i32| -> i32 {
-                    // Default Function:
-                    // Expectation:
-                    println!(
-                        "[vm->host] proxy_get_log_level() -> (...) status: {:?}",
-                        get_status()
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>, return_level: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_log_level");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_log_level") {
+                        return status;
+                    }
+                    // Default Function: respond with the log level of the host (Info by default)
+                    assert_eq!(
+                        state.host.lock().unwrap().staged.get_abi_version(),
+                        AbiVersion::ProxyAbiVersion0_2_1
                     );
-                    println!(
-                        "[vm<-host] proxy_get_log_level() -> (..) return: {:?}",
-                        Status::InternalFailure
+                    let mem = match caller.get_export("memory") {
+                        Some(Extern::Memory(mem)) => mem,
+                        _ => {
+                            error!("Error: proxy_get_log_level cannot get export \"memory\"");
+                            trace!(
+                                "[vm<-host] proxy_get_log_level() -> (return_level) return: {:?}",
+                                Status::InternalFailure
+                            );
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    let level = state.host.lock().unwrap().staged.get_log_level();
+                    let start = return_level as u32 as usize;
+                    match mem.data_mut(&mut caller).get_mut(start..start + 4) {
+                        Some(data) => data.copy_from_slice(&level.to_le_bytes()),
+                        None => return Status::InvalidMemoryAccess as i32,
+                    }
+                    trace!(
+                        "[vm->host] proxy_get_log_level() -> (level={}) status: {:?}",
+                        level,
+                        state.get_status()
                     );
-                    return Status::InternalFailure as i32;
+                    trace!(
+                        "[vm<-host] proxy_get_log_level() -> (return_level) return: {:?}",
+                        Status::Ok
+                    );
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
+                    return Status::Ok as i32;
                 },
             ))
         }
 
         /* ---------------------------------- Timer ---------------------------------- */
         "proxy_set_tick_period_milliseconds" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>, period: i32| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>, period: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_set_tick_period_milliseconds");
+                    if let Some(status) =
+                        get_forced_status(&state, "proxy_set_tick_period_milliseconds")
+                    {
+                        return status;
+                    }
                     // Default Function: receive and store tick period from proxy-wasm module
                     // Expectation: assert received tick period is equal to expected
-                    HOST.lock()
+                    state
+                        .host
+                        .lock()
                         .unwrap()
                         .staged
-                        .set_tick_period_millis(period as u64);
-                    EXPECT
+                        .set_tick_period_millis(period as u32 as u64);
+                    state
+                        .expect
                         .lock()
                         .unwrap()
                         .staged
-                        .get_expect_set_tick_period_millis(period as u128);
+                        .get_expect_set_tick_period_millis(period as u32 as u64);
+                    state.record(TracedCall::SetTickPeriodMillis {
+                        period_millis: period as u32 as u64,
+                    });
 
-                    println!(
+                    trace!(
                         "[vm->host] proxy_set_tick_period_milliseconds(period={}) status: {:?}",
-                        period,
-                        get_status()
+                        period as u32,
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_set_tick_period_milliseconds(...) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
@@ -237,32 +429,35 @@ fn get_hostfunc(
 
         /* ---------------------------------- Time ---------------------------------- */
         "proxy_get_current_time_nanoseconds" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>, return_time: i32| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>, return_time: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_current_time_nanoseconds");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_current_time_nanoseconds") {
+                        return status;
+                    }
                     // Default Function: respond to proxy-wasm module with the current time
                     // Expectation: respond with a pre-set expected time
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!("Error: proxy_get_current_time_nanoseconds cannot get export \"memory\"");
-                            println!("[vm<-host] proxy_get_current_time_nanoseconds(...) -> (return_time) return: {:?}", Status::InternalFailure);
+                            error!("Error: proxy_get_current_time_nanoseconds cannot get export \"memory\"");
+                            trace!("[vm<-host] proxy_get_current_time_nanoseconds(...) -> (return_time) return: {:?}", Status::InternalFailure);
                             return Status::InternalFailure as i32;
                         }
                     };
 
-                    let time = match EXPECT
-                        .lock()
+                    let time = match state.expect.lock()
                         .unwrap()
                         .staged
                         .get_expect_get_current_time_nanos()
                     {
                         Some(current_time_nanos) => current_time_nanos as u64,
-                        None => SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_nanos() as u64,
+                        None => state.host.lock().unwrap().staged.get_current_time_nanos(),
                     };
+                    state.record(TracedCall::GetCurrentTimeNanos { time_nanos: time });
 
                     unsafe {
                         let data = mem.data_mut(&mut caller).get_unchecked_mut(
@@ -271,16 +466,16 @@ fn get_hostfunc(
 
                         data.copy_from_slice(&time.to_le_bytes());
                     }
-                    println!(
+                    trace!(
                         "[vm->host] proxy_get_current_time_nanoseconds() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_get_current_time_nanoseconds() -> (return_time) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
@@ -288,146 +483,298 @@ fn get_hostfunc(
 
         /* ---------------------------------- State Accessors ---------------------------------- */
         "proxy_get_property" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
-                 _path_data: i32,
-                 _path_size: i32,
-                 _return_value_data: i32,
-                 _return_value_size: i32|
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
+                 path_data: i32,
+                 path_size: i32,
+                 return_value_data: i32,
+                 return_value_size: i32|
                  -> i32 {
-                    // Default Function:
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_property");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_property") {
+                        return status;
+                    }
+                    // Default Function: look up the path in the host's (Envoy-like) attribute set
                     // Expectation:
-                    println!(
-                        "[vm->host] proxy_get_property(path_data, path_size) -> (...) status: {:?}",
-                        get_status()
+                    let mem = match caller.get_export("memory") {
+                        Some(Extern::Memory(mem)) => mem,
+                        _ => {
+                            error!("Error: proxy_get_property cannot get export \"memory\"");
+                            trace!("[vm<-host] proxy_get_property(...) -> (return_value_data, return_value_size) return: {:?}", Status::InternalFailure);
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    let malloc = match get_allocator(&mut caller) {
+                        Some(Extern::Func(func)) => func,
+                        _ => {
+                            error!("Error: proxy_get_property cannot get export \"malloc\"");
+                            trace!("[vm<-host] proxy_get_property(...) -> (return_value_data, return_value_size) return: {:?}", Status::InternalFailure);
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    // path parts are separated by '\0'
+                    let path = mem
+                        .data(&caller)
+                        .get(path_data as u32 as usize..)
+                        .and_then(|arr| arr.get(..path_size as u32 as usize))
+                        .map(|string_msg| String::from_utf8_lossy(string_msg).to_string())
+                        .unwrap();
+                    let path: Vec<&str> = path.split('\0').collect();
+
+                    trace!(
+                        "[vm->host] proxy_get_property(path={:?}) -> (...) status: {:?}",
+                        path,
+                        state.get_status()
                     );
-                    println!("[vm<-host] proxy_get_property(...) -> (return_value_data, return_value_size) return: {:?}", Status::InternalFailure);
-                    return Status::InternalFailure as i32;
+                    let value = match state.host.lock().unwrap().staged.get_property(&path) {
+                        Some(value) => value,
+                        None => {
+                            trace!("[vm<-host] proxy_get_property(...) -> (return_value_data, return_value_size) return: {:?}", Status::NotFound);
+                            return Status::NotFound as i32;
+                        }
+                    };
+
+                    unsafe {
+                        // allocate memory and store the value
+                        let value_data_add = match allocate(&mut caller, &malloc, &mem, value.len()) {
+                            Some(address) => address,
+                            None => {
+                                trace!("[vm<-host] proxy_get_property(...) return: {:?}", Status::InvalidMemoryAccess);
+                                return Status::InvalidMemoryAccess as i32;
+                            }
+                        };
+
+                        let value_data_ptr = mem
+                            .data_mut(&mut caller)
+                            .get_unchecked_mut(value_data_add..value_data_add + value.len());
+                        value_data_ptr.copy_from_slice(&value);
+
+                        let return_value_data_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
+                            return_value_data as u32 as usize
+                                ..return_value_data as u32 as usize + 4,
+                        );
+                        return_value_data_ptr
+                            .copy_from_slice(&(value_data_add as u32).to_le_bytes());
+                        let return_value_size_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
+                            return_value_size as u32 as usize
+                                ..return_value_size as u32 as usize + 4,
+                        );
+                        return_value_size_ptr.copy_from_slice(&(value.len() as u32).to_le_bytes());
+                    }
+
+                    trace!("[vm<-host] proxy_get_property(...) -> (return_value_data={:?}, return_value_size={}) return: {:?}", String::from_utf8_lossy(&value), value.len(), Status::Ok);
+                    return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_set_property" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
-                 _path_data: i32,
-                 _path_size: i32,
-                 _value_data: i32,
-                 _value_size: i32|
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
+                 path_data: i32,
+                 path_size: i32,
+                 value_data: i32,
+                 value_size: i32|
                  -> i32 {
-                    // Default Function:
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_set_property");
+                    if let Some(status) = get_forced_status(&state, "proxy_set_property") {
+                        return status;
+                    }
+                    // Default Function: store the value as filter state (named "wasm.<path>")
                     // Expectation:
-                    println!("[vm->host] proxy_set_property(path_data, path_size, value_data, value_size) status: {:?}", get_status());
-                    println!(
+                    let mem = match caller.get_export("memory") {
+                        Some(Extern::Memory(mem)) => mem,
+                        _ => {
+                            error!("Error: proxy_set_property cannot get export \"memory\"");
+                            trace!(
+                                "[vm<-host] proxy_set_property(...) return: {:?}",
+                                Status::InternalFailure
+                            );
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    let path = mem
+                        .data(&caller)
+                        .get(path_data as u32 as usize..)
+                        .and_then(|arr| arr.get(..path_size as u32 as usize))
+                        .map(|string_msg| String::from_utf8_lossy(string_msg).to_string())
+                        .unwrap();
+                    let path: Vec<&str> = path.split('\0').collect();
+                    let value = mem
+                        .data(&caller)
+                        .get(value_data as u32 as usize..)
+                        .and_then(|arr| arr.get(..value_size as u32 as usize))
+                        .map(|value| value.to_vec())
+                        .unwrap_or_default();
+
+                    trace!(
+                        "[vm->host] proxy_set_property(path={:?}, value={:?}) status: {:?}",
+                        path,
+                        String::from_utf8_lossy(&value),
+                        state.get_status()
+                    );
+                    state
+                        .host
+                        .lock()
+                        .unwrap()
+                        .staged
+                        .write_property(&path, value);
+                    trace!(
                         "[vm<-host] proxy_set_property(...) return: {:?}",
-                        Status::InternalFailure
+                        Status::Ok
                     );
-                    return Status::InternalFailure as i32;
+                    return Status::Ok as i32;
                 },
             ))
         }
 
         /* ---------------------------------- Continue/Close/Reply/Route ---------------------------------- */
         "proxy_continue_stream" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>, stream_type: i32| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>, stream_type: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_continue_stream");
+                    if let Some(status) = get_forced_status(&state, "proxy_continue_stream") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    assert_eq!(
-                        HOST.lock().unwrap().staged.get_abi_version(),
-                        AbiVersion::ProxyAbiVersion0_2_0
-                    );
-                    println!(
+                    let abi_version = state.host.lock().unwrap().staged.get_abi_version();
+                    assert!(
+                        abi_version.is_0_2(),
+                        "Error: proxy_continue_stream called under ABI {:?}, it was added in 0.2.0",
+                        abi_version
+                    );
+                    state
+                        .host
+                        .lock()
+                        .unwrap()
+                        .staged
+                        .continue_stream(stream_type);
+                    trace!(
                         "[vm->host] proxy_continue_stream(stream_type={stream_type}) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_continue_stream(...) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_close_stream" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>, stream_type: i32| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>, stream_type: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_close_stream");
+                    if let Some(status) = get_forced_status(&state, "proxy_close_stream") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    assert_eq!(
-                        HOST.lock().unwrap().staged.get_abi_version(),
-                        AbiVersion::ProxyAbiVersion0_2_0
+                    let abi_version = state.host.lock().unwrap().staged.get_abi_version();
+                    assert!(
+                        abi_version.is_0_2(),
+                        "Error: proxy_close_stream called under ABI {:?}, it was added in 0.2.0",
+                        abi_version
                     );
-                    println!(
+                    trace!(
                         "[vm->host] proxy_close_stream(stream_type={stream_type}) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_close_stream(...) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_continue_request" => {
-            Some(Func::wrap(store, |_caller: Caller<'_, ()>| -> i32 {
-                // Default Function:
-                // Expectation:
-                assert_eq!(
-                    HOST.lock().unwrap().staged.get_abi_version(),
-                    AbiVersion::ProxyAbiVersion0_1_0
-                );
-                println!(
-                    "[vm->host] proxy_continue_request() status: {:?}",
-                    get_status()
-                );
-                println!(
-                    "[vm<-host] proxy_continue_request() return: {:?}",
-                    Status::Ok
-                );
-                assert_ne!(get_status(), ExpectStatus::Failed);
-                set_status(ExpectStatus::Unexpected);
-                return Status::Ok as i32;
-            }))
+            Some(
+                linker.func_wrap(module, name, |caller: Caller<'_, HostState>| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_continue_request");
+                    if let Some(status) = get_forced_status(&state, "proxy_continue_request") {
+                        return status;
+                    }
+                    // Default Function:
+                    // Expectation:
+                    assert_eq!(
+                        state.host.lock().unwrap().staged.get_abi_version(),
+                        AbiVersion::ProxyAbiVersion0_1_0
+                    );
+                    state.host.lock().unwrap().staged.continue_stream(0);
+                    trace!(
+                        "[vm->host] proxy_continue_request() status: {:?}",
+                        state.get_status()
+                    );
+                    trace!(
+                        "[vm<-host] proxy_continue_request() return: {:?}",
+                        Status::Ok
+                    );
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
+                    return Status::Ok as i32;
+                }),
+            )
         }
 
         "proxy_continue_response" => {
-            Some(Func::wrap(store, |_caller: Caller<'_, ()>| -> i32 {
-                // Default Function:
-                // Expectation:
-                assert_eq!(
-                    HOST.lock().unwrap().staged.get_abi_version(),
-                    AbiVersion::ProxyAbiVersion0_1_0
-                );
-                println!(
-                    "[vm->host] proxy_continue_response() status: {:?}",
-                    get_status()
-                );
-                println!(
-                    "[vm<-host] proxy_continue_response() return: {:?}",
-                    Status::Ok
-                );
-                assert_ne!(get_status(), ExpectStatus::Failed);
-                set_status(ExpectStatus::Unexpected);
-                return Status::Ok as i32;
-            }))
+            Some(
+                linker.func_wrap(module, name, |caller: Caller<'_, HostState>| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_continue_response");
+                    if let Some(status) = get_forced_status(&state, "proxy_continue_response") {
+                        return status;
+                    }
+                    // Default Function:
+                    // Expectation:
+                    assert_eq!(
+                        state.host.lock().unwrap().staged.get_abi_version(),
+                        AbiVersion::ProxyAbiVersion0_1_0
+                    );
+                    state.host.lock().unwrap().staged.continue_stream(1);
+                    trace!(
+                        "[vm->host] proxy_continue_response() status: {:?}",
+                        state.get_status()
+                    );
+                    trace!(
+                        "[vm<-host] proxy_continue_response() return: {:?}",
+                        Status::Ok
+                    );
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
+                    return Status::Ok as i32;
+                }),
+            )
         }
 
         "proxy_send_local_response" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
                  status_code: i32,
                  _status_code_details_data: i32,
                  _status_code_details_size: i32,
@@ -437,15 +784,20 @@ fn get_hostfunc(
                  headers_size: i32,
                  grpc_status: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_send_local_response");
+                    if let Some(status) = get_forced_status(&state, "proxy_send_local_response") {
+                        return status;
+                    }
                     // Default Function: receive and display local response
                     // Expectation: assert equal the received local response with the expected one
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!(
+                            error!(
                                 "Error: proxy_send_local_response cannot get export \"memory\""
                             );
-                            println!(
+                            trace!(
                                 "[vm<-host] proxy_send_local_response(...) return: {:?}",
                                 Status::InternalFailure
                             );
@@ -454,14 +806,14 @@ fn get_hostfunc(
                     };
 
                     unsafe {
+                        let body_data_ptr = mem
+                            .data(&caller)
+                            .get(body_data as u32 as usize..)
+                            .and_then(|arr| arr.get(..body_size as u32 as usize))
+                            .unwrap_or_default();
                         let mut string_body: Option<&str> = None;
                         if body_size > 0 {
-                            let body_data_ptr = mem
-                                .data(&caller)
-                                .get(body_data as u32 as usize..)
-                                .and_then(|arr| arr.get(..body_size as u32 as usize));
-                            string_body = body_data_ptr
-                                .map(|string_msg| std::str::from_utf8(string_msg).unwrap());
+                            string_body = std::str::from_utf8(body_data_ptr).ok();
                         }
 
                         let header_data_ptr = mem.data(&caller).get_unchecked(
@@ -469,190 +821,548 @@ fn get_hostfunc(
                                 ..headers_data as u32 as usize + headers_size as u32 as usize,
                         );
                         let deserialized_header = serial_utils::deserialize_map(header_data_ptr);
+                        state.host.lock().unwrap().staged.send_local_response(
+                            status_code,
+                            body_data_ptr,
+                            serial_utils::deserialize_map_bytes(header_data_ptr),
+                        );
 
-                        EXPECT
-                            .lock()
+                        state.expect.lock()
                             .unwrap()
                             .staged
                             .get_expect_send_local_response(
                                 status_code,
-                                string_body,
-                                &header_data_ptr,
+                                body_data_ptr,
+                                &deserialized_header,
                                 grpc_status,
                             );
-
-                        println!("[vm->host] proxy_send_local_response(status_code={}, status_code_details_data, status_code_details_size", status_code);
-                        println!(
+                        state.record(TracedCall::SendLocalResponse {
+                            status_code,
+                            body: body_data_ptr.to_vec(),
+                            headers: deserialized_header.clone(),
+                            grpc_status,
+                        });
+
+                        trace!("[vm->host] proxy_send_local_response(status_code={}, status_code_details_data, status_code_details_size", status_code);
+                        trace!(
                             "                                     body_data={}, body_size={}",
                             string_body.unwrap_or("None"),
                             body_size
                         );
-                        println!("                                     headers_data={:?}, headers_size={}) status: {:?}", deserialized_header, headers_size, get_status());
+                        trace!("                                     headers_data={:?}, headers_size={}) status: {:?}", deserialized_header, headers_size, state.get_status());
                     }
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_send_local_response(...) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_clear_route_cache" => {
-            Some(Func::wrap(store, |_caller: Caller<'_, ()>| -> i32 {
-                // Default Function:
-                // Expectation:
-                println!(
-                    "[vm->host] proxy_clear_route_cache() status: {:?}",
-                    get_status()
-                );
-                println!(
-                    "[vm<-host] proxy_clear_route_cache() return: {:?}",
-                    Status::InternalFailure
-                );
-                return Status::InternalFailure as i32;
-            }))
+            Some(
+                linker.func_wrap(module, name, |caller: Caller<'_, HostState>| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_clear_route_cache");
+                    if let Some(status) = get_forced_status(&state, "proxy_clear_route_cache") {
+                        return status;
+                    }
+                    // Default Function:
+                    // Expectation:
+                    trace!(
+                        "[vm->host] proxy_clear_route_cache() status: {:?}",
+                        state.get_status()
+                    );
+                    trace!(
+                        "[vm<-host] proxy_clear_route_cache() return: {:?}",
+                        Status::InternalFailure
+                    );
+                    return Status::InternalFailure as i32;
+                }),
+            )
         }
 
         /* ---------------------------------- SharedData ---------------------------------- */
         "proxy_get_shared_data" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
-                 _key_data: i32,
-                 _key_size: i32,
-                 _return_value_data: i32,
-                 _return_value_size: i32,
-                 _return_cas: i32|
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
+                 key_data: i32,
+                 key_size: i32,
+                 return_value_data: i32,
+                 return_value_size: i32,
+                 return_cas: i32|
                  -> i32 {
-                    // Default Function:
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_shared_data");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_shared_data") {
+                        return status;
+                    }
+                    // Default Function: look up the key in the host's shared data store
                     // Expectation:
-                    println!("[vm->host] proxy_get_shared_data(key_data, key_size) -> (...) status: {:?}", get_status());
-                    println!("[vm<-host] proxy_get_shared_data(...) -> (return_value_data, return_value_size, return_cas) return: {:?}", Status::InternalFailure);
-                    return Status::InternalFailure as i32;
+                    let mem = match caller.get_export("memory") {
+                        Some(Extern::Memory(mem)) => mem,
+                        _ => {
+                            error!("Error: proxy_get_shared_data cannot get export \"memory\"");
+                            trace!("[vm<-host] proxy_get_shared_data(...) -> (return_value_data, return_value_size, return_cas) return: {:?}", Status::InternalFailure);
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    let malloc = match get_allocator(&mut caller) {
+                        Some(Extern::Func(func)) => func,
+                        _ => {
+                            error!("Error: proxy_get_shared_data cannot get export \"malloc\"");
+                            trace!("[vm<-host] proxy_get_shared_data(...) -> (return_value_data, return_value_size, return_cas) return: {:?}", Status::InternalFailure);
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    let key = mem
+                        .data(&caller)
+                        .get(key_data as u32 as usize..)
+                        .and_then(|arr| arr.get(..key_size as u32 as usize))
+                        .map(|string_msg| String::from_utf8_lossy(string_msg).to_string())
+                        .unwrap();
+
+                    let (value, cas) = match state.host.lock().unwrap().staged.get_shared_data(&key) {
+                        Some(shared_data) => shared_data,
+                        None => {
+                            trace!(
+                                "[vm->host] proxy_get_shared_data(key={:?}) -> (...) status: {:?}",
+                                key,
+                                state.get_status()
+                            );
+                            trace!("[vm<-host] proxy_get_shared_data(...) -> (return_value_data, return_value_size, return_cas) return: {:?}", Status::NotFound);
+                            return Status::NotFound as i32;
+                        }
+                    };
+
+                    unsafe {
+                        // allocate memory and store the value
+                        let value_data_add = match allocate(&mut caller, &malloc, &mem, value.len()) {
+                            Some(address) => address,
+                            None => {
+                                trace!("[vm<-host] proxy_get_shared_data(...) return: {:?}", Status::InvalidMemoryAccess);
+                                return Status::InvalidMemoryAccess as i32;
+                            }
+                        };
+
+                        let value_data_ptr = mem
+                            .data_mut(&mut caller)
+                            .get_unchecked_mut(value_data_add..value_data_add + value.len());
+                        value_data_ptr.copy_from_slice(&value);
+
+                        let return_value_data_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
+                            return_value_data as u32 as usize
+                                ..return_value_data as u32 as usize + 4,
+                        );
+                        return_value_data_ptr
+                            .copy_from_slice(&(value_data_add as u32).to_le_bytes());
+                        let return_value_size_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
+                            return_value_size as u32 as usize
+                                ..return_value_size as u32 as usize + 4,
+                        );
+                        return_value_size_ptr.copy_from_slice(&(value.len() as u32).to_le_bytes());
+                        let return_cas_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
+                            return_cas as u32 as usize..return_cas as u32 as usize + 4,
+                        );
+                        return_cas_ptr.copy_from_slice(&cas.to_le_bytes());
+                    }
+
+                    trace!(
+                        "[vm->host] proxy_get_shared_data(key={:?}) -> (...) status: {:?}",
+                        key,
+                        state.get_status()
+                    );
+                    trace!("[vm<-host] proxy_get_shared_data(...) -> (return_value_data={:?}, return_value_size={}, return_cas={}) return: {:?}", String::from_utf8_lossy(&value), value.len(), cas, Status::Ok);
+                    return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_set_shared_data" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
-                 _key_data: i32,
-                 _key_size: i32,
-                 _value_data: i32,
-                 _value_size: i32,
-                 _cas: i32|
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
+                 key_data: i32,
+                 key_size: i32,
+                 value_data: i32,
+                 value_size: i32,
+                 cas: i32|
                  -> i32 {
-                    // Default Function:
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_set_shared_data");
+                    if let Some(status) = get_forced_status(&state, "proxy_set_shared_data") {
+                        return status;
+                    }
+                    // Default Function: store the value in the host's shared data store
                     // Expectation:
-                    println!("[vm->host] proxy_set_shared_data(key_data, key_size, value_data, value_size, cas) status: {:?}", get_status());
-                    println!(
-                        "[vm<-host] proxy_set_shared_data(...) return: {:?}",
-                        Status::InternalFailure
-                    );
-                    return Status::InternalFailure as i32;
+                    let mem = match caller.get_export("memory") {
+                        Some(Extern::Memory(mem)) => mem,
+                        _ => {
+                            error!("Error: proxy_set_shared_data cannot get export \"memory\"");
+                            trace!(
+                                "[vm<-host] proxy_set_shared_data(...) return: {:?}",
+                                Status::InternalFailure
+                            );
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    let key = mem
+                        .data(&caller)
+                        .get(key_data as u32 as usize..)
+                        .and_then(|arr| arr.get(..key_size as u32 as usize))
+                        .map(|string_msg| String::from_utf8_lossy(string_msg).to_string())
+                        .unwrap();
+                    let value = mem
+                        .data(&caller)
+                        .get(value_data as u32 as usize..)
+                        .and_then(|arr| arr.get(..value_size as u32 as usize))
+                        .map(|value| value.to_vec())
+                        .unwrap_or_default();
+
+                    trace!(
+                        "[vm->host] proxy_set_shared_data(key={:?}, value={:?}, cas={}) status: {:?}",
+                        key,
+                        String::from_utf8_lossy(&value),
+                        cas,
+                        state.get_status()
+                    );
+                    let status = state.host.lock()
+                        .unwrap()
+                        .staged
+                        .set_shared_data(&key, value, cas as u32);
+                    trace!("[vm<-host] proxy_set_shared_data(...) return: {:?}", status);
+                    status as i32
                 },
             ))
         }
 
         /* ---------------------------------- SharedQueue ---------------------------------- */
         "proxy_register_shared_queue" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
-                 _name_data: i32,
-                 _name_size: i32,
-                 _return_id: i32|
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
+                 name_data: i32,
+                 name_size: i32,
+                 return_id: i32|
                  -> i32 {
-                    // Default Function:
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_register_shared_queue");
+                    if let Some(status) = get_forced_status(&state, "proxy_register_shared_queue") {
+                        return status;
+                    }
+                    // Default Function: register the queue on the shared queue bus for this VM
                     // Expectation:
-                    println!("[vm->host] proxy_register_shared_queue(name_data, name_size) -> (...) status: {:?}", get_status());
-                    println!(
-                        "[vm<-host] proxy_register_shared_queue(...) -> (return_id) return: {:?}",
-                        Status::InternalFailure
+                    let mem = match caller.get_export("memory") {
+                        Some(Extern::Memory(mem)) => mem,
+                        _ => {
+                            error!(
+                                "Error: proxy_register_shared_queue cannot get export \"memory\""
+                            );
+                            trace!(
+                                "[vm<-host] proxy_register_shared_queue(...) -> (return_id) return: {:?}",
+                                Status::InternalFailure
+                            );
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    let name = mem
+                        .data(&caller)
+                        .get(name_data as u32 as usize..)
+                        .and_then(|arr| arr.get(..name_size as u32 as usize))
+                        .map(|string_msg| String::from_utf8_lossy(string_msg).to_string())
+                        .unwrap();
+
+                    let (vm_id, context_id) = {
+                        let mut host = state.host.lock().unwrap();
+                        (
+                            host.staged.get_vm_id().to_string(),
+                            host.staged.get_effective_context(),
+                        )
+                    };
+                    let queue_id = state.queues.lock().unwrap().register(&vm_id, &name, context_id);
+
+                    unsafe {
+                        let return_id_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
+                            return_id as u32 as usize..return_id as u32 as usize + 4,
+                        );
+                        return_id_ptr.copy_from_slice(&queue_id.to_le_bytes());
+                    }
+
+                    trace!(
+                        "[vm->host] proxy_register_shared_queue(name={:?}) -> (...) status: {:?}",
+                        name,
+                        state.get_status()
                     );
-                    return Status::InternalFailure as i32;
+                    trace!(
+                        "[vm<-host] proxy_register_shared_queue(...) -> (return_id={}) return: {:?}",
+                        queue_id,
+                        Status::Ok
+                    );
+                    return Status::Ok as i32;
                 },
             ))
         }
 
-        "proxy_resolve_shared_queue" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
-                 _vm_id_data: i32,
-                 _vm_id_size: i32,
-                 _name_data: i32,
-                 _name_size: i32,
-                 _return_id: i32|
-                 -> i32 {
-                    // Default Function:
-                    // Expectation:
-                    println!("[vm->host] proxy_resolve_shared_queue(vm_id_data, vm_id_size, name_data, name_size) -> (...) status: {:?}", get_status());
-                    println!(
-                        "[vm<-host] proxy_resolve_shared_queue(...) -> (return_id) return: {:?}",
-                        Status::InternalFailure
+        "proxy_resolve_shared_queue" => {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
+                 vm_id_data: i32,
+                 vm_id_size: i32,
+                 name_data: i32,
+                 name_size: i32,
+                 return_id: i32|
+                 -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_resolve_shared_queue");
+                    if let Some(status) = get_forced_status(&state, "proxy_resolve_shared_queue") {
+                        return status;
+                    }
+                    // Default Function: look up a queue registered by any VM on the shared queue bus
+                    // Expectation:
+                    let mem = match caller.get_export("memory") {
+                        Some(Extern::Memory(mem)) => mem,
+                        _ => {
+                            error!(
+                                "Error: proxy_resolve_shared_queue cannot get export \"memory\""
+                            );
+                            trace!(
+                                "[vm<-host] proxy_resolve_shared_queue(...) -> (return_id) return: {:?}",
+                                Status::InternalFailure
+                            );
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    let vm_id = mem
+                        .data(&caller)
+                        .get(vm_id_data as u32 as usize..)
+                        .and_then(|arr| arr.get(..vm_id_size as u32 as usize))
+                        .map(|string_msg| String::from_utf8_lossy(string_msg).to_string())
+                        .unwrap();
+                    let name = mem
+                        .data(&caller)
+                        .get(name_data as u32 as usize..)
+                        .and_then(|arr| arr.get(..name_size as u32 as usize))
+                        .map(|string_msg| String::from_utf8_lossy(string_msg).to_string())
+                        .unwrap();
+
+                    trace!(
+                        "[vm->host] proxy_resolve_shared_queue(vm_id={:?}, name={:?}) -> (...) status: {:?}",
+                        vm_id,
+                        name,
+                        state.get_status()
+                    );
+                    let queue_id = match state.queues.lock().unwrap().resolve(&vm_id, &name) {
+                        Some(queue_id) => queue_id,
+                        None => {
+                            trace!(
+                                "[vm<-host] proxy_resolve_shared_queue(...) -> (return_id) return: {:?}",
+                                Status::NotFound
+                            );
+                            return Status::NotFound as i32;
+                        }
+                    };
+
+                    unsafe {
+                        let return_id_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
+                            return_id as u32 as usize..return_id as u32 as usize + 4,
+                        );
+                        return_id_ptr.copy_from_slice(&queue_id.to_le_bytes());
+                    }
+
+                    trace!(
+                        "[vm<-host] proxy_resolve_shared_queue(...) -> (return_id={}) return: {:?}",
+                        queue_id,
+                        Status::Ok
                     );
-                    return Status::InternalFailure as i32;
+                    return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_dequeue_shared_queue" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
-                 _queue_id: i32,
-                 _payload_data: i32,
-                 _payload_size: i32|
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
+                 queue_id: i32,
+                 payload_data: i32,
+                 payload_size: i32|
                  -> i32 {
-                    // Default Function:
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_dequeue_shared_queue");
+                    if let Some(status) = get_forced_status(&state, "proxy_dequeue_shared_queue") {
+                        return status;
+                    }
+                    // Default Function: pop the oldest message off the shared queue bus
                     // Expectation:
-                    println!("[vm->host] proxy_dequeue_shared_queue(queue_id, payload_data, payload_size) status: {:?}", get_status());
-                    println!(
-                        "[vm<-host] proxy_dequeue_shared_queue(...) return: {:?}",
-                        Status::InternalFailure
+                    let mem = match caller.get_export("memory") {
+                        Some(Extern::Memory(mem)) => mem,
+                        _ => {
+                            error!(
+                                "Error: proxy_dequeue_shared_queue cannot get export \"memory\""
+                            );
+                            trace!(
+                                "[vm<-host] proxy_dequeue_shared_queue(...) return: {:?}",
+                                Status::InternalFailure
+                            );
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    let malloc = match get_allocator(&mut caller) {
+                        Some(Extern::Func(func)) => func,
+                        _ => {
+                            error!(
+                                "Error: proxy_dequeue_shared_queue cannot get export \"malloc\""
+                            );
+                            trace!(
+                                "[vm<-host] proxy_dequeue_shared_queue(...) return: {:?}",
+                                Status::InternalFailure
+                            );
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    trace!(
+                        "[vm->host] proxy_dequeue_shared_queue(queue_id={}) status: {:?}",
+                        queue_id,
+                        state.get_status()
+                    );
+                    let payload = match state.queues.lock().unwrap().dequeue(queue_id as u32) {
+                        Ok(payload) => payload,
+                        Err(status) => {
+                            trace!(
+                                "[vm<-host] proxy_dequeue_shared_queue(...) return: {:?}",
+                                status
+                            );
+                            return status as i32;
+                        }
+                    };
+
+                    unsafe {
+                        // allocate memory and store the payload
+                        let payload_data_add = match allocate(&mut caller, &malloc, &mem, payload.len()) {
+                            Some(address) => address,
+                            None => {
+                                trace!("[vm<-host] proxy_dequeue_shared_queue(...) return: {:?}", Status::InvalidMemoryAccess);
+                                return Status::InvalidMemoryAccess as i32;
+                            }
+                        };
+
+                        let payload_data_ptr = mem
+                            .data_mut(&mut caller)
+                            .get_unchecked_mut(payload_data_add..payload_data_add + payload.len());
+                        payload_data_ptr.copy_from_slice(&payload);
+
+                        let return_payload_data_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
+                            payload_data as u32 as usize..payload_data as u32 as usize + 4,
+                        );
+                        return_payload_data_ptr
+                            .copy_from_slice(&(payload_data_add as u32).to_le_bytes());
+                        let return_payload_size_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
+                            payload_size as u32 as usize..payload_size as u32 as usize + 4,
+                        );
+                        return_payload_size_ptr
+                            .copy_from_slice(&(payload.len() as u32).to_le_bytes());
+                    }
+
+                    trace!(
+                        "[vm<-host] proxy_dequeue_shared_queue(...) -> (payload_data={:?}, payload_size={}) return: {:?}",
+                        String::from_utf8_lossy(&payload),
+                        payload.len(),
+                        Status::Ok
                     );
-                    return Status::InternalFailure as i32;
+                    return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_enqueue_shared_queue" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
-                 _queue_id: i32,
-                 _value_data: i32,
-                 _value_size: i32|
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
+                 queue_id: i32,
+                 value_data: i32,
+                 value_size: i32|
                  -> i32 {
-                    // Default Function:
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_enqueue_shared_queue");
+                    if let Some(status) = get_forced_status(&state, "proxy_enqueue_shared_queue") {
+                        return status;
+                    }
+                    // Default Function: push the message onto the shared queue bus, the registering
+                    // context is notified through proxy_on_queue_ready
                     // Expectation:
-                    println!("[vm->host] proxy_enqueue_shared_queue(queue_id, value_data, value_size) status: {:?}", get_status());
-                    println!(
+                    let mem = match caller.get_export("memory") {
+                        Some(Extern::Memory(mem)) => mem,
+                        _ => {
+                            error!(
+                                "Error: proxy_enqueue_shared_queue cannot get export \"memory\""
+                            );
+                            trace!(
+                                "[vm<-host] proxy_enqueue_shared_queue(...) return: {:?}",
+                                Status::InternalFailure
+                            );
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    let value = mem
+                        .data(&caller)
+                        .get(value_data as u32 as usize..)
+                        .and_then(|arr| arr.get(..value_size as u32 as usize))
+                        .map(|value| value.to_vec())
+                        .unwrap_or_default();
+
+                    trace!(
+                        "[vm->host] proxy_enqueue_shared_queue(queue_id={}, value={:?}) status: {:?}",
+                        queue_id,
+                        String::from_utf8_lossy(&value),
+                        state.get_status()
+                    );
+                    let status = state.queues.lock().unwrap().enqueue(queue_id as u32, value);
+                    trace!(
                         "[vm<-host] proxy_enqueue_shared_queue(...) return: {:?}",
-                        Status::InternalFailure
+                        status
                     );
-                    return Status::InternalFailure as i32;
+                    status as i32
                 },
             ))
         }
 
         /* ---------------------------------- Headers/Trailers/Metadata Maps ---------------------------------- */
         "proxy_get_header_map_size" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>, _map_type: i32, _map_size: i32| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>, _map_type: i32, _map_size: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_header_map_size");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_header_map_size") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    println!(
+                    trace!(
                         "[vm->host] proxy_get_header_map_size() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_get_header_map_size() -> (..) return: {:?}",
                         Status::InternalFailure
                     );
@@ -662,22 +1372,34 @@ fn get_hostfunc(
         }
 
         "proxy_get_header_map_pairs" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
                  map_type: i32,
                  return_map_data: i32,
                  return_map_size: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_header_map_pairs");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_header_map_pairs") {
+                        return status;
+                    }
+                    state
+                        .host
+                        .lock()
+                        .unwrap()
+                        .staged
+                        .check_header_map_access("proxy_get_header_map_pairs", map_type, false);
                     // Default Function: respond with default header map pairs depending on map_type
                     // Expectation: respond with set expected header map pairs
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!(
+                            error!(
                                 "Error: proxy_get_header_map_pairs cannot get export \"memory\""
                             );
-                            println!("[vm<-host] proxy_get_header_map_pairs(...) -> (return_map_data, return_map_size) return: {:?}", Status::InternalFailure);
+                            trace!("[vm<-host] proxy_get_header_map_pairs(...) -> (return_map_data, return_map_size) return: {:?}", Status::InternalFailure);
                             return Status::InternalFailure as i32;
                         }
                     };
@@ -685,35 +1407,34 @@ fn get_hostfunc(
                     let malloc = match get_allocator(&mut caller) {
                         Some(Extern::Func(func)) => func,
                         _ => {
-                            println!(
+                            error!(
                                 "Error: proxy_get_header_map_pairs cannot get export \"malloc\""
                             );
-                            println!("[vm<-host] proxy_get_header_map_pairs(...) -> (return_map_data, return_map_size) return: {:?}", Status::InternalFailure);
+                            trace!("[vm<-host] proxy_get_header_map_pairs(...) -> (return_map_data, return_map_size) return: {:?}", Status::InternalFailure);
                             return Status::InternalFailure as i32;
                         }
                     };
 
-                    let serial_map = match EXPECT
-                        .lock()
+                    let serial_map = match state.expect.lock()
                         .unwrap()
                         .staged
                         .get_expect_get_header_map_pairs(map_type)
                     {
                         Some(header_map_pairs) => header_map_pairs,
-                        None => HOST.lock().unwrap().staged.get_header_map_pairs(map_type),
+                        None => state.host.lock().unwrap().staged.get_header_map_pairs(map_type),
                     };
+                    state.record(TracedCall::GetHeaderMapPairs {
+                        map_type,
+                        pairs: serial_utils::deserialize_map(&serial_map),
+                    });
                     let serial_map_size = serial_map.len();
 
-                    let map_data_add = {
-                        let mut result = [Val::I32(0)];
-                        malloc
-                            .call(
-                                &mut caller,
-                                &[Val::I32(serial_map_size as i32)],
-                                &mut result,
-                            )
-                            .unwrap();
-                        result[0].i32().unwrap() as u32 as usize
+                    let map_data_add = match allocate(&mut caller, &malloc, &mem, serial_map_size) {
+                        Some(address) => address,
+                        None => {
+                            trace!("[vm<-host] proxy_get_header_map_pairs(...) return: {:?}", Status::InvalidMemoryAccess);
+                            return Status::InvalidMemoryAccess as i32;
+                        }
                     };
 
                     unsafe {
@@ -731,33 +1452,45 @@ fn get_hostfunc(
                         return_map_size_ptr
                             .copy_from_slice(&(serial_map_size as u32).to_le_bytes());
                     }
-                    println!(
+                    trace!(
                         "[vm->host] proxy_get_header_map_pairs(map_type={}) -> (...) status: {:?}",
                         map_type,
-                        get_status()
+                        state.get_status()
                     );
-                    println!("[vm<-host] proxy_get_header_map_pairs(...) -> (return_map_data, return_map_size) return: {:?}", Status::Ok);
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    trace!("[vm<-host] proxy_get_header_map_pairs(...) -> (return_map_data, return_map_size) return: {:?}", Status::Ok);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_set_header_map_pairs" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>, map_type: i32, map_data: i32, map_size: i32| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>, map_type: i32, map_data: i32, map_size: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_set_header_map_pairs");
+                    if let Some(status) = get_forced_status(&state, "proxy_set_header_map_pairs") {
+                        return status;
+                    }
+                    state
+                        .host
+                        .lock()
+                        .unwrap()
+                        .staged
+                        .check_header_map_access("proxy_set_header_map_pairs", map_type, true);
                     // Default Function: Reads and sets the according header map as the simulator default for the given map type
                     // Expectation: asserts that the received header map and header map type corresponds to the expected one
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!(
+                            trace!(
                                 "[vm<-host] proxy_set_header_map_pairs(...) return: {:?}",
                                 Status::InternalFailure
                             );
-                            println!(
+                            error!(
                                 "Error: proxy_set_header_map_pairs cannot get export \"memory\""
                             );
                             return Status::InternalFailure as i32;
@@ -769,53 +1502,68 @@ fn get_hostfunc(
                             map_data as u32 as usize..(map_data + map_size) as u32 as usize,
                         );
 
-                        HOST.lock().unwrap().staged.set_header_map_pairs(
+                        state.host.lock().unwrap().staged.set_header_map_data(
                             map_type,
-                            serial_utils::deserialize_map(header_map_ptr)
-                                .iter()
-                                .map(|(k, v)| (k as &str, v as &str))
-                                .collect(),
+                            serial_utils::deserialize_map_bytes(header_map_ptr),
                         );
-                        EXPECT
-                            .lock()
+                        state.expect.lock()
                             .unwrap()
                             .staged
-                            .get_expect_set_header_map_pairs(map_type, header_map_ptr);
+                            .get_expect_set_header_map_pairs(
+                                map_type,
+                                &serial_utils::deserialize_map(header_map_ptr),
+                            );
+                        state.record(TracedCall::SetHeaderMapPairs {
+                            map_type,
+                            pairs: serial_utils::deserialize_map(header_map_ptr),
+                        });
                     }
-                    println!("[vm->host] proxy_set_header_map_pairs(map_type={}, map_data, map_size) status: {:?}",
-                        map_type, get_status()
+                    trace!("[vm->host] proxy_set_header_map_pairs(map_type={}, map_data, map_size) status: {:?}",
+                        map_type, state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_set_header_map_pairs(...) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_get_header_map_value" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
                  map_type: i32,
                  key_data: i32,
                  key_size: i32,
                  return_value_data: i32,
                  return_value_size: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_header_map_value");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_header_map_value") {
+                        return status;
+                    }
+                    state
+                        .host
+                        .lock()
+                        .unwrap()
+                        .staged
+                        .check_header_map_access("proxy_get_header_map_value", map_type, false);
                     // Default Function: respond with a default header map value corresponding to map_type (if exists)
                     // Expectation: respond with set expected header map value for the given key and map_type
                     // Panics if there is no header map value in expectation or host simulator for the provided map_type and key and one was expected
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!(
+                            error!(
                                 "Error: proxy_get_header_map_value cannot get export \"memory\""
                             );
-                            println!("[vm<-host] proxy_get_header_map_value(...) -> (return_value_data, return_value_size) return: {:?}", Status::InternalFailure);
+                            trace!("[vm<-host] proxy_get_header_map_value(...) -> (return_value_data, return_value_size) return: {:?}", Status::InternalFailure);
                             return Status::InternalFailure as i32;
                         }
                     };
@@ -823,10 +1571,10 @@ fn get_hostfunc(
                     let malloc = match get_allocator(&mut caller) {
                         Some(Extern::Func(func)) => func,
                         _ => {
-                            println!(
+                            error!(
                                 "Error: proxy_get_header_map_value cannot get export \"malloc\""
                             );
-                            println!("[vm<-host] proxy_get_header_map_value(...) -> (return_value_data, return_value_size) return: {:?}", Status::InternalFailure);
+                            trace!("[vm<-host] proxy_get_header_map_value(...) -> (return_value_data, return_value_size) return: {:?}", Status::InternalFailure);
                             return Status::InternalFailure as i32;
                         }
                     };
@@ -841,38 +1589,38 @@ fn get_hostfunc(
                                 .map(|string_msg| std::str::from_utf8(string_msg).unwrap())
                                 .unwrap();
 
-                            let maybe_string_value = EXPECT
-                                .lock()
+                            let maybe_string_value = state.expect.lock()
                                 .unwrap()
                                 .staged
                                 .get_expect_get_header_map_value(map_type, string_key)
                                 .or_else(|| {
-                                    HOST.lock()
+                                    state.host.lock()
                                         .unwrap()
                                         .staged
                                         .get_header_map_value(map_type, &string_key)
                                 });
                             (string_key.to_string(), maybe_string_value)
                         };
+                        state.record(TracedCall::GetHeaderMapValue {
+                            map_type,
+                            key: string_key.clone(),
+                            value: maybe_string_value.clone(),
+                        });
 
                         match maybe_string_value {
                             Some(string_value) => {
-                                let value_data_add = {
-                                    let mut result = [Val::I32(0)];
-                                    malloc
-                                        .call(
-                                            &mut caller,
-                                            &[Val::I32(string_value.len() as i32)],
-                                            &mut result,
-                                        )
-                                        .unwrap();
-                                    result[0].i32().unwrap() as u32 as usize
+                                let value_data_add = match allocate(&mut caller, &malloc, &mem, string_value.len()) {
+                                    Some(address) => address,
+                                    None => {
+                                        trace!("[vm<-host] proxy_get_header_map_value(...) return: {:?}", Status::InvalidMemoryAccess);
+                                        return Status::InvalidMemoryAccess as i32;
+                                    }
                                 };
 
                                 let value_data_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
                                     value_data_add..value_data_add + string_value.len(),
                                 );
-                                value_data_ptr.copy_from_slice((&string_value).as_bytes());
+                                value_data_ptr.copy_from_slice(&string_value);
 
                                 let return_value_data_ptr =
                                     mem.data_mut(&mut caller).get_unchecked_mut(
@@ -890,40 +1638,54 @@ fn get_hostfunc(
                                 return_value_size_ptr
                                     .copy_from_slice(&(string_value.len() as u32).to_le_bytes());
 
-                                println!("[vm->host] proxy_get_header_map_value(map_type={}, key_data={}, key_size={}) -> (...) status: {:?}", map_type, string_key, key_size, get_status());
-                                println!("[vm<-host] proxy_get_header_map_value(...) -> (return_value_data={}, return_value_size={}) return: {:?}", string_value, string_value.len(), Status::Ok);
+                                trace!("[vm->host] proxy_get_header_map_value(map_type={}, key_data={}, key_size={}) -> (...) status: {:?}", map_type, string_key, key_size, state.get_status());
+                                trace!("[vm<-host] proxy_get_header_map_value(...) -> (return_value_data={}, return_value_size={}) return: {:?}", String::from_utf8_lossy(&string_value), string_value.len(), Status::Ok);
                             }
                             None => {
-                                let mut data_ptr =
-                                    mem.data_ptr(&mut caller).offset(return_value_data as isize);
-                                data_ptr = std::ptr::null_mut();
+                                trace!("[vm->host] proxy_get_header_map_value(map_type={}, key_data={}, key_size={}) -> (...) status: {:?}", map_type, string_key, key_size, state.get_status());
+                                trace!("[vm<-host] proxy_get_header_map_value(...) -> (return_value_data, return_value_size) return: {:?}", Status::NotFound);
+                                assert_ne!(state.get_status(), ExpectStatus::Failed);
+                                state.set_status(ExpectStatus::Unexpected);
+                                return Status::NotFound as i32;
                             }
                         }
                     }
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_replace_header_map_value" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
                  map_type: i32,
                  key_data: i32,
                  key_size: i32,
                  value_data: i32,
                  value_size: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_replace_header_map_value");
+                    if let Some(status) = get_forced_status(&state, "proxy_replace_header_map_value") {
+                        return status;
+                    }
+                    state
+                        .host
+                        .lock()
+                        .unwrap()
+                        .staged
+                        .check_header_map_access("proxy_replace_header_map_value", map_type, true);
                     // Default Function: replace the specified key-value pair in the default host environment if it exists
                     // Expectation: assert that the received key-value pair are as expected
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!("Error: proxy_replace_header_map_value cannot get export \"memory\"");
-                            println!(
+                            error!("Error: proxy_replace_header_map_value cannot get export \"memory\"");
+                            trace!(
                                 "[vm<-host] proxy_replace_header_map_value(...) return: {:?}",
                                 Status::InternalFailure
                             );
@@ -943,47 +1705,61 @@ fn get_hostfunc(
                         .data(&caller)
                         .get(value_data as u32 as usize..)
                         .and_then(|arr| arr.get(..value_size as u32 as usize));
-                    let string_value = value_data_ptr
-                        .map(|string_msg| std::str::from_utf8(string_msg).unwrap())
-                        .unwrap();
+                    let string_value = value_data_ptr.unwrap();
 
-                    EXPECT
-                        .lock()
+                    state.expect.lock()
                         .unwrap()
                         .staged
                         .get_expect_replace_header_map_value(map_type, string_key, string_value);
-                    HOST.lock().unwrap().staged.replace_header_map_value(
+                    state.record(TracedCall::ReplaceHeaderMapValue {
+                        map_type,
+                        key: string_key.to_string(),
+                        value: string_value.to_vec(),
+                    });
+                    state.host.lock().unwrap().staged.replace_header_map_value(
                         map_type,
                         string_key,
                         string_value,
                     );
-                    println!("[vm->host] proxy_replace_header_map_value(map_type={}, key_data={}, key_size={}, value_data={}, value_size={}) status: {:?}",
-                        map_type, string_key, string_key.len(), string_value, string_value.len(), get_status()
+                    trace!("[vm->host] proxy_replace_header_map_value(map_type={}, key_data={}, key_size={}, value_data={}, value_size={}) status: {:?}",
+                        map_type, string_key, string_key.len(), String::from_utf8_lossy(string_value), string_value.len(), state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_replace_header_map_value(...) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_remove_header_map_value" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>, map_type: i32, key_data: i32, key_size: i32| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>, map_type: i32, key_data: i32, key_size: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_remove_header_map_value");
+                    if let Some(status) = get_forced_status(&state, "proxy_remove_header_map_value") {
+                        return status;
+                    }
+                    state
+                        .host
+                        .lock()
+                        .unwrap()
+                        .staged
+                        .check_header_map_access("proxy_remove_header_map_value", map_type, true);
                     // Default Function: remove the specified key-value pair in the default host environment if it exists
                     // Expectation: assert that the received key is as expected
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!(
+                            error!(
                                 "Error: proxy_remove_header_map_value cannot get export \"memory\""
                             );
-                            println!(
+                            trace!(
                                 "[vm<-host] proxy_remove_header_map_value(...) return: {:?}",
                                 Status::InternalFailure
                             );
@@ -999,48 +1775,63 @@ fn get_hostfunc(
                         .map(|string_msg| std::str::from_utf8(string_msg).unwrap())
                         .unwrap();
 
-                    EXPECT
-                        .lock()
+                    state.expect.lock()
                         .unwrap()
                         .staged
                         .get_expect_remove_header_map_value(map_type, string_key);
-                    HOST.lock()
+                    state.record(TracedCall::RemoveHeaderMapValue {
+                        map_type,
+                        key: string_key.to_string(),
+                    });
+                    state.host.lock()
                         .unwrap()
                         .staged
                         .remove_header_map_value(map_type, string_key);
-                    println!("[vm->host] proxy_remove_header_map_value(map_type={}, key_data={}, key_size={}) status: {:?}",
-                        map_type, string_key, string_key.len(), get_status()
+                    trace!("[vm->host] proxy_remove_header_map_value(map_type={}, key_data={}, key_size={}) status: {:?}",
+                        map_type, string_key, string_key.len(), state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_remove_header_map_value(...) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_add_header_map_value" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
                  map_type: i32,
                  key_data: i32,
                  key_size: i32,
                  value_data: i32,
                  value_size: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_add_header_map_value");
+                    if let Some(status) = get_forced_status(&state, "proxy_add_header_map_value") {
+                        return status;
+                    }
+                    state
+                        .host
+                        .lock()
+                        .unwrap()
+                        .staged
+                        .check_header_map_access("proxy_add_header_map_value", map_type, true);
                     // Default Function: add the specified key-value pair in the default host environment if it exists
                     // Expectation: assert that the received key-value pair are as expected
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!(
+                            error!(
                                 "Error: proxy_add_header_map_value cannot get export \"memory\""
                             );
-                            println!(
+                            trace!(
                                 "[vm<-host] proxy_add_header_map_value(...) return: {:?}",
                                 Status::InternalFailure
                             );
@@ -1060,29 +1851,31 @@ fn get_hostfunc(
                         .data(&caller)
                         .get(value_data as u32 as usize..)
                         .and_then(|arr| arr.get(..value_size as u32 as usize));
-                    let string_value = value_data_ptr
-                        .map(|string_msg| std::str::from_utf8(string_msg).unwrap())
-                        .unwrap();
+                    let string_value = value_data_ptr.unwrap();
 
-                    EXPECT
-                        .lock()
+                    state.expect.lock()
                         .unwrap()
                         .staged
                         .get_expect_add_header_map_value(map_type, string_key, string_value);
-                    HOST.lock().unwrap().staged.add_header_map_value(
+                    state.record(TracedCall::AddHeaderMapValue {
+                        map_type,
+                        key: string_key.to_string(),
+                        value: string_value.to_vec(),
+                    });
+                    state.host.lock().unwrap().staged.add_header_map_value(
                         map_type,
                         string_key,
                         string_value,
                     );
-                    println!("[vm->host] proxy_add_header_map_value(map_type={}, key_data={}, key_size={}, value_data={}, value_size={}) status: {:?}",
-                        map_type, string_key, string_key.len(), string_value, string_value.len(), get_status()
+                    trace!("[vm->host] proxy_add_header_map_value(map_type={}, key_data={}, key_size={}, value_data={}, value_size={}) status: {:?}",
+                        map_type, string_key, string_key.len(), String::from_utf8_lossy(string_value), string_value.len(), state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_add_header_map_value(...) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
@@ -1090,20 +1883,26 @@ fn get_hostfunc(
 
         /* ---------------------------------- Buffer ---------------------------------- */
         "proxy_get_buffer_status" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>,
                  _buffer_type: i32,
                  _length_ptr: i32,
                  _flags_ptr: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_buffer_status");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_buffer_status") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    println!(
+                    trace!(
                         "[vm->host] proxy_get_buffer_status() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_get_buffer_status() -> (..) return: {:?}",
                         Status::InternalFailure
                     );
@@ -1113,22 +1912,28 @@ fn get_hostfunc(
         }
 
         "proxy_get_buffer_bytes" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
                  buffer_type: i32,
                  start: i32,
                  max_size: i32,
                  return_buffer_data: i32,
                  return_buffer_size: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_buffer_bytes");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_buffer_bytes") {
+                        return status;
+                    }
                     // Default Function: generate and return random buffer_bytes of length max_size - start
                     // Expectation: return buffer bytes set in expectation
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!("Error: proxy_get_buffer_bytes cannot get export \"memory\"");
-                            println!("[vm<-host] proxy_get_buffer_bytes(...) -> (return_buffer_data, return_buffer_size) return: {:?}", Status::InternalFailure);
+                            error!("Error: proxy_get_buffer_bytes cannot get export \"memory\"");
+                            trace!("[vm<-host] proxy_get_buffer_bytes(...) -> (return_buffer_data, return_buffer_size) return: {:?}", Status::InternalFailure);
                             return Status::InternalFailure as i32;
                         }
                     };
@@ -1136,14 +1941,13 @@ fn get_hostfunc(
                     let malloc = match get_allocator(&mut caller) {
                         Some(Extern::Func(func)) => func,
                         _ => {
-                            println!("Error: proxy_get_buffer_bytes cannot get export \"malloc\"");
-                            println!("[vm<-host] proxy_get_buffer_bytes(...) -> (return_buffer_data, return_buffer_size) return: {:?}", Status::InternalFailure);
+                            error!("Error: proxy_get_buffer_bytes cannot get export \"malloc\"");
+                            trace!("[vm<-host] proxy_get_buffer_bytes(...) -> (return_buffer_data, return_buffer_size) return: {:?}", Status::InternalFailure);
                             return Status::InternalFailure as i32;
                         }
                     };
 
-                    let response_body = match EXPECT
-                        .lock()
+                    let response_body = match state.expect.lock()
                         .unwrap()
                         .staged
                         .get_expect_get_buffer_bytes(buffer_type)
@@ -1155,31 +1959,50 @@ fn get_hostfunc(
                         None => {
                             let buffer_bytes: Bytes;
                             let host_buffer_bytes =
-                                HOST.lock().unwrap().staged.get_buffer_bytes(buffer_type);
-                            if host_buffer_bytes.len() == (max_size - start) as usize {
+                                state.host.lock().unwrap().staged.get_buffer_bytes(buffer_type);
+                            if host_buffer_bytes.is_empty() {
+                                state.record(TracedCall::GetBufferBytes {
+                                    buffer_type,
+                                    buffer_data: None,
+                                });
+                                trace!(
+                                    "[vm->host] proxy_get_buffer_bytes(buffer_type={}, start={}, max_size={}) -> (...) status: {:?}",
+                                    buffer_type, start, max_size, state.get_status()
+                                );
+                                trace!(
+                                    "[vm<-host] proxy_get_buffer_bytes(...) -> (return_buffer_data, return_buffer_size) return: {:?}", Status::NotFound
+                                );
+                                assert_ne!(state.get_status(), ExpectStatus::Failed);
+                                state.set_status(ExpectStatus::Unexpected);
+                                return Status::NotFound as i32;
+                            } else if host_buffer_bytes.len() == (max_size - start) as usize {
                                 buffer_bytes = host_buffer_bytes;
                             } else {
-                                buffer_bytes = serial_utils::generate_random_string(
-                                    (max_size - start) as usize,
-                                )
-                                .as_bytes()
-                                .to_vec();
+                                buffer_bytes = state
+                                    .host
+                                    .lock()
+                                    .unwrap()
+                                    .staged
+                                    .random_string((max_size - start) as usize)
+                                    .into_bytes();
                             }
                             buffer_bytes
                         }
                     };
+                    state.record(TracedCall::GetBufferBytes {
+                        buffer_type,
+                        buffer_data: Some(response_body.clone()),
+                    });
 
                     unsafe {
                         // allocate memory and store buffer bytes
-                        let mut result = [Val::I32(0)];
-                        malloc
-                            .call(
-                                &mut caller,
-                                &[Val::I32(response_body.len() as i32)],
-                                &mut result,
-                            )
-                            .unwrap();
-                        let buffer_data_add = result[0].i32().unwrap() as u32 as usize;
+                        let buffer_data_add = match allocate(&mut caller, &malloc, &mem, response_body.len()) {
+                            Some(address) => address,
+                            None => {
+                                trace!("[vm<-host] proxy_get_buffer_bytes(...) return: {:?}", Status::InvalidMemoryAccess);
+                                return Status::InvalidMemoryAccess as i32;
+                            }
+                        };
 
                         let buffer_data_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
                             buffer_data_add..buffer_data_add + response_body.len(),
@@ -1199,37 +2022,49 @@ fn get_hostfunc(
                         return_buffer_data_ptr
                             .copy_from_slice(&(buffer_data_add as u32).to_le_bytes());
                     }
-                    println!(
+                    trace!(
                         "[vm->host] proxy_get_buffer_bytes(buffer_type={}, start={}, max_size={}) -> (...) status: {:?}",
-                        buffer_type, start, max_size, get_status()
+                        buffer_type, start, max_size, state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_get_buffer_bytes(...) -> (return_buffer_data, return_buffer_size) return: {:?}", Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_set_buffer_bytes" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
                  buffer_type: i32,
                  start: i32,
                  size: i32,
                  buffer_data: i32,
                  buffer_size: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_set_buffer_bytes");
+                    if let Some(status) = get_forced_status(&state, "proxy_set_buffer_bytes") {
+                        return status;
+                    }
+                    state
+                        .host
+                        .lock()
+                        .unwrap()
+                        .staged
+                        .check_body_access("proxy_set_buffer_bytes", buffer_type);
                     // Default Function: set received buffer data as default
                     // Expectation: assert that the received buffer bytes is as expected
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!("Error: proxy_set_buffer_bytes cannot get export \"memory\"");
-                            println!(
+                            error!("Error: proxy_set_buffer_bytes cannot get export \"memory\"");
+                            trace!(
                                 "[vm<-host] proxy_set_buffer_bytes(...) return: {:?}",
                                 Status::InternalFailure
                             );
@@ -1237,26 +2072,39 @@ fn get_hostfunc(
                         }
                     };
 
-                    unsafe {
-                        let buffer_data_ptr = mem.data(&mut caller).get_unchecked(
-                            buffer_data as u32 as usize
-                                ..(buffer_data + buffer_size) as u32 as usize,
-                        );
-                        assert_ge!(buffer_data_ptr.len(), (start + size) as usize);
+                    // the data replaces the bytes from start to start + size of the buffer
+                    let buffer_data_ptr = match mem
+                        .data(&caller)
+                        .get(buffer_data as u32 as usize..)
+                        .and_then(|arr| arr.get(..buffer_size as u32 as usize))
+                    {
+                        Some(buffer_data_ptr) => buffer_data_ptr.to_vec(),
+                        None => {
+                            trace!(
+                                "[vm<-host] proxy_set_buffer_bytes(...) return: {:?}",
+                                Status::InvalidMemoryAccess
+                            );
+                            return Status::InvalidMemoryAccess as i32;
+                        }
+                    };
 
-                        EXPECT.lock().unwrap().staged.get_expect_set_buffer_bytes(
-                            buffer_type,
-                            &buffer_data_ptr[start as usize..(start + size) as usize],
-                        );
-                        HOST.lock().unwrap().staged.set_buffer_bytes(
-                            buffer_type,
-                            std::str::from_utf8(
-                                &buffer_data_ptr[start as usize..(start + size) as usize],
-                            )
-                            .unwrap(),
-                        );
-                    }
-                    println!(
+                    state
+                        .expect
+                        .lock()
+                        .unwrap()
+                        .staged
+                        .get_expect_set_buffer_bytes(buffer_type, &buffer_data_ptr);
+                    state.record(TracedCall::SetBufferBytes {
+                        buffer_type,
+                        buffer_data: buffer_data_ptr.clone(),
+                    });
+                    let status = state.host.lock().unwrap().staged.copy_into_buffer(
+                        buffer_type,
+                        start as u32 as usize,
+                        size as u32 as usize,
+                        &buffer_data_ptr,
+                    );
+                    trace!(
                         "[vm<-host] proxy_set_buffer_bytes(buffer_type={},
                             start={},
                             size={},
@@ -1265,24 +2113,25 @@ fn get_hostfunc(
                         buffer_type,
                         start,
                         size,
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_set_buffer_bytes(...) return: {:?}",
-                        Status::Ok
+                        status
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
-                    return Status::Ok as i32;
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
+                    status as i32
                 },
             ))
         }
 
         /* ---------------------------------- HTTP ---------------------------------- */
         "proxy_http_call" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
                  upstream_data: i32,
                  upstream_size: i32,
                  headers_data: i32,
@@ -1294,13 +2143,18 @@ fn get_hostfunc(
                  timeout: i32,
                  return_token: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_http_call");
+                    if let Some(status) = get_forced_status(&state, "proxy_http_call") {
+                        return status;
+                    }
                     // Default Function: receives and displays http call from proxy-wasm module
                     // Expectation: asserts equal the receieved http call with the expected one
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!("Error: proxy_http_call cannot get export \"memory\"");
-                            println!(
+                            error!("Error: proxy_http_call cannot get export \"memory\"");
+                            trace!(
                                 "[vm<-host] proxy_http_call(...) -> (return_token) return: {:?}",
                                 Status::InternalFailure
                             );
@@ -1319,14 +2173,14 @@ fn get_hostfunc(
                                 .map(|string_msg| std::str::from_utf8(string_msg).unwrap())
                                 .unwrap();
 
+                            let body_data_ptr = mem
+                                .data(&caller)
+                                .get(body_data as u32 as usize..)
+                                .and_then(|arr| arr.get(..body_size as u32 as usize))
+                                .unwrap_or_default();
                             let mut string_body: Option<&str> = None;
                             if body_size > 0 {
-                                let body_data_ptr = mem
-                                    .data(&caller)
-                                    .get(body_data as u32 as usize..)
-                                    .and_then(|arr| arr.get(..body_size as u32 as usize));
-                                string_body = body_data_ptr
-                                    .map(|string_msg| std::str::from_utf8(string_msg).unwrap());
+                                string_body = std::str::from_utf8(body_data_ptr).ok();
                             }
 
                             let header_data_ptr = mem.data(&caller).get_unchecked(
@@ -1342,17 +2196,52 @@ fn get_hostfunc(
                             );
                             let deserialized_trailer =
                                 serial_utils::deserialize_map(trailer_data_ptr);
-                            let token_id = match EXPECT.lock().unwrap().staged.get_expect_http_call(
-                                string_upstream,
-                                header_data_ptr,
-                                string_body,
-                                trailer_data_ptr,
-                                timeout,
-                            ) {
-                                Some(expect_token) => expect_token,
-                                None => 0,
+                            let token_id = state
+                                .expect
+                                .lock()
+                                .unwrap()
+                                .staged
+                                .get_expect_http_call(
+                                    string_upstream,
+                                    &deserialized_header,
+                                    body_data_ptr,
+                                    &deserialized_trailer,
+                                    timeout as u32 as u64,
+                                )
+                                .unwrap_or_default();
+
+                            // calls to a mock upstream are answered once the current callback returns
+                            let mock_response = state
+                                .host
+                                .lock()
+                                .unwrap()
+                                .staged
+                                .get_mock_upstream(string_upstream, &deserialized_header);
+                            let token_id = match mock_response {
+                                Some(response) => {
+                                    let mut host = state.host.lock().unwrap();
+                                    let token_id = match token_id {
+                                        0 => host.staged.next_token_id(),
+                                        token_id => token_id,
+                                    };
+                                    host.staged.queue_http_call_response(
+                                        token_id,
+                                        response,
+                                        timeout as u32 as u64,
+                                    );
+                                    token_id
+                                }
+                                None => token_id,
                             };
-                            println!(
+                            state.record(TracedCall::HttpCall {
+                                upstream: string_upstream.to_string(),
+                                headers: deserialized_header.clone(),
+                                body: body_data_ptr.to_vec(),
+                                trailers: deserialized_trailer.clone(),
+                                timeout_millis: timeout as u32 as u64,
+                                token_id,
+                            });
+                            trace!(
                                 "[vm->host] proxy_http_call(upstream_data={:?}, upstream_size={}",
                                 string_upstream,
                                 string_upstream.len()
@@ -1370,31 +2259,33 @@ fn get_hostfunc(
                         );
                         return_token_add.copy_from_slice(&token_id.to_le_bytes());
 
-                        println!(
+                        trace!(
                             "                           headers_data={:?}, headers_size={}",
-                            deserialized_header, headers_size
+                            deserialized_header,
+                            headers_size
                         );
                         let body_len = string_body.as_ref().map_or(0, |data| data.len());
-                        println!(
+                        trace!(
                             "                           body_data={}, body_size={body_len}",
                             string_body.unwrap_or("None".to_string())
                         );
-                        println!(
+                        trace!(
                             "                           trailers_data={:?}, trailers_size={}",
-                            deserialized_trailer, trailers_size
+                            deserialized_trailer,
+                            trailers_size
                         );
-                        println!(
+                        trace!(
                             "                           timeout) -> (...) status: {:?}",
-                            get_status()
+                            state.get_status()
                         );
-                        println!(
+                        trace!(
                             "[vm<-host] proxy_http_call(...) -> (return_token={}) return: {:?}",
                             token_id,
                             Status::Ok
                         );
                     }
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
@@ -1402,41 +2293,116 @@ fn get_hostfunc(
 
         /* ---------------------------------- gRPC ---------------------------------- */
         "proxy_grpc_call" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
-                 _service_ptr: i32,
-                 _service_size: i32,
-                 _service_name_ptr: i32,
-                 _service_name_size: i32,
-                 _method_name_ptr: i32,
-                 _method_name_size: i32,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
+                 service_ptr: i32,
+                 service_size: i32,
+                 service_name_ptr: i32,
+                 service_name_size: i32,
+                 method_name_ptr: i32,
+                 method_name_size: i32,
                  _initial_metadata_ptr: i32,
                  _initial_metadata_size: i32,
-                 _request_ptr: i32,
-                 _request_size: i32,
-                 _timeout_milliseconds: i32,
-                 _token_ptr: i32|
+                 request_ptr: i32,
+                 request_size: i32,
+                 timeout_milliseconds: i32,
+                 token_ptr: i32|
                  -> i32 {
-                    // Default Function:
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_grpc_call");
+                    if let Some(status) = get_forced_status(&state, "proxy_grpc_call") {
+                        return status;
+                    }
+                    // Default Function: answer the call from a mock gRPC service, if any
                     // Expectation:
-                    println!(
-                        "[vm->host] proxy_grpc_call() -> (...) status: {:?}",
-                        get_status()
-                    );
-                    println!(
-                        "[vm<-host] proxy_grpc_call() -> (..) return: {:?}",
-                        Status::InternalFailure
+                    let mem = match caller.get_export("memory") {
+                        Some(Extern::Memory(mem)) => mem,
+                        _ => {
+                            error!("Error: proxy_grpc_call cannot get export \"memory\"");
+                            trace!(
+                                "[vm<-host] proxy_grpc_call(...) -> (return_token) return: {:?}",
+                                Status::InternalFailure
+                            );
+                            return Status::InternalFailure as i32;
+                        }
+                    };
+
+                    let read_string = |ptr: i32, size: i32| {
+                        mem.data(&caller)
+                            .get(ptr as u32 as usize..)
+                            .and_then(|arr| arr.get(..size as u32 as usize))
+                            .map(|string_msg| String::from_utf8_lossy(string_msg).to_string())
+                            .unwrap_or_default()
+                    };
+                    let service = read_string(service_ptr, service_size);
+                    let service_name = read_string(service_name_ptr, service_name_size);
+                    let method_name = read_string(method_name_ptr, method_name_size);
+                    let request = mem
+                        .data(&caller)
+                        .get(request_ptr as u32 as usize..)
+                        .and_then(|arr| arr.get(..request_size as u32 as usize))
+                        .map(|request| request.to_vec())
+                        .unwrap_or_default();
+
+                    trace!(
+                        "[vm->host] proxy_grpc_call(service={:?}, service_name={:?}, method_name={:?}, request_size={}, timeout={}) status: {:?}",
+                        service,
+                        service_name,
+                        method_name,
+                        request.len(),
+                        timeout_milliseconds,
+                        state.get_status()
+                    );
+
+                    let token_id = {
+                        let mut host = state.host.lock().unwrap();
+                        match host
+                            .staged
+                            .get_mock_grpc_reply(&service_name, &method_name, &request)
+                        {
+                            Some(reply) => {
+                                let token_id = host.staged.next_token_id();
+                                host.staged.queue_grpc_call_reply(
+                                    token_id,
+                                    reply,
+                                    timeout_milliseconds as u32 as u64,
+                                );
+                                token_id
+                            }
+                            None => {
+                                trace!(
+                                    "[vm<-host] proxy_grpc_call(...) -> (return_token) return: {:?}",
+                                    Status::InternalFailure
+                                );
+                                return Status::InternalFailure as i32;
+                            }
+                        }
+                    };
+
+                    unsafe {
+                        let return_token_add = mem.data_mut(&mut caller).get_unchecked_mut(
+                            token_ptr as u32 as usize..token_ptr as u32 as usize + 4,
+                        );
+                        return_token_add.copy_from_slice(&token_id.to_le_bytes());
+                    }
+
+                    trace!(
+                        "[vm<-host] proxy_grpc_call(...) -> (return_token={}) return: {:?}",
+                        token_id,
+                        Status::Ok
                     );
-                    return Status::InternalFailure as i32;
+                    return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_grpc_stream" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>,
                  _service_ptr: i32,
                  _service_size: i32,
                  _service_name_ptr: i32,
@@ -1447,13 +2413,18 @@ fn get_hostfunc(
                  _initial_metadata_size: i32,
                  _token_ptr: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_grpc_stream");
+                    if let Some(status) = get_forced_status(&state, "proxy_grpc_stream") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    println!(
+                    trace!(
                         "[vm->host] proxy_grpc_stream() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_grpc_stream() -> (..) return: {:?}",
                         Status::InternalFailure
                     );
@@ -1463,16 +2434,22 @@ fn get_hostfunc(
         }
 
         "proxy_grpc_cancel" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>, _token: i32| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>, _token: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_grpc_cancel");
+                    if let Some(status) = get_forced_status(&state, "proxy_grpc_cancel") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    println!(
+                    trace!(
                         "[vm->host] proxy_grpc_cancel() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_grpc_cancel() -> (..) return: {:?}",
                         Status::InternalFailure
                     );
@@ -1482,16 +2459,22 @@ fn get_hostfunc(
         }
 
         "proxy_grpc_close" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>, _token: i32| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>, _token: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_grpc_close");
+                    if let Some(status) = get_forced_status(&state, "proxy_grpc_close") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    println!(
+                    trace!(
                         "[vm->host] proxy_grpc_close() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_grpc_close() -> (..) return: {:?}",
                         Status::InternalFailure
                     );
@@ -1501,21 +2484,27 @@ fn get_hostfunc(
         }
 
         "proxy_grpc_send" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>,
                  _token: i32,
                  _message_ptr: i32,
                  _message_size: i32,
                  _end_of_stream: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_grpc_send");
+                    if let Some(status) = get_forced_status(&state, "proxy_grpc_send") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    println!(
+                    trace!(
                         "[vm->host] proxy_grpc_send() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_grpc_send() -> (..) return: {:?}",
                         Status::InternalFailure
                     );
@@ -1526,21 +2515,27 @@ fn get_hostfunc(
 
         /* ---------------------------------- Metrics ---------------------------------- */
         "proxy_define_metric" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>,
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>,
                  metric_type: i32,
                  name_data: i32,
                  name_size: i32,
                  return_id: i32|
                  -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_define_metric");
+                    if let Some(status) = get_forced_status(&state, "proxy_define_metric") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!("Error: proxy_define_metric cannot get export \"memory\"");
-                            println!(
+                            error!("Error: proxy_define_metric cannot get export \"memory\"");
+                            trace!(
                                 "[vm<-host] proxy_define_metric() -> (..) return: {:?}",
                                 Status::InternalFailure
                             );
@@ -1557,13 +2552,23 @@ fn get_hostfunc(
                             .map(|string_msg| std::str::from_utf8(string_msg).unwrap())
                             .unwrap();
 
-                        EXPECT
+                        state
+                            .expect
                             .lock()
                             .unwrap()
                             .staged
                             .get_expect_metric_create(metric_type, string_name);
+                        state.record(TracedCall::MetricCreate {
+                            metric_type,
+                            name: string_name.to_string(),
+                        });
 
-                        let metric_id = HOST.lock().unwrap().staged.get_metric_id(string_name);
+                        let metric_id = state
+                            .host
+                            .lock()
+                            .unwrap()
+                            .staged
+                            .define_metric(metric_type, string_name);
 
                         let return_id_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
                             return_id as u32 as usize..return_id as u32 as usize + 4,
@@ -1571,94 +2576,128 @@ fn get_hostfunc(
                         return_id_ptr.copy_from_slice(&(metric_id as u32).to_le_bytes());
                     }
 
-                    println!(
+                    trace!(
                         "[vm->host] proxy_define_metric() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_define_metric() -> (..) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_increment_metric" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>, metric_id: i32, offset: i64| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>, metric_id: i32, offset: i64| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_increment_metric");
+                    if let Some(status) = get_forced_status(&state, "proxy_increment_metric") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    EXPECT
+                    state
+                        .expect
                         .lock()
                         .unwrap()
                         .staged
                         .get_expect_metric_increment(metric_id, offset);
+                    state.record(TracedCall::MetricIncrement { metric_id, offset });
+                    state.forward_metric_update(metric_id, MetricUpdate::Increment(offset));
 
-                    HOST.lock()
+                    state
+                        .host
+                        .lock()
                         .unwrap()
                         .staged
                         .increment_metric(metric_id, offset);
 
-                    println!(
+                    trace!(
                         "[vm->host] proxy_increment_metric() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_increment_metric() -> (..) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_record_metric" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>, metric_id: i32, value: i64| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>, metric_id: i32, value: i64| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_record_metric");
+                    if let Some(status) = get_forced_status(&state, "proxy_record_metric") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    EXPECT
+                    state
+                        .expect
                         .lock()
                         .unwrap()
                         .staged
                         .get_expect_metric_record(metric_id, value.try_into().unwrap());
+                    state.record(TracedCall::MetricRecord {
+                        metric_id,
+                        value: value.try_into().unwrap(),
+                    });
+                    state.forward_metric_update(metric_id, MetricUpdate::Record(value));
+
+                    state
+                        .host
+                        .lock()
+                        .unwrap()
+                        .staged
+                        .record_metric(metric_id, value);
 
-                    HOST.lock().unwrap().staged.record_metric(metric_id, value);
-
-                    println!(
+                    trace!(
                         "[vm->host] proxy_record_metric() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_record_metric() -> (..) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_get_metric" => {
-            Some(Func::wrap(
-                store,
-                |mut caller: Caller<'_, ()>, metric_id: i32, return_value: i32| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |mut caller: Caller<'_, HostState>, metric_id: i32, return_value: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_get_metric");
+                    if let Some(status) = get_forced_status(&state, "proxy_get_metric") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
 
                     let mem = match caller.get_export("memory") {
                         Some(Extern::Memory(mem)) => mem,
                         _ => {
-                            println!("Error: proxy_define_metric cannot get export \"memory\"");
-                            println!(
+                            error!("Error: proxy_define_metric cannot get export \"memory\"");
+                            trace!(
                                 "[vm<-host] proxy_define_metric() -> (..) return: {:?}",
                                 Status::InternalFailure
                             );
@@ -1666,13 +2705,18 @@ fn get_hostfunc(
                         }
                     };
 
-                    let metric_value = HOST.lock().unwrap().staged.get_metric(metric_id);
+                    let metric_value = state.host.lock().unwrap().staged.get_metric(metric_id);
 
-                    EXPECT
+                    state
+                        .expect
                         .lock()
                         .unwrap()
                         .staged
                         .get_expect_metric_get(metric_id, metric_value);
+                    state.record(TracedCall::MetricGet {
+                        metric_id,
+                        value: metric_value,
+                    });
 
                     unsafe {
                         let return_value_ptr = mem.data_mut(&mut caller).get_unchecked_mut(
@@ -1681,105 +2725,165 @@ fn get_hostfunc(
                         return_value_ptr.copy_from_slice(&(metric_value as u32).to_le_bytes());
                     }
 
-                    println!(
+                    trace!(
                         "[vm->host] proxy_get_metric() -> (...) status: {:?}",
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm<-host] proxy_get_metric() -> (..) return: {:?}",
                         Status::Ok
                     );
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         /* ---------------------------------- System ---------------------------------- */
-        "clock_time_get" => Some(Func::wrap(
-            store,
-            |mut _caller: Caller<'_, ()>, _clock_id: i32, _precision: i64, _time: i32| -> i32 {
+        "clock_time_get" => Some(linker.func_wrap(
+            module,
+            name,
+            |mut _caller: Caller<'_, HostState>,
+             _clock_id: i32,
+             _precision: i64,
+             _time: i32|
+             -> i32 {
+                let _timer = _caller.data().time_host_call("clock_time_get");
                 Status::Ok as i32
             },
         )),
 
-        "random_get" => Some(Func::wrap(
-            store,
-            |mut _caller: Caller<'_, ()>, _buf: i32, _buf_len: i32| -> i32 { Status::Ok as i32 },
+        "random_get" => Some(linker.func_wrap(
+            module,
+            name,
+            |mut caller: Caller<'_, HostState>, buf: i32, buf_len: i32| -> i32 {
+                // seeded as the rest of the host, so that runs are reproducible
+                let state = caller.data().clone();
+                let _timer = state.time_host_call("random_get");
+                let random = state
+                    .host
+                    .lock()
+                    .unwrap()
+                    .staged
+                    .random_bytes(buf_len as u32 as usize);
+                match caller.get_export("memory") {
+                    Some(Extern::Memory(mem)) => {
+                        match mem.write(&mut caller, buf as u32 as usize, &random) {
+                            Ok(()) => Status::Ok as i32,
+                            Err(_) => Status::InvalidMemoryAccess as i32,
+                        }
+                    }
+                    _ => Status::InternalFailure as i32,
+                }
+            },
         )),
 
-        "fd_write" => Some(Func::wrap(
-            store,
-            |mut _caller: Caller<'_, ()>,
+        "fd_write" => Some(linker.func_wrap(
+            module,
+            name,
+            |mut _caller: Caller<'_, HostState>,
              _param1: i32,
              _param2: i32,
              _param3: i32,
              _param4: i32|
-             -> i32 { Status::Ok as i32 },
+             -> i32 {
+                let _timer = _caller.data().time_host_call("fd_write");
+                Status::Ok as i32
+            },
         )),
 
-        "environ_get" => Some(Func::wrap(
-            store,
-            |mut _caller: Caller<'_, ()>, _param1: i32, _param2: i32| -> i32 { Status::Ok as i32 },
+        "environ_get" => Some(linker.func_wrap(
+            module,
+            name,
+            |mut _caller: Caller<'_, HostState>, _param1: i32, _param2: i32| -> i32 {
+                let _timer = _caller.data().time_host_call("environ_get");
+                Status::Ok as i32
+            },
         )),
 
-        "environ_sizes_get" => Some(Func::wrap(
-            store,
-            |mut _caller: Caller<'_, ()>, _param1: i32, _param2: i32| -> i32 { Status::Ok as i32 },
+        "environ_sizes_get" => Some(linker.func_wrap(
+            module,
+            name,
+            |mut _caller: Caller<'_, HostState>, _param1: i32, _param2: i32| -> i32 {
+                let _timer = _caller.data().time_host_call("environ_sizes_get");
+                Status::Ok as i32
+            },
         )),
 
-        "proc_exit" => Some(Func::wrap(
-            store,
-            |mut _caller: Caller<'_, ()>, _param1: i32| -> () { () },
+        "proc_exit" => Some(linker.func_wrap(
+            module,
+            name,
+            |mut _caller: Caller<'_, HostState>, _param1: i32| -> () { () },
         )),
 
-        "sched_yield" => Some(Func::wrap(store, |mut _caller: Caller<'_, ()>| -> i32 {
-            Status::Ok as i32
-        })),
+        "sched_yield" => {
+            Some(
+                linker.func_wrap(module, name, |mut _caller: Caller<'_, HostState>| -> i32 {
+                    let _timer = _caller.data().time_host_call("sched_yield");
+                    Status::Ok as i32
+                }),
+            )
+        }
 
         "proxy_set_effective_context" => {
-            Some(Func::wrap(
-                store,
-                |_caller: Caller<'_, ()>, context_id: i32| -> i32 {
+            Some(linker.func_wrap(
+                module,
+                name,
+                |caller: Caller<'_, HostState>, context_id: i32| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_set_effective_context");
+                    if let Some(status) = get_forced_status(&state, "proxy_set_effective_context") {
+                        return status;
+                    }
                     // Default Function:
                     // Expectation:
-                    println!(
+                    trace!(
                         "[vm->host] proxy_set_effective_context(context_id={}) status: {:?}",
                         context_id,
-                        get_status()
+                        state.get_status()
                     );
-                    println!(
+                    trace!(
                         "[vm->host] proxy_set_effective_context(...) return: {:?}",
                         Status::Ok
                     );
-                    HOST.lock()
+                    state
+                        .host
+                        .lock()
                         .unwrap()
                         .staged
                         .set_effective_context(context_id);
-                    assert_ne!(get_status(), ExpectStatus::Failed);
-                    set_status(ExpectStatus::Unexpected);
+                    assert_ne!(state.get_status(), ExpectStatus::Failed);
+                    state.set_status(ExpectStatus::Unexpected);
                     return Status::Ok as i32;
                 },
             ))
         }
 
         "proxy_done" => {
-            Some(Func::wrap(store, |_caller: Caller<'_, ()>| -> i32 {
-                // Default Function:
-                // Expectation:
-                println!("[vm->host] proxy_done() status: {:?}", get_status());
-                println!(
-                    "[vm->host] proxy_done() return: {:?}",
-                    Status::InternalFailure
-                );
-                return Status::InternalFailure as i32;
-            }))
+            Some(
+                linker.func_wrap(module, name, |caller: Caller<'_, HostState>| -> i32 {
+                    let state = caller.data().clone();
+                    let _timer = state.time_host_call("proxy_done");
+                    if let Some(status) = get_forced_status(&state, "proxy_done") {
+                        return status;
+                    }
+                    // Default Function:
+                    // Expectation:
+                    trace!("[vm->host] proxy_done() status: {:?}", state.get_status());
+                    trace!(
+                        "[vm->host] proxy_done() return: {:?}",
+                        Status::InternalFailure
+                    );
+                    return Status::InternalFailure as i32;
+                }),
+            )
         }
 
-        "proxy_call_foreign_function" => Some(Func::wrap(
-            store,
-            |_caller: Caller<'_, ()>,
+        "proxy_call_foreign_function" => Some(linker.func_wrap(
+            module,
+            name,
+            |caller: Caller<'_, HostState>,
              _function_name: i32,
              _function_name_size: i32,
              _arguments: i32,
@@ -1787,11 +2891,16 @@ fn get_hostfunc(
              _results: i32,
              _size_t: i32|
              -> i32 {
-                println!(
+                let state = caller.data().clone();
+                let _timer = state.time_host_call("proxy_call_foreign_function");
+                if let Some(status) = get_forced_status(&state, "proxy_call_foreign_function") {
+                    return status;
+                }
+                trace!(
                     "[vm->host] proxy_call_foreign_function() status: {:?}",
-                    get_status()
+                    state.get_status()
                 );
-                println!(
+                trace!(
                     "[vm->host] proxy_call_foreign_function() return: {:?}",
                     Status::InternalFailure
                 );
@@ -1800,6 +2909,13 @@ fn get_hostfunc(
         )),
 
         _ => None,
+    };
+    match linked {
+        Some(result) => {
+            result?;
+            Ok(true)
+        }
+        None => Ok(false),
     }
 }
 
@@ -1830,27 +2946,35 @@ pub mod serial_utils {
         bytes
     }
 
-    pub fn serialize_map(map: Vec<(&str, &str)>) -> Bytes {
+    pub fn serialize_map<K: AsRef<[u8]>, V: AsRef<[u8]>>(map: Vec<(K, V)>) -> Bytes {
         let mut size: usize = 4;
         for (name, value) in &map {
-            size += name.len() + value.len() + 10;
+            size += name.as_ref().len() + value.as_ref().len() + 10;
         }
         let mut bytes: Bytes = Vec::with_capacity(size);
         bytes.extend_from_slice(&(map.len() as u32).to_le_bytes());
         for (name, value) in &map {
-            bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
-            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(name.as_ref().len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(value.as_ref().len() as u32).to_le_bytes());
         }
         for (name, value) in &map {
-            bytes.extend_from_slice(&name.as_bytes());
+            bytes.extend_from_slice(name.as_ref());
             bytes.push(0);
-            bytes.extend_from_slice(&value.as_bytes());
+            bytes.extend_from_slice(value.as_ref());
             bytes.push(0);
         }
         bytes
     }
 
+    // Header values may hold arbitrary bytes, those which are not UTF-8 are matched lossily
     pub fn deserialize_map(bytes: &[u8]) -> Vec<(String, String)> {
+        deserialize_map_bytes(bytes)
+            .into_iter()
+            .map(|(key, value)| (key, String::from_utf8_lossy(&value).to_string()))
+            .collect()
+    }
+
+    pub fn deserialize_map_bytes(bytes: &[u8]) -> Vec<(String, Bytes)> {
         let mut map = Vec::new();
         if bytes.is_empty() {
             return map;
@@ -1866,16 +2990,12 @@ pub mod serial_utils {
                 u32::from_le_bytes(<[u8; 4]>::try_from(&bytes[s + 4..s + 8]).unwrap()) as usize;
             let value = bytes[p..p + size].to_vec();
             p += size + 1;
-            map.push((
-                String::from_utf8(key).unwrap(),
-                String::from_utf8(value).unwrap(),
-            ));
+            map.push((String::from_utf8_lossy(&key).to_string(), value));
         }
         map
     }
 
-    pub fn generate_random_string(string_len: usize) -> String {
-        let mut rng = rand::thread_rng();
+    pub fn generate_random_string(rng: &mut impl Rng, string_len: usize) -> String {
         let random_string: String = (0..string_len)
             .map(|_| {
                 let idx = rng.gen_range(0..CHARSET.len());
@@ -0,0 +1,261 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// HTTP messages driven through the headers, body and trailers callbacks by Tester::send_request
+// and Tester::send_response, e.g.
+//
+//   tester.send_request(http_context, HttpRequest::get("/hello").header("x-user", "admin"))?
+//   tester.send_response(http_context, HttpResponse::ok().body("Hello, World!"))?
+
+use crate::types::Bytes;
+use std::iter::FromIterator;
+
+// Header (or trailer) map: names are looked up case-insensitively, entries are kept and iterated
+// in insertion order (repeated names included), as they are serialized for the module
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap::default()
+    }
+
+    // Replaces every value of name, in place of the first one
+    pub fn insert(&mut self, name: &str, value: &str) -> &mut Self {
+        match self.position(name) {
+            Some(index) => {
+                // the later values all come after the first one
+                let (key, _) = self.entries.remove(index);
+                self.entries
+                    .retain(|(other, _)| !other.eq_ignore_ascii_case(name));
+                self.entries.insert(index, (key, value.to_string()));
+            }
+            None => self.entries.push((name.to_string(), value.to_string())),
+        }
+        self
+    }
+
+    // Adds a value of name after the existing ones
+    pub fn append(&mut self, name: &str, value: &str) -> &mut Self {
+        self.entries.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // First value of name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        let index = self.position(name)?;
+        Some(self.entries[index].1.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    // Removes every value of name, returning the first one
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let value = self.get(name).map(str::to_string);
+        self.entries
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        value
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    // Pairs as taken by the combination calls
+    pub fn pairs(&self) -> Vec<(&str, &str)> {
+        self.iter().collect()
+    }
+
+    pub fn into_pairs(self) -> Vec<(String, String)> {
+        self.entries
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+}
+
+impl<K: AsRef<str>, V: AsRef<str>> FromIterator<(K, V)> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(pairs: I) -> HeaderMap {
+        HeaderMap {
+            entries: pairs
+                .into_iter()
+                .map(|(name, value)| (name.as_ref().to_string(), value.as_ref().to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl From<Vec<(&str, &str)>> for HeaderMap {
+    fn from(pairs: Vec<(&str, &str)>) -> HeaderMap {
+        pairs.into_iter().collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Box<dyn Iterator<Item = (&'a str, &'a str)> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HttpBody {
+    Full(Bytes),
+    // delivered as `transfer-encoding: chunked`, one body callback per chunk
+    Chunked(Vec<Bytes>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    pub headers: HeaderMap,
+    pub body: Option<HttpBody>,
+    pub trailers: Option<HeaderMap>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub headers: HeaderMap,
+    pub body: Option<HttpBody>,
+    pub trailers: Option<HeaderMap>,
+}
+
+impl HttpRequest {
+    // Request with the :method and :path pseudo-headers, in that order
+    pub fn new(method: &str, path: &str) -> HttpRequest {
+        HttpRequest {
+            headers: vec![(":method", method), (":path", path)].into(),
+            body: None,
+            trailers: None,
+        }
+    }
+
+    pub fn get(path: &str) -> HttpRequest {
+        HttpRequest::new("GET", path)
+    }
+
+    pub fn head(path: &str) -> HttpRequest {
+        HttpRequest::new("HEAD", path)
+    }
+
+    pub fn post(path: &str) -> HttpRequest {
+        HttpRequest::new("POST", path)
+    }
+
+    pub fn put(path: &str) -> HttpRequest {
+        HttpRequest::new("PUT", path)
+    }
+
+    pub fn patch(path: &str) -> HttpRequest {
+        HttpRequest::new("PATCH", path)
+    }
+
+    pub fn delete(path: &str) -> HttpRequest {
+        HttpRequest::new("DELETE", path)
+    }
+
+    pub fn authority(self, authority: &str) -> HttpRequest {
+        self.header(":authority", authority)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> HttpRequest {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl AsRef<[u8]>) -> HttpRequest {
+        self.body = Some(HttpBody::Full(body.as_ref().to_vec()));
+        self
+    }
+
+    pub fn chunked(mut self, chunks: Vec<impl AsRef<[u8]>>) -> HttpRequest {
+        self.body = Some(HttpBody::Chunked(to_chunks(chunks)));
+        self
+    }
+
+    pub fn trailer(mut self, name: &str, value: &str) -> HttpRequest {
+        push_trailer(&mut self.trailers, name, value);
+        self
+    }
+}
+
+impl HttpResponse {
+    // Response with the :status pseudo-header
+    pub fn new(status: u32) -> HttpResponse {
+        HttpResponse {
+            headers: vec![(":status", status.to_string().as_str())].into(),
+            body: None,
+            trailers: None,
+        }
+    }
+
+    pub fn ok() -> HttpResponse {
+        HttpResponse::new(200)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> HttpResponse {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl AsRef<[u8]>) -> HttpResponse {
+        self.body = Some(HttpBody::Full(body.as_ref().to_vec()));
+        self
+    }
+
+    pub fn chunked(mut self, chunks: Vec<impl AsRef<[u8]>>) -> HttpResponse {
+        self.body = Some(HttpBody::Chunked(to_chunks(chunks)));
+        self
+    }
+
+    pub fn trailer(mut self, name: &str, value: &str) -> HttpResponse {
+        push_trailer(&mut self.trailers, name, value);
+        self
+    }
+}
+
+fn to_chunks(chunks: Vec<impl AsRef<[u8]>>) -> Vec<Bytes> {
+    chunks.iter().map(|chunk| chunk.as_ref().to_vec()).collect()
+}
+
+fn push_trailer(trailers: &mut Option<HeaderMap>, name: &str, value: &str) {
+    trailers
+        .get_or_insert_with(HeaderMap::new)
+        .append(name, value);
+}
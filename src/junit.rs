@@ -0,0 +1,203 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// JUnit-compatible XML reports (one <testsuite> per scenario or test group, one <testcase>
+// per test), as read by CI dashboards and code review tools
+
+use anyhow::Result;
+use std::fmt;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    pub time: Duration,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestSuite {
+    pub name: String,
+    pub cases: Vec<TestCase>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub name: String,
+    pub suites: Vec<TestSuite>,
+}
+
+impl TestSuite {
+    pub fn new(name: &str) -> TestSuite {
+        TestSuite {
+            name: name.to_string(),
+            cases: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, time: Duration, outcome: Outcome) -> &mut Self {
+        self.cases.push(TestCase {
+            name: name.to_string(),
+            time,
+            outcome,
+        });
+        self
+    }
+
+    fn count(&self, matches: fn(&Outcome) -> bool) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| matches(&case.outcome))
+            .count()
+    }
+
+    fn failures(&self) -> usize {
+        self.count(|outcome| matches!(outcome, Outcome::Failed(_)))
+    }
+
+    fn skipped(&self) -> usize {
+        self.count(|outcome| matches!(outcome, Outcome::Skipped(_)))
+    }
+
+    fn time(&self) -> Duration {
+        self.cases.iter().map(|case| case.time).sum()
+    }
+}
+
+impl Report {
+    pub fn new(name: &str) -> Report {
+        Report {
+            name: name.to_string(),
+            suites: Vec::new(),
+        }
+    }
+
+    pub fn add_suite(&mut self, suite: TestSuite) -> &mut Self {
+        self.suites.push(suite);
+        self
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tests: usize = self.suites.iter().map(|suite| suite.cases.len()).sum();
+        let failures: usize = self.suites.iter().map(TestSuite::failures).sum();
+        let skipped: usize = self.suites.iter().map(TestSuite::skipped).sum();
+        let time: Duration = self.suites.iter().map(TestSuite::time).sum();
+        writeln!(f, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            f,
+            r#"<testsuites name="{}" tests="{}" failures="{}" errors="0" skipped="{}" time="{:.3}">"#,
+            escape(&self.name),
+            tests,
+            failures,
+            skipped,
+            time.as_secs_f64()
+        )?;
+        for suite in &self.suites {
+            writeln!(
+                f,
+                r#"  <testsuite name="{}" tests="{}" failures="{}" errors="0" skipped="{}" time="{:.3}">"#,
+                escape(&suite.name),
+                suite.cases.len(),
+                suite.failures(),
+                suite.skipped(),
+                suite.time().as_secs_f64()
+            )?;
+            for case in &suite.cases {
+                write!(
+                    f,
+                    r#"    <testcase name="{}" classname="{}" time="{:.3}""#,
+                    escape(&case.name),
+                    escape(&suite.name),
+                    case.time.as_secs_f64()
+                )?;
+                match &case.outcome {
+                    Outcome::Passed => writeln!(f, "/>")?,
+                    Outcome::Failed(message) => {
+                        // first line as the summary, the whole message as the body
+                        let summary = message.lines().next().unwrap_or_default();
+                        writeln!(f, ">")?;
+                        writeln!(
+                            f,
+                            r#"      <failure message="{}">{}</failure>"#,
+                            escape(summary),
+                            escape(message)
+                        )?;
+                        writeln!(f, "    </testcase>")?;
+                    }
+                    Outcome::Skipped(reason) => {
+                        writeln!(f, ">")?;
+                        writeln!(f, r#"      <skipped message="{}"/>"#, escape(reason))?;
+                        writeln!(f, "    </testcase>")?;
+                    }
+                }
+            }
+            writeln!(f, "  </testsuite>")?;
+        }
+        writeln!(f, "</testsuites>")
+    }
+}
+
+// Escapes text and attribute values, dropping the control characters XML 1.0 cannot carry
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' | '\r' | '\t' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Runs a test step, turning unmet expectations (which panic) and errors into failure reasons
+pub fn catch<T>(step: impl FnOnce() -> Result<T>) -> Result<T, String> {
+    match panic::catch_unwind(AssertUnwindSafe(step)) {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(error)) => Err(format!("{:#}", error)),
+        Err(payload) => Err(match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => "panicked (see the output above)".to_string(),
+            },
+        }),
+    }
+}
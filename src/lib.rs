@@ -15,7 +15,48 @@
 #![crate_type = "lib"]
 #![crate_name = "proxy_wasm_test_framework"]
 
+pub mod access_log;
+pub mod artifacts;
+pub mod bench;
+pub mod build;
+pub mod cases;
+pub mod chain;
+pub mod compression;
+#[cfg(feature = "scenario")]
+pub mod conformance;
+pub mod coverage;
+pub mod dsl;
+pub mod envoy;
+pub mod flamegraph;
+pub mod fuzz;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "scenario")]
+pub mod har;
+pub mod http;
+pub mod junit;
+pub mod matchers;
+#[cfg(feature = "scenario")]
+pub mod openapi;
+pub mod otlp;
+pub mod pcap;
+pub mod profile;
+#[cfg(feature = "protobuf")]
+pub mod proto_config;
+pub mod runtime;
+#[cfg(feature = "scenario")]
+pub mod scenario;
+#[cfg(feature = "schema")]
+pub mod schema;
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+pub mod statsd;
+#[cfg(feature = "proptest")]
+pub mod strategies;
+#[cfg(feature = "scenario")]
+pub mod tap;
 pub mod tester;
+pub mod trace;
 pub mod types;
 pub mod utility;
 
@@ -23,4 +64,10 @@ mod expect_interface;
 mod expectations;
 mod host_settings;
 mod hostcalls;
+mod macros;
+mod phases;
 mod settings_interface;
+#[cfg(feature = "vnext")]
+mod vnext;
+
+pub use proxy_wasm_test_macros::proxy_wasm_test;
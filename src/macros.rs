@@ -0,0 +1,88 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Shorthands for the most common expectations, expanding to the Tester builder calls with
+// wildcards for whatever is left out, e.g.
+//
+//   expect_log!(tester, LogLevel::Info, "#2 -> :path: /hello");
+//   expect_http_call!(tester, "httpbin", timeout = 1000).returning(Some(0));
+//   expect_headers!(tester, MapType::HttpResponseHeaders, { "powered-by" => "proxy-wasm" });
+
+// expect_log!(tester, [level,] message): any level when omitted
+#[macro_export]
+macro_rules! expect_log {
+    ($tester:expr, $message:expr $(,)?) => {
+        $tester.expect_log(None, $message)
+    };
+    ($tester:expr, $level:expr, $message:expr $(,)?) => {
+        $tester.expect_log(Some($level), $message)
+    };
+}
+
+// expect_http_call!(tester, upstream [, headers = .., body = .., trailers = .., timeout = ..]):
+// the fields left out match anything, the result takes .returning(token) as expect_http_call()
+#[macro_export]
+macro_rules! expect_http_call {
+    ($tester:expr, $upstream:expr $(, $field:ident = $value:expr)* $(,)?) => {{
+        #[allow(unused_mut)]
+        let (mut headers, mut body, mut trailers, mut timeout): (
+            $crate::matchers::Matches<[(String, String)]>,
+            $crate::matchers::Matches<[u8]>,
+            $crate::matchers::Matches<[(String, String)]>,
+            $crate::matchers::Matches<u64>,
+        ) = (
+            $crate::matchers::Any.into(),
+            $crate::matchers::Any.into(),
+            $crate::matchers::Any.into(),
+            $crate::matchers::Any.into(),
+        );
+        $($crate::expect_http_call!(
+            @field (headers, body, trailers, timeout), $field = $value
+        );)*
+        $tester.expect_http_call($upstream, headers, body, trailers, timeout)
+    }};
+    (@field ($headers:ident, $body:ident, $trailers:ident, $timeout:ident), headers = $value:expr) => {
+        $headers = $value.into()
+    };
+    (@field ($headers:ident, $body:ident, $trailers:ident, $timeout:ident), body = $value:expr) => {
+        $body = $value.into()
+    };
+    (@field ($headers:ident, $body:ident, $trailers:ident, $timeout:ident), trailers = $value:expr) => {
+        $trailers = $value.into()
+    };
+    (@field ($headers:ident, $body:ident, $trailers:ident, $timeout:ident), timeout = $value:expr) => {
+        $timeout = $value.into()
+    };
+}
+
+// expect_headers!(tester, [map_type,] { name => value, .. }): the header map set by the module,
+// of any map type when omitted
+#[macro_export]
+macro_rules! expect_headers {
+    ($tester:expr, { $($name:expr => $value:expr),* $(,)? }) => {
+        $tester.expect_set_header_map_pairs(None, $crate::expect_headers!(@map $($name => $value),*))
+    };
+    ($tester:expr, $map_type:expr, { $($name:expr => $value:expr),* $(,)? }) => {
+        $tester.expect_set_header_map_pairs(
+            Some($map_type),
+            $crate::expect_headers!(@map $($name => $value),*),
+        )
+    };
+    (@map $($name:expr => $value:expr),*) => {{
+        #[allow(unused_mut)]
+        let mut headers = $crate::http::HeaderMap::new();
+        $(headers.append($name, $value);)*
+        headers
+    }};
+}
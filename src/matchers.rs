@@ -0,0 +1,381 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::http::HeaderMap;
+use crate::types::{BufferType, LogLevel, MapType, MetricType};
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::sync::Arc;
+
+// Matching semantics for a single field of a low-level expectation
+pub trait Matcher<T: ?Sized>: fmt::Debug + Send + Sync {
+    fn matches(&self, value: &T) -> bool;
+}
+
+// Matches any value (the wildcard previously expressed as None)
+#[derive(Debug, Clone, Copy)]
+pub struct Any;
+
+impl<T: ?Sized> Matcher<T> for Any {
+    fn matches(&self, _value: &T) -> bool {
+        true
+    }
+}
+
+// Matches a value equal to the given one
+#[derive(Debug, Clone)]
+pub struct Exact<U>(pub U);
+
+impl<T, U> Matcher<T> for Exact<U>
+where
+    T: ?Sized + PartialEq,
+    U: Borrow<T> + fmt::Debug + Send + Sync,
+{
+    fn matches(&self, value: &T) -> bool {
+        self.0.borrow() == value
+    }
+}
+
+// Matches a value equal to any of the given ones
+#[derive(Debug, Clone)]
+pub struct OneOf<U>(pub Vec<U>);
+
+impl<T, U> Matcher<T> for OneOf<U>
+where
+    T: ?Sized + PartialEq,
+    U: Borrow<T> + fmt::Debug + Send + Sync,
+{
+    fn matches(&self, value: &T) -> bool {
+        self.0.iter().any(|candidate| candidate.borrow() == value)
+    }
+}
+
+// Matches durations (tick periods, timeouts) within +/- tolerance of the given one
+#[derive(Debug, Clone, Copy)]
+pub struct Within {
+    pub value: u64,
+    pub tolerance: u64,
+}
+
+impl Within {
+    pub fn new(value: u64, tolerance: u64) -> Within {
+        Within { value, tolerance }
+    }
+}
+
+impl Matcher<u64> for Within {
+    fn matches(&self, value: &u64) -> bool {
+        self.value.abs_diff(*value) <= self.tolerance
+    }
+}
+
+// Matches strings (or UTF-8 byte strings) against a regular expression
+#[derive(Debug, Clone)]
+pub struct Regex(regex::Regex);
+
+impl Regex {
+    pub fn new(pattern: &str) -> Regex {
+        match regex::Regex::new(pattern) {
+            Ok(regex) => Regex(regex),
+            Err(error) => panic!("Error: invalid regex \"{}\": {}", pattern, error),
+        }
+    }
+}
+
+impl Matcher<str> for Regex {
+    fn matches(&self, value: &str) -> bool {
+        self.0.is_match(value)
+    }
+}
+
+impl Matcher<[u8]> for Regex {
+    fn matches(&self, value: &[u8]) -> bool {
+        std::str::from_utf8(value).is_ok_and(|value| self.0.is_match(value))
+    }
+}
+
+// Matches values for which the given closure returns true
+#[derive(Clone)]
+pub struct Predicate<F>(pub F);
+
+impl<F> fmt::Debug for Predicate<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Predicate")
+    }
+}
+
+impl<T, F> Matcher<T> for Predicate<F>
+where
+    T: ?Sized,
+    F: Fn(&T) -> bool + Send + Sync,
+{
+    fn matches(&self, value: &T) -> bool {
+        (self.0)(value)
+    }
+}
+
+// Type-erased matcher stored by staged expectations, the matchers above (or a plain value, or an
+// Option following the legacy "None means wildcard" convention) convert into it, custom Matcher
+// implementations can be wrapped with Matches::new
+pub struct Matches<T: ?Sized>(Arc<dyn Matcher<T>>);
+
+impl<T: ?Sized> Matches<T> {
+    pub fn matches(&self, value: &T) -> bool {
+        self.0.matches(value)
+    }
+}
+
+impl<T: ?Sized> Clone for Matches<T> {
+    fn clone(&self) -> Self {
+        Matches(self.0.clone())
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Matches<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Matches<T> {
+    pub fn new(matcher: impl Matcher<T> + 'static) -> Matches<T> {
+        Matches(Arc::new(matcher))
+    }
+}
+
+impl<T: ?Sized> From<Any> for Matches<T> {
+    fn from(matcher: Any) -> Self {
+        Matches::new(matcher)
+    }
+}
+
+impl<T, U> From<Exact<U>> for Matches<T>
+where
+    T: ?Sized + PartialEq,
+    U: Borrow<T> + fmt::Debug + Send + Sync + 'static,
+{
+    fn from(matcher: Exact<U>) -> Self {
+        Matches::new(matcher)
+    }
+}
+
+impl<T, U> From<OneOf<U>> for Matches<T>
+where
+    T: ?Sized + PartialEq,
+    U: Borrow<T> + fmt::Debug + Send + Sync + 'static,
+{
+    fn from(matcher: OneOf<U>) -> Self {
+        Matches::new(matcher)
+    }
+}
+
+impl From<Within> for Matches<u64> {
+    fn from(matcher: Within) -> Self {
+        Matches::new(matcher)
+    }
+}
+
+impl From<Regex> for Matches<str> {
+    fn from(matcher: Regex) -> Self {
+        Matches::new(matcher)
+    }
+}
+
+impl From<Regex> for Matches<[u8]> {
+    fn from(matcher: Regex) -> Self {
+        Matches::new(matcher)
+    }
+}
+
+impl<T, F> From<Predicate<F>> for Matches<T>
+where
+    T: ?Sized,
+    F: Fn(&T) -> bool + Send + Sync + 'static,
+{
+    fn from(matcher: Predicate<F>) -> Self {
+        Matches::new(matcher)
+    }
+}
+
+fn exact_or_any<T, U>(value: Option<U>) -> Matches<T>
+where
+    T: ?Sized + PartialEq,
+    U: Borrow<T> + fmt::Debug + Send + Sync + 'static,
+{
+    match value {
+        Some(value) => Exact(value).into(),
+        None => Any.into(),
+    }
+}
+
+impl From<&str> for Matches<str> {
+    fn from(value: &str) -> Self {
+        Exact(value.to_string()).into()
+    }
+}
+
+impl From<Option<&str>> for Matches<str> {
+    fn from(value: Option<&str>) -> Self {
+        exact_or_any(value.map(|value| value.to_string()))
+    }
+}
+
+impl From<&str> for Matches<[u8]> {
+    fn from(value: &str) -> Self {
+        Exact(value.as_bytes().to_vec()).into()
+    }
+}
+
+impl From<Option<&str>> for Matches<[u8]> {
+    fn from(value: Option<&str>) -> Self {
+        exact_or_any(value.map(|value| value.as_bytes().to_vec()))
+    }
+}
+
+impl From<&[u8]> for Matches<[u8]> {
+    fn from(value: &[u8]) -> Self {
+        Exact(value.to_vec()).into()
+    }
+}
+
+// Byte string literals, e.g. b"\x08\x96\x01"
+impl<const N: usize> From<&[u8; N]> for Matches<[u8]> {
+    fn from(value: &[u8; N]) -> Self {
+        Exact(value.to_vec()).into()
+    }
+}
+
+impl From<Vec<u8>> for Matches<[u8]> {
+    fn from(value: Vec<u8>) -> Self {
+        Exact(value).into()
+    }
+}
+
+impl From<Vec<(&str, &str)>> for Matches<[(String, String)]> {
+    fn from(value: Vec<(&str, &str)>) -> Self {
+        Exact(to_owned_pairs(value)).into()
+    }
+}
+
+impl From<Option<Vec<(&str, &str)>>> for Matches<[(String, String)]> {
+    fn from(value: Option<Vec<(&str, &str)>>) -> Self {
+        exact_or_any(value.map(to_owned_pairs))
+    }
+}
+
+impl From<HeaderMap> for Matches<[(String, String)]> {
+    fn from(value: HeaderMap) -> Self {
+        Exact(value.into_pairs()).into()
+    }
+}
+
+impl From<Option<HeaderMap>> for Matches<[(String, String)]> {
+    fn from(value: Option<HeaderMap>) -> Self {
+        exact_or_any(value.map(HeaderMap::into_pairs))
+    }
+}
+
+impl From<i32> for Matches<i32> {
+    fn from(value: i32) -> Self {
+        Exact(value).into()
+    }
+}
+
+impl From<Option<i32>> for Matches<i32> {
+    fn from(value: Option<i32>) -> Self {
+        exact_or_any(value)
+    }
+}
+
+// Typed buffer, map, log level and metric type fields, e.g.
+// set_expect_get_buffer_bytes(BufferType::HttpRequestBody, ...) rather than Some(0)
+impl From<BufferType> for Matches<i32> {
+    fn from(value: BufferType) -> Self {
+        Exact(value as i32).into()
+    }
+}
+
+impl From<Option<BufferType>> for Matches<i32> {
+    fn from(value: Option<BufferType>) -> Self {
+        exact_or_any(value.map(|value| value as i32))
+    }
+}
+
+impl From<MapType> for Matches<i32> {
+    fn from(value: MapType) -> Self {
+        Exact(value as i32).into()
+    }
+}
+
+impl From<Option<MapType>> for Matches<i32> {
+    fn from(value: Option<MapType>) -> Self {
+        exact_or_any(value.map(|value| value as i32))
+    }
+}
+
+impl From<LogLevel> for Matches<i32> {
+    fn from(value: LogLevel) -> Self {
+        Exact(value as i32).into()
+    }
+}
+
+impl From<Option<LogLevel>> for Matches<i32> {
+    fn from(value: Option<LogLevel>) -> Self {
+        exact_or_any(value.map(|value| value as i32))
+    }
+}
+
+impl From<MetricType> for Matches<i32> {
+    fn from(value: MetricType) -> Self {
+        Exact(value as i32).into()
+    }
+}
+
+impl From<Option<MetricType>> for Matches<i32> {
+    fn from(value: Option<MetricType>) -> Self {
+        exact_or_any(value.map(|value| value as i32))
+    }
+}
+
+impl From<i64> for Matches<i64> {
+    fn from(value: i64) -> Self {
+        Exact(value).into()
+    }
+}
+
+impl From<Option<i64>> for Matches<i64> {
+    fn from(value: Option<i64>) -> Self {
+        exact_or_any(value)
+    }
+}
+
+impl From<u64> for Matches<u64> {
+    fn from(value: u64) -> Self {
+        Exact(value).into()
+    }
+}
+
+impl From<Option<u64>> for Matches<u64> {
+    fn from(value: Option<u64>) -> Self {
+        exact_or_any(value)
+    }
+}
+
+fn to_owned_pairs(pairs: Vec<(&str, &str)>) -> Vec<(String, String)> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
@@ -0,0 +1,890 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Requests generated from an OpenAPI spec (3.x, or Swagger 2.0, in YAML or JSON), for API-gateway
+// style plugins: for each operation, valid requests (from the examples, defaults and constraints
+// of the spec), boundary cases (lengths and values at their limits, last enum values, optional
+// parameters) and invalid ones (missing required parameters, values out of range or of the wrong
+// type, malformed bodies). TestSetup::run_openapi drives every request through the module, with
+// the documented success response from upstream when the module lets it through, and checks an
+// invariant on each exchange, e.g.
+//
+//   let spec = OpenApi::load("tests/petstore.yaml")?;
+//   TestSetup::new("gateway.wasm").run_openapi(&spec, |generated, exchange| {
+//       match (generated.kind, exchange.outcome) {
+//           (RequestKind::Invalid, StreamOutcome::LocalReply(400)) => Ok(()),
+//           (RequestKind::Invalid, outcome) => bail!("not rejected: {:?}", outcome),
+//           (_, StreamOutcome::Forwarded) => Ok(()),
+//           (_, outcome) => bail!("not forwarded: {:?}", outcome),
+//       }
+//   })?
+
+use crate::http::{HttpRequest, HttpResponse};
+use crate::junit::catch;
+use crate::tester::{TestSetup, Tester, ROOT_CONTEXT};
+use crate::trace::json_bytes;
+use crate::types::{ReturnType, StreamOutcome};
+
+use anyhow::{bail, Context, Result};
+use serde_yaml::{Mapping, Value};
+use std::fs;
+use std::path::Path;
+use tracing::info;
+
+const METHODS: [&str; 8] = [
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+// Depth of the inlined $refs, deeper (recursive) schemas are left empty
+const MAX_DEPTH: usize = 8;
+
+pub struct OpenApi {
+    pub operations: Vec<Operation>,
+    authority: Option<String>,
+    base_path: String,
+}
+
+pub struct Operation {
+    pub method: String,
+    // as in the spec, e.g. /pets/{petId}
+    pub path: String,
+    pub operation_id: Option<String>,
+    parameters: Vec<Parameter>,
+    body: Option<RequestBody>,
+    // documented success response, sent from upstream
+    response: HttpResponse,
+}
+
+struct Parameter {
+    name: String,
+    location: Location,
+    required: bool,
+    schema: Value,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Location {
+    Path,
+    Query,
+    Header,
+    Cookie,
+}
+
+// Kind and description of a case, values of the parameters (None when left out) and body
+type Variant = (RequestKind, String, Vec<Option<Value>>, Option<String>);
+
+struct RequestBody {
+    required: bool,
+    content_type: String,
+    schema: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    // conforms to the spec
+    Valid,
+    // conforms to the spec, at the limits of its constraints
+    Boundary,
+    // breaks the spec, one constraint at a time
+    Invalid,
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedRequest {
+    // e.g. GET /pets/{petId}
+    pub operation: String,
+    // what the request exercises, e.g. "limit above maximum"
+    pub case: String,
+    pub kind: RequestKind,
+    pub request: HttpRequest,
+}
+
+// What became of a generated request
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub context_id: i32,
+    pub outcome: StreamOutcome,
+    // request as it went upstream, None when the module did not let it through
+    pub upstream_request: Option<HttpRequest>,
+    // response as it went downstream (local replies included)
+    pub response: Option<HttpResponse>,
+}
+
+impl OpenApi {
+    pub fn load(path: impl AsRef<Path>) -> Result<OpenApi> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("cannot read OpenAPI spec {}", path.display()))?;
+        OpenApi::parse(&text).with_context(|| format!("invalid OpenAPI spec {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<OpenApi> {
+        // JSON being valid YAML
+        let root: Value = serde_yaml::from_str(text)?;
+        let paths = match root.get("paths") {
+            Some(Value::Mapping(paths)) => paths,
+            _ => bail!("no paths"),
+        };
+        let mut operations = Vec::new();
+        for (path, item) in paths {
+            let path = match path.as_str() {
+                Some(path) => path,
+                None => bail!("invalid path {:?}", path),
+            };
+            let item = inline(&root, item, 0);
+            let shared = item.get("parameters");
+            for method in METHODS {
+                if let Some(operation) = item.get(method) {
+                    let operation = Operation::parse(&root, method, path, shared, operation)
+                        .with_context(|| format!("in {} {}", method.to_uppercase(), path))?;
+                    operations.push(operation);
+                }
+            }
+        }
+        let (authority, base_path) = server(&root);
+        Ok(OpenApi {
+            operations,
+            authority,
+            base_path,
+        })
+    }
+
+    // Requests of every operation, in the order of the spec
+    pub fn requests(&self) -> Vec<GeneratedRequest> {
+        let mut requests = Vec::new();
+        for operation in &self.operations {
+            for (kind, case, values, body) in operation.cases() {
+                requests.push(GeneratedRequest {
+                    operation: operation.name(),
+                    case,
+                    kind,
+                    request: self.request(operation, &values, body),
+                });
+            }
+        }
+        requests
+    }
+
+    fn request(
+        &self,
+        operation: &Operation,
+        values: &[Option<Value>],
+        body: Option<String>,
+    ) -> HttpRequest {
+        let mut path = operation.path.clone();
+        let mut query = Vec::new();
+        let mut cookies = Vec::new();
+        let mut headers = Vec::new();
+        for (parameter, value) in operation.parameters.iter().zip(values) {
+            let value = match value {
+                Some(value) => value,
+                // e.g. /pets/ for a missing petId
+                None if parameter.location == Location::Path => &Value::String(String::new()),
+                None => continue,
+            };
+            match parameter.location {
+                Location::Path => {
+                    let placeholder = format!("{{{}}}", parameter.name);
+                    path = path.replace(&placeholder, &encode(&scalar(value)));
+                }
+                Location::Query => match value {
+                    // form style, exploded
+                    Value::Sequence(items) => query.extend(items.iter().map(|item| {
+                        format!("{}={}", encode(&parameter.name), encode(&scalar(item)))
+                    })),
+                    value => query.push(format!(
+                        "{}={}",
+                        encode(&parameter.name),
+                        encode(&scalar(value))
+                    )),
+                },
+                Location::Header => headers.push((parameter.name.to_lowercase(), scalar(value))),
+                Location::Cookie => cookies.push(format!("{}={}", parameter.name, scalar(value))),
+            }
+        }
+        let mut path = format!("{}{}", self.base_path, path);
+        if !query.is_empty() {
+            path = format!("{}?{}", path, query.join("&"));
+        }
+        let mut request = HttpRequest::new(&operation.method, &path);
+        if let Some(authority) = &self.authority {
+            request = request.authority(authority);
+        }
+        for (name, value) in headers {
+            request = request.header(&name, &value);
+        }
+        if !cookies.is_empty() {
+            request = request.header("cookie", &cookies.join("; "));
+        }
+        if let Some(body) = body {
+            let content_type = match &operation.body {
+                Some(request_body) => request_body.content_type.as_str(),
+                None => "application/json",
+            };
+            request = request.header("content-type", content_type).body(body);
+        }
+        request
+    }
+}
+
+impl Operation {
+    fn parse(
+        root: &Value,
+        method: &str,
+        path: &str,
+        shared: Option<&Value>,
+        operation: &Value,
+    ) -> Result<Operation> {
+        let operation = inline(root, operation, 0);
+        // parameters of the operation override the ones of the path
+        let mut parameters: Vec<Parameter> = Vec::new();
+        let mut body = None;
+        let declared = [shared, operation.get("parameters")];
+        for parameter in declared
+            .iter()
+            .flatten()
+            .filter_map(|p| p.as_sequence())
+            .flatten()
+        {
+            let name = match parameter.get("name").and_then(Value::as_str) {
+                Some(name) => name.to_string(),
+                None => bail!("parameter without a name"),
+            };
+            let location = match parameter.get("in").and_then(Value::as_str) {
+                Some("path") => Location::Path,
+                Some("query") => Location::Query,
+                Some("header") => Location::Header,
+                Some("cookie") => Location::Cookie,
+                // Swagger 2.0
+                Some("body") => {
+                    body = Some(RequestBody {
+                        required: flag(parameter, "required"),
+                        content_type: "application/json".to_string(),
+                        schema: parameter.get("schema").cloned().unwrap_or(Value::Null),
+                    });
+                    continue;
+                }
+                Some("formData") => continue,
+                location => bail!("parameter {} in {:?}", name, location),
+            };
+            // Swagger 2.0 has the schema in the parameter itself
+            let schema = parameter.get("schema").unwrap_or(parameter).clone();
+            parameters.retain(|p| p.name != name || p.location != location);
+            parameters.push(Parameter {
+                name,
+                required: location == Location::Path || flag(parameter, "required"),
+                location,
+                schema,
+            });
+        }
+        if let Some(request_body) = operation.get("requestBody") {
+            if let Some((content_type, media)) = media(request_body) {
+                body = Some(RequestBody {
+                    required: flag(request_body, "required"),
+                    content_type,
+                    schema: media.get("schema").cloned().unwrap_or(Value::Null),
+                });
+            }
+        }
+        Ok(Operation {
+            method: method.to_uppercase(),
+            path: path.to_string(),
+            operation_id: operation
+                .get("operationId")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            parameters,
+            body,
+            response: success_response(&operation),
+        })
+    }
+
+    fn name(&self) -> String {
+        format!("{} {}", self.method, self.path)
+    }
+
+    fn cases(&self) -> Vec<Variant> {
+        let required: Vec<Option<Value>> = self
+            .parameters
+            .iter()
+            .map(|p| p.required.then(|| example(&p.schema, false)))
+            .collect();
+        let all: Vec<Option<Value>> = self
+            .parameters
+            .iter()
+            .map(|p| Some(example(&p.schema, true)))
+            .collect();
+        let body = self.body.as_ref();
+        let required_body = body
+            .filter(|body| body.required)
+            .map(|body| example(&body.schema, false));
+        let full_body = body.map(|body| example(&body.schema, true));
+        let json = |value: &Option<Value>| value.as_ref().map(to_json);
+
+        let mut cases = vec![(
+            RequestKind::Valid,
+            "required parameters".to_string(),
+            required.clone(),
+            json(&required_body),
+        )];
+        if all != required || full_body != required_body {
+            cases.push((
+                RequestKind::Valid,
+                "all parameters".to_string(),
+                all.clone(),
+                json(&full_body),
+            ));
+        }
+        for (index, parameter) in self.parameters.iter().enumerate() {
+            if parameter.required {
+                let mut values = required.clone();
+                values[index] = None;
+                let case = format!("{} missing", parameter.name);
+                cases.push((RequestKind::Invalid, case, values, json(&required_body)));
+            }
+            for (kind, variation, value) in variations(&parameter.schema) {
+                let mut values = required.clone();
+                values[index] = Some(value);
+                let case = format!("{} {}", parameter.name, variation);
+                cases.push((kind, case, values, json(&required_body)));
+            }
+        }
+        let body = match body {
+            Some(body) => body,
+            None => return cases,
+        };
+        let full = full_body.unwrap_or(Value::Null);
+        if body.required {
+            cases.push((
+                RequestKind::Invalid,
+                "body missing".to_string(),
+                required.clone(),
+                None,
+            ));
+        }
+        cases.push((
+            RequestKind::Invalid,
+            "body malformed".to_string(),
+            required.clone(),
+            Some("{\"".to_string()),
+        ));
+        // one property of the body at a time, the others as in the full body
+        let properties = body.schema.get("properties").and_then(Value::as_mapping);
+        let body_required = body.schema.get("required").and_then(Value::as_sequence);
+        for (name, schema) in properties.into_iter().flatten() {
+            let mut without = full.clone();
+            if let Value::Mapping(object) = &mut without {
+                object.remove(name);
+            }
+            let name = scalar(name);
+            if body_required.is_some_and(|r| r.iter().any(|r| r.as_str() == Some(name.as_str()))) {
+                let case = format!("body.{} missing", name);
+                cases.push((
+                    RequestKind::Invalid,
+                    case,
+                    required.clone(),
+                    json(&Some(without)),
+                ));
+            }
+            for (kind, variation, value) in variations(schema) {
+                let mut varied = full.clone();
+                if let Value::Mapping(object) = &mut varied {
+                    object.insert(Value::String(name.clone()), value);
+                }
+                let case = format!("body.{} {}", name, variation);
+                cases.push((kind, case, required.clone(), json(&Some(varied))));
+            }
+        }
+        cases
+    }
+}
+
+impl TestSetup {
+    // Drives every request generated from the spec through the started module (with every host
+    // call allowed), each on a new HTTP context, failing with the list of the requests whose
+    // exchange broke the invariant (or the module). The module is restarted after a failure
+    pub fn run_openapi(
+        &self,
+        spec: &OpenApi,
+        invariant: impl Fn(&GeneratedRequest, &Exchange) -> Result<()>,
+    ) -> Result<()> {
+        let mut setup = self.clone();
+        setup.mock_settings.allow_unexpected = true;
+        let requests = spec.requests();
+        let mut failures = Vec::new();
+        let mut tester = None;
+        for (index, generated) in requests.iter().enumerate() {
+            let response = spec
+                .operations
+                .iter()
+                .find(|operation| operation.name() == generated.operation)
+                .map(|operation| &operation.response);
+            let result = catch(|| {
+                let current = match tester.as_mut() {
+                    Some(current) => current,
+                    None => tester.insert(setup.start()?),
+                };
+                let context_id = ROOT_CONTEXT + 1 + index as i32;
+                let exchange = exchange(current, context_id, &generated.request, response)?;
+                invariant(generated, &exchange)
+            });
+            info!(
+                "[openapi] {} ({}) ... {}",
+                generated.operation,
+                generated.case,
+                if result.is_ok() { "ok" } else { "FAILED" }
+            );
+            if let Err(reason) = result {
+                tester = None;
+                failures.push(format!(
+                    "  {} ({}): {}",
+                    generated.operation, generated.case, reason
+                ));
+            }
+        }
+        if !failures.is_empty() {
+            bail!(
+                "{} of {} requests failed:\n{}",
+                failures.len(),
+                requests.len(),
+                failures.join("\n")
+            );
+        }
+        Ok(())
+    }
+}
+
+fn exchange(
+    tester: &mut Tester,
+    context_id: i32,
+    request: &HttpRequest,
+    response: Option<&HttpResponse>,
+) -> Result<Exchange> {
+    tester
+        .call_proxy_on_context_create(context_id, ROOT_CONTEXT)
+        .execute_and_expect(ReturnType::None)?;
+    tester.send_request(context_id, request.clone())?;
+    tester.execute_all().context("request failed")?;
+    if let Some(response) = response {
+        if tester.stream_outcome(context_id) == StreamOutcome::Forwarded {
+            tester.send_response(context_id, response.clone())?;
+            tester.execute_all().context("response failed")?;
+        }
+    }
+    Ok(Exchange {
+        context_id,
+        outcome: tester.stream_outcome(context_id),
+        upstream_request: tester.final_request(context_id),
+        response: tester.final_response(context_id),
+    })
+}
+
+// Authority and base path of the first server (Swagger 2.0: host and basePath)
+fn server(root: &Value) -> (Option<String>, String) {
+    let host = root.get("host").and_then(Value::as_str);
+    let base_path = root.get("basePath").and_then(Value::as_str);
+    if host.is_some() || base_path.is_some() {
+        let base_path = base_path.unwrap_or_default().trim_end_matches('/');
+        return (host.map(str::to_string), base_path.to_string());
+    }
+    let server = root
+        .get("servers")
+        .and_then(Value::as_sequence)
+        .and_then(|servers| servers.first());
+    let mut url = match server
+        .and_then(|server| server.get("url"))
+        .and_then(Value::as_str)
+    {
+        Some(url) => url.to_string(),
+        None => return (None, String::new()),
+    };
+    let variables = server.and_then(|server| server.get("variables"));
+    for (name, variable) in variables.and_then(Value::as_mapping).into_iter().flatten() {
+        let default = variable.get("default").map(scalar).unwrap_or_default();
+        url = url.replace(&format!("{{{}}}", scalar(name)), &default);
+    }
+    let (authority, path) = match url.split_once("://") {
+        Some((_, rest)) => match rest.split_once('/') {
+            Some((authority, path)) => (Some(authority.to_string()), format!("/{}", path)),
+            None => (Some(rest.to_string()), String::new()),
+        },
+        None => (None, url),
+    };
+    (authority, path.trim_end_matches('/').to_string())
+}
+
+// First 2xx response documented (or the default one), with its JSON example
+fn success_response(operation: &Value) -> HttpResponse {
+    let responses = operation.get("responses").and_then(Value::as_mapping);
+    let mut documented: Vec<(String, &Value)> = responses
+        .into_iter()
+        .flatten()
+        .map(|(status, response)| (scalar(status), response))
+        .filter(|(status, _)| status.starts_with('2') || status == "default")
+        .collect();
+    documented.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let (status, response) = match documented.first() {
+        Some(documented) => documented,
+        None => return HttpResponse::ok(),
+    };
+    let status_code = status.parse().unwrap_or(200);
+    let mut http_response = HttpResponse::new(status_code);
+    if status_code == 204 {
+        return http_response;
+    }
+    // OpenAPI 3.x content, or Swagger 2.0 schema
+    let (content_type, body) = match media(response) {
+        Some((content_type, media)) => {
+            let body = match media.get("example") {
+                Some(example) => example.clone(),
+                None => match media.get("schema") {
+                    Some(schema) => example(schema, true),
+                    None => return http_response,
+                },
+            };
+            (content_type, body)
+        }
+        None => match response.get("schema") {
+            Some(schema) => ("application/json".to_string(), example(schema, true)),
+            None => return http_response,
+        },
+    };
+    http_response = http_response.header("content-type", &content_type);
+    match body {
+        Value::String(text) if !content_type.contains("json") => http_response.body(text),
+        body => http_response.body(to_json(&body)),
+    }
+}
+
+// JSON media type of a request body or response, or the first one
+fn media(object: &Value) -> Option<(String, &Value)> {
+    let content = object.get("content")?.as_mapping()?;
+    let json = content
+        .iter()
+        .find(|(content_type, _)| scalar(content_type).contains("json"));
+    let (content_type, media) = json.or_else(|| content.iter().next())?;
+    Some((scalar(content_type), media))
+}
+
+// Copy of the value with its local $refs (#/components/...) replaced by their targets
+fn inline(root: &Value, value: &Value, depth: usize) -> Value {
+    if depth > MAX_DEPTH {
+        return Value::Null;
+    }
+    match value {
+        Value::Mapping(mapping) => {
+            if let Some(reference) = mapping.get("$ref").and_then(Value::as_str) {
+                return match pointer(root, reference) {
+                    Some(target) => inline(root, target, depth + 1),
+                    None => Value::Null,
+                };
+            }
+            let mut inlined = Mapping::new();
+            for (key, value) in mapping {
+                inlined.insert(key.clone(), inline(root, value, depth));
+            }
+            Value::Mapping(inlined)
+        }
+        Value::Sequence(items) => {
+            Value::Sequence(items.iter().map(|item| inline(root, item, depth)).collect())
+        }
+        value => value.clone(),
+    }
+}
+
+fn pointer<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    let mut value = root;
+    for token in reference.strip_prefix("#/")?.split('/') {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        value = match value {
+            Value::Sequence(items) => items.get(token.parse::<usize>().ok()?)?,
+            value => value.get(token.as_str())?,
+        };
+    }
+    Some(value)
+}
+
+fn flag(object: &Value, name: &str) -> bool {
+    object.get(name).and_then(Value::as_bool).unwrap_or(false)
+}
+
+fn schema_type(schema: &Value) -> &str {
+    match schema.get("type") {
+        Some(Value::String(name)) => name,
+        // OpenAPI 3.1, e.g. [string, "null"]
+        Some(Value::Sequence(names)) => names
+            .iter()
+            .filter_map(Value::as_str)
+            .find(|name| *name != "null")
+            .unwrap_or("string"),
+        _ if schema.get("properties").is_some() => "object",
+        _ if schema.get("items").is_some() => "array",
+        _ => "string",
+    }
+}
+
+fn number(schema: &Value, name: &str) -> Option<f64> {
+    schema.get(name).and_then(Value::as_f64)
+}
+
+// Inclusive bounds, from minimum/maximum and the exclusive ones (booleans in 3.0, numbers in 3.1)
+fn bounds(schema: &Value, step: f64) -> (Option<f64>, Option<f64>) {
+    let exclusive = |name: &str, bound: Option<f64>, step: f64| match schema.get(name) {
+        Some(Value::Bool(true)) => bound.map(|bound| bound + step),
+        Some(value) => value.as_f64().map(|bound| bound + step).or(bound),
+        None => bound,
+    };
+    (
+        exclusive("exclusiveMinimum", number(schema, "minimum"), step),
+        exclusive("exclusiveMaximum", number(schema, "maximum"), -step),
+    )
+}
+
+fn numeric(schema: &Value, value: f64) -> Value {
+    match schema_type(schema) {
+        "integer" => Value::Number((value as i64).into()),
+        _ => Value::Number(value.into()),
+    }
+}
+
+// Valid value of the schema: its example, default or first enum value, or one built from its
+// constraints (with the optional properties of objects when full)
+fn example(schema: &Value, full: bool) -> Value {
+    for name in ["example", "default", "const"] {
+        if let Some(value) = schema.get(name) {
+            return value.clone();
+        }
+    }
+    for name in ["enum", "examples", "oneOf", "anyOf"] {
+        if let Some(first) = schema
+            .get(name)
+            .and_then(Value::as_sequence)
+            .and_then(|s| s.first())
+        {
+            return match name {
+                "oneOf" | "anyOf" => example(first, full),
+                _ => first.clone(),
+            };
+        }
+    }
+    if let Some(all_of) = schema.get("allOf").and_then(Value::as_sequence) {
+        let mut merged = Mapping::new();
+        for part in all_of {
+            if let Value::Mapping(object) = example(part, full) {
+                merged.extend(object);
+            }
+        }
+        return Value::Mapping(merged);
+    }
+    match schema_type(schema) {
+        "integer" | "number" => {
+            let step = if schema_type(schema) == "integer" {
+                1.0
+            } else {
+                0.5
+            };
+            let value = match bounds(schema, step) {
+                (Some(minimum), _) => minimum,
+                (None, Some(maximum)) if maximum < 1.0 => maximum,
+                _ => 1.0,
+            };
+            numeric(schema, value)
+        }
+        "boolean" => Value::Bool(true),
+        "array" => {
+            let items = schema.get("items").cloned().unwrap_or(Value::Null);
+            let count = number(schema, "minItems").unwrap_or(1.0).max(1.0) as usize;
+            let count = count.min(number(schema, "maxItems").unwrap_or(f64::MAX) as usize);
+            Value::Sequence((0..count).map(|_| example(&items, full)).collect())
+        }
+        "object" => {
+            let required = schema.get("required").and_then(Value::as_sequence);
+            let mut object = Mapping::new();
+            let properties = schema.get("properties").and_then(Value::as_mapping);
+            for (name, property) in properties.into_iter().flatten() {
+                if full || required.is_some_and(|required| required.contains(name)) {
+                    object.insert(name.clone(), example(property, full));
+                }
+            }
+            Value::Mapping(object)
+        }
+        _ => match schema.get("format").and_then(Value::as_str) {
+            Some("date") => "2020-01-01".into(),
+            Some("date-time") => "2020-01-01T00:00:00Z".into(),
+            Some("uuid") => "3f2a1c4e-8b7d-4e6f-9a0b-1c2d3e4f5a6b".into(),
+            Some("email") => "user@example.com".into(),
+            Some("uri") | Some("url") => "https://example.com/".into(),
+            Some("ipv4") => "192.0.2.1".into(),
+            Some("ipv6") => "2001:db8::1".into(),
+            Some("byte") => "YQ==".into(),
+            _ => {
+                let length = number(schema, "minLength").unwrap_or(1.0).max(1.0);
+                let length = length.min(number(schema, "maxLength").unwrap_or(f64::MAX));
+                Value::String("a".repeat(length as usize))
+            }
+        },
+    }
+}
+
+// Boundary and invalid values of a scalar or array schema, one constraint at a time
+fn variations(schema: &Value) -> Vec<(RequestKind, String, Value)> {
+    use RequestKind::{Boundary, Invalid};
+    let mut variations = Vec::new();
+    let mut push = |kind, variation: &str, value: Value| {
+        variations.push((kind, variation.to_string(), value));
+    };
+    if let Some(values) = schema.get("enum").and_then(Value::as_sequence) {
+        if let Some(last) = values.last().filter(|_| values.len() > 1) {
+            push(Boundary, "last enum value", last.clone());
+        }
+        push(Invalid, "outside the enum", "not-in-enum".into());
+        return variations;
+    }
+    match schema_type(schema) {
+        kind @ ("integer" | "number") => {
+            let step = if kind == "integer" { 1.0 } else { 0.5 };
+            let (minimum, maximum) = bounds(schema, step);
+            if let Some(minimum) = minimum {
+                push(Boundary, "at minimum", numeric(schema, minimum));
+                push(Invalid, "below minimum", numeric(schema, minimum - step));
+            }
+            if let Some(maximum) = maximum {
+                push(Boundary, "at maximum", numeric(schema, maximum));
+                push(Invalid, "above maximum", numeric(schema, maximum + step));
+            }
+            if minimum.is_none() && maximum.is_none() {
+                push(Boundary, "zero", numeric(schema, 0.0));
+                if kind == "integer" {
+                    let largest = match schema.get("format").and_then(Value::as_str) {
+                        Some("int32") => i32::MAX as i64,
+                        _ => i64::MAX,
+                    };
+                    push(Boundary, "largest", Value::Number(largest.into()));
+                }
+            }
+            push(Invalid, "not a number", "NaN!".into());
+        }
+        "boolean" => {
+            push(Boundary, "false", Value::Bool(false));
+            push(Invalid, "not a boolean", "maybe".into());
+        }
+        "array" => {
+            let items = schema.get("items").cloned().unwrap_or(Value::Null);
+            let items = |count: f64| Value::Sequence(vec![example(&items, false); count as usize]);
+            match number(schema, "minItems").filter(|minimum| *minimum > 0.0) {
+                Some(minimum) => {
+                    push(Boundary, "at minItems", items(minimum));
+                    push(Invalid, "below minItems", items(minimum - 1.0));
+                }
+                None => push(Boundary, "empty", items(0.0)),
+            }
+            if let Some(maximum) = number(schema, "maxItems") {
+                push(Boundary, "at maxItems", items(maximum));
+                push(Invalid, "above maxItems", items(maximum + 1.0));
+            }
+        }
+        "object" => {}
+        _ => {
+            let minimum = number(schema, "minLength").unwrap_or(0.0);
+            let format = schema.get("format").and_then(Value::as_str);
+            if format.is_some() || schema.get("pattern").is_some() {
+                // arbitrary strings hardly match a format or pattern
+                push(Invalid, "malformed", "%not valid%".into());
+            } else {
+                let text = |length: f64| Value::String("a".repeat(length as usize));
+                if minimum > 0.0 {
+                    push(Boundary, "at minLength", text(minimum));
+                    push(Invalid, "below minLength", text(minimum - 1.0));
+                } else {
+                    push(Boundary, "empty", text(0.0));
+                }
+                if let Some(maximum) = number(schema, "maxLength") {
+                    push(Boundary, "at maxLength", text(maximum));
+                    push(Invalid, "above maxLength", text(maximum + 1.0));
+                }
+            }
+            if minimum > 0.0 && format.is_some() {
+                push(Invalid, "empty", "".into());
+            }
+        }
+    }
+    variations
+}
+
+// Text of a scalar, items of sequences separated by commas (simple style)
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Number(number) => number.to_string(),
+        Value::Bool(flag) => flag.to_string(),
+        Value::Sequence(items) => items.iter().map(scalar).collect::<Vec<_>>().join(","),
+        Value::Tagged(tagged) => scalar(&tagged.value),
+        Value::Null | Value::Mapping(_) => String::new(),
+    }
+}
+
+fn to_json(value: &Value) -> String {
+    let mut json = String::new();
+    write_json(&mut json, value);
+    json
+}
+
+fn write_json(json: &mut String, value: &Value) {
+    match value {
+        Value::Null => json.push_str("null"),
+        Value::Bool(flag) => json.push_str(&flag.to_string()),
+        Value::Number(number) => match number.as_f64() {
+            Some(float) if !float.is_finite() => json.push_str("null"),
+            _ => json.push_str(&number.to_string()),
+        },
+        Value::String(text) => json_bytes(json, text.as_bytes()),
+        Value::Sequence(items) => {
+            json.push('[');
+            for (index, item) in items.iter().enumerate() {
+                if index > 0 {
+                    json.push(',');
+                }
+                write_json(json, item);
+            }
+            json.push(']');
+        }
+        Value::Mapping(object) => {
+            json.push('{');
+            for (index, (key, value)) in object.iter().enumerate() {
+                if index > 0 {
+                    json.push(',');
+                }
+                json_bytes(json, scalar(key).as_bytes());
+                json.push(':');
+                write_json(json, value);
+            }
+            json.push('}');
+        }
+        Value::Tagged(tagged) => write_json(json, &tagged.value),
+    }
+}
+
+// Percent-encodes all but the unreserved characters
+fn encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
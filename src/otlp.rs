@@ -0,0 +1,321 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// OpenTelemetry export of a test run, to look at the interaction with the module in Jaeger (or
+// any OTLP backend), e.g.
+//
+//   tester.set_otlp_export(OtlpHttpWriter::new("http://localhost:4318")?);
+//
+// or into a file, imported later (e.g. by the collector's otlpjsonfile receiver):
+//
+//   tester.set_otlp_export(File::create("target/spans.jsonl")?);
+//
+// Each callback is a span, with a child span per host call it made, and the callbacks of a
+// context share a trace, so that a stream shows up as one timeline. The spans of a callback are
+// written when it ends, as an ExportTraceServiceRequest in the OTLP/JSON encoding on one line.
+
+use crate::trace::{json_bytes, TraceValue, TracedEvent};
+
+use anyhow::{bail, Result};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct OtlpExport {
+    writer: Box<dyn Write + Send>,
+    service_name: String,
+    // high half of the trace ids, random per export, the low half is the context id
+    trace_base: u64,
+    last_span_id: u64,
+    context_id: Option<i32>,
+    // callback running, with the host calls made so far
+    callback: Option<Vec<Span>>,
+}
+
+struct Span {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: String,
+    start_nanos: u128,
+    end_nanos: u128,
+    attributes: Vec<(String, TraceValue)>,
+}
+
+impl OtlpExport {
+    pub(crate) fn new(writer: Box<dyn Write + Send>, service_name: &str) -> OtlpExport {
+        OtlpExport {
+            writer,
+            service_name: service_name.to_string(),
+            trace_base: rand::random(),
+            last_span_id: 0,
+            context_id: None,
+            callback: None,
+        }
+    }
+
+    // Context of the callback about to start (see EventLog::enter)
+    pub(crate) fn enter(&mut self, context_id: Option<i32>) {
+        self.context_id = context_id;
+    }
+
+    pub(crate) fn write(&mut self, event: &TracedEvent) {
+        match event {
+            TracedEvent::Callback(callback) => {
+                self.finish();
+                // the callback as traced, e.g. ProxyOnRequestHeaders(2, 3, false)
+                let (name, args) = callback.split_at(callback.find('(').unwrap_or(callback.len()));
+                let mut span = self.span(snake_case(name), None);
+                span.attributes.push((
+                    "proxy_wasm.args".to_string(),
+                    TraceValue::Bytes(args.as_bytes().to_vec()),
+                ));
+                if let Some(context_id) = self.context_id {
+                    span.attributes.push((
+                        "proxy_wasm.context_id".to_string(),
+                        TraceValue::Int(context_id as i128),
+                    ));
+                }
+                self.callback = Some(vec![span]);
+            }
+            TracedEvent::Return(value) => {
+                if let Some(callback) = self.callback.as_mut() {
+                    callback[0].attributes.push((
+                        "proxy_wasm.returned".to_string(),
+                        TraceValue::Int(*value as i128),
+                    ));
+                }
+            }
+            TracedEvent::HostCall(call) => {
+                let parent_span_id = match &self.callback {
+                    Some(callback) => callback[0].span_id.clone(),
+                    // e.g. from _start, outside of any callback
+                    None => return,
+                };
+                let mut span = self.span(call.name().to_string(), Some(parent_span_id));
+                let (arguments, returns) = call.values();
+                let (argument_names, return_names) = call.names();
+                for (name, value) in argument_names.iter().zip(arguments) {
+                    span.attributes.push((format!("arg.{}", name), value));
+                }
+                for (name, value) in return_names.iter().zip(returns) {
+                    span.attributes.push((format!("return.{}", name), value));
+                }
+                let callback = self.callback.as_mut().unwrap();
+                callback[0].end_nanos = span.end_nanos;
+                callback.push(span);
+            }
+        }
+    }
+
+    // Ends the callback running, if any, and writes its spans
+    pub(crate) fn finish(&mut self) {
+        let mut spans = match self.callback.take() {
+            Some(spans) => spans,
+            None => return,
+        };
+        spans[0].end_nanos = now_nanos();
+        let request = self.export_request(&spans);
+        if let Err(error) = self
+            .writer
+            .write_all(request.as_bytes())
+            .and_then(|_| self.writer.flush())
+        {
+            panic!("Error: cannot write to the OTLP export: {}", error);
+        }
+    }
+
+    fn span(&mut self, name: String, parent_span_id: Option<String>) -> Span {
+        self.last_span_id += 1;
+        let now = now_nanos();
+        Span {
+            trace_id: format!(
+                "{:016x}{:016x}",
+                self.trace_base,
+                self.context_id.unwrap_or(0) as u32
+            ),
+            span_id: format!("{:016x}", self.last_span_id),
+            parent_span_id,
+            name,
+            start_nanos: now,
+            end_nanos: now,
+            attributes: Vec::new(),
+        }
+    }
+
+    fn export_request(&self, spans: &[Span]) -> String {
+        let mut line = String::from(r#"{"resourceSpans":[{"resource":{"attributes":["#);
+        json_attribute(
+            &mut line,
+            "service.name",
+            &TraceValue::Bytes(self.service_name.as_bytes().to_vec()),
+        );
+        line.push_str(
+            r#"]},"scopeSpans":[{"scope":{"name":"proxy-wasm-test-framework"},"spans":["#,
+        );
+        for (index, span) in spans.iter().enumerate() {
+            if index > 0 {
+                line.push(',');
+            }
+            line.push_str(&format!(
+                r#"{{"traceId":"{}","spanId":"{}","#,
+                span.trace_id, span.span_id
+            ));
+            if let Some(parent_span_id) = &span.parent_span_id {
+                line.push_str(&format!(r#""parentSpanId":"{}","#, parent_span_id));
+            }
+            line.push_str(r#""name":"#);
+            json_bytes(&mut line, span.name.as_bytes());
+            // SPAN_KIND_SERVER for the callbacks (the host calls into the module), CLIENT for
+            // the host calls
+            line.push_str(&format!(
+                r#","kind":{},"startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":["#,
+                if span.parent_span_id.is_none() { 2 } else { 3 },
+                span.start_nanos,
+                span.end_nanos
+            ));
+            let attributes = span
+                .attributes
+                .iter()
+                .filter(|(_, value)| *value != TraceValue::None);
+            for (index, (key, value)) in attributes.enumerate() {
+                if index > 0 {
+                    line.push(',');
+                }
+                json_attribute(&mut line, key, value);
+            }
+            line.push_str("]}");
+        }
+        line.push_str("]}]}]}\n");
+        line
+    }
+}
+
+impl Drop for OtlpExport {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            self.finish();
+        }
+    }
+}
+
+// Writer posting each line written to it to an OTLP/HTTP collector (e.g. Jaeger listening on
+// port 4318), as JSON over plain HTTP
+pub struct OtlpHttpWriter {
+    address: String,
+    host: String,
+    path: String,
+    pending: Vec<u8>,
+}
+
+impl OtlpHttpWriter {
+    // e.g. http://localhost:4318, the spans are posted to /v1/traces
+    pub fn new(endpoint: &str) -> Result<OtlpHttpWriter> {
+        let rest = match endpoint.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => bail!(
+                "unsupported OTLP endpoint {}, expected http://host:port",
+                endpoint
+            ),
+        };
+        let (host, base_path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let address = match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{}:80", host),
+        };
+        Ok(OtlpHttpWriter {
+            address,
+            host: host.to_string(),
+            path: format!("{}/v1/traces", base_path.trim_end_matches('/')),
+            pending: Vec::new(),
+        })
+    }
+
+    fn post(&self, body: &[u8]) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let status_line = response.lines().next().unwrap_or_default();
+        match status_line.split(' ').nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "the collector at {} answered {:?}",
+                self.address, status_line
+            ))),
+        }
+    }
+}
+
+impl Write for OtlpHttpWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        while let Some(end) = self.pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            self.post(&line[..end])?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// AnyValue of OTLP/JSON: 64-bit integers are strings, bytes are shown as text
+fn json_attribute(line: &mut String, key: &str, value: &TraceValue) {
+    line.push_str(r#"{"key":"#);
+    json_bytes(line, key.as_bytes());
+    line.push_str(r#","value":"#);
+    match value {
+        TraceValue::Int(value) => line.push_str(&format!(r#"{{"intValue":"{}"}}"#, value)),
+        TraceValue::Bytes(bytes) => {
+            line.push_str(r#"{"stringValue":"#);
+            json_bytes(line, String::from_utf8_lossy(bytes).as_bytes());
+            line.push('}');
+        }
+        value => {
+            line.push_str(r#"{"stringValue":"#);
+            json_bytes(line, value.to_string().as_bytes());
+            line.push('}');
+        }
+    }
+    line.push('}');
+}
+
+// ProxyOnRequestHeaders as proxy_on_request_headers
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && !snake.is_empty() {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
@@ -0,0 +1,413 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// TCP connections read from packet captures (tcpdump's pcap or Wireshark's pcapng), replayed
+// through a network filter segment by segment, as the proxy would have received them, e.g.
+//
+//   let connection = TcpCapture::load("tests/redis.pcap")?;
+//   let context_id = connection.replay(&mut tester)?;
+//
+// The client is the peer sending the SYN (or the first segment, when the capture starts with the
+// connection open): its segments go through proxy_on_downstream_data and the server's through
+// proxy_on_upstream_data. Retransmissions are dropped, segments out of order are put back in
+// order, and a FIN ends the data of its direction (end_of_stream). Captures over Ethernet (VLAN
+// tagged or not), Linux cooked (SLL, SLL2), BSD loopback and raw IP are read, IPv4 and IPv6;
+// fragmented IP packets are not reassembled.
+
+use crate::tester::{Tester, ROOT_CONTEXT};
+use crate::types::{Bytes, ReturnType};
+
+use anyhow::{bail, Context, Result};
+use std::convert::TryInto;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct TcpCapture {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub segments: Vec<TcpSegment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcpSegment {
+    pub flow: Flow,
+    pub data: Bytes,
+    // FIN of the sender
+    pub end_of_stream: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    // client to server
+    Downstream,
+    // server to client
+    Upstream,
+}
+
+impl TcpCapture {
+    // First connection of the capture
+    pub fn load(path: impl AsRef<Path>) -> Result<TcpCapture> {
+        let path = path.as_ref();
+        match TcpCapture::load_all(path)?.into_iter().next() {
+            Some(connection) => Ok(connection),
+            None => bail!("no TCP connection in {}", path.display()),
+        }
+    }
+
+    // Every connection of the capture, in order of their first packet
+    pub fn load_all(path: impl AsRef<Path>) -> Result<Vec<TcpCapture>> {
+        let path = path.as_ref();
+        let bytes =
+            fs::read(path).with_context(|| format!("cannot read capture {}", path.display()))?;
+        TcpCapture::parse(&bytes).with_context(|| format!("invalid capture {}", path.display()))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Vec<TcpCapture>> {
+        let mut connections: Vec<Connection> = Vec::new();
+        for (link_type, frame) in frames(bytes)? {
+            let packet = match tcp_packet(link_type, frame)? {
+                Some(packet) => packet,
+                None => continue,
+            };
+            let known = connections.iter_mut().find_map(|connection| {
+                match (packet.source, packet.destination) {
+                    addresses if addresses == (connection.client, connection.server) => {
+                        Some((connection, Flow::Downstream))
+                    }
+                    addresses if addresses == (connection.server, connection.client) => {
+                        Some((connection, Flow::Upstream))
+                    }
+                    _ => None,
+                }
+            });
+            match known {
+                Some((connection, flow)) => connection.receive(flow, packet),
+                None => {
+                    // the SYN-ACK comes from the server, when the capture missed the SYN
+                    let (client, server) = match packet.flags & (SYN | ACK) == SYN | ACK {
+                        true => (packet.destination, packet.source),
+                        false => (packet.source, packet.destination),
+                    };
+                    let flow = match client == packet.source {
+                        true => Flow::Downstream,
+                        false => Flow::Upstream,
+                    };
+                    let mut connection = Connection {
+                        client,
+                        server,
+                        halves: Default::default(),
+                        segments: Vec::new(),
+                        reset: false,
+                    };
+                    connection.receive(flow, packet);
+                    connections.push(connection);
+                }
+            }
+        }
+        Ok(connections
+            .into_iter()
+            .map(|connection| TcpCapture {
+                client: connection.client,
+                server: connection.server,
+                segments: connection.segments,
+            })
+            .collect())
+    }
+
+    // Runs the connection on a new network context of the started module (with every host call
+    // allowed), its addresses as the downstream addresses, and returns the context. The
+    // connection close callbacks are left to the test
+    pub fn replay(&self, tester: &mut Tester) -> Result<i32> {
+        let context_id = tester
+            .contexts()
+            .iter()
+            .map(|context| context.context_id)
+            .max()
+            .unwrap_or(ROOT_CONTEXT)
+            + 1;
+        tester
+            .set_downstream_remote(&self.client.to_string())
+            .set_downstream_local(&self.server.to_string());
+        tester
+            .call_proxy_on_context_create(context_id, ROOT_CONTEXT)
+            .execute_and_expect(ReturnType::None)?;
+        tester.call_proxy_on_new_connection(context_id);
+        for segment in &self.segments {
+            match segment.flow {
+                Flow::Downstream => {
+                    tester.downstream_data(context_id, &segment.data, segment.end_of_stream)?
+                }
+                Flow::Upstream => {
+                    tester.upstream_data(context_id, &segment.data, segment.end_of_stream)?
+                }
+            };
+        }
+        tester
+            .execute_all()
+            .with_context(|| format!("connection {} -> {} failed", self.client, self.server))?;
+        Ok(context_id)
+    }
+}
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const ACK: u8 = 0x10;
+
+struct TcpPacket<'a> {
+    source: SocketAddr,
+    destination: SocketAddr,
+    sequence: u32,
+    flags: u8,
+    payload: &'a [u8],
+}
+
+struct Connection {
+    client: SocketAddr,
+    server: SocketAddr,
+    // downstream, upstream
+    halves: [HalfConnection; 2],
+    segments: Vec<TcpSegment>,
+    reset: bool,
+}
+
+#[derive(Default)]
+struct HalfConnection {
+    // sequence number of the next byte expected, once known
+    next_sequence: Option<u32>,
+    ended: bool,
+    // segments ahead of the next byte expected: sequence number, payload and FIN
+    ahead: Vec<(u32, Bytes, bool)>,
+}
+
+impl Connection {
+    fn receive(&mut self, flow: Flow, packet: TcpPacket) {
+        if self.reset {
+            return;
+        }
+        if packet.flags & RST != 0 {
+            self.reset = true;
+            return;
+        }
+        let half = &mut self.halves[flow as usize];
+        let mut sequence = packet.sequence;
+        if packet.flags & SYN != 0 {
+            sequence = sequence.wrapping_add(1);
+            half.next_sequence = Some(sequence);
+        }
+        let fin = packet.flags & FIN != 0;
+        if packet.payload.is_empty() && !fin {
+            return;
+        }
+        half.ahead.push((sequence, packet.payload.to_vec(), fin));
+        // segments in order, from the next byte expected (the first one seen if the capture
+        // started with the connection open)
+        let mut next = *half.next_sequence.get_or_insert(sequence);
+        while let Some(index) = half
+            .ahead
+            .iter()
+            .position(|(sequence, _, _)| (sequence.wrapping_sub(next) as i32) <= 0)
+        {
+            let (sequence, mut data, fin) = half.ahead.remove(index);
+            let already_received = next.wrapping_sub(sequence) as usize;
+            if half.ended || (already_received >= data.len() && !fin) {
+                // retransmission
+                continue;
+            }
+            data.drain(..already_received.min(data.len()));
+            next = next.wrapping_add(data.len() as u32 + fin as u32);
+            half.ended = fin;
+            self.segments.push(TcpSegment {
+                flow,
+                data,
+                end_of_stream: fin,
+            });
+        }
+        half.next_sequence = Some(next);
+    }
+}
+
+// Link type and bytes of every frame captured
+fn frames(bytes: &[u8]) -> Result<Vec<(u32, &[u8])>> {
+    match read_u32(bytes, 0, false)? {
+        0x0A0D0D0A => pcapng_frames(bytes),
+        0xA1B2C3D4 | 0xA1B23C4D => pcap_frames(bytes, false),
+        0xD4C3B2A1 | 0x4D3CB2A1 => pcap_frames(bytes, true),
+        _ => bail!("not a pcap or pcapng capture"),
+    }
+}
+
+fn pcap_frames(bytes: &[u8], big_endian: bool) -> Result<Vec<(u32, &[u8])>> {
+    let link_type = read_u32(bytes, 20, big_endian)? & 0xFFFF;
+    let mut frames = Vec::new();
+    let mut offset = 24;
+    while offset < bytes.len() {
+        let captured_length = read_u32(bytes, offset + 8, big_endian)? as usize;
+        frames.push((link_type, slice(bytes, offset + 16, captured_length)?));
+        offset += 16 + captured_length;
+    }
+    Ok(frames)
+}
+
+fn pcapng_frames(bytes: &[u8]) -> Result<Vec<(u32, &[u8])>> {
+    let mut frames = Vec::new();
+    let mut big_endian = false;
+    // link type of each interface of the current section
+    let mut link_types = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let block_type = read_u32(bytes, offset, big_endian)?;
+        if block_type == 0x0A0D0D0A {
+            big_endian = read_u32(bytes, offset + 8, false)? != 0x1A2B3C4D;
+            link_types.clear();
+        }
+        let block_length = read_u32(bytes, offset + 4, big_endian)? as usize;
+        if block_length < 12 {
+            bail!("invalid pcapng block length {}", block_length);
+        }
+        let body = slice(bytes, offset + 8, block_length - 12)?;
+        match block_type {
+            // interface description
+            1 => link_types.push(read_u16(body, 0, big_endian)? as u32),
+            // enhanced packet
+            6 => {
+                let interface = read_u32(body, 0, big_endian)? as usize;
+                let captured_length = read_u32(body, 12, big_endian)? as usize;
+                match link_types.get(interface) {
+                    Some(link_type) => frames.push((*link_type, slice(body, 20, captured_length)?)),
+                    None => bail!("packet of undescribed interface {}", interface),
+                }
+            }
+            // simple packet, on the first interface
+            3 => {
+                let original_length = read_u32(body, 0, big_endian)? as usize;
+                let captured_length = original_length.min(body.len() - 4);
+                match link_types.first() {
+                    Some(link_type) => frames.push((*link_type, slice(body, 4, captured_length)?)),
+                    None => bail!("packet of undescribed interface 0"),
+                }
+            }
+            _ => {}
+        }
+        offset += block_length;
+    }
+    Ok(frames)
+}
+
+// None for anything but a TCP packet over IP
+fn tcp_packet(link_type: u32, frame: &[u8]) -> Result<Option<TcpPacket<'_>>> {
+    let ip = match link_type {
+        // Ethernet, skipping VLAN tags
+        1 => {
+            let mut offset = 12;
+            while matches!(read_u16(frame, offset, true)?, 0x8100 | 0x88A8) {
+                offset += 4;
+            }
+            match read_u16(frame, offset, true)? {
+                0x0800 | 0x86DD => &frame[offset + 2..],
+                _ => return Ok(None),
+            }
+        }
+        // BSD loopback (address family)
+        0 | 108 => slice(frame, 4, frame.len().saturating_sub(4))?,
+        // raw IP
+        12 | 14 | 101 | 228 | 229 => frame,
+        // Linux cooked capture, v1 and v2
+        113 => slice(frame, 16, frame.len().saturating_sub(16))?,
+        276 => slice(frame, 20, frame.len().saturating_sub(20))?,
+        _ => bail!("unsupported link type {}", link_type),
+    };
+    let (source, destination, tcp) = match ip.first().map(|byte| byte >> 4) {
+        Some(4) => {
+            let header_length = (ip[0] & 0x0F) as usize * 4;
+            if header_length < 20 || header_length > ip.len() {
+                bail!("invalid IPv4 header length {}", header_length);
+            }
+            let total_length = read_u16(ip, 2, true)? as usize;
+            let fragment = read_u16(ip, 6, true)?;
+            // more fragments, or a fragment offset
+            if slice(ip, 9, 1)?[0] != 6 || fragment & 0x3FFF != 0 {
+                return Ok(None);
+            }
+            let source: [u8; 4] = slice(ip, 12, 4)?.try_into()?;
+            let destination: [u8; 4] = slice(ip, 16, 4)?.try_into()?;
+            (
+                IpAddr::from(Ipv4Addr::from(source)),
+                IpAddr::from(Ipv4Addr::from(destination)),
+                slice(
+                    ip,
+                    header_length,
+                    total_length.saturating_sub(header_length),
+                )?,
+            )
+        }
+        // without extension headers
+        Some(6) => {
+            let payload_length = read_u16(ip, 4, true)? as usize;
+            if slice(ip, 6, 1)?[0] != 6 {
+                return Ok(None);
+            }
+            let source: [u8; 16] = slice(ip, 8, 16)?.try_into()?;
+            let destination: [u8; 16] = slice(ip, 24, 16)?.try_into()?;
+            (
+                IpAddr::from(Ipv6Addr::from(source)),
+                IpAddr::from(Ipv6Addr::from(destination)),
+                slice(ip, 40, payload_length)?,
+            )
+        }
+        _ => return Ok(None),
+    };
+    let header_length = (slice(tcp, 12, 1)?[0] >> 4) as usize * 4;
+    if header_length < 20 {
+        bail!("invalid TCP header length {}", header_length);
+    }
+    Ok(Some(TcpPacket {
+        source: SocketAddr::new(source, read_u16(tcp, 0, true)?),
+        destination: SocketAddr::new(destination, read_u16(tcp, 2, true)?),
+        sequence: read_u32(tcp, 4, true)?,
+        flags: slice(tcp, 13, 1)?[0],
+        payload: slice(tcp, header_length, tcp.len().saturating_sub(header_length))?,
+    }))
+}
+
+// Fails on the packets cut short by the snapshot length of the capture, their data being lost
+fn slice(bytes: &[u8], offset: usize, length: usize) -> Result<&[u8]> {
+    match bytes.get(offset..offset + length) {
+        Some(slice) => Ok(slice),
+        None => bail!(
+            "truncated capture, {} bytes at offset {} of a {} bytes record",
+            length,
+            offset,
+            bytes.len()
+        ),
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize, big_endian: bool) -> Result<u16> {
+    let bytes: [u8; 2] = slice(bytes, offset, 2)?.try_into()?;
+    Ok(match big_endian {
+        true => u16::from_be_bytes(bytes),
+        false => u16::from_le_bytes(bytes),
+    })
+}
+
+fn read_u32(bytes: &[u8], offset: usize, big_endian: bool) -> Result<u32> {
+    let bytes: [u8; 4] = slice(bytes, offset, 4)?.try_into()?;
+    Ok(match big_endian {
+        true => u32::from_be_bytes(bytes),
+        false => u32::from_le_bytes(bytes),
+    })
+}
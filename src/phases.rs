@@ -0,0 +1,357 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Progress of an HTTP stream through its callbacks, as Envoy drives them: request headers, body
+// and trailers, then (possibly before the request is over) response headers, body and trailers,
+// then proxy_on_log. The host checks the callbacks a test drives against it, and the host calls
+// the module makes against what the proxy allows at that point, e.g. request headers can only be
+// modified until they are forwarded: while their callback runs, or later while it holds them.
+
+use crate::types::{Action, BufferType, Bytes, MapType, StreamOutcome};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamPhase {
+    RequestHeaders,
+    RequestBody,
+    RequestTrailers,
+    ResponseHeaders,
+    ResponseBody,
+    ResponseTrailers,
+    Log,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+enum Progress {
+    #[default]
+    NotStarted,
+    Headers,
+    Body,
+    Trailers,
+    // end_of_stream was set
+    Ended,
+}
+
+// One direction (request or response) of a stream
+#[derive(Debug, Clone, Default)]
+struct HalfStream {
+    progress: Progress,
+    headers_forwarded: bool,
+    // the last callback of this direction paused and the module has not resumed it since
+    held: bool,
+    // held by a body callback, the host buffers the body until the module resumes the stream
+    body_held: bool,
+    // body let through so far (as the module left each part of it)
+    forwarded_body: Bytes,
+    trailers_received: bool,
+    // header maps as the module left them when they were let through
+    forwarded_headers: Option<HeaderPairs>,
+    forwarded_trailers: Option<HeaderPairs>,
+}
+
+pub type HeaderPairs = Vec<(String, Bytes)>;
+
+// Headers and trailers of one direction, as the host holds them
+pub type HeaderMaps<'a> = (&'a [(String, Bytes)], &'a [(String, Bytes)]);
+
+// Answer of the module with proxy_send_local_response
+#[derive(Debug, Clone)]
+pub struct LocalReply {
+    pub status_code: u32,
+    pub headers: HeaderPairs,
+    pub body: Bytes,
+    // request headers as the module left them when it answered
+    pub request_headers: HeaderPairs,
+}
+
+impl HalfStream {
+    fn enter(&mut self, progress: Progress, end_of_stream: bool) -> Result<(), String> {
+        let allowed = match progress {
+            Progress::Headers => self.progress == Progress::NotStarted,
+            _ => self.progress >= Progress::Headers && self.progress < Progress::Trailers,
+        };
+        if !allowed {
+            return Err(match self.progress {
+                Progress::NotStarted => "before the headers".to_string(),
+                Progress::Ended => "after end_of_stream".to_string(),
+                Progress::Trailers => "after the trailers".to_string(),
+                _ => "after the headers".to_string(),
+            });
+        }
+        self.trailers_received |= progress == Progress::Trailers;
+        self.progress = match end_of_stream {
+            true => Progress::Ended,
+            false => progress,
+        };
+        Ok(())
+    }
+
+    // A callback continuing the stream forwards the headers, if they were still held, and the
+    // body buffer of a body callback
+    fn leave(&mut self, returned: Option<i32>, body: Option<&[u8]>, maps: HeaderMaps) {
+        self.held = returned == Some(Action::Pause as i32);
+        self.body_held = self.held && body.is_some();
+        if !self.held {
+            self.forward(maps);
+            self.forwarded_body
+                .extend_from_slice(body.unwrap_or_default());
+        }
+    }
+
+    fn resume(&mut self, body: &[u8], maps: HeaderMaps) {
+        if self.body_held {
+            self.forwarded_body.extend_from_slice(body);
+        }
+        self.held = false;
+        self.body_held = false;
+        if self.progress != Progress::NotStarted {
+            self.forward(maps);
+        }
+    }
+
+    fn forward(&mut self, (headers, trailers): HeaderMaps) {
+        if !self.headers_forwarded {
+            self.headers_forwarded = true;
+            self.forwarded_headers = Some(headers.to_vec());
+        }
+        if self.trailers_received && self.forwarded_trailers.is_none() {
+            self.forwarded_trailers = Some(trailers.to_vec());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StreamPhases {
+    request: HalfStream,
+    response: HalfStream,
+    logged: bool,
+    // callback running on the stream, if any
+    current: Option<StreamPhase>,
+    local_reply: Option<LocalReply>,
+    // host clock at the request headers and at proxy_on_log
+    started_nanos: Option<u64>,
+    logged_nanos: Option<u64>,
+}
+
+impl StreamPhases {
+    // Checks that the callback can come next, e.g. not the request body before its headers
+    pub fn enter(
+        &mut self,
+        phase: StreamPhase,
+        end_of_stream: bool,
+        now_nanos: u64,
+    ) -> Result<(), String> {
+        if self.logged {
+            return Err("after proxy_on_log".to_string());
+        }
+        match phase {
+            StreamPhase::RequestHeaders => {
+                self.started_nanos.get_or_insert(now_nanos);
+            }
+            StreamPhase::Log => self.logged_nanos = Some(now_nanos),
+            _ => {}
+        }
+        let result = match phase {
+            StreamPhase::RequestHeaders => self.request.enter(Progress::Headers, end_of_stream),
+            StreamPhase::RequestBody => self.request.enter(Progress::Body, end_of_stream),
+            StreamPhase::RequestTrailers => self.request.enter(Progress::Trailers, true),
+            StreamPhase::ResponseHeaders
+            | StreamPhase::ResponseBody
+            | StreamPhase::ResponseTrailers
+                if self.request.progress == Progress::NotStarted =>
+            {
+                Err("before the request headers".to_string())
+            }
+            StreamPhase::ResponseHeaders => self.response.enter(Progress::Headers, end_of_stream),
+            StreamPhase::ResponseBody => self.response.enter(Progress::Body, end_of_stream),
+            StreamPhase::ResponseTrailers => self.response.enter(Progress::Trailers, true),
+            StreamPhase::Log => {
+                self.logged = true;
+                Ok(())
+            }
+        };
+        if result.is_ok() {
+            self.current = Some(phase);
+        }
+        result
+    }
+
+    // With the body buffer, if the callback was a body callback, and the headers and trailers
+    // of its direction (see StreamPhase::header_maps)
+    pub fn leave(&mut self, returned: Option<i32>, body: Option<&[u8]>, maps: HeaderMaps) {
+        match self.current.take().map(StreamPhase::is_request) {
+            Some(Some(true)) => self.request.leave(returned, body, maps),
+            Some(Some(false)) => self.response.leave(returned, body, maps),
+            _ => {}
+        }
+    }
+
+    // proxy_continue_stream (or proxy_continue_request/response), 0 request and 1 response, with
+    // the body buffer, headers and trailers of that direction
+    pub fn resume(&mut self, stream_type: i32, body: &[u8], maps: HeaderMaps) {
+        match stream_type {
+            0 => self.request.resume(body, maps),
+            1 => self.response.resume(body, maps),
+            _ => {}
+        }
+    }
+
+    // The next chunk adds to the body buffer rather than replacing it
+    pub fn is_body_held(&self, request: bool) -> bool {
+        match request {
+            true => self.request.body_held,
+            false => self.response.body_held,
+        }
+    }
+
+    pub fn forwarded_body(&self, request: bool) -> &[u8] {
+        match request {
+            true => &self.request.forwarded_body,
+            false => &self.response.forwarded_body,
+        }
+    }
+
+    // Headers (None until forwarded) and trailers (None unless received and forwarded)
+    pub fn forwarded_header_maps(
+        &self,
+        request: bool,
+    ) -> (Option<&HeaderPairs>, Option<&HeaderPairs>) {
+        let half = match request {
+            true => &self.request,
+            false => &self.response,
+        };
+        (
+            half.forwarded_headers.as_ref(),
+            half.forwarded_trailers.as_ref(),
+        )
+    }
+
+    pub fn reply_locally(&mut self, local_reply: LocalReply) {
+        self.local_reply = Some(local_reply);
+    }
+
+    pub fn local_reply(&self) -> Option<&LocalReply> {
+        self.local_reply.as_ref()
+    }
+
+    // Host clock at the request headers and at proxy_on_log, if the stream got that far
+    pub fn times_nanos(&self) -> (Option<u64>, Option<u64>) {
+        (self.started_nanos, self.logged_nanos)
+    }
+
+    // A local reply wins over the request going upstream, it replaces the upstream response
+    pub fn outcome(&self) -> StreamOutcome {
+        match &self.local_reply {
+            Some(local_reply) => StreamOutcome::LocalReply(local_reply.status_code),
+            None if self.request.headers_forwarded => StreamOutcome::Forwarded,
+            None => StreamOutcome::Pending,
+        }
+    }
+
+    // Reason why the header map cannot be read (or modified) now, if it cannot
+    pub fn check_header_map(&self, map_type: i32, modify: bool) -> Option<String> {
+        let (half, request, headers) = match map_type {
+            t if t == MapType::HttpRequestHeaders as i32 => (&self.request, true, true),
+            t if t == MapType::HttpRequestTrailers as i32 => (&self.request, true, false),
+            t if t == MapType::HttpResponseHeaders as i32 => (&self.response, false, true),
+            t if t == MapType::HttpResponseTrailers as i32 => (&self.response, false, false),
+            _ => return None,
+        };
+        // proxy_on_log may look for headers that never came, e.g. to log the status
+        let logging = self.current == Some(StreamPhase::Log);
+        if headers && half.progress == Progress::NotStarted && (modify || !logging) {
+            return Some(format!(
+                "the {} have not been received yet",
+                map_name(map_type)
+            ));
+        }
+        if !modify {
+            return None;
+        }
+        if headers && half.headers_forwarded {
+            return Some(format!(
+                "the {} have already been forwarded",
+                map_name(map_type)
+            ));
+        }
+        // trailers can be added until the end of the stream has gone through
+        let in_callback = self.current.and_then(StreamPhase::is_request) == Some(request);
+        if !headers && half.progress == Progress::Ended && !half.held && !in_callback {
+            return Some(format!(
+                "the {} have already been forwarded",
+                map_name(map_type)
+            ));
+        }
+        None
+    }
+
+    // Reason why the body cannot be modified now: outside of its callbacks, only while held
+    pub fn check_body(&self, buffer_type: i32) -> Option<String> {
+        let (half, phase, name) = match buffer_type {
+            t if t == BufferType::HttpRequestBody as i32 => {
+                (&self.request, StreamPhase::RequestBody, "request body")
+            }
+            t if t == BufferType::HttpResponseBody as i32 => {
+                (&self.response, StreamPhase::ResponseBody, "response body")
+            }
+            _ => return None,
+        };
+        if self.current == Some(phase) || (half.held && half.progress >= Progress::Body) {
+            return None;
+        }
+        Some(format!(
+            "the {} can only be modified in its callbacks or while they hold it",
+            name
+        ))
+    }
+}
+
+impl StreamPhase {
+    pub fn body_buffer(self) -> Option<BufferType> {
+        match self {
+            StreamPhase::RequestBody => Some(BufferType::HttpRequestBody),
+            StreamPhase::ResponseBody => Some(BufferType::HttpResponseBody),
+            _ => None,
+        }
+    }
+
+    // Headers and trailers of the direction of the callback, None for proxy_on_log
+    pub fn header_maps(self) -> Option<(MapType, MapType)> {
+        match self.is_request()? {
+            true => Some((MapType::HttpRequestHeaders, MapType::HttpRequestTrailers)),
+            false => Some((MapType::HttpResponseHeaders, MapType::HttpResponseTrailers)),
+        }
+    }
+
+    // None for proxy_on_log
+    fn is_request(self) -> Option<bool> {
+        match self {
+            StreamPhase::RequestHeaders
+            | StreamPhase::RequestBody
+            | StreamPhase::RequestTrailers => Some(true),
+            StreamPhase::ResponseHeaders
+            | StreamPhase::ResponseBody
+            | StreamPhase::ResponseTrailers => Some(false),
+            StreamPhase::Log => None,
+        }
+    }
+}
+
+fn map_name(map_type: i32) -> &'static str {
+    match map_type {
+        t if t == MapType::HttpRequestHeaders as i32 => "request headers",
+        t if t == MapType::HttpRequestTrailers as i32 => "request trailers",
+        t if t == MapType::HttpResponseHeaders as i32 => "response headers",
+        _ => "response trailers",
+    }
+}
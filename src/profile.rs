@@ -0,0 +1,277 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Time and fuel spent inside each callback of the module (host calls included), accumulated by
+// the Tester over its lifetime, e.g.
+//
+//   tester.send_request(2, request)?.execute_all()?;
+//   println!("{}", tester.profile());
+//   let headers = tester.profile().get("proxy_on_request_headers").unwrap();
+//
+// With PROXY_WASM_TEST_PROFILE set, every Tester prints its table when dropped:
+//
+//   callback                     calls      total       mean        max   time     fuel/call
+//   proxy_on_request_headers         2    1.204ms    602.0µs    950.1µs  81.3%         41210
+//   ...
+//
+// followed by the host call report (see Tester::host_call_report), where chatty modules stand out:
+//
+//   host call                       calls  share      total       mean        max
+//   proxy_get_property               2048  97.5%    3.921ms      1.9µs     25.3µs
+//   ...
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CallbackProfile {
+    pub calls: u64,
+    pub total_time: Duration,
+    pub max_time: Duration,
+    pub total_fuel: u64,
+    pub max_fuel: u64,
+}
+
+impl CallbackProfile {
+    pub fn mean_time(&self) -> Duration {
+        match self.calls {
+            0 => Duration::ZERO,
+            calls => self.total_time / calls as u32,
+        }
+    }
+
+    pub fn mean_fuel(&self) -> u64 {
+        self.total_fuel.checked_div(self.calls).unwrap_or(0)
+    }
+}
+
+// Profiles keyed by the export of the callback (e.g. proxy_on_request_headers)
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Profile {
+    callbacks: BTreeMap<&'static str, CallbackProfile>,
+}
+
+impl Profile {
+    pub(crate) fn record(&mut self, callback: &'static str, time: Duration, fuel: u64) {
+        let profile = self.callbacks.entry(callback).or_default();
+        profile.calls += 1;
+        profile.total_time += time;
+        profile.max_time = profile.max_time.max(time);
+        profile.total_fuel += fuel;
+        profile.max_fuel = profile.max_fuel.max(fuel);
+    }
+
+    pub fn get(&self, callback: &str) -> Option<&CallbackProfile> {
+        self.callbacks.get(callback)
+    }
+
+    // Callbacks by decreasing total time
+    pub fn callbacks(&self) -> Vec<(&'static str, CallbackProfile)> {
+        let mut callbacks: Vec<(&'static str, CallbackProfile)> = self
+            .callbacks
+            .iter()
+            .map(|(callback, profile)| (*callback, *profile))
+            .collect();
+        callbacks.sort_by_key(|(_, profile)| std::cmp::Reverse(profile.total_time));
+        callbacks
+    }
+
+    // Callback the module spent the most time in
+    pub fn hottest(&self) -> Option<(&'static str, CallbackProfile)> {
+        self.callbacks().into_iter().next()
+    }
+
+    pub fn total_time(&self) -> Duration {
+        self.callbacks
+            .values()
+            .map(|profile| profile.total_time)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+}
+
+// Table of the callbacks by decreasing total time, with their share of the time in the module
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self
+            .callbacks
+            .keys()
+            .map(|callback| callback.len())
+            .chain(std::iter::once("callback".len()))
+            .max()
+            .unwrap();
+        writeln!(
+            f,
+            "{:<width$} {:>8} {:>10} {:>10} {:>10} {:>6} {:>13}",
+            "callback",
+            "calls",
+            "total",
+            "mean",
+            "max",
+            "time",
+            "fuel/call",
+            width = width
+        )?;
+        let total_time = self.total_time().as_secs_f64();
+        for (callback, profile) in self.callbacks() {
+            let share = match total_time {
+                total if total > 0.0 => 100.0 * profile.total_time.as_secs_f64() / total,
+                _ => 0.0,
+            };
+            writeln!(
+                f,
+                "{:<width$} {:>8} {:>10} {:>10} {:>10} {:>5.1}% {:>13}",
+                callback,
+                profile.calls,
+                format!("{:.3?}", profile.total_time),
+                format!("{:.1?}", profile.mean_time()),
+                format!("{:.1?}", profile.max_time),
+                share,
+                profile.mean_fuel(),
+                width = width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HostCallProfile {
+    pub calls: u64,
+    pub total_time: Duration,
+    pub max_time: Duration,
+}
+
+impl HostCallProfile {
+    pub fn mean_time(&self) -> Duration {
+        match self.calls {
+            0 => Duration::ZERO,
+            calls => self.total_time / calls as u32,
+        }
+    }
+}
+
+// Calls to each host function and the time the host spent handling them (including the
+// allocations it makes in the module to return data), keyed by import name
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HostCallReport {
+    host_calls: BTreeMap<&'static str, HostCallProfile>,
+}
+
+impl HostCallReport {
+    pub(crate) fn record(&mut self, host_call: &'static str, time: Duration) {
+        let profile = self.host_calls.entry(host_call).or_default();
+        profile.calls += 1;
+        profile.total_time += time;
+        profile.max_time = profile.max_time.max(time);
+    }
+
+    pub fn get(&self, host_call: &str) -> Option<&HostCallProfile> {
+        self.host_calls.get(host_call)
+    }
+
+    // Host functions by decreasing number of calls
+    pub fn host_calls(&self) -> Vec<(&'static str, HostCallProfile)> {
+        let mut host_calls: Vec<(&'static str, HostCallProfile)> = self
+            .host_calls
+            .iter()
+            .map(|(host_call, profile)| (*host_call, *profile))
+            .collect();
+        host_calls.sort_by_key(|(_, profile)| std::cmp::Reverse(profile.calls));
+        host_calls
+    }
+
+    pub fn total_calls(&self) -> u64 {
+        self.host_calls.values().map(|profile| profile.calls).sum()
+    }
+
+    pub fn total_time(&self) -> Duration {
+        self.host_calls
+            .values()
+            .map(|profile| profile.total_time)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.host_calls.is_empty()
+    }
+}
+
+// Table of the host functions by decreasing number of calls, with their share of the calls
+impl fmt::Display for HostCallReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self
+            .host_calls
+            .keys()
+            .map(|host_call| host_call.len())
+            .chain(std::iter::once("host call".len()))
+            .max()
+            .unwrap();
+        writeln!(
+            f,
+            "{:<width$} {:>8} {:>6} {:>10} {:>10} {:>10}",
+            "host call",
+            "calls",
+            "share",
+            "total",
+            "mean",
+            "max",
+            width = width
+        )?;
+        let total_calls = self.total_calls();
+        for (host_call, profile) in self.host_calls() {
+            writeln!(
+                f,
+                "{:<width$} {:>8} {:>5.1}% {:>10} {:>10} {:>10}",
+                host_call,
+                profile.calls,
+                100.0 * profile.calls as f64 / total_calls as f64,
+                format!("{:.3?}", profile.total_time),
+                format!("{:.1?}", profile.mean_time()),
+                format!("{:.1?}", profile.max_time),
+                width = width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Records the host call in the report when dropped, whichever way the host function returns
+pub(crate) struct HostCallTimer {
+    report: Arc<Mutex<HostCallReport>>,
+    host_call: &'static str,
+    started: Instant,
+}
+
+impl HostCallTimer {
+    pub(crate) fn start(report: Arc<Mutex<HostCallReport>>, host_call: &'static str) -> Self {
+        HostCallTimer {
+            report,
+            host_call,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for HostCallTimer {
+    fn drop(&mut self) {
+        let time = self.started.elapsed();
+        self.report.lock().unwrap().record(self.host_call, time);
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Plugin configurations in the protobuf forms Envoy delivers them (`protobuf` feature). The
+// configuration of a wasm filter is a google.protobuf.Any, of which the module reads the string
+// of a StringValue, the bytes of a BytesValue, and the serialized message otherwise (e.g. a
+// google.protobuf.Struct, or the plugin's own message), e.g.
+//
+//   TestSetup::new(wasm_path).plugin_config_any(&struct_any(r#"{"tenant": "acme"}"#)?)
+//
+// or with a message of the plugin (prost code generated from its .proto):
+//
+//   let config = pack("type.googleapis.com/acme.filter.Config", &Config { tenant: "acme".into() });
+//   TestSetup::new(wasm_path).plugin_config_any(&config)
+
+use anyhow::{bail, Result};
+use prost::Message;
+use prost_types::value::Kind;
+use prost_types::{Any, ListValue, Struct, Value};
+use serde_yaml::Value as Json;
+
+const TYPE_URL_PREFIX: &str = "type.googleapis.com/";
+
+// Any holding the message, e.g. pack("type.googleapis.com/acme.filter.Config", &config)
+pub fn pack(type_url: &str, message: &impl Message) -> Any {
+    Any {
+        type_url: type_url.to_string(),
+        value: message.encode_to_vec(),
+    }
+}
+
+// Struct of a JSON object, as Envoy builds it from a YAML or JSON configuration
+pub fn json_to_struct(json: &str) -> Result<Struct> {
+    match serde_yaml::from_str(json)? {
+        Json::Mapping(object) => to_struct(object),
+        _ => bail!("a google.protobuf.Struct is a JSON object, found {}", json),
+    }
+}
+
+pub fn struct_any(json: &str) -> Result<Any> {
+    Ok(pack(
+        &format!("{}google.protobuf.Struct", TYPE_URL_PREFIX),
+        &json_to_struct(json)?,
+    ))
+}
+
+pub fn string_any(value: &str) -> Any {
+    pack(
+        &format!("{}google.protobuf.StringValue", TYPE_URL_PREFIX),
+        &value.to_string(),
+    )
+}
+
+pub fn bytes_any(value: &[u8]) -> Any {
+    pack(
+        &format!("{}google.protobuf.BytesValue", TYPE_URL_PREFIX),
+        &value.to_vec(),
+    )
+}
+
+// Plugin configuration buffer the module reads for the Any (see Envoy's MessageUtil::anyToBytes)
+pub fn delivered_bytes(any: &Any) -> Result<Vec<u8>> {
+    let type_name = any.type_url.rsplit('/').next().unwrap_or_default();
+    Ok(match type_name {
+        "google.protobuf.StringValue" => String::decode(any.value.as_slice())?.into_bytes(),
+        "google.protobuf.BytesValue" => Vec::<u8>::decode(any.value.as_slice())?,
+        _ => any.value.clone(),
+    })
+}
+
+fn to_struct(object: serde_yaml::Mapping) -> Result<Struct> {
+    let mut fields = std::collections::BTreeMap::new();
+    for (key, value) in object {
+        let key = match key {
+            Json::String(key) => key,
+            key => bail!("Struct keys are strings, found {:?}", key),
+        };
+        fields.insert(key, to_value(value)?);
+    }
+    Ok(Struct { fields })
+}
+
+fn to_value(value: Json) -> Result<Value> {
+    let kind = match value {
+        Json::Null => Kind::NullValue(0),
+        Json::Bool(flag) => Kind::BoolValue(flag),
+        Json::Number(number) => Kind::NumberValue(number.as_f64().unwrap_or_default()),
+        Json::String(text) => Kind::StringValue(text),
+        Json::Sequence(items) => Kind::ListValue(ListValue {
+            values: items.into_iter().map(to_value).collect::<Result<_>>()?,
+        }),
+        Json::Mapping(object) => Kind::StructValue(to_struct(object)?),
+        Json::Tagged(tagged) => return to_value(tagged.value),
+    };
+    Ok(Value { kind: Some(kind) })
+}
@@ -0,0 +1,460 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Wasm engine running the module under test, selected at build time: wasmtime (default) or the
+// wasmi interpreter with the "wasmi" feature (which wins when both are enabled, as features
+// are additive). Both engines share the embedding API used by the host functions (Linker,
+// Caller, Memory, Val, ...), what differs is abstracted by the Runtime trait.
+
+#[cfg(not(feature = "wasmi"))]
+pub use wasmtime::{
+    Caller, Extern, Func, ImportType, Instance, IntoFunc, Linker, Memory, Module, Store,
+    StoreLimits, StoreLimitsBuilder, Val,
+};
+
+#[cfg(feature = "wasmi")]
+pub use wasmi::{
+    Caller, Extern, Func, ImportType, Instance, IntoFunc, Linker, Memory, Module, Store,
+    StoreLimits, StoreLimitsBuilder, Val,
+};
+
+// Module linked against the host functions, ready to be instantiated: wasmtime type-checks the
+// imports once in an InstancePre, wasmi resolves them from the linker on each instantiation
+#[cfg(not(feature = "wasmi"))]
+pub type LinkedModule = wasmtime::InstancePre<HostState>;
+
+#[cfg(feature = "wasmi")]
+pub type LinkedModule = Linker<HostState>;
+
+// Data of the stores (e.g. Caller<'_, HostState> in host extensions)
+#[cfg(not(feature = "wasmi"))]
+use crate::flamegraph::StackSamples;
+pub use crate::hostcalls::HostState;
+
+#[cfg(not(any(feature = "wasmtime", feature = "wasmi")))]
+compile_error!("Error: either the \"wasmtime\" or the \"wasmi\" feature must be enabled");
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(not(feature = "wasmi"))]
+use std::time::Instant;
+
+// Compiled modules are cached in-process keyed by the hash of the file contents (a rebuilt
+// module gets recompiled), and optionally on disk as precompiled .cwasm files. Modules linked
+// against the built-in host functions only are cached as well, so that each Tester gets a fresh
+// instance without resolving the imports again.
+#[derive(Default)]
+struct ModuleCache {
+    modules: HashMap<u64, Module>,
+    linked: HashMap<u64, LinkedModule>,
+    cache_dir: Option<PathBuf>,
+}
+
+// Module loaded from a file, identified by the hash of its contents
+#[derive(Clone)]
+pub struct LoadedModule {
+    pub module: Module,
+    key: u64,
+}
+
+lazy_static! {
+    static ref MODULE_CACHE: Mutex<ModuleCache> = Mutex::new(ModuleCache::default());
+}
+
+// Persists compiled modules in the given directory so that they are reused across test runs
+// (only with wasmtime, wasmi compiles lazily and has no precompiled format)
+pub fn set_module_cache_dir(cache_dir: impl Into<PathBuf>) {
+    MODULE_CACHE.lock().unwrap().cache_dir = Some(cache_dir.into());
+}
+
+pub fn clear_module_cache() {
+    let mut cache = MODULE_CACHE.lock().unwrap();
+    cache.modules.clear();
+    cache.linked.clear();
+}
+
+fn module_key(wasm: &[u8]) -> u64 {
+    // not stable across toolchains, which only costs a recompilation of the on-disk entries
+    let mut hasher = DefaultHasher::new();
+    wasm.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub trait Runtime {
+    fn name() -> &'static str;
+
+    // Stores share the engine, so that modules compiled once can be instantiated in any of them,
+    // fuel is always metered (see Tester::set_fuel_limit) and unlimited to begin with, as is
+    // memory (see Tester::set_memory_limit)
+    fn new_store(state: HostState) -> Store<HostState>;
+
+    // Compiles a module from the binary or text format
+    fn compile(wasm: &[u8]) -> Result<Module>;
+
+    fn serialize(module: &Module) -> Option<Vec<u8>>;
+
+    fn deserialize(path: &Path) -> Option<Module>;
+
+    fn new_linker() -> Linker<HostState>;
+
+    fn link(linker: Linker<HostState>, module: &Module) -> Result<LinkedModule>;
+
+    fn instantiate(
+        store: &mut Store<HostState>,
+        module: &Module,
+        linked: &LinkedModule,
+    ) -> Result<Instance>;
+
+    // Interrupts the module once it runs past the deadline (from now on), if any
+    fn set_deadline(store: &mut Store<HostState>, timeout: Option<Duration>);
+
+    // Samples the stack of the module into HostState::samples at every epoch
+    fn set_sampling(store: &mut Store<HostState>, sampling: bool);
+
+    // Readable description of the trap behind a failed call (e.g. unreachable executed, out of
+    // fuel), with the wasm backtrace where the engine provides one, None for other errors
+    fn describe_trap(error: &anyhow::Error) -> Option<String>;
+
+    // Instantiates the module with the host functions defined by `define`, which is only called
+    // when the linked module is not cached yet (shared ones must not depend on the Tester)
+    fn instantiate_shared(
+        store: &mut Store<HostState>,
+        loaded: &LoadedModule,
+        define: impl FnOnce(&mut Linker<HostState>) -> Result<()>,
+    ) -> Result<Instance> {
+        let cached = MODULE_CACHE
+            .lock()
+            .unwrap()
+            .linked
+            .get(&loaded.key)
+            .cloned();
+        let linked = match cached {
+            Some(linked) => linked,
+            None => {
+                let mut linker = Self::new_linker();
+                define(&mut linker)?;
+                let linked = Self::link(linker, &loaded.module)?;
+                MODULE_CACHE
+                    .lock()
+                    .unwrap()
+                    .linked
+                    .insert(loaded.key, linked.clone());
+                linked
+            }
+        };
+        Self::instantiate(store, &loaded.module, &linked)
+    }
+
+    // Instantiates the module with host functions specific to this instance
+    fn instantiate_with(
+        store: &mut Store<HostState>,
+        loaded: &LoadedModule,
+        define: impl FnOnce(&mut Linker<HostState>) -> Result<()>,
+    ) -> Result<Instance> {
+        let mut linker = Self::new_linker();
+        define(&mut linker)?;
+        let linked = Self::link(linker, &loaded.module)?;
+        Self::instantiate(store, &loaded.module, &linked)
+    }
+
+    fn load_module(wasm_path: &str) -> Result<LoadedModule> {
+        let wasm = fs::read(wasm_path)?;
+        Self::load_module_bytes(&wasm)
+    }
+
+    // Module in the binary or (both engines) the text format, cached by contents as files are
+    fn load_module_bytes(wasm: &[u8]) -> Result<LoadedModule> {
+        let key = module_key(wasm);
+        let cache_dir = {
+            let cache = MODULE_CACHE.lock().unwrap();
+            if let Some(module) = cache.modules.get(&key) {
+                let module = module.clone();
+                return Ok(LoadedModule { module, key });
+            }
+            cache.cache_dir.clone()
+        };
+
+        let cached_path =
+            cache_dir.map(|dir| dir.join(format!("{}-{:016x}.cwasm", Self::name(), key)));
+        let module = match cached_path.as_deref().and_then(Self::deserialize) {
+            Some(module) => module,
+            None => {
+                let module = Self::compile(wasm)?;
+                if let (Some(path), Some(serialized)) = (&cached_path, Self::serialize(&module)) {
+                    // written aside and renamed so that concurrent tests never read a partial file
+                    let partial_path = path.with_extension(format!("{}.tmp", std::process::id()));
+                    fs::create_dir_all(path.parent().unwrap())?;
+                    fs::write(&partial_path, serialized)?;
+                    fs::rename(&partial_path, path)?;
+                }
+                module
+            }
+        };
+        MODULE_CACHE
+            .lock()
+            .unwrap()
+            .modules
+            .insert(key, module.clone());
+        Ok(LoadedModule { module, key })
+    }
+}
+
+#[cfg(not(feature = "wasmi"))]
+lazy_static! {
+    static ref WASMTIME_ENGINE: wasmtime::Engine = {
+        let mut config = wasmtime::Config::new();
+        // engine-wide, so the compiled code of every module pays for the fuel accounting
+        config.consume_fuel(true);
+        config.epoch_interruption(true);
+        wasmtime::Engine::new(&config).unwrap()
+    };
+}
+
+// Period of the epochs checked against the deadlines (and of the stack samples), advanced by a
+// background thread started with the first deadline or sampling Tester
+#[cfg(not(feature = "wasmi"))]
+const EPOCH_PERIOD: Duration = Duration::from_millis(1);
+
+// Epochs only advance while a deadline is in use, so this is never reached (unlike u64::MAX,
+// it does not overflow when added to the current epoch)
+#[cfg(not(feature = "wasmi"))]
+const NO_DEADLINE: u64 = u64::MAX / 2;
+
+#[cfg(not(feature = "wasmi"))]
+fn start_epoch_ticker() {
+    static TICKER: std::sync::Once = std::sync::Once::new();
+    TICKER.call_once(|| {
+        std::thread::spawn(|| loop {
+            std::thread::sleep(EPOCH_PERIOD);
+            WASMTIME_ENGINE.increment_epoch();
+        });
+    });
+}
+
+#[cfg(not(feature = "wasmi"))]
+pub struct Wasmtime;
+
+#[cfg(not(feature = "wasmi"))]
+impl Runtime for Wasmtime {
+    fn name() -> &'static str {
+        "wasmtime"
+    }
+
+    fn new_store(state: HostState) -> Store<HostState> {
+        let mut store = Store::new(&WASMTIME_ENGINE, state);
+        store.set_fuel(u64::MAX).unwrap();
+        store.limiter(|state| &mut state.limits);
+        store.set_epoch_deadline(NO_DEADLINE);
+        store
+    }
+
+    fn compile(wasm: &[u8]) -> Result<Module> {
+        Module::new(&WASMTIME_ENGINE, wasm)
+    }
+
+    fn serialize(module: &Module) -> Option<Vec<u8>> {
+        module.serialize().ok()
+    }
+
+    fn deserialize(path: &Path) -> Option<Module> {
+        // safe as long as the cache directory is only written by this framework, entries
+        // compiled by an incompatible wasmtime version are rejected (and recompiled)
+        unsafe { Module::deserialize_file(&WASMTIME_ENGINE, path).ok() }
+    }
+
+    fn new_linker() -> Linker<HostState> {
+        let mut linker = Linker::new(&WASMTIME_ENGINE);
+        // modules may import the same function more than once
+        linker.allow_shadowing(true);
+        linker
+    }
+
+    fn link(linker: Linker<HostState>, module: &Module) -> Result<LinkedModule> {
+        linker.instantiate_pre(module)
+    }
+
+    fn instantiate(
+        store: &mut Store<HostState>,
+        _module: &Module,
+        linked: &LinkedModule,
+    ) -> Result<Instance> {
+        linked.instantiate(store)
+    }
+
+    fn set_deadline(store: &mut Store<HostState>, timeout: Option<Duration>) {
+        if store.data().samples.lock().unwrap().is_some() {
+            // the sampler runs at every epoch and interrupts the module itself
+            store.data_mut().deadline = timeout.map(|timeout| Instant::now() + timeout);
+            store.set_epoch_deadline(1);
+            return;
+        }
+        let ticks = match timeout {
+            Some(timeout) => {
+                start_epoch_ticker();
+                // rounded up, plus one as the current epoch may be about to end, so that the
+                // module is never interrupted before the deadline
+                let period = EPOCH_PERIOD.as_nanos();
+                timeout.as_nanos().div_ceil(period) as u64 + 1
+            }
+            None => NO_DEADLINE,
+        };
+        store.set_epoch_deadline(ticks);
+    }
+
+    fn set_sampling(store: &mut Store<HostState>, sampling: bool) {
+        *store.data().samples.lock().unwrap() = match sampling {
+            true => Some(StackSamples::default()),
+            false => None,
+        };
+        if sampling {
+            start_epoch_ticker();
+            store.epoch_deadline_callback(sample_stack);
+            store.set_epoch_deadline(1);
+        } else {
+            store.data_mut().deadline = None;
+            store.epoch_deadline_trap();
+            store.set_epoch_deadline(NO_DEADLINE);
+        }
+    }
+
+    fn describe_trap(error: &anyhow::Error) -> Option<String> {
+        let trap = error.downcast_ref::<wasmtime::Trap>()?;
+        let mut description = trap.to_string();
+        if let Some(backtrace) = error.downcast_ref::<wasmtime::WasmBacktrace>() {
+            description.push_str("\nwasm backtrace:");
+            for (index, frame) in backtrace.frames().iter().enumerate() {
+                let name = frame_name(frame);
+                description.push_str(&format!("\n  {:>2}: {}", index, name));
+                for symbol in frame.symbols() {
+                    if let (Some(file), Some(line)) = (symbol.file(), symbol.line()) {
+                        description.push_str(&format!("\n        at {}:{}", file, line));
+                    }
+                }
+            }
+        }
+        Some(description)
+    }
+}
+
+// Names come from the name section, without one only the index is known
+#[cfg(not(feature = "wasmi"))]
+fn frame_name(frame: &wasmtime::FrameInfo) -> String {
+    match frame.func_name() {
+        Some(name) => format!("{:#}", rustc_demangle::demangle(name)),
+        None => format!("<wasm function {}>", frame.func_index()),
+    }
+}
+
+// Records the stack of the module (outermost frame first), then interrupts it past the
+// deadline of the callback as the engine would
+#[cfg(not(feature = "wasmi"))]
+fn sample_stack(context: wasmtime::StoreContextMut<HostState>) -> Result<wasmtime::UpdateDeadline> {
+    let backtrace = wasmtime::WasmBacktrace::capture(&context);
+    let stack = backtrace.frames().iter().rev().map(frame_name).collect();
+    if let Some(samples) = context.data().samples.lock().unwrap().as_mut() {
+        samples.record(stack);
+    }
+    match context.data().deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(wasmtime::Trap::Interrupt.into()),
+        _ => Ok(wasmtime::UpdateDeadline::Continue(1)),
+    }
+}
+
+#[cfg(feature = "wasmi")]
+lazy_static! {
+    static ref WASMI_ENGINE: wasmi::Engine = {
+        let mut config = wasmi::Config::default();
+        // engine-wide, so the compiled code of every module pays for the fuel accounting
+        config.consume_fuel(true);
+        wasmi::Engine::new(&config)
+    };
+}
+
+#[cfg(feature = "wasmi")]
+pub struct Wasmi;
+
+#[cfg(feature = "wasmi")]
+impl Runtime for Wasmi {
+    fn name() -> &'static str {
+        "wasmi"
+    }
+
+    fn new_store(state: HostState) -> Store<HostState> {
+        let mut store = Store::new(&WASMI_ENGINE, state);
+        store.set_fuel(u64::MAX).unwrap();
+        store.limiter(|state| &mut state.limits);
+        store
+    }
+
+    fn compile(wasm: &[u8]) -> Result<Module> {
+        // unlike wasmtime, wasmi only accepts the binary format
+        let wasm = wat::parse_bytes(wasm)?;
+        Ok(Module::new(&WASMI_ENGINE, &wasm[..])?)
+    }
+
+    fn serialize(_module: &Module) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn deserialize(_path: &Path) -> Option<Module> {
+        None
+    }
+
+    fn new_linker() -> Linker<HostState> {
+        let mut linker = Linker::new(&WASMI_ENGINE);
+        // modules may import the same function more than once
+        linker.allow_shadowing(true);
+        linker
+    }
+
+    fn link(linker: Linker<HostState>, _module: &Module) -> Result<LinkedModule> {
+        Ok(linker)
+    }
+
+    fn instantiate(
+        store: &mut Store<HostState>,
+        module: &Module,
+        linked: &LinkedModule,
+    ) -> Result<Instance> {
+        Ok(linked.instantiate(&mut *store, module)?.start(store)?)
+    }
+
+    fn set_deadline(_store: &mut Store<HostState>, _timeout: Option<Duration>) {
+        // wasmi has no epoch interruption, runaway modules can only be stopped by fuel
+    }
+
+    fn set_sampling(_store: &mut Store<HostState>, _sampling: bool) {
+        // nor a way to interrupt the module and inspect its stack, nothing is ever sampled
+    }
+
+    fn describe_trap(error: &anyhow::Error) -> Option<String> {
+        // the interpreter keeps no backtrace, only the cause of the trap
+        let trap = error.downcast_ref::<wasmi::Error>()?.as_trap_code()?;
+        Some(format!("wasm trap: {}", trap))
+    }
+}
+
+// Engine selected by the enabled features
+#[cfg(not(feature = "wasmi"))]
+pub type Engine = Wasmtime;
+
+#[cfg(feature = "wasmi")]
+pub type Engine = Wasmi;
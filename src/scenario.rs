@@ -0,0 +1,738 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Test scenarios described in YAML (or JSON) files, so that filters can be covered without
+// writing Rust: the plugin configuration, mock upstreams and a list of requests, each with the
+// host calls expected from every callback and the action it should return, e.g.
+//
+//   wasm: target/wasm32-wasip1/release/my_filter.wasm
+//   plugin_config: '{"header": "x-tenant"}'
+//   upstreams:
+//     - name: auth
+//       status: 200
+//       headers: { ":status": "200" }
+//   requests:
+//     - name: adds the tenant header
+//       headers: { ":method": GET, ":path": /, x-tenant: acme }
+//       expect:
+//         request_headers:
+//           action: continue
+//           calls:
+//             - log: { level: info, message: "tenant acme" }
+//             - add_header: { map: request_headers, name: x-tenant-seen, value: "true" }
+//
+// Omitted fields of an expected call match anything. Run with the proxy-wasm-test binary.
+
+use crate::tester::{self, MockSettings, Tester};
+use crate::trace::diff_lines;
+use crate::types::*;
+
+use anyhow::{bail, Context, Result};
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const ROOT_CONTEXT: i32 = 1;
+
+// Clock and seed of both runs of a differential test, so that time and randomness cannot tell
+// the builds apart
+const DIFF_TIME_NANOS: u64 = 1_600_000_000_000_000_000;
+const DIFF_SEED: u64 = 0;
+
+// Request whose host call trace differs between two builds of a module (see Scenario::diff),
+// "(setup)" for the configuration of the plugin
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub request: String,
+    // - baseline, + candidate
+    pub diff: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scenario {
+    // relative to the scenario file, overridden by the runner's --wasm
+    pub wasm: Option<String>,
+    pub quiet: bool,
+    #[serde(default = "default_true")]
+    pub allow_unexpected: bool,
+    pub vm_config: String,
+    pub plugin_config: String,
+    pub upstreams: Vec<Upstream>,
+    // host calls expected while starting the VM and configuring the plugin
+    pub configure: Vec<ExpectedCall>,
+    pub requests: Vec<Request>,
+    #[serde(skip)]
+    base_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Upstream {
+    // cluster or authority, as passed to http_call
+    pub name: String,
+    #[serde(default = "default_status")]
+    pub status: u32,
+    #[serde(default)]
+    pub headers: Headers,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub trailers: Headers,
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Request {
+    pub name: String,
+    pub headers: Headers,
+    pub body: Option<String>,
+    pub trailers: Option<Headers>,
+    pub response: Option<Response>,
+    pub expect: Expectations,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Response {
+    pub headers: Headers,
+    pub body: Option<String>,
+    pub trailers: Option<Headers>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Expectations {
+    pub request_headers: Phase,
+    pub request_body: Phase,
+    pub request_trailers: Phase,
+    pub response_headers: Phase,
+    pub response_body: Phase,
+    pub response_trailers: Phase,
+    // host calls expected from proxy_on_log, called once the request is complete
+    pub log: Vec<ExpectedCall>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Phase {
+    pub action: PhaseAction,
+    pub calls: Vec<ExpectedCall>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PhaseAction {
+    #[default]
+    Continue,
+    Pause,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ExpectedCall {
+    Log {
+        level: Option<Level>,
+        message: Option<String>,
+    },
+    AddHeader {
+        map: Option<HeaderMap>,
+        name: Option<String>,
+        value: Option<String>,
+    },
+    ReplaceHeader {
+        map: Option<HeaderMap>,
+        name: Option<String>,
+        value: Option<String>,
+    },
+    RemoveHeader {
+        map: Option<HeaderMap>,
+        name: Option<String>,
+    },
+    SetBody {
+        buffer: Option<Buffer>,
+        data: Option<String>,
+    },
+    SendLocalResponse {
+        status: Option<i32>,
+        body: Option<String>,
+        headers: Option<Headers>,
+        grpc_status: Option<i32>,
+    },
+    HttpCall {
+        upstream: Option<String>,
+        headers: Option<Headers>,
+        body: Option<String>,
+        trailers: Option<Headers>,
+        timeout_ms: Option<u64>,
+    },
+    SetTickPeriod {
+        millis: Option<u64>,
+    },
+    IncrementMetric {
+        name: String,
+        offset: Option<i64>,
+    },
+    RecordMetric {
+        name: String,
+        value: Option<u64>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderMap {
+    RequestHeaders,
+    RequestTrailers,
+    ResponseHeaders,
+    ResponseTrailers,
+    HttpCallResponseHeaders,
+    HttpCallResponseTrailers,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Buffer {
+    RequestBody,
+    ResponseBody,
+    HttpCallResponseBody,
+}
+
+// Header pairs in order, written either as a map ({name: value}) or as a list of
+// [name, value] pairs when a name repeats; numbers and booleans are taken as text
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers(pub Vec<(String, String)>);
+
+impl Headers {
+    fn pairs(&self) -> Vec<(&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+}
+
+impl<'de> Deserialize<'de> for Headers {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Headers, D::Error> {
+        struct HeadersVisitor;
+
+        impl<'de> Visitor<'de> for HeadersVisitor {
+            type Value = Headers;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of header names to values, or a list of [name, value]")
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<Headers, E> {
+                Ok(Headers::default())
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Headers, A::Error> {
+                let mut pairs = Vec::new();
+                while let Some((name, value)) = map.next_entry::<Text, Text>()? {
+                    pairs.push((name.0, value.0));
+                }
+                Ok(Headers(pairs))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Headers, A::Error> {
+                let mut pairs = Vec::new();
+                while let Some((name, value)) = seq.next_element::<(Text, Text)>()? {
+                    pairs.push((name.0, value.0));
+                }
+                Ok(Headers(pairs))
+            }
+        }
+
+        deserializer.deserialize_any(HeadersVisitor)
+    }
+}
+
+// A header name or value, e.g. `:status: 200` without quoting the number
+struct Text(String);
+
+impl<'de> Deserialize<'de> for Text {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Text, D::Error> {
+        match serde_yaml::Value::deserialize(deserializer)? {
+            serde_yaml::Value::String(text) => Ok(Text(text)),
+            serde_yaml::Value::Number(number) => Ok(Text(number.to_string())),
+            serde_yaml::Value::Bool(flag) => Ok(Text(flag.to_string())),
+            other => Err(de::Error::custom(format!(
+                "expected a string for a header, got {:?}",
+                other
+            ))),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_status() -> u32 {
+    200
+}
+
+impl Scenario {
+    // YAML or JSON (JSON being valid YAML)
+    pub fn load(path: impl AsRef<Path>) -> Result<Scenario> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("cannot read scenario {}", path.display()))?;
+        let mut scenario = Scenario::parse(&text)
+            .with_context(|| format!("invalid scenario {}", path.display()))?;
+        scenario.base_dir = path.parent().map(Path::to_path_buf);
+        Ok(scenario)
+    }
+
+    pub fn parse(text: &str) -> Result<Scenario> {
+        // expected calls are written as `- log: {...}` rather than as YAML tags (`- !log {...}`)
+        let deserializer = serde_yaml::Deserializer::from_str(text);
+        Ok(serde_yaml::with::singleton_map_recursive::deserialize(
+            deserializer,
+        )?)
+    }
+
+    fn wasm_path(&self) -> Result<String> {
+        let wasm = match &self.wasm {
+            Some(wasm) => Path::new(wasm),
+            None => bail!("Error: scenario does not name a wasm module (wasm: <path>)"),
+        };
+        let path = match &self.base_dir {
+            Some(base_dir) if wasm.is_relative() => base_dir.join(wasm),
+            _ => wasm.to_path_buf(),
+        };
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    // Runs the scenario against a fresh Tester: start and configure the plugin, then every
+    // request on its own HTTP context, one callback (and its expectations) at a time
+    pub fn run(&self) -> Result<()> {
+        let mut tester = self.start()?;
+        for index in 0..self.requests.len() {
+            self.run_request(&mut tester, index)?;
+        }
+        Ok(())
+    }
+
+    // Differential test of two builds of the module (e.g. before and after an upgrade): runs the
+    // requests against both, every host call allowed and no expectation checked, and returns
+    // the requests (in order) whose host call traces differ
+    pub fn diff(&self, baseline: &str, candidate: &str) -> Result<Vec<Divergence>> {
+        let baseline = self.record(baseline)?;
+        let candidate = self.record(candidate)?;
+        let not_run = (String::new(), "not run\n".to_string());
+        Ok((0..baseline.len().max(candidate.len()))
+            .filter_map(|index| {
+                let (request, baseline) = baseline.get(index).unwrap_or(&not_run);
+                let (other, candidate) = candidate.get(index).unwrap_or(&not_run);
+                if baseline == candidate {
+                    return None;
+                }
+                Some(Divergence {
+                    request: if request.is_empty() { other } else { request }.clone(),
+                    diff: diff_lines(baseline, candidate),
+                })
+            })
+            .collect())
+    }
+
+    // Traces of the setup and of every request run against the module, as text; a run failing
+    // (e.g. trapping) ends with the error, and the requests after it are not run
+    fn record(&self, wasm_path: &str) -> Result<Vec<(String, String)>> {
+        let mut scenario = self.clone();
+        scenario.wasm = Some(wasm_path.to_string());
+        scenario.base_dir = None;
+        let mut traces = Vec::new();
+        let mut tester = match scenario.start_with(true) {
+            Ok(mut tester) => {
+                traces.push(("(setup)".to_string(), take_trace(&mut tester, None)));
+                tester
+            }
+            Err(error) => {
+                traces.push(("(setup)".to_string(), format!("error: {:#}", error)));
+                return Ok(traces);
+            }
+        };
+        for (index, request) in self.requests.iter().enumerate() {
+            let context_id = ROOT_CONTEXT + 1 + index as i32;
+            tester.record_trace();
+            let result = request.run(&mut tester, context_id, true);
+            let error = result.as_ref().err();
+            traces.push((request.name.clone(), take_trace(&mut tester, error)));
+            if error.is_some() {
+                break;
+            }
+        }
+        Ok(traces)
+    }
+
+    // Starts and configures the plugin on a fresh Tester, ready for run_request
+    pub fn start(&self) -> Result<Tester> {
+        self.start_with(false)
+    }
+
+    // Observing rather than checking: host calls allowed, expectations and actions ignored, the
+    // clock and seed pinned and the trace recorded
+    pub(crate) fn start_with(&self, observe: bool) -> Result<Tester> {
+        let mut tester = tester::mock(MockSettings {
+            wasm_path: self.wasm_path()?,
+            quiet: self.quiet,
+            allow_unexpected: self.allow_unexpected || observe,
+        })?;
+        if observe {
+            tester
+                .set_default_current_time_nanos(DIFF_TIME_NANOS)
+                .set_seed(DIFF_SEED)
+                .record_trace();
+        }
+
+        for upstream in &self.upstreams {
+            tester
+                .set_mock_upstream(&upstream.name)
+                .with_delay_millis(upstream.delay_ms)
+                .returning(
+                    upstream.status,
+                    upstream.headers.pairs(),
+                    upstream.body.as_deref(),
+                    upstream.trailers.pairs(),
+                );
+        }
+
+        tester.call_start().execute_and_expect(ReturnType::None)?;
+        tester
+            .call_proxy_on_context_create(ROOT_CONTEXT, 0)
+            .execute_and_expect(ReturnType::None)?;
+
+        tester
+            .set_default_buffer_bytes(BufferType::VmConfiguration)
+            .returning(&self.vm_config)
+            .set_default_buffer_bytes(BufferType::PluginConfiguration)
+            .returning(&self.plugin_config)
+            .call_proxy_on_vm_start(ROOT_CONTEXT, self.vm_config.len() as i32)
+            .call_proxy_on_configure(ROOT_CONTEXT, self.plugin_config.len() as i32);
+        if observe {
+            tester.execute_all()?;
+            return Ok(tester);
+        }
+        stage(&mut tester, &self.configure)?;
+        tester.execute_and_expect_n(vec![ReturnType::Bool(true), ReturnType::Bool(true)])?;
+        Ok(tester)
+    }
+
+    // Runs requests[index] on its own HTTP context of a started Tester
+    pub fn run_request(&self, tester: &mut Tester, index: usize) -> Result<()> {
+        let request = &self.requests[index];
+        let context_id = ROOT_CONTEXT + 1 + index as i32;
+        request
+            .run(tester, context_id, false)
+            .with_context(|| format!("request {:?} (#{})", request.name, index + 1))
+    }
+}
+
+impl Request {
+    pub(crate) fn run(&self, tester: &mut Tester, context_id: i32, observe: bool) -> Result<()> {
+        tester
+            .call_proxy_on_context_create(context_id, ROOT_CONTEXT)
+            .execute_and_expect(ReturnType::None)?;
+
+        let expect = &self.expect;
+        let body = self.body.as_deref();
+        let headers = tester.with_content_length(self.headers.pairs(), body.map(str::as_bytes));
+        let end_of_stream = body.is_none() && self.trailers.is_none();
+        tester
+            .set_default_header_map_pairs(MapType::HttpRequestHeaders)
+            .returning(pair_refs(&headers))
+            .call_proxy_on_request_headers(context_id, headers.len() as i32, end_of_stream);
+        expect.request_headers.run(tester, observe)?;
+
+        match body {
+            Some(body) => {
+                tester
+                    .set_default_buffer_bytes(BufferType::HttpRequestBody)
+                    .returning(body)
+                    .call_proxy_on_request_body(
+                        context_id,
+                        body.len() as i32,
+                        self.trailers.is_none(),
+                    );
+                expect.request_body.run(tester, observe)?;
+            }
+            None => expect
+                .request_body
+                .unreachable("request_body", "the request has no body")?,
+        }
+
+        match &self.trailers {
+            Some(trailers) => {
+                tester
+                    .set_default_header_map_pairs(MapType::HttpRequestTrailers)
+                    .returning(trailers.pairs())
+                    .call_proxy_on_request_trailers(context_id, trailers.0.len() as i32);
+                expect.request_trailers.run(tester, observe)?;
+            }
+            None => expect
+                .request_trailers
+                .unreachable("request_trailers", "the request has no trailers")?,
+        }
+
+        match &self.response {
+            Some(response) => response.run(tester, context_id, expect, observe)?,
+            None => {
+                let reason = "the request has no response";
+                expect
+                    .response_headers
+                    .unreachable("response_headers", reason)?;
+                expect.response_body.unreachable("response_body", reason)?;
+                expect
+                    .response_trailers
+                    .unreachable("response_trailers", reason)?;
+            }
+        }
+
+        tester.call_proxy_on_log(context_id);
+        if observe {
+            return tester.execute_all().map(|_| ());
+        }
+        stage(tester, &expect.log)?;
+        tester.execute_and_expect(ReturnType::None)
+    }
+}
+
+impl Response {
+    fn run(
+        &self,
+        tester: &mut Tester,
+        context_id: i32,
+        expect: &Expectations,
+        observe: bool,
+    ) -> Result<()> {
+        let body = self.body.as_deref();
+        let headers = tester.with_content_length(self.headers.pairs(), body.map(str::as_bytes));
+        let end_of_stream = body.is_none() && self.trailers.is_none();
+        tester
+            .set_default_header_map_pairs(MapType::HttpResponseHeaders)
+            .returning(pair_refs(&headers))
+            .call_proxy_on_response_headers(context_id, headers.len() as i32, end_of_stream);
+        expect.response_headers.run(tester, observe)?;
+
+        match body {
+            Some(body) => {
+                tester
+                    .set_default_buffer_bytes(BufferType::HttpResponseBody)
+                    .returning(body)
+                    .call_proxy_on_response_body(
+                        context_id,
+                        body.len() as i32,
+                        self.trailers.is_none(),
+                    );
+                expect.response_body.run(tester, observe)?;
+            }
+            None => expect
+                .response_body
+                .unreachable("response_body", "the response has no body")?,
+        }
+
+        match &self.trailers {
+            Some(trailers) => {
+                tester
+                    .set_default_header_map_pairs(MapType::HttpResponseTrailers)
+                    .returning(trailers.pairs())
+                    .call_proxy_on_response_trailers(context_id, trailers.0.len() as i32);
+                expect.response_trailers.run(tester, observe)
+            }
+            None => expect
+                .response_trailers
+                .unreachable("response_trailers", "the response has no trailers"),
+        }
+    }
+}
+
+impl Phase {
+    // Stages the expected calls for the pending callback and checks the returned action
+    fn run(&self, tester: &mut Tester, observe: bool) -> Result<()> {
+        if observe {
+            return tester.execute_all().map(|_| ());
+        }
+        stage(tester, &self.calls)?;
+        let action = match self.action {
+            PhaseAction::Continue => Action::Continue,
+            PhaseAction::Pause => Action::Pause,
+        };
+        tester.execute_and_expect(ReturnType::Action(action))
+    }
+
+    // Expectations for a callback that is never called are a mistake in the scenario
+    fn unreachable(&self, phase: &str, reason: &str) -> Result<()> {
+        if !self.calls.is_empty() || self.action != PhaseAction::Continue {
+            bail!("Error: expectations on {} but {}", phase, reason);
+        }
+        Ok(())
+    }
+}
+
+fn stage(tester: &mut Tester, calls: &[ExpectedCall]) -> Result<()> {
+    for call in calls {
+        match call {
+            ExpectedCall::Log { level, message } => {
+                tester.expect_log(level.map(LogLevel::from), message.as_deref());
+            }
+            ExpectedCall::AddHeader { map, name, value } => {
+                tester.expect_add_header_map_value(
+                    map.map(MapType::from),
+                    name.as_deref(),
+                    value.as_deref(),
+                );
+            }
+            ExpectedCall::ReplaceHeader { map, name, value } => {
+                tester.expect_replace_header_map_value(
+                    map.map(MapType::from),
+                    name.as_deref(),
+                    value.as_deref(),
+                );
+            }
+            ExpectedCall::RemoveHeader { map, name } => {
+                tester.expect_remove_header_map_value(map.map(MapType::from), name.as_deref());
+            }
+            ExpectedCall::SetBody { buffer, data } => {
+                tester.expect_set_buffer_bytes(buffer.map(BufferType::from), data.as_deref());
+            }
+            ExpectedCall::SendLocalResponse {
+                status,
+                body,
+                headers,
+                grpc_status,
+            } => {
+                tester.expect_send_local_response(
+                    *status,
+                    body.as_deref(),
+                    headers.as_ref().map(Headers::pairs),
+                    *grpc_status,
+                );
+            }
+            ExpectedCall::HttpCall {
+                upstream,
+                headers,
+                body,
+                trailers,
+                timeout_ms,
+            } => {
+                tester
+                    .expect_http_call(
+                        upstream.as_deref(),
+                        headers.as_ref().map(Headers::pairs),
+                        body.as_deref(),
+                        trailers.as_ref().map(Headers::pairs),
+                        *timeout_ms,
+                    )
+                    .returning(None);
+            }
+            ExpectedCall::SetTickPeriod { millis } => {
+                tester.expect_set_tick_period_millis(*millis);
+            }
+            ExpectedCall::IncrementMetric { name, offset } => {
+                check_metric(tester, name)?;
+                tester.expect_metric_increment(name, *offset);
+            }
+            ExpectedCall::RecordMetric { name, value } => {
+                check_metric(tester, name)?;
+                tester.expect_metric_record(name, *value);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Metrics are expected by name, so they must have been defined by the module beforehand
+fn check_metric(tester: &Tester, name: &str) -> Result<()> {
+    if tester
+        .get_settings_handle()
+        .staged
+        .get_metric_by_name(name)
+        .is_none()
+    {
+        bail!(
+            "Error: metric {:?} has not been defined by the module",
+            name
+        );
+    }
+    Ok(())
+}
+
+// Trace recorded since the last call, ending with the error that stopped the run (if any)
+fn take_trace(tester: &mut Tester, error: Option<&anyhow::Error>) -> String {
+    let mut trace = tester.take_trace().to_string();
+    if let Some(error) = error {
+        trace.push_str(&format!("error: {:#}\n", error));
+    }
+    trace
+}
+
+fn pair_refs(pairs: &[(String, String)]) -> Vec<(&str, &str)> {
+    pairs
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect()
+}
+
+impl From<Level> for LogLevel {
+    fn from(level: Level) -> LogLevel {
+        match level {
+            Level::Trace => LogLevel::Trace,
+            Level::Debug => LogLevel::Debug,
+            Level::Info => LogLevel::Info,
+            Level::Warn => LogLevel::Warn,
+            Level::Error => LogLevel::Error,
+            Level::Critical => LogLevel::Critical,
+        }
+    }
+}
+
+impl From<HeaderMap> for MapType {
+    fn from(map: HeaderMap) -> MapType {
+        match map {
+            HeaderMap::RequestHeaders => MapType::HttpRequestHeaders,
+            HeaderMap::RequestTrailers => MapType::HttpRequestTrailers,
+            HeaderMap::ResponseHeaders => MapType::HttpResponseHeaders,
+            HeaderMap::ResponseTrailers => MapType::HttpResponseTrailers,
+            HeaderMap::HttpCallResponseHeaders => MapType::HttpCallResponseHeaders,
+            HeaderMap::HttpCallResponseTrailers => MapType::HttpCallResponseTrailers,
+        }
+    }
+}
+
+impl From<Buffer> for BufferType {
+    fn from(buffer: Buffer) -> BufferType {
+        match buffer {
+            Buffer::RequestBody => BufferType::HttpRequestBody,
+            Buffer::ResponseBody => BufferType::HttpResponseBody,
+            Buffer::HttpCallResponseBody => BufferType::HttpCallResponseBody,
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// JSON schema of the plugin configuration, checked before proxy_on_configure runs so that a
+// misconfigured test fails on the configuration rather than on whatever the module makes of it,
+// e.g.
+//
+//   let setup = TestSetup::new(wasm_path)
+//       .plugin_config(r#"{"upstream": "auth", "timeout_ms": "250"}"#)
+//       .config_schema(ConfigSchema::load("config.schema.json")?);
+//
+// fails to start with
+//
+//   Error: ProxyOnConfigure(1, 41) not run, the plugin configuration violates its schema:
+//     at /timeout_ms: "250" is not of type "integer"
+//
+// An empty configuration is validated as null.
+
+use anyhow::{bail, Context, Result};
+use jsonschema::Validator;
+use serde_json::Value;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct ConfigSchema {
+    validator: Arc<Validator>,
+}
+
+impl ConfigSchema {
+    pub fn load(path: impl AsRef<Path>) -> Result<ConfigSchema> {
+        let path = path.as_ref();
+        let schema = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read schema {}", path.display()))?;
+        ConfigSchema::parse(&schema).with_context(|| format!("invalid schema {}", path.display()))
+    }
+
+    pub fn parse(schema: &str) -> Result<ConfigSchema> {
+        let schema: Value = serde_json::from_str(schema).context("schema is not JSON")?;
+        let validator = match jsonschema::validator_for(&schema) {
+            Ok(validator) => validator,
+            Err(error) => bail!("{}", error),
+        };
+        Ok(ConfigSchema {
+            validator: Arc::new(validator),
+        })
+    }
+
+    // Every violation of the schema by the configuration, with its location
+    pub fn validate(&self, config: &[u8]) -> Result<()> {
+        let config: Value = match config.is_empty() {
+            true => Value::Null,
+            false => match serde_json::from_slice(config) {
+                Ok(config) => config,
+                Err(error) => bail!("the plugin configuration is not JSON: {}", error),
+            },
+        };
+        let violations: Vec<String> = self
+            .validator
+            .iter_errors(&config)
+            .map(|error| match error.instance_path.to_string() {
+                path if path.is_empty() => format!("  at the root: {}", error),
+                path => format!("  at {}: {}", path, error),
+            })
+            .collect();
+        if !violations.is_empty() {
+            bail!(
+                "the plugin configuration violates its schema:\n{}",
+                violations.join("\n")
+            );
+        }
+        Ok(())
+    }
+}
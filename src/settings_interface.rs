@@ -12,7 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::compression::{encode, Encoding};
+use crate::host_settings::{
+    grpc_trailers_only_headers, set_content_length, GrpcReply, MockGrpcRule, MockResponse,
+};
+use crate::http::HeaderMap;
 use crate::tester::Tester;
+use crate::types::{GrpcStatus, UpstreamFault};
+
+use std::marker::PhantomData;
+use std::sync::Arc;
 
 pub struct DefaultBufferBytes<'a> {
     tester: &'a mut Tester,
@@ -34,6 +43,24 @@ impl<'a> DefaultBufferBytes<'a> {
             .set_buffer_bytes(self.buffer_type, buffer_data);
         self.tester
     }
+
+    // Binary (non-UTF-8) buffer data, e.g. an encoded protobuf message
+    pub fn returning_bytes(&mut self, buffer_data: &[u8]) -> &mut Tester {
+        self.tester
+            .get_settings_handle()
+            .staged
+            .set_buffer_data(self.buffer_type, buffer_data.to_vec());
+        self.tester
+    }
+
+    // The buffer holds the data compressed with the given content coding
+    pub fn returning_compressed(&mut self, encoding: Encoding, buffer_data: &str) -> &mut Tester {
+        self.tester
+            .get_settings_handle()
+            .staged
+            .set_buffer_data(self.buffer_type, encode(encoding, buffer_data.as_bytes()));
+        self.tester
+    }
 }
 
 pub struct DefaultHeaderMapPairs<'a> {
@@ -49,11 +76,304 @@ impl<'a> DefaultHeaderMapPairs<'a> {
         }
     }
 
-    pub fn returning(&mut self, header_map_pairs: Vec<(&str, &str)>) -> &mut Tester {
+    pub fn returning(&mut self, header_map_pairs: impl Into<HeaderMap>) -> &mut Tester {
         self.tester
             .get_settings_handle()
             .staged
-            .set_header_map_pairs(self.map_type, header_map_pairs);
+            .set_header_map_pairs(self.map_type, header_map_pairs.into().pairs());
+        self.tester
+    }
+
+    // Header values given as raw bytes, which need not be valid UTF-8
+    pub fn returning_bytes(&mut self, header_map_pairs: Vec<(&str, &[u8])>) -> &mut Tester {
+        self.tester
+            .get_settings_handle()
+            .staged
+            .set_header_map_data(
+                self.map_type,
+                header_map_pairs
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value.to_vec()))
+                    .collect(),
+            );
+        self.tester
+    }
+}
+
+pub struct MockUpstream<'a> {
+    tester: &'a mut Tester,
+    upstream: String,
+    delay_millis: u64,
+    faults: Vec<(UpstreamFault, u32)>,
+    encoding: Option<Encoding>,
+}
+
+impl<'a> MockUpstream<'a> {
+    pub fn expecting(tester: &'a mut Tester, upstream: &str) -> MockUpstream<'a> {
+        MockUpstream {
+            tester: tester,
+            upstream: upstream.to_string(),
+            delay_millis: 0,
+            faults: vec![],
+            encoding: None,
+        }
+    }
+
+    // The body is compressed with the given coding and labelled with content-encoding
+    pub fn with_content_encoding(&mut self, encoding: Encoding) -> &mut Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    // Fails the given percentage of calls with the fault, drawn from the seeded fault RNG (see
+    // Tester::set_fault_seed) so that runs are deterministic
+    pub fn with_fault(&mut self, fault: UpstreamFault, percent: u32) -> &mut Self {
+        self.faults.push((fault, percent));
+        assert!(
+            self.faults.iter().map(|(_, percent)| percent).sum::<u32>() <= 100,
+            "Error: fault percentages of upstream \"{}\" exceed 100",
+            self.upstream
+        );
+        self
+    }
+
+    // Virtual latency: the response is only delivered once the host time has advanced this far
+    pub fn with_delay_millis(&mut self, delay_millis: u64) -> &mut Self {
+        self.delay_millis = delay_millis;
+        self
+    }
+
+    pub fn returning(
+        &mut self,
+        status_code: u32,
+        headers: impl Into<HeaderMap>,
+        body: Option<&str>,
+        trailers: impl Into<HeaderMap>,
+    ) -> &mut Tester {
+        self.returning_bytes(status_code, headers, body.map(str::as_bytes), trailers)
+    }
+
+    // Same as returning() for a binary (non-UTF-8) body
+    pub fn returning_bytes(
+        &mut self,
+        status_code: u32,
+        headers: impl Into<HeaderMap>,
+        body: Option<&[u8]>,
+        trailers: impl Into<HeaderMap>,
+    ) -> &mut Tester {
+        let mut response_headers = vec![(":status".to_string(), status_code.to_string())];
+        response_headers.extend(headers.into().into_pairs());
+        let body = body.unwrap_or_default();
+        let body = match self.encoding {
+            Some(encoding) => {
+                response_headers
+                    .push(("content-encoding".to_string(), encoding.name().to_string()));
+                encode(encoding, body)
+            }
+            None => body.to_vec(),
+        };
+        if self.tester.auto_content_length() {
+            set_content_length(&mut response_headers, body.len());
+        }
+        let response = MockResponse {
+            headers: response_headers,
+            body,
+            trailers: trailers.into().into_pairs(),
+            delay_millis: self.delay_millis,
+            faults: self.faults.clone(),
+        };
+        self.tester
+            .get_settings_handle()
+            .staged
+            .set_mock_upstream(&self.upstream, response);
+        self.tester
+    }
+
+    // gRPC trailers-only response: grpc-status (and grpc-message) in the headers frame, with
+    // neither a body nor trailers
+    pub fn returning_grpc_trailers_only(
+        &mut self,
+        status: GrpcStatus,
+        message: &str,
+    ) -> &mut Tester {
+        let mut response_headers = vec![(":status".to_string(), "200".to_string())];
+        response_headers.extend(grpc_trailers_only_headers(status, message));
+        let response = MockResponse {
+            headers: response_headers,
+            body: vec![],
+            trailers: vec![],
+            delay_millis: self.delay_millis,
+            faults: self.faults.clone(),
+        };
+        self.tester
+            .get_settings_handle()
+            .staged
+            .set_mock_upstream(&self.upstream, response);
+        self.tester
+    }
+}
+
+// Populates the TLS attributes of the emulated downstream connection (see Tester::set_tls_connection)
+pub struct TlsConnection<'a> {
+    tester: &'a mut Tester,
+}
+
+impl<'a> TlsConnection<'a> {
+    pub fn expecting(tester: &'a mut Tester) -> TlsConnection<'a> {
+        let mut tls_connection = TlsConnection { tester };
+        tls_connection
+            .set(vec!["connection", "tls_version"], "TLSv1.3")
+            .set(vec!["request", "scheme"], "https");
+        tls_connection
+    }
+
+    fn set(&mut self, path: Vec<&str>, value: &str) -> &mut Self {
+        self.tester
+            .get_settings_handle()
+            .staged
+            .set_property(&path, value.as_bytes().to_vec());
+        self
+    }
+
+    pub fn with_tls_version(&mut self, tls_version: &str) -> &mut Self {
+        self.set(vec!["connection", "tls_version"], tls_version)
+    }
+
+    pub fn with_sni(&mut self, server_name: &str) -> &mut Self {
+        self.set(vec!["connection", "requested_server_name"], server_name)
+    }
+
+    // Envoy does not expose ALPN directly, the negotiated protocol shows up as request.protocol
+    pub fn with_alpn(&mut self, alpn: &str) -> &mut Self {
+        let protocol = match alpn {
+            "h2" => "HTTP/2",
+            "h3" => "HTTP/3",
+            "http/1.0" => "HTTP/1.0",
+            _ => "HTTP/1.1",
+        };
+        self.set(vec!["request", "protocol"], protocol)
+    }
+
+    pub fn with_local_certificate(&mut self, subject: &str) -> &mut Self {
+        self.set(vec!["connection", "subject_local_certificate"], subject)
+    }
+
+    // A peer certificate implies mutual TLS, unless overridden with with_mtls(false)
+    pub fn with_peer_certificate(&mut self, subject: &str) -> &mut Self {
+        self.set(vec!["connection", "subject_peer_certificate"], subject)
+            .with_mtls(true)
+    }
+
+    pub fn with_peer_uri_san(&mut self, uri_san: &str) -> &mut Self {
+        self.set(vec!["connection", "uri_san_peer_certificate"], uri_san)
+    }
+
+    pub fn with_peer_dns_san(&mut self, dns_san: &str) -> &mut Self {
+        self.set(vec!["connection", "dns_san_peer_certificate"], dns_san)
+    }
+
+    pub fn with_peer_certificate_digest(&mut self, sha256_digest: &str) -> &mut Self {
+        self.set(
+            vec!["connection", "sha256_peer_certificate_digest"],
+            sha256_digest,
+        )
+    }
+
+    pub fn with_mtls(&mut self, mtls: bool) -> &mut Self {
+        self.tester
+            .get_settings_handle()
+            .staged
+            .set_property(&["connection", "mtls"], vec![mtls as u8]);
+        self
+    }
+}
+
+type GrpcRequestMatcher<Req> = Arc<dyn Fn(&Req) -> bool + Send + Sync>;
+
+// Rule of a mock gRPC method (see Tester::set_mock_grpc_service), requests are decoded as Req
+// and matched on their fields, replies are encoded and delivered via the gRPC callbacks
+pub struct MockGrpcService<'a, Req> {
+    tester: &'a mut Tester,
+    service: String,
+    method: String,
+    matcher: Option<GrpcRequestMatcher<Req>>,
+    delay_millis: u64,
+    request: PhantomData<Req>,
+}
+
+impl<'a, Req> MockGrpcService<'a, Req>
+where
+    Req: prost::Message + Default + 'static,
+{
+    pub fn expecting(
+        tester: &'a mut Tester,
+        service: &str,
+        method: &str,
+    ) -> MockGrpcService<'a, Req> {
+        MockGrpcService {
+            tester,
+            service: service.to_string(),
+            method: method.to_string(),
+            matcher: None,
+            delay_millis: 0,
+            request: PhantomData,
+        }
+    }
+
+    // Only requests for which the predicate holds are answered by this rule
+    pub fn matching(
+        &mut self,
+        matcher: impl Fn(&Req) -> bool + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.matcher = Some(Arc::new(matcher));
+        self
+    }
+
+    // Virtual latency, a delay beyond the call's timeout fails it with DeadlineExceeded
+    pub fn with_delay_millis(&mut self, delay_millis: u64) -> &mut Self {
+        self.delay_millis = delay_millis;
+        self
+    }
+
+    pub fn returning(&mut self, response: impl prost::Message) -> &mut Tester {
+        self.reply(GrpcStatus::Ok, response.encode_to_vec(), None)
+    }
+
+    pub fn returning_status(&mut self, status: GrpcStatus) -> &mut Tester {
+        self.reply(status, vec![], None)
+    }
+
+    // Trailers-only response, delivered as initial metadata holding grpc-status followed by
+    // proxy_on_grpc_close, the message callback is never invoked (even for Ok)
+    pub fn returning_trailers_only(&mut self, status: GrpcStatus, message: &str) -> &mut Tester {
+        self.reply(status, vec![], Some(message.to_string()))
+    }
+
+    fn reply(
+        &mut self,
+        status: GrpcStatus,
+        message: Vec<u8>,
+        trailers_only: Option<String>,
+    ) -> &mut Tester {
+        let matcher = self.matcher.clone();
+        let reply = GrpcReply {
+            status,
+            message,
+            delay_millis: self.delay_millis,
+            trailers_only,
+        };
+        let rule = MockGrpcRule(Arc::new(move |request: &[u8]| {
+            let request = Req::decode(request).ok()?;
+            match &matcher {
+                Some(matcher) if !matcher(&request) => None,
+                _ => Some(reply.clone()),
+            }
+        }));
+        self.tester.get_settings_handle().staged.add_mock_grpc_rule(
+            &self.service,
+            &self.method,
+            rule,
+        );
         self.tester
     }
 }
@@ -0,0 +1,213 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Serializable views of traces, header maps and metrics for snapshot testing, e.g. with insta:
+//
+//   tester.record_trace();
+//   tester.send_request(2, request)?.execute_all()?;
+//   insta::assert_yaml_snapshot!(tester.take_trace().snapshot());
+//   insta::assert_yaml_snapshot!(tester.header_map(MapType::HttpRequestHeaders).snapshot());
+//   insta::assert_yaml_snapshot!(tester.metrics().snapshot());
+//
+// The ordering is stable across runs: trace events in the order they happened, header names
+// (lowercased) and metric names sorted, the values of a repeated header in order. A trace reads
+//
+//   stages:
+//     - - callback: "ProxyOnRequestHeaders(2, 3, false)"
+//       - host_call:
+//           name: proxy_get_header_map_value
+//           arguments:
+//             - 0
+//             - ":path"
+//           returns:
+//             - /admin
+//       - return: 1
+
+use crate::http::HeaderMap;
+use crate::trace::{Trace, TraceValue, TracedEvent};
+use crate::types::{Histogram, MetricType, Metrics};
+
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TraceSnapshot {
+    pub stages: Vec<Vec<EventSnapshot>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventSnapshot {
+    Callback(String),
+    HostCall {
+        name: String,
+        arguments: Vec<ValueSnapshot>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        returns: Vec<ValueSnapshot>,
+    },
+    Return(i32),
+}
+
+// Argument or returned value of a host call: bytes as text when they are UTF-8, header pairs as
+// a map in the order of the call
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum ValueSnapshot {
+    Int(i128),
+    Text(String),
+    Bytes(Vec<u8>),
+    Pairs(PairsSnapshot),
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PairsSnapshot(pub Vec<(String, String)>);
+
+impl Serialize for PairsSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, value) in &self.0 {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+// Header names lowercased and sorted, a repeated header with the list of its values
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct HeaderMapSnapshot(pub BTreeMap<String, HeaderValues>);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum HeaderValues {
+    One(String),
+    Many(Vec<String>),
+}
+
+// Metrics by name
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct MetricsSnapshot(pub BTreeMap<String, MetricSnapshot>);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MetricSnapshot {
+    Counter {
+        value: i64,
+    },
+    Gauge {
+        value: i64,
+    },
+    Histogram {
+        count: usize,
+        sum: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+        // in recording order
+        samples: Vec<i64>,
+    },
+}
+
+impl Trace {
+    pub fn snapshot(&self) -> TraceSnapshot {
+        let event = |event: &TracedEvent| match event {
+            TracedEvent::Callback(callback) => EventSnapshot::Callback(callback.clone()),
+            TracedEvent::Return(returned) => EventSnapshot::Return(*returned),
+            TracedEvent::HostCall(call) => {
+                let (arguments, returns) = call.values();
+                EventSnapshot::HostCall {
+                    name: call.name().to_string(),
+                    arguments: arguments.into_iter().map(value).collect(),
+                    returns: returns.into_iter().map(value).collect(),
+                }
+            }
+        };
+        TraceSnapshot {
+            stages: self
+                .stages
+                .iter()
+                .map(|stage| stage.events.iter().map(event).collect())
+                .collect(),
+        }
+    }
+}
+
+fn value(value: TraceValue) -> ValueSnapshot {
+    match value {
+        TraceValue::Int(value) => ValueSnapshot::Int(value),
+        TraceValue::Bytes(bytes) => match String::from_utf8(bytes) {
+            Ok(text) => ValueSnapshot::Text(text),
+            Err(error) => ValueSnapshot::Bytes(error.into_bytes()),
+        },
+        TraceValue::Pairs(pairs) => ValueSnapshot::Pairs(PairsSnapshot(pairs)),
+        TraceValue::None => ValueSnapshot::None,
+    }
+}
+
+impl HeaderMap {
+    pub fn snapshot(&self) -> HeaderMapSnapshot {
+        let mut headers: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (name, value) in self.iter() {
+            headers
+                .entry(name.to_lowercase())
+                .or_default()
+                .push(value.to_string());
+        }
+        HeaderMapSnapshot(
+            headers
+                .into_iter()
+                .map(|(name, mut values)| match values.len() {
+                    1 => (name, HeaderValues::One(values.remove(0))),
+                    _ => (name, HeaderValues::Many(values)),
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let metric = |metric_type: i32, value: i64, samples: &[i64]| match metric_type {
+            metric_type if metric_type == MetricType::Counter as i32 => {
+                MetricSnapshot::Counter { value }
+            }
+            metric_type if metric_type == MetricType::Gauge as i32 => {
+                MetricSnapshot::Gauge { value }
+            }
+            _ => {
+                let histogram = Histogram {
+                    samples: samples.to_vec(),
+                };
+                MetricSnapshot::Histogram {
+                    count: histogram.count(),
+                    sum: histogram.sum(),
+                    min: histogram.min(),
+                    max: histogram.max(),
+                    samples: histogram.samples,
+                }
+            }
+        };
+        MetricsSnapshot(
+            self.entries
+                .iter()
+                .map(|entry| {
+                    let snapshot = metric(entry.metric_type, entry.value, &entry.samples);
+                    (entry.name.clone(), snapshot)
+                })
+                .collect(),
+        )
+    }
+}
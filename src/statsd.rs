@@ -0,0 +1,151 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// StatsD forwarding of the metric updates of the module, to reuse assertions written against
+// statsd tooling, e.g.
+//
+//   let sink = StatsdSink::memory().prefix("envoy");
+//   tester.set_statsd_sink(sink.clone());
+//   tester.send_request(2, request)?.execute_all()?;
+//   assert_eq!(sink.take_lines(), vec!["envoy.auth.denied:1|c"]);
+//
+// or to a statsd daemon (or a test listener) over UDP, one datagram per update:
+//
+//   tester.set_statsd_sink(StatsdSink::udp("127.0.0.1:8125")?);
+//
+// Updates are written the way Envoy's statsd sink does: counter increments as name:delta|c,
+// gauges as name:value|g (name:+delta|g or name:-delta|g when incremented) and histogram samples
+// as name:value|ms. Lines sent over UDP are kept in memory as well.
+
+use crate::types::{Metric, MetricType};
+
+use anyhow::Result;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+#[derive(Clone)]
+pub struct StatsdSink {
+    socket: Option<Arc<UdpSocket>>,
+    prefix: Option<String>,
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+// Update of a metric by the module, before the host applies it
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum MetricUpdate {
+    Increment(i64),
+    Record(i64),
+}
+
+impl StatsdSink {
+    // Keeps the lines in memory only, see lines() and take_lines()
+    pub fn memory() -> StatsdSink {
+        StatsdSink {
+            socket: None,
+            prefix: None,
+            lines: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Sends each line as a datagram to the address, e.g. 127.0.0.1:8125
+    pub fn udp(address: impl ToSocketAddrs) -> Result<StatsdSink> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(address)?;
+        Ok(StatsdSink {
+            socket: Some(Arc::new(socket)),
+            ..StatsdSink::memory()
+        })
+    }
+
+    // Prefixes the metric names, e.g. "envoy" as Envoy's statsd sink does by default
+    pub fn prefix(mut self, prefix: &str) -> StatsdSink {
+        self.prefix = Some(prefix.to_string());
+        self
+    }
+
+    // Lines sent so far, in update order
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().clone()
+    }
+
+    pub fn take_lines(&self) -> Vec<String> {
+        std::mem::take(&mut self.lines.lock().unwrap())
+    }
+
+    pub(crate) fn send(&self, metric: &Metric, update: MetricUpdate) {
+        let line = match update_value(metric, update) {
+            Some(value) => format!("{}:{}", self.name(&metric.name), value),
+            None => return,
+        };
+        if let Some(socket) = &self.socket {
+            // statsd is fire and forget, a lost datagram does not fail the test
+            if let Err(error) = socket.send(line.as_bytes()) {
+                warn!(
+                    "[host] cannot send {:?} to the statsd sink: {}",
+                    line, error
+                );
+            }
+        }
+        self.lines.lock().unwrap().push(line);
+    }
+
+    // Metric name with the prefix, the characters reserved by the protocol replaced by '_'
+    fn name(&self, name: &str) -> String {
+        let name: String = name
+            .chars()
+            .map(|c| match c {
+                ':' | '|' | '@' => '_',
+                c => c,
+            })
+            .collect();
+        match &self.prefix {
+            Some(prefix) => format!("{}.{}", prefix, name),
+            None => name,
+        }
+    }
+}
+
+// Value and type of the line of an update, None for updates Envoy would not send (e.g. a counter
+// recorded below its current value)
+fn update_value(metric: &Metric, update: MetricUpdate) -> Option<String> {
+    match (metric.metric_type, update) {
+        (metric_type, MetricUpdate::Increment(offset))
+            if metric_type == MetricType::Counter as i32 =>
+        {
+            Some(format!("{}|c", offset))
+        }
+        // the module sets the counter, Envoy adds the difference
+        (metric_type, MetricUpdate::Record(value)) if metric_type == MetricType::Counter as i32 => {
+            match value - metric.value {
+                delta if delta > 0 => Some(format!("{}|c", delta)),
+                _ => None,
+            }
+        }
+        (metric_type, MetricUpdate::Increment(offset))
+            if metric_type == MetricType::Gauge as i32 =>
+        {
+            Some(format!("{:+}|g", offset))
+        }
+        (metric_type, MetricUpdate::Record(value)) if metric_type == MetricType::Gauge as i32 => {
+            // Envoy gauges are unsigned, and -n|g would read as a decrement
+            match value < 0 {
+                true => None,
+                false => Some(format!("{}|g", value)),
+            }
+        }
+        (_, MetricUpdate::Record(value)) => Some(format!("{}|ms", value)),
+        (_, MetricUpdate::Increment(_)) => None,
+    }
+}
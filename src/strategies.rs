@@ -0,0 +1,225 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// proptest strategies for property-based tests of a module (`proptest` feature): plausible
+// requests, responses and plugin configurations, mixed with the edge cases filters tend to get
+// wrong (empty values, huge headers and bodies, unusual casing, repeated names), e.g.
+//
+//   proptest!(|(request in strategies::http_request())| {
+//       let mut tester = setup.start()?;
+//       tester.call_proxy_on_context_create(2, ROOT_CONTEXT);
+//       tester.send_request(2, request)?.execute_all()?;
+//       prop_assert_ne!(tester.stream_outcome(2), StreamOutcome::LocalReply(500));
+//   });
+
+use crate::http::{HeaderMap, HttpBody, HttpRequest, HttpResponse};
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+// Past the usual limits of proxies (e.g. 60KiB of request headers in Envoy)
+const HUGE: usize = 64 * 1024;
+
+pub fn method() -> impl Strategy<Value = String> {
+    prop_oneof![
+        8 => prop::sample::select(vec!["GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS"])
+            .prop_map(str::to_string),
+        1 => prop::sample::select(vec!["CONNECT", "TRACE", "PURGE", "get", "Post"])
+            .prop_map(str::to_string),
+        1 => "[A-Z]{1,12}",
+    ]
+}
+
+pub fn path() -> impl Strategy<Value = String> {
+    prop_oneof![
+        6 => "(/[a-zA-Z0-9._~-]{0,12}){1,5}(\\?[a-z]{1,6}=[a-zA-Z0-9%]{0,10}(&[a-z]{1,6}=[a-z0-9]{0,6}){0,3})?",
+        2 => prop::sample::select(vec![
+            "/",
+            "*",
+            "//",
+            "/?",
+            "/#fragment",
+            "/a/../b",
+            "/%2e%2e/%2f",
+            "/%00",
+            "/caf\u{e9}",
+            "/a;b=c",
+        ])
+        .prop_map(str::to_string),
+        1 => (1..HUGE / 8).prop_map(|length| format!("/{}", "a".repeat(length))),
+    ]
+}
+
+pub fn authority() -> impl Strategy<Value = String> {
+    prop_oneof![
+        4 => "[a-z]{1,10}(\\.[a-z]{2,6}){1,2}(:[0-9]{2,5})?",
+        1 => prop::sample::select(vec![
+            "localhost",
+            "127.0.0.1:8080",
+            "[::1]:443",
+            "EXAMPLE.com",
+            "",
+        ])
+        .prop_map(str::to_string),
+    ]
+}
+
+// Well-known names in unusual casing, arbitrary tokens and very long names
+pub fn header_name() -> impl Strategy<Value = String> {
+    let known = prop::sample::select(vec![
+        "content-type",
+        "content-length",
+        "authorization",
+        "cookie",
+        "host",
+        "user-agent",
+        "x-forwarded-for",
+        "x-request-id",
+        "te",
+        "transfer-encoding",
+    ]);
+    prop_oneof![
+        4 => known.clone().prop_map(str::to_string),
+        2 => (known, any::<u64>()).prop_map(|(name, casing)| weird_casing(name, casing)),
+        3 => "x-[a-z0-9-]{1,20}",
+        1 => (256..1024usize).prop_map(|length| format!("x-{}", "n".repeat(length))),
+    ]
+}
+
+// Empty, padded, listed, binary-looking and huge values
+pub fn header_value() -> impl Strategy<Value = String> {
+    prop_oneof![
+        6 => "[ -~]{0,40}",
+        1 => Just(String::new()),
+        1 => "[a-z]{1,8}".prop_map(|value| format!("  {}\t", value)),
+        1 => vec("[a-z0-9=]{1,8}", 2..6).prop_map(|values| values.join(", ")),
+        1 => "\\PC{1,16}",
+        1 => (HUGE / 4..HUGE).prop_map(|length| "v".repeat(length)),
+    ]
+}
+
+// Up to 16 headers, names repeating now and then
+pub fn header_map() -> impl Strategy<Value = HeaderMap> {
+    vec((header_name(), header_value()), 0..16).prop_map(|pairs| {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(&name, &value);
+        }
+        headers
+    })
+}
+
+// No body, an empty one, text or binary, huge, or chunked (including empty chunks)
+pub fn body() -> impl Strategy<Value = Option<HttpBody>> {
+    prop_oneof![
+        3 => Just(None),
+        1 => Just(Some(HttpBody::Full(Vec::new()))),
+        3 => "[ -~]{1,256}".prop_map(|body| Some(HttpBody::Full(body.into_bytes()))),
+        2 => vec(any::<u8>(), 1..512).prop_map(|body| Some(HttpBody::Full(body))),
+        1 => (HUGE..4 * HUGE).prop_map(|length| Some(HttpBody::Full(vec![b'b'; length]))),
+        2 => vec(vec(any::<u8>(), 0..64), 1..6).prop_map(|chunks| Some(HttpBody::Chunked(chunks))),
+    ]
+}
+
+// Mostly absent trailers, sometimes an empty map
+pub fn trailers() -> impl Strategy<Value = Option<HeaderMap>> {
+    prop_oneof![
+        6 => Just(None),
+        1 => Just(Some(HeaderMap::new())),
+        2 => vec((header_name(), header_value()), 1..4).prop_map(|pairs| {
+            let mut trailers = HeaderMap::new();
+            for (name, value) in pairs {
+                trailers.append(&name, &value);
+            }
+            Some(trailers)
+        }),
+    ]
+}
+
+pub fn http_request() -> impl Strategy<Value = HttpRequest> {
+    (
+        method(),
+        path(),
+        authority(),
+        header_map(),
+        body(),
+        trailers(),
+    )
+        .prop_map(|(method, path, authority, headers, body, trailers)| {
+            let mut request = HttpRequest::new(&method, &path).authority(&authority);
+            for (name, value) in headers.iter() {
+                request.headers.append(name, value);
+            }
+            request.body = body;
+            request.trailers = trailers;
+            request
+        })
+}
+
+pub fn status_code() -> impl Strategy<Value = u32> {
+    prop_oneof![
+        6 => prop::sample::select(vec![200, 201, 204, 301, 302, 304, 400, 401, 403, 404, 429, 500, 502, 503]),
+        1 => 100..600u32,
+    ]
+}
+
+pub fn http_response() -> impl Strategy<Value = HttpResponse> {
+    (status_code(), header_map(), body(), trailers()).prop_map(
+        |(status_code, headers, body, trailers)| {
+            let mut response = HttpResponse::new(status_code);
+            for (name, value) in headers.iter() {
+                response.headers.append(name, value);
+            }
+            response.body = body;
+            response.trailers = trailers;
+            response
+        },
+    )
+}
+
+// JSON plugin configurations (flat objects of strings, numbers and booleans) along with the
+// ones a plugin has to reject gracefully: empty, null, of the wrong type, malformed, huge
+pub fn plugin_config() -> impl Strategy<Value = String> {
+    let value = prop_oneof![
+        "[ -~&&[^\"\\\\]]{0,16}".prop_map(|text| format!("{:?}", text)),
+        any::<i64>().prop_map(|number| number.to_string()),
+        any::<bool>().prop_map(|flag| flag.to_string()),
+        Just("null".to_string()),
+    ];
+    let object = vec(("[a-z_]{1,12}", value), 0..8).prop_map(|fields| {
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|(key, value)| format!("{:?}: {}", key, value))
+            .collect();
+        format!("{{{}}}", fields.join(", "))
+    });
+    prop_oneof![
+        6 => object,
+        2 => prop::sample::select(vec!["", "{}", "null", "[]", "\"\"", "0", "{", "{\"a\":}", "\u{feff}{}"])
+            .prop_map(str::to_string),
+        1 => "\\PC{0,64}",
+        1 => (HUGE..4 * HUGE).prop_map(|length| format!("{{\"padding\": \"{}\"}}", "p".repeat(length))),
+    ]
+}
+
+// Each letter upper or lower case, by the bits of casing
+fn weird_casing(name: &str, casing: u64) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(index, c)| match casing >> (index % 64) & 1 {
+            1 => c.to_ascii_uppercase(),
+            _ => c,
+        })
+        .collect()
+}
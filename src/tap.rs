@@ -0,0 +1,436 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Streams captured by Envoy's tap filter (envoy.data.tap.v3.TraceWrapper, as written by its file
+// sink), replayed through the module to reproduce what happened in production, e.g.
+//
+//   let capture = TapCapture::load("incident/")?;
+//   let streams = capture.replay(&mut tester)?;
+//
+// Captures are read from .json files (JSON_BODY_AS_BYTES or JSON_BODY_AS_STRING), .pb files
+// (PROTO_BINARY) and .pb_length_delimited files (PROTO_BINARY_LENGTH_DELIMITED), or from all of
+// them in a directory. Buffered traces are a stream each, and streamed trace segments are put
+// together by trace id, the body chunks they carry delivered one body callback per chunk.
+// Bodies truncated by the tap (max_buffered_rx_bytes / max_buffered_tx_bytes) are replayed as
+// far as they were captured.
+
+use crate::har::{decode_base64, replay_streams};
+use crate::http::{HeaderMap, HttpBody, HttpRequest, HttpResponse};
+use crate::tester::Tester;
+use crate::types::Bytes;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct TapCapture {
+    pub traces: Vec<TapTrace>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TapTrace {
+    pub request: HttpRequest,
+    // None when the stream ended before the response headers
+    pub response: Option<HttpResponse>,
+}
+
+// The messages of envoy/data/tap/v3/wrapper.proto and http.proto, decoded from both the binary
+// and the JSON encodings (the oneofs as optional fields, the same on the wire)
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+struct TraceWrapper {
+    #[prost(message, optional, tag = "1")]
+    #[serde(default, alias = "httpBufferedTrace")]
+    http_buffered_trace: Option<HttpBufferedTrace>,
+    #[prost(message, optional, tag = "2")]
+    #[serde(default, alias = "httpStreamedTraceSegment")]
+    http_streamed_trace_segment: Option<HttpStreamedTraceSegment>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+struct HttpBufferedTrace {
+    #[prost(message, optional, tag = "1")]
+    #[serde(default)]
+    request: Option<TapMessage>,
+    #[prost(message, optional, tag = "2")]
+    #[serde(default)]
+    response: Option<TapMessage>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+struct TapMessage {
+    #[prost(message, repeated, tag = "1")]
+    #[serde(default)]
+    headers: Vec<TapHeader>,
+    #[prost(message, optional, tag = "2")]
+    #[serde(default)]
+    body: Option<TapBody>,
+    #[prost(message, repeated, tag = "3")]
+    #[serde(default)]
+    trailers: Vec<TapHeader>,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+struct HttpStreamedTraceSegment {
+    #[prost(uint64, tag = "1")]
+    #[serde(default, alias = "traceId", deserialize_with = "json_uint64")]
+    trace_id: u64,
+    #[prost(message, optional, tag = "2")]
+    #[serde(default, alias = "requestHeaders")]
+    request_headers: Option<TapHeaderMap>,
+    #[prost(message, optional, tag = "3")]
+    #[serde(default, alias = "requestBodyChunk")]
+    request_body_chunk: Option<TapBody>,
+    #[prost(message, optional, tag = "4")]
+    #[serde(default, alias = "requestTrailers")]
+    request_trailers: Option<TapHeaderMap>,
+    #[prost(message, optional, tag = "5")]
+    #[serde(default, alias = "responseHeaders")]
+    response_headers: Option<TapHeaderMap>,
+    #[prost(message, optional, tag = "6")]
+    #[serde(default, alias = "responseBodyChunk")]
+    response_body_chunk: Option<TapBody>,
+    #[prost(message, optional, tag = "7")]
+    #[serde(default, alias = "responseTrailers")]
+    response_trailers: Option<TapHeaderMap>,
+}
+
+// envoy.config.core.v3.HeaderMap
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+struct TapHeaderMap {
+    #[prost(message, repeated, tag = "1")]
+    #[serde(default)]
+    headers: Vec<TapHeader>,
+}
+
+// envoy.config.core.v3.HeaderValue, newer Envoys setting raw_value rather than value
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+struct TapHeader {
+    #[prost(string, tag = "1")]
+    #[serde(default)]
+    key: String,
+    #[prost(string, tag = "2")]
+    #[serde(default)]
+    value: String,
+    #[prost(bytes = "vec", tag = "3")]
+    #[serde(default, alias = "rawValue", deserialize_with = "json_bytes")]
+    raw_value: Bytes,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Deserialize)]
+struct TapBody {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    #[serde(default, alias = "asBytes", deserialize_with = "json_optional_bytes")]
+    as_bytes: Option<Bytes>,
+    #[prost(string, optional, tag = "2")]
+    #[serde(default, alias = "asString")]
+    as_string: Option<String>,
+    #[prost(bool, tag = "3")]
+    #[serde(default)]
+    truncated: bool,
+}
+
+impl TapCapture {
+    // A capture file, or every capture file of a directory (in name order)
+    pub fn load(path: impl AsRef<Path>) -> Result<TapCapture> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            return TapCapture::from_wrappers(read_wrappers(path)?);
+        }
+        let mut files = fs::read_dir(path)
+            .with_context(|| format!("cannot read tap directory {}", path.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        files.sort();
+        let mut wrappers = Vec::new();
+        for file in files.iter().filter(|file| capture_format(file).is_some()) {
+            wrappers.extend(read_wrappers(file)?);
+        }
+        TapCapture::from_wrappers(wrappers)
+    }
+
+    // One or more TraceWrapper JSON objects, one after the other
+    pub fn parse_json(text: &str) -> Result<TapCapture> {
+        TapCapture::from_wrappers(json_wrappers(text)?)
+    }
+
+    // A TraceWrapper, or TraceWrappers each prefixed with its length (as a varint)
+    pub fn parse_proto(bytes: &[u8], length_delimited: bool) -> Result<TapCapture> {
+        TapCapture::from_wrappers(proto_wrappers(bytes, length_delimited)?)
+    }
+
+    // Runs every trace on a new HTTP context of the started module, as Har::replay does
+    pub fn replay(&self, tester: &mut Tester) -> Result<Vec<i32>> {
+        let names: Vec<String> = self
+            .traces
+            .iter()
+            .enumerate()
+            .map(|(index, trace)| match trace.request.headers.get(":path") {
+                Some(path) => format!("{} (trace {})", path, index),
+                None => format!("trace {}", index),
+            })
+            .collect();
+        let streams = self
+            .traces
+            .iter()
+            .zip(&names)
+            .map(|(trace, name)| (name.as_str(), &trace.request, trace.response.as_ref()));
+        replay_streams(tester, streams)
+    }
+
+    fn from_wrappers(wrappers: Vec<TraceWrapper>) -> Result<TapCapture> {
+        // the segments of a streamed trace go where its first segment was
+        let mut pending: Vec<PendingTrace> = Vec::new();
+        for wrapper in wrappers {
+            match (
+                wrapper.http_buffered_trace,
+                wrapper.http_streamed_trace_segment,
+            ) {
+                (Some(trace), _) => pending.push(PendingTrace::Buffered(trace)),
+                (None, Some(segment)) => {
+                    let streamed = pending.iter_mut().find_map(|trace| match trace {
+                        PendingTrace::Streamed(id, segments) if *id == segment.trace_id => {
+                            Some(segments)
+                        }
+                        _ => None,
+                    });
+                    match streamed {
+                        Some(segments) => segments.push(segment),
+                        None => {
+                            pending.push(PendingTrace::Streamed(segment.trace_id, vec![segment]))
+                        }
+                    }
+                }
+                // e.g. socket traces, of no use to an HTTP filter
+                (None, None) => {}
+            }
+        }
+        let traces = pending
+            .into_iter()
+            .map(|trace| match trace {
+                PendingTrace::Buffered(trace) => TapTrace::from_buffered(trace),
+                PendingTrace::Streamed(trace_id, segments) => {
+                    TapTrace::from_segments(trace_id, segments)
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok(TapCapture { traces })
+    }
+}
+
+enum PendingTrace {
+    Buffered(HttpBufferedTrace),
+    Streamed(u64, Vec<HttpStreamedTraceSegment>),
+}
+
+impl TapTrace {
+    fn from_buffered(trace: HttpBufferedTrace) -> Result<TapTrace> {
+        let request = match trace.request {
+            Some(request) => request,
+            None => bail!("buffered trace without a request"),
+        };
+        let response = trace
+            .response
+            .filter(|response| !response.headers.is_empty());
+        Ok(TapTrace {
+            request: HttpRequest {
+                headers: header_map(request.headers),
+                body: http_body(request.body.into_iter().map(TapBody::bytes).collect()),
+                trailers: trailers(request.trailers),
+            },
+            response: response.map(|response| HttpResponse {
+                headers: header_map(response.headers),
+                body: http_body(response.body.into_iter().map(TapBody::bytes).collect()),
+                trailers: trailers(response.trailers),
+            }),
+        })
+    }
+
+    fn from_segments(trace_id: u64, segments: Vec<HttpStreamedTraceSegment>) -> Result<TapTrace> {
+        let (mut request_headers, mut request_chunks, mut request_trailers) = (None, vec![], None);
+        let (mut response_headers, mut response_chunks, mut response_trailers) =
+            (None, vec![], None);
+        for segment in segments {
+            request_headers = request_headers.or(segment.request_headers);
+            request_chunks.extend(segment.request_body_chunk.map(TapBody::bytes));
+            request_trailers = request_trailers.or(segment.request_trailers);
+            response_headers = response_headers.or(segment.response_headers);
+            response_chunks.extend(segment.response_body_chunk.map(TapBody::bytes));
+            response_trailers = response_trailers.or(segment.response_trailers);
+        }
+        let request_headers = match request_headers {
+            Some(request_headers) => request_headers,
+            None => bail!("streamed trace {} without request headers", trace_id),
+        };
+        Ok(TapTrace {
+            request: HttpRequest {
+                headers: header_map(request_headers.headers),
+                body: http_body(request_chunks),
+                trailers: request_trailers.and_then(|map| trailers(map.headers)),
+            },
+            response: response_headers.map(|response_headers| HttpResponse {
+                headers: header_map(response_headers.headers),
+                body: http_body(response_chunks),
+                trailers: response_trailers.and_then(|map| trailers(map.headers)),
+            }),
+        })
+    }
+}
+
+impl TapBody {
+    fn bytes(self) -> Bytes {
+        match (self.as_bytes, self.as_string) {
+            (Some(bytes), _) => bytes,
+            (None, Some(text)) => text.into_bytes(),
+            (None, None) => Bytes::new(),
+        }
+    }
+}
+
+fn header_map(headers: Vec<TapHeader>) -> HeaderMap {
+    headers
+        .into_iter()
+        .map(|header| match header.raw_value.is_empty() {
+            true => (header.key, header.value),
+            false => {
+                let value = String::from_utf8_lossy(&header.raw_value).into_owned();
+                (header.key, value)
+            }
+        })
+        .collect()
+}
+
+fn trailers(headers: Vec<TapHeader>) -> Option<HeaderMap> {
+    match headers.is_empty() {
+        true => None,
+        false => Some(header_map(headers)),
+    }
+}
+
+// A body callback per chunk captured
+fn http_body(chunks: Vec<Bytes>) -> Option<HttpBody> {
+    let mut chunks: Vec<Bytes> = chunks
+        .into_iter()
+        .filter(|chunk| !chunk.is_empty())
+        .collect();
+    match chunks.len() {
+        0 => None,
+        1 => chunks.pop().map(HttpBody::Full),
+        _ => Some(HttpBody::Chunked(chunks)),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum CaptureFormat {
+    Json,
+    Proto,
+    ProtoLengthDelimited,
+}
+
+// From the extension the file sink gives each format
+fn capture_format(path: &Path) -> Option<CaptureFormat> {
+    match path.extension()?.to_str()? {
+        "json" => Some(CaptureFormat::Json),
+        "pb" => Some(CaptureFormat::Proto),
+        "pb_length_delimited" => Some(CaptureFormat::ProtoLengthDelimited),
+        _ => None,
+    }
+}
+
+fn read_wrappers(path: &Path) -> Result<Vec<TraceWrapper>> {
+    let format = match capture_format(path) {
+        Some(format) => format,
+        None => bail!(
+            "unsupported tap capture {}, expected .json, .pb or .pb_length_delimited",
+            path.display()
+        ),
+    };
+    let bytes =
+        fs::read(path).with_context(|| format!("cannot read tap capture {}", path.display()))?;
+    let wrappers = match format {
+        CaptureFormat::Json => json_wrappers(&String::from_utf8_lossy(&bytes)),
+        CaptureFormat::Proto => proto_wrappers(&bytes, false),
+        CaptureFormat::ProtoLengthDelimited => proto_wrappers(&bytes, true),
+    };
+    wrappers.with_context(|| format!("invalid tap capture {}", path.display()))
+}
+
+fn proto_wrappers(mut bytes: &[u8], length_delimited: bool) -> Result<Vec<TraceWrapper>> {
+    use prost::Message;
+    if !length_delimited {
+        return Ok(vec![TraceWrapper::decode(bytes)?]);
+    }
+    let mut wrappers = Vec::new();
+    while !bytes.is_empty() {
+        wrappers.push(TraceWrapper::decode_length_delimited(&mut bytes)?);
+    }
+    Ok(wrappers)
+}
+
+// The file sink writes the segments of a streamed trace as JSON objects one after the other
+fn json_wrappers(text: &str) -> Result<Vec<TraceWrapper>> {
+    let mut wrappers = Vec::new();
+    let (mut depth, mut in_string, mut escaped, mut start) = (0, false, false, 0);
+    for (index, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '{' if !in_string => {
+                if depth == 0 {
+                    start = index;
+                }
+                depth += 1;
+            }
+            '}' if !in_string => {
+                depth -= 1;
+                if depth == 0 {
+                    // JSON being valid YAML
+                    wrappers.push(serde_yaml::from_str(&text[start..=index])?);
+                }
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 || in_string {
+        bail!("unterminated JSON object");
+    }
+    Ok(wrappers)
+}
+
+// uint64 fields are strings in the JSON encoding of protobuf
+fn json_uint64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Uint64 {
+        Number(u64),
+        Text(String),
+    }
+    match Uint64::deserialize(deserializer)? {
+        Uint64::Number(number) => Ok(number),
+        Uint64::Text(text) => text.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+// and bytes fields base64
+fn json_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    decode_base64(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn json_optional_bytes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Bytes>, D::Error> {
+    json_bytes(deserializer).map(Some)
+}
@@ -12,17 +12,43 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::access_log::AccessLogFormat;
+use crate::artifacts;
+use crate::coverage::{self, COVERAGE_EXPORT};
+use crate::envoy::EnvoyConfig;
 use crate::expect_interface::*;
 use crate::expectations::ExpectHandle;
-use crate::host_settings::HostHandle;
-use crate::hostcalls::{generate_import_list, get_abi_version};
+use crate::flamegraph::{self, StackSamples};
+use crate::host_settings::{
+    grpc_trailers_only_headers, set_content_length, HostHandle, HostSnapshot, Route,
+};
+use crate::hostcalls::{get_abi_version, link_host_functions, HostState};
+use crate::http::{HeaderMap, HttpBody, HttpRequest, HttpResponse};
+use crate::matchers::Matches;
+use crate::otlp::OtlpExport;
+use crate::phases::StreamPhase;
+use crate::profile::{HostCallReport, Profile};
+use crate::runtime::*;
+#[cfg(feature = "schema")]
+use crate::schema::ConfigSchema;
 use crate::settings_interface::*;
+use crate::statsd::StatsdSink;
+use crate::trace::{assert_golden, EventLog, Trace, TracedEvent, TracedStage};
 use crate::types::*;
 
 use anyhow::Result;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
-use wasmtime::*;
+use tracing::subscriber::NoSubscriber;
+pub use tracing::Level;
+use tracing::{debug, info, Dispatch};
 
 #[derive(Debug, StructOpt, Clone)]
 #[structopt(
@@ -38,17 +64,108 @@ pub struct MockSettings {
     pub allow_unexpected: bool,
 }
 
+type HostExtension = Box<dyn FnOnce(&mut Linker<HostState>, &str, &str) -> Result<()>>;
+
+// Additional host functions (beyond the proxy-wasm ABI) linked into the module by
+// mock_with_extensions, they take precedence over the built-in implementations
+#[derive(Default)]
+pub struct HostExtensions {
+    functions: Vec<(String, String, HostExtension)>,
+}
+
+impl HostExtensions {
+    pub fn new() -> HostExtensions {
+        HostExtensions::default()
+    }
+
+    // e.g. register("env", "acme_get_tenant", |_caller: Caller<'_, HostState>, ptr: i32| -> i32 { 0 })
+    pub fn register<Params, Results>(
+        &mut self,
+        module: &str,
+        name: &str,
+        func: impl IntoFunc<HostState, Params, Results>,
+    ) -> &mut Self {
+        self.functions.push((
+            module.to_string(),
+            name.to_string(),
+            Box::new(
+                move |linker: &mut Linker<HostState>, module: &str, name: &str| {
+                    linker.func_wrap(module, name, func)?;
+                    Ok(())
+                },
+            ),
+        ));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    // Returns whether an extension was registered for the imported function
+    pub(crate) fn link(
+        &mut self,
+        linker: &mut Linker<HostState>,
+        module: &str,
+        name: &str,
+    ) -> Result<bool> {
+        let index = self
+            .functions
+            .iter()
+            .position(|(ext_module, ext_name, _)| ext_module == module && ext_name == name);
+        match index {
+            Some(index) => {
+                let (_, _, func) = self.functions.remove(index);
+                func(linker, module, name)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
 pub fn mock(mock_settings: MockSettings) -> Result<Tester> {
+    mock_with_extensions(mock_settings, HostExtensions::new())
+}
+
+pub fn mock_with_extensions(
+    mock_settings: MockSettings,
+    extensions: HostExtensions,
+) -> Result<Tester> {
     // initialize wasm engine and shared cache
-    let mut store = Store::<()>::default();
-    let module = Module::from_file(store.engine(), &mock_settings.wasm_path)?;
+    let loaded = Engine::load_module(&mock_settings.wasm_path)?;
+    let tester = mock_loaded(mock_settings, &loaded, extensions)?;
+    tester.log_seed();
+    Ok(tester)
+}
+
+// Name standing for the module path of Testers created from bytes or text
+const INLINE_MODULE: &str = "<inline module>";
 
-    // generate and link host function implementations
-    let abi_version = get_abi_version(&module);
-    let imports: Arc<Mutex<Vec<Extern>>> = Arc::new(Mutex::new(Vec::new()));
-    let (host_settings, expectations): (Arc<Mutex<HostHandle>>, Arc<Mutex<ExpectHandle>>) =
-        generate_import_list(&mut store, &module, imports.clone());
-    let instance = Instance::new(&mut store, &module, &(*imports).lock().unwrap()[..])?;
+// Tester which has not logged its seed yet, the caller does once it set it (and the verbosity)
+fn mock_loaded(
+    mock_settings: MockSettings,
+    loaded: &LoadedModule,
+    mut extensions: HostExtensions,
+) -> Result<Tester> {
+    let extensions_empty = extensions.is_empty();
+
+    // host state is owned by the store, so that each Tester is independent of the others
+    let abi_version = get_abi_version(&loaded.module);
+    let state = HostState::new(abi_version);
+    let (host_settings, expectations) = (state.host.clone(), state.expect.clone());
+    let mut store = Engine::new_store(state);
+
+    // link host function implementations and instantiate, the linked module is shared by all
+    // Testers unless extensions are registered
+    let define = |linker: &mut Linker<HostState>| {
+        link_host_functions(linker, &loaded.module, &mut extensions)
+    };
+    let instance = if extensions_empty {
+        Engine::instantiate_shared(&mut store, loaded, define)?
+    } else {
+        Engine::instantiate_with(&mut store, loaded, define)?
+    };
 
     // create mock test proxy-wasm object
     let tester = Tester::new(
@@ -62,6 +179,428 @@ pub fn mock(mock_settings: MockSettings) -> Result<Tester> {
     return Ok(tester);
 }
 
+// Structured creation of a Tester, as an alternative to mock(MockSettings) as the settings grow,
+// e.g. Tester::builder().wasm_file("filter.wasm").quiet(true).clock(0).build()?
+#[derive(Default)]
+pub struct TesterBuilder {
+    wasm_file: Option<String>,
+    wasm_bytes: Option<Vec<u8>>,
+    quiet: bool,
+    allow_unexpected: bool,
+    extensions: HostExtensions,
+    verbosity: Option<Level>,
+    current_time_nanos: Option<u64>,
+    seed: Option<u64>,
+    fuel_limit: Option<u64>,
+    timeout: Option<Duration>,
+    run_timeout: Option<Duration>,
+    memory_limit: Option<usize>,
+}
+
+impl TesterBuilder {
+    pub fn new() -> TesterBuilder {
+        TesterBuilder::default()
+    }
+
+    pub fn wasm_file(mut self, wasm_file: &str) -> TesterBuilder {
+        self.wasm_file = Some(wasm_file.to_string());
+        self
+    }
+
+    // Module in the binary or the text format, instead of a file
+    pub fn wasm_bytes(mut self, wasm: &[u8]) -> TesterBuilder {
+        self.wasm_bytes = Some(wasm.to_vec());
+        self
+    }
+
+    pub fn wat(self, wat: &str) -> TesterBuilder {
+        self.wasm_bytes(wat.as_bytes())
+    }
+
+    pub fn quiet(mut self, quiet: bool) -> TesterBuilder {
+        self.quiet = quiet;
+        self
+    }
+
+    pub fn allow_unexpected(mut self, allow_unexpected: bool) -> TesterBuilder {
+        self.allow_unexpected = allow_unexpected;
+        self
+    }
+
+    pub fn extensions(mut self, extensions: HostExtensions) -> TesterBuilder {
+        self.extensions = extensions;
+        self
+    }
+
+    pub fn verbosity(mut self, verbosity: Level) -> TesterBuilder {
+        self.verbosity = Some(verbosity);
+        self
+    }
+
+    // Starting time of the virtual clock, see Tester::set_default_current_time_nanos
+    pub fn clock(mut self, current_time_nanos: u64) -> TesterBuilder {
+        self.current_time_nanos = Some(current_time_nanos);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> TesterBuilder {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn fuel_limit(mut self, fuel: u64) -> TesterBuilder {
+        self.fuel_limit = Some(fuel);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> TesterBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Counted from build()
+    pub fn run_timeout(mut self, timeout: Duration) -> TesterBuilder {
+        self.run_timeout = Some(timeout);
+        self
+    }
+
+    pub fn memory_limit(mut self, bytes: usize) -> TesterBuilder {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    pub fn build(self) -> Result<Tester> {
+        let loaded = match (&self.wasm_bytes, &self.wasm_file) {
+            (Some(wasm), _) => Engine::load_module_bytes(wasm)?,
+            (None, Some(wasm_file)) => Engine::load_module(wasm_file)?,
+            (None, None) => anyhow::bail!("no module to test: call wasm_file() before build()"),
+        };
+        let mock_settings = MockSettings {
+            wasm_path: match (self.wasm_bytes, self.wasm_file) {
+                (None, Some(wasm_file)) => wasm_file,
+                _ => INLINE_MODULE.to_string(),
+            },
+            quiet: self.quiet,
+            allow_unexpected: self.allow_unexpected,
+        };
+        let mut tester = mock_loaded(mock_settings, &loaded, self.extensions)?;
+        if let Some(verbosity) = self.verbosity {
+            tester.set_verbosity(verbosity);
+        }
+        if let Some(current_time_nanos) = self.current_time_nanos {
+            tester.set_default_current_time_nanos(current_time_nanos);
+        }
+        if let Some(seed) = self.seed {
+            tester.set_seed(seed);
+        }
+        tester.log_seed();
+        tester
+            .set_fuel_limit(self.fuel_limit)
+            .set_timeout(self.timeout)
+            .set_run_timeout(self.run_timeout)
+            .set_memory_limit(self.memory_limit);
+        Ok(tester)
+    }
+}
+
+impl From<MockSettings> for TesterBuilder {
+    fn from(mock_settings: MockSettings) -> TesterBuilder {
+        TesterBuilder::new()
+            .wasm_file(&mock_settings.wasm_path)
+            .quiet(mock_settings.quiet)
+            .allow_unexpected(mock_settings.allow_unexpected)
+    }
+}
+
+// Context id of the root context created by TestSetup
+pub const ROOT_CONTEXT: i32 = 1;
+
+// Scaffolding of #[proxy_wasm_test]: loads the module, starts it and brings the root context
+// through proxy_on_vm_start and proxy_on_configure (both expected to return true) before the test,
+// then checks that nothing was left staged, e.g.
+//
+//   TestSetup::new("filter.wasm").plugin_config("{}").run(|tester| { ... })?
+//
+// Suites share a fixture, a function returning the TestSetup with the hooks common to their tests
+// (#[proxy_wasm_test(fixture = suite)]): before_start hooks stage host defaults (properties, mock
+// upstreams, ...) seen from the first callback, setup hooks run once the root context is
+// configured (e.g. common expectations) and teardown hooks after the test.
+#[derive(Clone)]
+pub struct TestSetup {
+    pub mock_settings: MockSettings,
+    pub vm_config: String,
+    pub plugin_config: Bytes,
+    before_start: Vec<Hook>,
+    setup: Vec<Hook>,
+    teardown: Vec<Hook>,
+}
+
+type Hook = Rc<dyn Fn(&mut Tester) -> Result<()>>;
+
+impl TestSetup {
+    pub fn new(wasm_path: &str) -> TestSetup {
+        TestSetup {
+            mock_settings: MockSettings {
+                wasm_path: wasm_path.to_string(),
+                quiet: false,
+                allow_unexpected: false,
+            },
+            vm_config: String::new(),
+            plugin_config: Vec::new(),
+            before_start: Vec::new(),
+            setup: Vec::new(),
+            teardown: Vec::new(),
+        }
+    }
+
+    // Module given by PROXY_WASM_TEST_MODULE
+    pub fn from_env() -> Result<TestSetup> {
+        match std::env::var("PROXY_WASM_TEST_MODULE") {
+            Ok(wasm_path) => Ok(TestSetup::new(&wasm_path)),
+            Err(_) => anyhow::bail!(
+                "no module to test: set PROXY_WASM_TEST_MODULE or give the .wasm path"
+            ),
+        }
+    }
+
+    pub fn wasm_path(mut self, wasm_path: &str) -> TestSetup {
+        self.mock_settings.wasm_path = wasm_path.to_string();
+        self
+    }
+
+    pub fn vm_config(mut self, vm_config: &str) -> TestSetup {
+        self.vm_config = vm_config.to_string();
+        self
+    }
+
+    pub fn plugin_config(mut self, plugin_config: &str) -> TestSetup {
+        self.plugin_config = plugin_config.as_bytes().to_vec();
+        self
+    }
+
+    // Binary plugin configuration, e.g. a serialized protobuf message
+    pub fn plugin_config_bytes(mut self, plugin_config: &[u8]) -> TestSetup {
+        self.plugin_config = plugin_config.to_vec();
+        self
+    }
+
+    // Plugin configuration as Envoy delivers the Any of the filter's configuration, see
+    // proto_config
+    #[cfg(feature = "protobuf")]
+    pub fn plugin_config_any(self, plugin_config: &prost_types::Any) -> Result<TestSetup> {
+        let plugin_config = crate::proto_config::delivered_bytes(plugin_config)?;
+        Ok(self.plugin_config_bytes(&plugin_config))
+    }
+
+    // Validates the plugin configuration against the schema before proxy_on_configure runs
+    #[cfg(feature = "schema")]
+    pub fn config_schema(self, schema: ConfigSchema) -> TestSetup {
+        self.before_start(move |tester: &mut Tester| {
+            tester.set_config_schema(schema.clone());
+        })
+    }
+
+    pub fn quiet(mut self) -> TestSetup {
+        self.mock_settings.quiet = true;
+        self
+    }
+
+    pub fn allow_unexpected(mut self) -> TestSetup {
+        self.mock_settings.allow_unexpected = true;
+        self
+    }
+
+    pub fn before_start<T: TestResult>(
+        mut self,
+        hook: impl Fn(&mut Tester) -> T + 'static,
+    ) -> TestSetup {
+        self.before_start.push(to_hook(hook));
+        self
+    }
+
+    pub fn setup<T: TestResult>(mut self, hook: impl Fn(&mut Tester) -> T + 'static) -> TestSetup {
+        self.setup.push(to_hook(hook));
+        self
+    }
+
+    // Teardown hooks run in reverse order of registration, after the test even if it failed
+    pub fn teardown<T: TestResult>(
+        mut self,
+        hook: impl Fn(&mut Tester) -> T + 'static,
+    ) -> TestSetup {
+        self.teardown.push(to_hook(hook));
+        self
+    }
+
+    // Tester with the root context configured
+    pub fn start(&self) -> Result<Tester> {
+        let mut tester = self.boot()?;
+        tester.execute_and_expect_n(vec![ReturnType::Bool(true), ReturnType::Bool(true)])?;
+        self.set_up(tester)
+    }
+
+    // Same as start(), None when the plugin rejects its configuration (proxy_on_vm_start or
+    // proxy_on_configure returning false)
+    pub(crate) fn try_start(&self) -> Result<Option<Tester>> {
+        let mut tester = self.boot()?;
+        if tester.execute_all()?.contains(&Some(0)) {
+            return Ok(None);
+        }
+        self.set_up(tester).map(Some)
+    }
+
+    // Module started, with proxy_on_vm_start and proxy_on_configure staged
+    fn boot(&self) -> Result<Tester> {
+        let mut tester = mock(self.mock_settings.clone())?;
+        for hook in &self.before_start {
+            hook(&mut tester)?;
+        }
+        tester.call_start().execute_and_expect(ReturnType::None)?;
+        tester
+            .call_proxy_on_context_create(ROOT_CONTEXT, 0)
+            .execute_and_expect(ReturnType::None)?;
+        tester
+            .set_default_buffer_bytes(BufferType::VmConfiguration)
+            .returning(&self.vm_config)
+            .set_default_buffer_bytes(BufferType::PluginConfiguration)
+            .returning_bytes(&self.plugin_config)
+            .call_proxy_on_vm_start(ROOT_CONTEXT, self.vm_config.len() as i32)
+            .call_proxy_on_configure(ROOT_CONTEXT, self.plugin_config.len() as i32);
+        Ok(tester)
+    }
+
+    fn set_up(&self, mut tester: Tester) -> Result<Tester> {
+        for hook in &self.setup {
+            hook(&mut tester)?;
+        }
+        Ok(tester)
+    }
+
+    #[track_caller]
+    pub fn run<T: TestResult>(&self, test: impl FnOnce(&mut Tester) -> T) -> Result<()> {
+        let mut tester = self.start()?;
+        let failure_artifacts = artifacts::capture_from_env(&mut tester);
+        let export = |tester: &Tester, failure: &str| {
+            export_envoy_config(tester);
+            if let Some(failure_artifacts) = &failure_artifacts {
+                let written = artifacts::write_to_env(failure_artifacts, tester, failure);
+                with_log(&tester.log, || info!("[host] {}", written));
+            }
+        };
+        let result = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            test(&mut tester).into_result()
+        })) {
+            Ok(result) => result,
+            Err(panic) => {
+                export(&tester, &artifacts::panic_message(panic.as_ref()));
+                std::panic::resume_unwind(panic);
+            }
+        };
+        if let Err(error) = &result {
+            export(&tester, &format!("{:#}", error));
+        }
+        for hook in self.teardown.iter().rev() {
+            // the failure of the test comes first
+            let teardown = hook(&mut tester);
+            if result.is_ok() {
+                teardown?;
+            }
+        }
+        result?;
+        tester.assert_finished();
+        Ok(())
+    }
+}
+
+// With PROXY_WASM_TEST_ENVOY_CONFIG=<directory>, failing tests write an Envoy configuration
+// running the module the same way, named after the test (see EnvoyConfig)
+fn export_envoy_config(tester: &Tester) {
+    let directory = match std::env::var("PROXY_WASM_TEST_ENVOY_CONFIG") {
+        Ok(directory) => directory,
+        Err(_) => return,
+    };
+    let test_name = match std::thread::current().name() {
+        Some("main") | None => "envoy".to_string(),
+        Some(name) => name.replace("::", "-"),
+    };
+    let path = Path::new(&directory).join(format!("{}.yaml", test_name));
+    let exported = std::fs::create_dir_all(&directory)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| EnvoyConfig::from_tester(tester))
+        .and_then(|config| config.save(&path));
+    with_log(&tester.log, || match exported {
+        Ok(command) => info!("[host] rerun against Envoy with: {}", command),
+        Err(error) => info!("[host] cannot export the Envoy configuration: {:#}", error),
+    });
+}
+
+// Entries sorted by lowercased name, the values of each name kept in order
+fn sorted_headers(headers: &HeaderMap) -> Vec<(String, &str)> {
+    let mut sorted: Vec<(String, &str)> = headers
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value))
+        .collect();
+    sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+    sorted
+}
+
+// Header values read as UTF-8
+pub(crate) fn lossy_header_map(header_map_pairs: Vec<(String, Bytes)>) -> HeaderMap {
+    header_map_pairs
+        .into_iter()
+        .map(|(name, value)| (name, String::from_utf8_lossy(&value).into_owned()))
+        .collect()
+}
+
+fn full_body(body: Bytes) -> Option<HttpBody> {
+    Some(body)
+        .filter(|body| !body.is_empty())
+        .map(HttpBody::Full)
+}
+
+fn to_hook<T: TestResult>(hook: impl Fn(&mut Tester) -> T + 'static) -> Hook {
+    Rc::new(move |tester| hook(tester).into_result())
+}
+
+// What a test run by TestSetup may return: nothing, or a Result
+pub trait TestResult {
+    fn into_result(self) -> Result<()>;
+}
+
+impl TestResult for () {
+    fn into_result(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<T, E: Into<anyhow::Error>> TestResult for std::result::Result<T, E> {
+    fn into_result(self) -> Result<()> {
+        self.map(|_| ()).map_err(Into::into)
+    }
+}
+
+const BOOL_RETURNS: &[(i32, &str)] = &[(0, "false"), (1, "true")];
+const STOP_RETURNS: &[(i32, &str)] = &[(0, "Continue"), (1, "StopIteration")];
+const HEADERS_RETURNS: &[(i32, &str)] = &[
+    (0, "Continue"),
+    (1, "StopIteration"),
+    (2, "ContinueAndEndStream"),
+    (3, "StopAllIterationAndBuffer"),
+    (4, "StopAllIterationAndWatermark"),
+];
+const HEADERS_RETURNS_0_2_1: &[(i32, &str)] = &[
+    (0, "Continue"),
+    (1, "StopIteration"),
+    (3, "StopAllIterationAndBuffer"),
+    (4, "StopAllIterationAndWatermark"),
+];
+const BODY_RETURNS: &[(i32, &str)] = &[
+    (0, "Continue"),
+    (1, "StopIterationAndBuffer"),
+    (2, "StopIterationAndWatermark"),
+    (3, "StopIterationNoBuffer"),
+];
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 enum FunctionCall {
     Start(),
@@ -90,9 +629,156 @@ enum FunctionCall {
     ProxyOnGrpcReceiveTrailingMetadata(i32, i32, i32),
     ProxyOnGrpcReceive(i32, i32, i32),
     ProxyOnGrpcClose(i32, i32, i32),
+    #[cfg(feature = "vnext")]
+    ProxyOnRedisCallResponse(i32, i32, i32, i32),
     ProxyOnDone(i32),
     ProxyOnLog(i32),
     ProxyOnDelete(i32),
+    AdvanceTime(Duration),
+}
+
+impl FunctionCall {
+    // Context the host is acting on while the call executes (the first argument of every callback)
+    fn context_id(&self) -> Option<i32> {
+        match *self {
+            FunctionCall::Start() | FunctionCall::AdvanceTime(..) => None,
+            FunctionCall::ProxyOnVmStart(context_id, ..)
+            | FunctionCall::ProxyValidateConfiguration(context_id, ..)
+            | FunctionCall::ProxyOnConfigure(context_id, ..)
+            | FunctionCall::ProxyOnTick(context_id)
+            | FunctionCall::ProxyOnForeignFunction(context_id, ..)
+            | FunctionCall::ProxyOnQueueReady(context_id, ..)
+            | FunctionCall::ProxyOnContextCreate(context_id, ..)
+            | FunctionCall::ProxyOnNewConnection(context_id)
+            | FunctionCall::ProxyOnDownstreamData(context_id, ..)
+            | FunctionCall::ProxyOnDownstreamConnectionClose(context_id, ..)
+            | FunctionCall::ProxyOnUpstreamData(context_id, ..)
+            | FunctionCall::ProxyOnUpstreamConnectionClose(context_id, ..)
+            | FunctionCall::ProxyOnRequestHeaders(context_id, ..)
+            | FunctionCall::ProxyOnRequestBody(context_id, ..)
+            | FunctionCall::ProxyOnRequestTrailers(context_id, ..)
+            | FunctionCall::ProxyOnRequestMetadata(context_id, ..)
+            | FunctionCall::ProxyOnResponseHeaders(context_id, ..)
+            | FunctionCall::ProxyOnResponseBody(context_id, ..)
+            | FunctionCall::ProxyOnResponseTrailers(context_id, ..)
+            | FunctionCall::ProxyOnResponseMetadata(context_id, ..)
+            | FunctionCall::ProxyOnHttpCallResponse(context_id, ..)
+            | FunctionCall::ProxyOnGrpcReceiveInitialMetadata(context_id, ..)
+            | FunctionCall::ProxyOnGrpcReceiveTrailingMetadata(context_id, ..)
+            | FunctionCall::ProxyOnGrpcReceive(context_id, ..)
+            | FunctionCall::ProxyOnGrpcClose(context_id, ..)
+            | FunctionCall::ProxyOnDone(context_id)
+            | FunctionCall::ProxyOnLog(context_id)
+            | FunctionCall::ProxyOnDelete(context_id) => Some(context_id),
+            #[cfg(feature = "vnext")]
+            FunctionCall::ProxyOnRedisCallResponse(context_id, ..) => Some(context_id),
+        }
+    }
+
+    // Export called into, None for what is not a callback (the start function may be _initialize,
+    // main or _start)
+    fn export_name(&self) -> Option<&'static str> {
+        let name = match *self {
+            FunctionCall::Start() => "_start",
+            FunctionCall::ProxyOnVmStart(..) => "proxy_on_vm_start",
+            FunctionCall::ProxyValidateConfiguration(..) => "proxy_validate_configuration",
+            FunctionCall::ProxyOnConfigure(..) => "proxy_on_configure",
+            FunctionCall::ProxyOnTick(..) => "proxy_on_tick",
+            FunctionCall::ProxyOnForeignFunction(..) => "proxy_on_foreign_function",
+            FunctionCall::ProxyOnQueueReady(..) => "proxy_on_queue_ready",
+            FunctionCall::ProxyOnContextCreate(..) => "proxy_on_context_create",
+            FunctionCall::ProxyOnNewConnection(..) => "proxy_on_new_connection",
+            FunctionCall::ProxyOnDownstreamData(..) => "proxy_on_downstream_data",
+            FunctionCall::ProxyOnDownstreamConnectionClose(..) => {
+                "proxy_on_downstream_connection_close"
+            }
+            FunctionCall::ProxyOnUpstreamData(..) => "proxy_on_upstream_data",
+            FunctionCall::ProxyOnUpstreamConnectionClose(..) => {
+                "proxy_on_upstream_connection_close"
+            }
+            FunctionCall::ProxyOnRequestHeaders(..) => "proxy_on_request_headers",
+            FunctionCall::ProxyOnRequestBody(..) => "proxy_on_request_body",
+            FunctionCall::ProxyOnRequestTrailers(..) => "proxy_on_request_trailers",
+            FunctionCall::ProxyOnRequestMetadata(..) => "proxy_on_request_metadata",
+            FunctionCall::ProxyOnResponseHeaders(..) => "proxy_on_response_headers",
+            FunctionCall::ProxyOnResponseBody(..) => "proxy_on_response_body",
+            FunctionCall::ProxyOnResponseTrailers(..) => "proxy_on_response_trailers",
+            FunctionCall::ProxyOnResponseMetadata(..) => "proxy_on_response_metadata",
+            FunctionCall::ProxyOnHttpCallResponse(..) => "proxy_on_http_call_response",
+            FunctionCall::ProxyOnGrpcReceiveInitialMetadata(..) => {
+                "proxy_on_grpc_receive_initial_metadata"
+            }
+            FunctionCall::ProxyOnGrpcReceiveTrailingMetadata(..) => {
+                "proxy_on_grpc_receive_trailing_metadata"
+            }
+            FunctionCall::ProxyOnGrpcReceive(..) => "proxy_on_grpc_receive",
+            FunctionCall::ProxyOnGrpcClose(..) => "proxy_on_grpc_close",
+            #[cfg(feature = "vnext")]
+            FunctionCall::ProxyOnRedisCallResponse(..) => "proxy_on_redis_call_response",
+            FunctionCall::ProxyOnDone(..) => "proxy_on_done",
+            FunctionCall::ProxyOnLog(..) => "proxy_on_log",
+            FunctionCall::ProxyOnDelete(..) => "proxy_on_delete",
+            FunctionCall::AdvanceTime(..) => return None,
+        };
+        Some(name)
+    }
+
+    // Values the callback may return under the ABI version, as Envoy reads them, None if the
+    // callback returns nothing (or anything, e.g. proxy_on_foreign_function)
+    fn legal_returns(&self, abi_version: AbiVersion) -> Option<&'static [(i32, &'static str)]> {
+        match *self {
+            FunctionCall::ProxyOnVmStart(..)
+            | FunctionCall::ProxyValidateConfiguration(..)
+            | FunctionCall::ProxyOnConfigure(..)
+            | FunctionCall::ProxyOnDone(..) => Some(BOOL_RETURNS),
+            // ContinueAndEndStream was dropped in 0.2.1
+            FunctionCall::ProxyOnRequestHeaders(..) | FunctionCall::ProxyOnResponseHeaders(..)
+                if abi_version == AbiVersion::ProxyAbiVersion0_2_1 =>
+            {
+                Some(HEADERS_RETURNS_0_2_1)
+            }
+            FunctionCall::ProxyOnRequestHeaders(..) | FunctionCall::ProxyOnResponseHeaders(..) => {
+                Some(HEADERS_RETURNS)
+            }
+            FunctionCall::ProxyOnRequestBody(..) | FunctionCall::ProxyOnResponseBody(..) => {
+                Some(BODY_RETURNS)
+            }
+            FunctionCall::ProxyOnRequestTrailers(..)
+            | FunctionCall::ProxyOnResponseTrailers(..)
+            | FunctionCall::ProxyOnNewConnection(..)
+            | FunctionCall::ProxyOnDownstreamData(..)
+            | FunctionCall::ProxyOnUpstreamData(..) => Some(STOP_RETURNS),
+            FunctionCall::ProxyOnRequestMetadata(..)
+            | FunctionCall::ProxyOnResponseMetadata(..) => Some(&STOP_RETURNS[..1]),
+            _ => None,
+        }
+    }
+
+    // Stream callbacks, checked against the progress of their stream (with end_of_stream)
+    fn stream_phase(&self) -> Option<(i32, StreamPhase, bool)> {
+        match *self {
+            FunctionCall::ProxyOnRequestHeaders(context_id, _, end_of_stream) => {
+                Some((context_id, StreamPhase::RequestHeaders, end_of_stream))
+            }
+            FunctionCall::ProxyOnRequestBody(context_id, _, end_of_stream) => {
+                Some((context_id, StreamPhase::RequestBody, end_of_stream))
+            }
+            FunctionCall::ProxyOnRequestTrailers(context_id, _) => {
+                Some((context_id, StreamPhase::RequestTrailers, true))
+            }
+            FunctionCall::ProxyOnResponseHeaders(context_id, _, end_of_stream) => {
+                Some((context_id, StreamPhase::ResponseHeaders, end_of_stream))
+            }
+            FunctionCall::ProxyOnResponseBody(context_id, _, end_of_stream) => {
+                Some((context_id, StreamPhase::ResponseBody, end_of_stream))
+            }
+            FunctionCall::ProxyOnResponseTrailers(context_id, _) => {
+                Some((context_id, StreamPhase::ResponseTrailers, true))
+            }
+            FunctionCall::ProxyOnLog(context_id) => Some((context_id, StreamPhase::Log, false)),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -102,22 +788,99 @@ enum FunctionType {
     ReturnAction,
 }
 
+// PROXY_WASM_TEST_LOG if set (error, warn, info, debug or trace), else WARN in quiet mode and INFO
+pub(crate) fn default_verbosity(quiet: bool) -> Level {
+    let verbosity = std::env::var("PROXY_WASM_TEST_LOG")
+        .ok()
+        .and_then(|verbosity| verbosity.parse().ok());
+    match verbosity {
+        Some(verbosity) => verbosity,
+        None if quiet => Level::WARN,
+        None => Level::INFO,
+    }
+}
+
+// Output of a Tester at the verbosity, see with_log
+pub(crate) fn log_dispatch(verbosity: Level) -> Dispatch {
+    let log = Dispatch::new(
+        tracing_subscriber::fmt()
+            .with_max_level(verbosity)
+            .with_test_writer()
+            .without_time()
+            .with_target(false)
+            .with_level(false)
+            .finish(),
+    );
+    // with a single subscriber around, tracing caches which events are enabled based on the
+    // default subscriber, which is only this one inside with_log
+    with_log(&log, tracing::callsite::rebuild_interest_cache);
+    log
+}
+
+// Runs f with the output of a Tester (see Tester::set_verbosity), unless a global subscriber
+// was installed, which then receives the events of the framework
+pub(crate) fn with_log<T>(log: &Dispatch, f: impl FnOnce() -> T) -> T {
+    let subscribed = tracing::dispatcher::get_default(|current| !current.is::<NoSubscriber>());
+    if subscribed {
+        f()
+    } else {
+        tracing::dispatcher::with_default(log, f)
+    }
+}
+
 pub struct Tester {
     abi_version: AbiVersion,
     mock_settings: MockSettings,
-    store: Store<()>,
+    store: Store<HostState>,
     instance: Instance,
     defaults: Arc<Mutex<HostHandle>>,
     expect: Arc<Mutex<ExpectHandle>>,
+    vm_id: String,
+    auto_content_length: bool,
+    // chunks staged by the chunked combination calls, keyed by (context_id, buffer_type)
+    body_chunks: HashMap<(i32, i32), VecDeque<Bytes>>,
+    // network data the module paused on (and the proxy keeps buffering), same keys
+    held_data: HashSet<(i32, i32)>,
+    fuel_limit: Option<u64>,
+    max_fuel: Option<u64>,
+    timeout: Option<Duration>,
+    run_timeout: Option<Duration>,
+    run_deadline: Option<Instant>,
+    fuel_consumed: u64,
+    memory_baseline: usize,
+    memory_growth: usize,
+    profile: Profile,
+    // format rendered when proxy_on_log returns, and the lines rendered with their context_id
+    access_log_format: Option<AccessLogFormat>,
+    access_log: Vec<(i32, String)>,
+    // schema the plugin configuration is validated against before proxy_on_configure
+    #[cfg(feature = "schema")]
+    config_schema: Option<ConfigSchema>,
     function_call: Vec<FunctionCall>,
     function_type: Vec<FunctionType>,
+    verbosity: Level,
+    log: Dispatch,
 }
 
 impl Tester {
+    pub fn builder() -> TesterBuilder {
+        TesterBuilder::new()
+    }
+
+    // Tester of a module embedded in the test (binary format), with the default settings
+    pub fn from_bytes(wasm: &[u8]) -> Result<Tester> {
+        TesterBuilder::new().wasm_bytes(wasm).build()
+    }
+
+    // Same as from_bytes() for a module in the text format, e.g. a tiny reproduction case
+    pub fn from_wat(wat: &str) -> Result<Tester> {
+        TesterBuilder::new().wat(wat).build()
+    }
+
     fn new(
         abi_version: AbiVersion,
         mock_settings: MockSettings,
-        store: Store<()>,
+        store: Store<HostState>,
         instance: Instance,
         host_settings: Arc<Mutex<HostHandle>>,
         expect: Arc<Mutex<ExpectHandle>>,
@@ -129,24 +892,68 @@ impl Tester {
             instance,
             defaults: host_settings,
             expect,
+            vm_id: String::new(),
+            auto_content_length: true,
+            body_chunks: HashMap::new(),
+            held_data: HashSet::new(),
+            fuel_limit: None,
+            max_fuel: None,
+            timeout: None,
+            run_timeout: None,
+            run_deadline: None,
+            fuel_consumed: 0,
+            memory_baseline: 0,
+            memory_growth: 0,
+            profile: Profile::default(),
+            access_log_format: None,
+            access_log: vec![],
+            #[cfg(feature = "schema")]
+            config_schema: None,
             function_call: vec![],
             function_type: vec![],
+            verbosity: Level::INFO,
+            log: Dispatch::none(),
         };
+        tester.set_verbosity(default_verbosity(tester.mock_settings.quiet));
         tester.update_expect_stage();
         tester.reset_host_settings();
+        tester.reset_memory_baseline();
+        if flamegraph::sampling_from_env() {
+            tester.record_stack_samples();
+        }
         tester
     }
 
+    // Once the creator of the tester has settled the seed, see mock_loaded
+    fn log_seed(&self) {
+        let seed = self.seed();
+        with_log(&self.log, || {
+            info!(
+                "[host] random seed: {} (replay with PROXY_WASM_TEST_SEED={})",
+                seed, seed
+            )
+        });
+    }
+
     /* ------------------------------------- Low-level Expectation Setting ------------------------------------- */
 
-    pub fn expect_log(&mut self, log_level: Option<LogLevel>, log_msg: Option<&str>) -> &mut Self {
+    #[track_caller]
+    pub fn expect_log(
+        &mut self,
+        log_level: Option<LogLevel>,
+        log_msg: impl Into<Matches<str>>,
+    ) -> &mut Self {
         self.get_expect_handle()
             .staged
-            .set_expect_log(log_level.map(|data| data as i32), log_msg);
+            .set_expect_log(log_level, log_msg);
         self
     }
 
-    pub fn expect_set_tick_period_millis(&mut self, tick_period_millis: Option<u64>) -> &mut Self {
+    #[track_caller]
+    pub fn expect_set_tick_period_millis(
+        &mut self,
+        tick_period_millis: impl Into<Matches<u64>>,
+    ) -> &mut Self {
         self.get_expect_handle()
             .staged
             .set_expect_set_tick_period_millis(tick_period_millis);
@@ -164,14 +971,15 @@ impl Tester {
         ExpectGetBufferBytes::expecting(self, buffer_type.map(|data| data as i32))
     }
 
+    #[track_caller]
     pub fn expect_set_buffer_bytes(
         &mut self,
         buffer_type: Option<BufferType>,
-        buffer_data: Option<&str>,
+        buffer_data: impl Into<Matches<[u8]>>,
     ) -> &mut Self {
         self.get_expect_handle()
             .staged
-            .set_expect_set_buffer_bytes(buffer_type.map(|data| data as i32), buffer_data);
+            .set_expect_set_buffer_bytes(buffer_type, buffer_data);
         self
     }
 
@@ -182,74 +990,75 @@ impl Tester {
         ExpectGetHeaderMapPairs::expecting(self, map_type.map(|data| data as i32))
     }
 
+    #[track_caller]
     pub fn expect_set_header_map_pairs(
         &mut self,
         map_type: Option<MapType>,
-        header_map_pairs: Option<Vec<(&str, &str)>>,
+        header_map_pairs: impl Into<Matches<[(String, String)]>>,
     ) -> &mut Self {
         self.get_expect_handle()
             .staged
-            .set_expect_set_header_map_pairs(map_type.map(|data| data as i32), header_map_pairs);
+            .set_expect_set_header_map_pairs(map_type, header_map_pairs);
         self
     }
 
     pub fn expect_get_header_map_value(
         &mut self,
         map_type: Option<MapType>,
-        header_map_key: Option<&'static str>,
+        header_map_key: impl Into<Matches<str>>,
     ) -> ExpectGetHeaderMapValue {
-        ExpectGetHeaderMapValue::expecting(self, map_type.map(|data| data as i32), header_map_key)
+        ExpectGetHeaderMapValue::expecting(
+            self,
+            map_type.map(|data| data as i32),
+            header_map_key.into(),
+        )
     }
 
+    #[track_caller]
     pub fn expect_replace_header_map_value(
         &mut self,
         map_type: Option<MapType>,
-        header_map_key: Option<&str>,
-        header_map_value: Option<&str>,
+        header_map_key: impl Into<Matches<str>>,
+        header_map_value: impl Into<Matches<[u8]>>,
     ) -> &mut Self {
         self.get_expect_handle()
             .staged
-            .set_expect_replace_header_map_value(
-                map_type.map(|data| data as i32),
-                header_map_key,
-                header_map_value,
-            );
+            .set_expect_replace_header_map_value(map_type, header_map_key, header_map_value);
         self
     }
 
+    #[track_caller]
     pub fn expect_remove_header_map_value(
         &mut self,
         map_type: Option<MapType>,
-        header_map_key: Option<&str>,
+        header_map_key: impl Into<Matches<str>>,
     ) -> &mut Self {
         self.get_expect_handle()
             .staged
-            .set_expect_remove_header_map_value(map_type.map(|data| data as i32), header_map_key);
+            .set_expect_remove_header_map_value(map_type, header_map_key);
         self
     }
 
+    #[track_caller]
     pub fn expect_add_header_map_value(
         &mut self,
         map_type: Option<MapType>,
-        header_map_key: Option<&str>,
-        header_map_value: Option<&str>,
+        header_map_key: impl Into<Matches<str>>,
+        header_map_value: impl Into<Matches<[u8]>>,
     ) -> &mut Self {
         self.get_expect_handle()
             .staged
-            .set_expect_add_header_map_value(
-                map_type.map(|data| data as i32),
-                header_map_key,
-                header_map_value,
-            );
+            .set_expect_add_header_map_value(map_type, header_map_key, header_map_value);
         self
     }
 
+    #[track_caller]
     pub fn expect_send_local_response(
         &mut self,
-        status_code: Option<i32>,
-        body: Option<&str>,
-        headers: Option<Vec<(&str, &str)>>,
-        grpc_status: Option<i32>,
+        status_code: impl Into<Matches<i32>>,
+        body: impl Into<Matches<[u8]>>,
+        headers: impl Into<Matches<[(String, String)]>>,
+        grpc_status: impl Into<Matches<i32>>,
     ) -> &mut Self {
         self.get_expect_handle()
             .staged
@@ -259,25 +1068,40 @@ impl Tester {
 
     pub fn expect_http_call(
         &mut self,
-        upstream: Option<&'static str>,
-        headers: Option<Vec<(&'static str, &'static str)>>,
-        body: Option<&'static str>,
-        trailers: Option<Vec<(&'static str, &'static str)>>,
-        timeout: Option<u64>,
+        upstream: impl Into<Matches<str>>,
+        headers: impl Into<Matches<[(String, String)]>>,
+        body: impl Into<Matches<[u8]>>,
+        trailers: impl Into<Matches<[(String, String)]>>,
+        timeout: impl Into<Matches<u64>>,
     ) -> ExpectHttpCall {
-        ExpectHttpCall::expecting(self, upstream, headers, body, trailers, timeout)
+        ExpectHttpCall::expecting(
+            self,
+            upstream.into(),
+            headers.into(),
+            body.into(),
+            trailers.into(),
+            timeout.into(),
+        )
     }
 
+    #[track_caller]
     pub fn expect_metric_creation(&mut self, metric_type: MetricType, name: &str) -> &mut Self {
-        self.get_settings_handle().staged.create_metric(name);
+        self.get_settings_handle()
+            .staged
+            .define_metric(metric_type as i32, name);
 
         self.get_expect_handle()
             .staged
-            .set_expect_metric_create(metric_type as i32, name);
+            .set_expect_metric_create(metric_type, name);
         self
     }
 
-    pub fn expect_metric_increment(&mut self, name: &str, offset: i64) -> &mut Self {
+    #[track_caller]
+    pub fn expect_metric_increment(
+        &mut self,
+        name: &str,
+        offset: impl Into<Matches<i64>>,
+    ) -> &mut Self {
         let metric_id = self.get_settings_handle().staged.get_metric_id(name);
 
         self.get_expect_handle()
@@ -286,7 +1110,12 @@ impl Tester {
         self
     }
 
-    pub fn expect_metric_record(&mut self, name: &str, value: u64) -> &mut Self {
+    #[track_caller]
+    pub fn expect_metric_record(
+        &mut self,
+        name: &str,
+        value: impl Into<Matches<u64>>,
+    ) -> &mut Self {
         let metric_id = self.get_settings_handle().staged.get_metric_id(name);
 
         self.get_expect_handle()
@@ -295,7 +1124,8 @@ impl Tester {
         self
     }
 
-    pub fn expect_metric_get(&mut self, name: &str, value: u64) -> &mut Self {
+    #[track_caller]
+    pub fn expect_metric_get(&mut self, name: &str, value: impl Into<Matches<u64>>) -> &mut Self {
         let metric_id = self.get_settings_handle().staged.get_metric_id(name);
 
         self.get_expect_handle()
@@ -304,54 +1134,864 @@ impl Tester {
         self
     }
 
-    /* ------------------------------------- High-level Expectation Setting ------------------------------------- */
+    /* ------------------------------------- Metrics Assertions ------------------------------------- */
 
-    pub fn set_quiet(&mut self, quiet: bool) {
-        self.mock_settings.quiet = quiet;
-        self.get_settings_handle().staged.set_quiet_mode(quiet);
+    // Asserts on the final state of the metrics registry instead of staging every metric call
+    #[track_caller]
+    pub fn assert_counter(&mut self, name: &str, value: i64) -> &mut Self {
+        let metric = self.get_metric(name, MetricType::Counter);
+        assert_eq!(
+            metric.value, value,
+            "Error: counter \"{}\" has value {}, expected {}",
+            name, metric.value, value
+        );
+        self
     }
 
-    pub fn reset_default_tick_period_millis(&mut self) -> &mut Self {
-        self.get_settings_handle().staged.reset_tick_period_millis();
+    #[track_caller]
+    pub fn assert_gauge(&mut self, name: &str, value: i64) -> &mut Self {
+        let metric = self.get_metric(name, MetricType::Gauge);
+        assert_eq!(
+            metric.value, value,
+            "Error: gauge \"{}\" has value {}, expected {}",
+            name, metric.value, value
+        );
         self
     }
 
-    pub fn set_default_tick_period_millis(&mut self, tick_period_millis: u64) -> &mut Self {
-        self.get_settings_handle()
-            .staged
-            .set_tick_period_millis(tick_period_millis);
+    #[track_caller]
+    pub fn assert_histogram_count(&mut self, name: &str, count: usize) -> &mut Self {
+        let histogram = self.get_histogram(name);
+        assert_eq!(
+            histogram.count(),
+            count,
+            "Error: histogram \"{}\" has {} samples, expected {}",
+            name,
+            histogram.count(),
+            count
+        );
         self
     }
 
-    pub fn reset_default_buffer_bytes(&mut self) -> &mut Self {
-        self.get_settings_handle().staged.reset_buffer_bytes();
+    // e.g. assert_histogram_percentile("latency", 99.0, Predicate(|p99: &i64| *p99 < 250))
+    #[track_caller]
+    pub fn assert_histogram_percentile(
+        &mut self,
+        name: &str,
+        percentile: f64,
+        value: impl Into<Matches<i64>>,
+    ) -> &mut Self {
+        let value = value.into();
+        let actual = match self.get_histogram(name).percentile(percentile) {
+            Some(actual) => actual,
+            None => panic!("Error: histogram \"{}\" has no samples", name),
+        };
+        assert!(
+            value.matches(&actual),
+            "Error: histogram \"{}\" has p{} of {}, expected {:?}",
+            name,
+            percentile,
+            actual,
+            value
+        );
         self
     }
 
-    pub fn set_default_buffer_bytes(&mut self, buffer_type: BufferType) -> DefaultBufferBytes {
-        DefaultBufferBytes::expecting(self, buffer_type as i32)
+    // Aggregated samples of a histogram (count, sum, min, max, percentiles) for custom checks
+    #[track_caller]
+    pub fn get_histogram(&self, name: &str) -> Histogram {
+        Histogram {
+            samples: self.get_metric(name, MetricType::Histogram).samples,
+        }
     }
 
-    pub fn reset_default_header_map_pairs(&mut self) -> &mut Self {
-        self.get_settings_handle().staged.reset_header_map_pairs();
+    // Forwards every counter, gauge and histogram update of the module to the sink from now on
+    // (see statsd), e.g. StatsdSink::memory() kept to assert on its lines
+    pub fn set_statsd_sink(&mut self, sink: StatsdSink) -> &mut Self {
+        *self.store.data().statsd.lock().unwrap() = Some(sink);
         self
     }
 
-    pub fn set_default_header_map_pairs(&mut self, map_type: MapType) -> DefaultHeaderMapPairs {
-        DefaultHeaderMapPairs::expecting(self, map_type as i32)
+    pub fn reset_statsd_sink(&mut self) -> &mut Self {
+        *self.store.data().statsd.lock().unwrap() = None;
+        self
     }
 
-    /* ------------------------------------- Utility Functions ------------------------------------- */
+    // Every metric defined so far, e.g. metrics().to_prometheus_text() for the whole metric
+    // surface of the module
+    pub fn metrics(&self) -> Metrics {
+        self.get_settings_handle().staged.get_metrics()
+    }
 
-    pub fn get_expect_handle(&self) -> MutexGuard<ExpectHandle> {
-        self.expect.lock().unwrap()
+    // Compares the Prometheus exposition of the metrics against a golden file, e.g. checked in
+    // under tests/golden, set PROXY_WASM_TEST_UPDATE_SNAPSHOTS to update it
+    #[track_caller]
+    pub fn assert_metrics_snapshot(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        let text = self.metrics().to_prometheus_text();
+        with_log(&self.log, || {
+            assert_golden("metrics exposition", path.as_ref(), &text)
+        });
+        self
     }
 
-    pub fn print_expectations(&self) {
+    #[track_caller]
+    fn get_metric(&self, name: &str, metric_type: MetricType) -> Metric {
+        let metric = match self.get_settings_handle().staged.get_metric_by_name(name) {
+            Some(metric) => metric.clone(),
+            None => panic!("Error: metric \"{}\" was never defined", name),
+        };
+        assert_eq!(
+            metric.metric_type, metric_type as i32,
+            "Error: metric \"{}\" is not a {:?}",
+            name, metric_type
+        );
+        metric
+    }
+
+    /* ------------------------------------- Log Assertions ------------------------------------- */
+
+    // Every message logged by the module since the start (or reset_logs), for checks that do
+    // not enumerate the logs in order, e.g. logs().contains(LogLevel::Warn, "denied")
+    pub fn logs(&self) -> Logs {
+        self.get_settings_handle().staged.get_logs()
+    }
+
+    pub fn reset_logs(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_logs();
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_logged(&mut self, level: LogLevel, substring: &str) -> &mut Self {
+        let logs = self.logs();
+        assert!(
+            logs.contains(level, substring),
+            "Error: no {:?} log contains \"{}\", logged: {:?}",
+            level,
+            substring,
+            logs.messages(level)
+        );
+        self
+    }
+
+    // Guard verifying the current expectation stage on verify() or drop
+    pub fn verifier(&self) -> Verifier {
+        Verifier::new(self.expect.clone(), self.log.clone())
+    }
+
+    // Applies to the most recently staged expectation: instead of being consumed by the first
+    // matching host call, it satisfies any number of calls (including none)
+    pub fn always(&mut self) -> &mut Self {
+        self.get_expect_handle().staged.set_expect_always();
+        self
+    }
+
+    /* ------------------------------------- Context Inspection ------------------------------------- */
+
+    // Contexts created so far (deleted ones included) in id order, e.g. to check that the module
+    // is done with every HTTP context once the streams are over
+    pub fn contexts(&self) -> Vec<ContextInfo> {
+        self.get_settings_handle().staged.get_contexts()
+    }
+
+    pub fn context(&self, context_id: i32) -> Option<ContextInfo> {
+        self.contexts()
+            .into_iter()
+            .find(|context| context.context_id == context_id)
+    }
+
+    #[track_caller]
+    pub fn assert_context(
+        &mut self,
+        context_id: i32,
+        context_type: ContextType,
+        state: ContextState,
+    ) -> &mut Self {
+        match self.context(context_id) {
+            Some(context) => assert!(
+                context.context_type == context_type && context.state == state,
+                "Error: expected context {} to be {:?} and {:?}, found {:?}",
+                context_id,
+                context_type,
+                state,
+                context
+            ),
+            None => panic!("Error: context {} was never created", context_id),
+        }
+        self
+    }
+
+    // Whether the HTTP stream went upstream or was answered by the module, as far as it got
+    pub fn stream_outcome(&self, context_id: i32) -> StreamOutcome {
+        self.get_settings_handle()
+            .staged
+            .get_stream_outcome(context_id)
+    }
+
+    #[track_caller]
+    pub fn assert_forwarded(&mut self, context_id: i32) -> &mut Self {
+        let outcome = self.stream_outcome(context_id);
+        assert!(
+            outcome == StreamOutcome::Forwarded,
+            "Error: expected stream {} to be forwarded upstream, found {:?}",
+            context_id,
+            outcome
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_local_reply(&mut self, context_id: i32, status_code: u32) -> &mut Self {
+        let outcome = self.stream_outcome(context_id);
+        assert!(
+            outcome == StreamOutcome::LocalReply(status_code),
+            "Error: expected stream {} to be answered locally with status {}, found {:?}",
+            context_id,
+            status_code,
+            outcome
+        );
+        self
+    }
+
+    // Request body as it went upstream: every part of it the module let through (continuing the
+    // body callbacks, or resuming the stream while holding the body), as the module left it
+    pub fn final_request_body(&self, context_id: i32) -> Bytes {
+        self.get_settings_handle()
+            .staged
+            .get_forwarded_body(context_id, true)
+    }
+
+    // Response body as it went downstream
+    pub fn final_response_body(&self, context_id: i32) -> Bytes {
+        self.get_settings_handle()
+            .staged
+            .get_forwarded_body(context_id, false)
+    }
+
+    // Request as it went upstream: its headers and trailers as the module let them through, and
+    // the final request body (None until the module let the headers through)
+    pub fn final_request(&self, context_id: i32) -> Option<HttpRequest> {
+        let (headers, trailers) = self
+            .get_settings_handle()
+            .staged
+            .get_forwarded_header_maps(context_id, true);
+        Some(HttpRequest {
+            headers: lossy_header_map(headers?),
+            body: full_body(self.final_request_body(context_id)),
+            trailers: trailers.map(lossy_header_map),
+        })
+    }
+
+    // Response as it went downstream: the local reply of the module if it answered, otherwise
+    // the upstream response as the module let it through
+    pub fn final_response(&self, context_id: i32) -> Option<HttpResponse> {
+        let local_reply = self
+            .get_settings_handle()
+            .staged
+            .get_local_reply(context_id);
+        if let Some(local_reply) = local_reply {
+            let status = vec![(
+                ":status".to_string(),
+                local_reply.status_code.to_string().into(),
+            )];
+            return Some(HttpResponse {
+                headers: lossy_header_map(status.into_iter().chain(local_reply.headers).collect()),
+                body: full_body(local_reply.body),
+                trailers: None,
+            });
+        }
+        let (headers, trailers) = self
+            .get_settings_handle()
+            .staged
+            .get_forwarded_header_maps(context_id, false);
+        Some(HttpResponse {
+            headers: lossy_header_map(headers?),
+            body: full_body(self.final_response_body(context_id)),
+            trailers: trailers.map(lossy_header_map),
+        })
+    }
+
+    // Renders the access log line of each stream when its proxy_on_log returns (see access_log),
+    // e.g. AccessLogFormat::envoy_default()
+    pub fn set_access_log_format(&mut self, format: AccessLogFormat) -> &mut Self {
+        self.access_log_format = Some(format);
+        self
+    }
+
+    // Lines rendered so far, in the order the streams were logged
+    pub fn access_log(&self) -> Vec<String> {
+        self.access_log
+            .iter()
+            .map(|(_, line)| line.clone())
+            .collect()
+    }
+
+    pub fn access_log_line(&self, context_id: i32) -> Option<String> {
+        self.access_log
+            .iter()
+            .rev()
+            .find(|(logged, _)| *logged == context_id)
+            .map(|(_, line)| line.clone())
+    }
+
+    #[track_caller]
+    pub fn assert_access_log(&mut self, context_id: i32, expected: &str) -> &mut Self {
+        let line = self.access_log_line(context_id);
+        assert!(
+            self.access_log_format.is_some(),
+            "Error: no access log format, see set_access_log_format"
+        );
+        assert!(
+            line.as_deref() == Some(expected),
+            "Error: expected access log line of stream {}\n  {:?}\nfound\n  {:?}",
+            context_id,
+            expected,
+            line
+        );
+        self
+    }
+
+    /* ------------------------------------- Header Map Inspection ------------------------------------- */
+
+    // Header map as the module left it: the maps staged for the callbacks, with every add,
+    // replace, remove and set_header_map_pairs of the module applied (values read as UTF-8)
+    pub fn header_map(&self, map_type: MapType) -> HeaderMap {
+        lossy_header_map(
+            self.get_settings_handle()
+                .staged
+                .get_header_map_data(map_type as i32),
+        )
+    }
+
+    // The same headers with the same values, whatever the order of the names (the values of a
+    // repeated name are compared in order)
+    #[track_caller]
+    pub fn assert_header_map(
+        &mut self,
+        map_type: MapType,
+        expected: impl Into<HeaderMap>,
+    ) -> &mut Self {
+        let found = self.header_map(map_type);
+        let expected = expected.into();
+        assert!(
+            sorted_headers(&found) == sorted_headers(&expected),
+            "Error: expected {:?} to be {:?}, found {:?}",
+            map_type,
+            expected.pairs(),
+            found.pairs()
+        );
+        self
+    }
+
+    // The first value of the header, or its absence with None
+    #[track_caller]
+    pub fn assert_header(
+        &mut self,
+        map_type: MapType,
+        name: &str,
+        value: Option<&str>,
+    ) -> &mut Self {
+        let found = self.header_map(map_type);
+        assert!(
+            found.get(name) == value,
+            "Error: expected {} to be {:?} in {:?}, found {:?}",
+            name,
+            value,
+            map_type,
+            found.pairs()
+        );
+        self
+    }
+
+    /* ------------------------------------- High-level Expectation Setting ------------------------------------- */
+
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.mock_settings.quiet = quiet;
+        self.get_settings_handle().staged.set_quiet_mode(quiet);
+        self.set_verbosity(default_verbosity(quiet));
+    }
+
+    // Level of the framework's own output, printed through tracing (and captured by the test
+    // harness): WARN and ERROR only report problems, INFO adds the module's logs and notices
+    // such as the random seed, DEBUG the callbacks and TRACE every host call. Defaults to INFO
+    // (WARN in quiet mode), or to PROXY_WASM_TEST_LOG (e.g. PROXY_WASM_TEST_LOG=trace) if set.
+    // An application that installs its own global subscriber receives the events instead.
+    pub fn set_verbosity(&mut self, verbosity: Level) -> &mut Self {
+        self.verbosity = verbosity;
+        self.log = log_dispatch(verbosity);
+        self
+    }
+
+    pub fn verbosity(&self) -> Level {
+        self.verbosity
+    }
+
+    pub(crate) fn log(&self) -> &Dispatch {
+        &self.log
+    }
+
+    pub fn reset_default_tick_period_millis(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_tick_period_millis();
+        self
+    }
+
+    pub fn set_default_tick_period_millis(&mut self, tick_period_millis: u64) -> &mut Self {
+        self.get_settings_handle()
+            .staged
+            .set_tick_period_millis(tick_period_millis);
+        self
+    }
+
+    // Current time of the host clock (see advance)
+    pub fn current_time_nanos(&self) -> u64 {
+        self.get_settings_handle().staged.get_current_time_nanos()
+    }
+
+    pub fn reset_default_current_time_nanos(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_current_time_nanos();
+        self
+    }
+
+    // Log level of the host returned by proxy_get_log_level (ABI 0.2.1), Info by default
+    pub fn set_default_log_level(&mut self, log_level: LogLevel) -> &mut Self {
+        self.get_settings_handle()
+            .staged
+            .set_log_level(log_level as i32);
+        self
+    }
+
+    // ABI version detected from the module, e.g. to skip steps a version does not support
+    pub fn abi_version(&self) -> AbiVersion {
+        self.abi_version
+    }
+
+    pub fn set_default_current_time_nanos(&mut self, current_time_nanos: u64) -> &mut Self {
+        self.get_settings_handle()
+            .staged
+            .set_current_time_nanos(current_time_nanos);
+        self
+    }
+
+    pub fn reset_default_return_status(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_return_status();
+        self
+    }
+
+    // Makes the named host function fail with the given status instead of running its default
+    // implementation, e.g. set_default_return_status("proxy_get_shared_data", Status::NotFound)
+    pub fn set_default_return_status(&mut self, host_call: &str, status: Status) -> &mut Self {
+        assert!(
+            host_call.starts_with("proxy_"),
+            "Error: \"{}\" is not a proxy-wasm host function",
+            host_call
+        );
+        self.get_settings_handle()
+            .staged
+            .set_return_status(host_call, status);
+        self
+    }
+
+    pub fn reset_default_properties(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_properties();
+        self
+    }
+
+    pub fn reset_default_property(&mut self, path: Vec<&str>) -> &mut Self {
+        self.get_settings_handle().staged.reset_property(&path);
+        self
+    }
+
+    // Overrides an attribute returned by proxy_get_property, e.g. (vec!["request", "path"], "/")
+    // or (vec!["response", "code"], 404i64.to_le_bytes()) following Envoy's value encoding
+    pub fn set_default_property(&mut self, path: Vec<&str>, value: impl AsRef<[u8]>) -> &mut Self {
+        self.get_settings_handle()
+            .staged
+            .set_property(&path, value.as_ref().to_vec());
+        self
+    }
+
+    // Spoofs the downstream client, e.g. "10.1.2.3:54321", updating the source attributes and the
+    // x-forwarded-for request header consistently
+    pub fn set_downstream_remote(&mut self, address: &str) -> &mut Self {
+        let socket_address = parse_socket_address(address);
+        let ip = socket_address.ip().to_string();
+        {
+            let mut host = self.get_settings_handle();
+            host.staged
+                .set_property(&["source", "address"], address.as_bytes().to_vec());
+            host.staged.set_property(
+                &["source", "port"],
+                (socket_address.port() as i64).to_le_bytes().to_vec(),
+            );
+            let request_headers = MapType::HttpRequestHeaders as i32;
+            host.staged
+                .remove_header_map_value(request_headers, "x-forwarded-for");
+            host.staged
+                .add_header_map_value(request_headers, "x-forwarded-for", ip.as_bytes());
+        }
+        self
+    }
+
+    // Address the downstream client connected to, e.g. "10.0.0.1:10000"
+    pub fn set_downstream_local(&mut self, address: &str) -> &mut Self {
+        let socket_address = parse_socket_address(address);
+        {
+            let mut host = self.get_settings_handle();
+            host.staged
+                .set_property(&["destination", "address"], address.as_bytes().to_vec());
+            host.staged.set_property(
+                &["destination", "port"],
+                (socket_address.port() as i64).to_le_bytes().to_vec(),
+            );
+        }
+        self
+    }
+
+    // Switches the emulated downstream connection to TLS, the returned builder fills in SNI,
+    // ALPN and certificate attributes (reset_default_properties reverts to plaintext)
+    pub fn set_tls_connection(&mut self) -> TlsConnection<'_> {
+        TlsConnection::expecting(self)
+    }
+
+    pub fn reset_default_filter_state(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_filter_state();
+        self
+    }
+
+    // Seeds filter state (as if set by a preceding native filter) under its full name
+    pub fn set_default_filter_state(
+        &mut self,
+        name: &str,
+        value: impl Into<FilterStateValue>,
+    ) -> &mut Self {
+        self.get_settings_handle()
+            .staged
+            .set_filter_state(name, value.into().to_bytes());
+        self
+    }
+
+    pub fn get_filter_state(&self, name: &str) -> Option<Bytes> {
+        self.get_settings_handle().staged.get_filter_state(name)
+    }
+
+    // Asserts on filter state left for subsequent filters, the module's writes through
+    // set_property(path) are named "wasm.<path>"
+    #[track_caller]
+    pub fn assert_filter_state(
+        &mut self,
+        name: &str,
+        value: impl Into<FilterStateValue>,
+    ) -> &mut Self {
+        let value = value.into();
+        match self.get_filter_state(name) {
+            Some(actual) => assert!(
+                actual == value.to_bytes(),
+                "Error: filter state \"{}\" is {:?}, expected {:?}",
+                name,
+                String::from_utf8_lossy(&actual),
+                value
+            ),
+            None => panic!("Error: filter state \"{}\" was never written", name),
+        }
+        self
+    }
+
+    // Untyped metadata of the matched route under the given filter namespace, e.g.
+    // ("envoy.lb", vec!["canary"], true) is read back from
+    // ["route_metadata", "filter_metadata", "envoy.lb", "canary"]
+    pub fn set_route_metadata(
+        &mut self,
+        filter: &str,
+        path: Vec<&str>,
+        value: impl Into<MetadataValue>,
+    ) -> &mut Self {
+        self.set_filter_metadata("route_metadata", filter, path, value.into())
+    }
+
+    pub fn set_cluster_metadata(
+        &mut self,
+        filter: &str,
+        path: Vec<&str>,
+        value: impl Into<MetadataValue>,
+    ) -> &mut Self {
+        self.set_filter_metadata("cluster_metadata", filter, path, value.into())
+    }
+
+    // Dynamic metadata of the request, read back from ["metadata", "filter_metadata", ...]
+    pub fn set_dynamic_metadata(
+        &mut self,
+        filter: &str,
+        path: Vec<&str>,
+        value: impl Into<MetadataValue>,
+    ) -> &mut Self {
+        self.set_filter_metadata("metadata", filter, path, value.into())
+    }
+
+    // Typed metadata is returned as is, i.e. the serialized protobuf message of the filter
+    pub fn set_route_typed_metadata(&mut self, filter: &str, value: &[u8]) -> &mut Self {
+        self.set_default_property(
+            vec!["route_metadata", "typed_filter_metadata", filter],
+            value,
+        )
+    }
+
+    pub fn set_cluster_typed_metadata(&mut self, filter: &str, value: &[u8]) -> &mut Self {
+        self.set_default_property(
+            vec!["cluster_metadata", "typed_filter_metadata", filter],
+            value,
+        )
+    }
+
+    fn set_filter_metadata(
+        &mut self,
+        metadata: &str,
+        filter: &str,
+        path: Vec<&str>,
+        value: MetadataValue,
+    ) -> &mut Self {
+        let mut property_path = vec![metadata, "filter_metadata", filter];
+        property_path.extend(path);
+        self.set_default_property(property_path, value.to_bytes())
+    }
+
+    // Route of the proxy configuration, matched by the requests whose :path starts with the prefix
+    // (the longest one wins): route_name is the route's name for the streams it matches, and the
+    // plugin configuration buffer read on them is the route's one, if given (per-route config)
+    pub fn set_route(
+        &mut self,
+        route_name: &str,
+        path_prefix: &str,
+        plugin_config: Option<&str>,
+    ) -> &mut Self {
+        self.get_settings_handle().staged.set_route(Route {
+            name: route_name.to_string(),
+            path_prefix: path_prefix.to_string(),
+            plugin_config: plugin_config.map(|plugin_config| plugin_config.as_bytes().to_vec()),
+        });
+        self
+    }
+
+    // Routes the stream whatever its :path
+    pub fn set_stream_route(&mut self, context_id: i32, route_name: &str) -> &mut Self {
+        self.get_settings_handle()
+            .staged
+            .set_stream_route(context_id, route_name);
+        self
+    }
+
+    pub fn reset_routes(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_routes();
+        self
+    }
+
+    // Overrides an attribute for a single HTTP (or stream) context only, so that concurrent
+    // streams can see e.g. different request paths or source addresses
+    pub fn set_stream_property(
+        &mut self,
+        context_id: i32,
+        path: Vec<&str>,
+        value: impl AsRef<[u8]>,
+    ) -> &mut Self {
+        self.get_settings_handle().staged.set_context_property(
+            context_id,
+            &path,
+            value.as_ref().to_vec(),
+        );
+        self
+    }
+
+    pub fn reset_stream_properties(&mut self, context_id: i32) -> &mut Self {
+        self.get_settings_handle()
+            .staged
+            .reset_context_properties(context_id);
+        self
+    }
+
+    // proxy_get_property returns NotFound for the given path
+    pub fn remove_default_property(&mut self, path: Vec<&str>) -> &mut Self {
+        self.get_settings_handle().staged.remove_property(&path);
+        self
+    }
+
+    pub fn reset_default_shared_data(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_shared_data();
+        self
+    }
+
+    // Seeds the shared data store (as if set by another plugin), returns nothing to the test
+    pub fn set_default_shared_data(&mut self, key: &str, value: &str) -> &mut Self {
+        let status =
+            self.get_settings_handle()
+                .staged
+                .set_shared_data(key, value.as_bytes().to_vec(), 0);
+        assert_eq!(status, Status::Ok);
+        self
+    }
+
+    // Entries set from now on expire after the given time on the host clock (None: never)
+    pub fn set_shared_data_ttl_millis(&mut self, ttl_millis: Option<u64>) -> &mut Self {
+        self.get_settings_handle()
+            .staged
+            .set_shared_data_ttl_millis(ttl_millis);
+        self
+    }
+
+    // Beyond the given number of entries the least recently used one is evicted (None: no limit)
+    pub fn set_shared_data_max_entries(&mut self, max_entries: Option<usize>) -> &mut Self {
+        self.get_settings_handle()
+            .staged
+            .set_shared_data_max_entries(max_entries);
+        self
+    }
+
+    // VM id under which this Tester registers shared queues, give producer and consumer Testers
+    // distinct ids so that each receives its own proxy_on_queue_ready notifications
+    pub fn set_vm_id(&mut self, vm_id: &str) -> &mut Self {
+        self.vm_id = vm_id.to_string();
+        self
+    }
+
+    // Clears the shared queues of this Tester (and of the Testers it shares them with)
+    pub fn reset_shared_queues(&mut self) -> &mut Self {
+        self.store.data().queues.lock().unwrap().reset();
+        self
+    }
+
+    // Connects this Tester to the shared queues of another one, so that queues registered by
+    // either plugin can be resolved and written to by the other
+    pub fn share_queues_with(&mut self, other: &Tester) -> &mut Self {
+        self.store.data_mut().queues = other.store.data().queues.clone();
+        self
+    }
+
+    pub fn reset_mock_upstreams(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_mock_upstreams();
+        self
+    }
+
+    // Seed of all the randomness of the host (fault injection, random buffer bytes, WASI
+    // random_get), random unless PROXY_WASM_TEST_SEED is set and printed when the Tester is created
+    pub fn set_seed(&mut self, seed: u64) -> &mut Self {
+        self.get_settings_handle().set_seed(seed);
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.get_settings_handle().seed()
+    }
+
+    pub fn set_fault_seed(&mut self, seed: u64) -> &mut Self {
+        self.get_settings_handle().staged.set_fault_seed(seed);
+        self
+    }
+
+    // vNEXT redis_calls to the cluster (after redis_init) are answered with the given RESP
+    // encoded reply, e.g. set_mock_redis("redis", "+OK\r\n")
+    #[cfg(feature = "vnext")]
+    pub fn set_mock_redis(&mut self, cluster: &str, reply: impl AsRef<[u8]>) -> &mut Self {
+        self.get_settings_handle()
+            .staged
+            .set_mock_redis(cluster, reply.as_ref().to_vec());
+        self
+    }
+
+    #[cfg(feature = "vnext")]
+    pub fn reset_mock_redis(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_mock_redis();
+        self
+    }
+
+    // Queries the module sent to the mock Redis cluster, in order
+    #[cfg(feature = "vnext")]
+    pub fn redis_queries(&self, cluster: &str) -> Vec<Bytes> {
+        self.get_settings_handle().staged.get_redis_queries(cluster)
+    }
+
+    pub fn reset_mock_grpc_services(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_mock_grpc_services();
+        self
+    }
+
+    // grpc_calls to the given method are answered automatically, each call stages another rule
+    // (first match wins), e.g. set_mock_grpc_service::<CheckRequest>(
+    // "envoy.service.auth.v3.Authorization", "Check").returning(CheckResponse::default())
+    pub fn set_mock_grpc_service<Req>(
+        &mut self,
+        service: &str,
+        method: &str,
+    ) -> MockGrpcService<'_, Req>
+    where
+        Req: prost::Message + Default + 'static,
+    {
+        MockGrpcService::expecting(self, service, method)
+    }
+
+    // http_calls to the given cluster (or :authority) are answered automatically
+    pub fn set_mock_upstream(&mut self, upstream: &str) -> MockUpstream<'_> {
+        MockUpstream::expecting(self, upstream)
+    }
+
+    pub fn reset_default_buffer_bytes(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_buffer_bytes();
+        self
+    }
+
+    // Validates the plugin configuration against the schema whenever proxy_on_configure is
+    // executed, failing the call (without running it) on violations, see schema
+    #[cfg(feature = "schema")]
+    pub fn set_config_schema(&mut self, schema: ConfigSchema) -> &mut Self {
+        self.config_schema = Some(schema);
+        self
+    }
+
+    pub fn set_default_buffer_bytes(&mut self, buffer_type: BufferType) -> DefaultBufferBytes {
+        DefaultBufferBytes::expecting(self, buffer_type as i32)
+    }
+
+    pub fn reset_default_header_map_pairs(&mut self) -> &mut Self {
+        self.get_settings_handle().staged.reset_header_map_pairs();
+        self
+    }
+
+    pub fn set_default_header_map_pairs(&mut self, map_type: MapType) -> DefaultHeaderMapPairs {
+        DefaultHeaderMapPairs::expecting(self, map_type as i32)
+    }
+
+    /* ------------------------------------- Utility Functions ------------------------------------- */
+
+    pub fn get_expect_handle(&self) -> MutexGuard<ExpectHandle> {
+        self.expect.lock().unwrap()
+    }
+
+    // End of a test: every staged callback was executed, and no expectation was staged after
+    // the last one
+    #[track_caller]
+    pub fn assert_finished(&mut self) -> &mut Self {
+        assert!(
+            self.function_call.is_empty(),
+            "Error: callbacks staged but never executed: {:?}",
+            self.function_call
+        );
+        let expect = self.get_expect_handle();
+        assert!(
+            expect.staged.expect_count <= 0,
+            "Error: expectations staged after the last callback - total remaining: {}\n{}",
+            expect.staged.expect_count,
+            expect.staged.summary()
+        );
+        drop(expect);
+        self
+    }
+
+    pub fn print_expectations(&self) {
         self.expect.lock().unwrap().print_staged();
     }
 
     fn update_expect_stage(&mut self) {
+        self.max_fuel = None;
+        if let Some(trace) = self.store.data().trace.lock().unwrap().as_mut() {
+            trace.end_stage();
+        }
         self.expect
             .lock()
             .unwrap()
@@ -362,41 +2002,687 @@ impl Tester {
         self.expect.lock().unwrap().assert_stage();
     }
 
-    pub fn get_settings_handle(&self) -> MutexGuard<HostHandle> {
-        self.defaults.lock().unwrap()
+    pub fn get_settings_handle(&self) -> MutexGuard<HostHandle> {
+        self.defaults.lock().unwrap()
+    }
+
+    pub fn print_host_settings(&self) {
+        self.defaults.lock().unwrap().print_staged();
+    }
+
+    // Captures the host settings, to be restored later with Tester::restore. Neither the state of
+    // the module nor the expectations and the shared queues are part of the snapshot.
+    pub fn snapshot(&self) -> HostSnapshot {
+        self.get_settings_handle().snapshot()
+    }
+
+    pub fn restore(&mut self, snapshot: &HostSnapshot) -> &mut Self {
+        self.get_settings_handle().restore(snapshot);
+        self
+    }
+
+    // Records the host calls made by the module from now on (see Trace for the saved format)
+    pub fn record_trace(&mut self) -> &mut Self {
+        *self.store.data().trace.lock().unwrap() = Some(Trace::new());
+        self
+    }
+
+    // Stops recording and returns the host calls recorded so far
+    pub fn take_trace(&mut self) -> Trace {
+        self.store
+            .data()
+            .trace
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_default()
+    }
+
+    // Stages the host calls recorded over one stage of a trace as exact expectations, e.g.
+    // tester.call_proxy_on_request_headers(2, 3, false).replay(&trace.stages[0])
+    #[track_caller]
+    pub fn replay(&mut self, stage: &TracedStage) -> &mut Self {
+        stage.stage(&mut self.get_expect_handle().staged);
+        self
+    }
+
+    // Compares the interaction recorded so far (see Tester::record_trace) against a golden file,
+    // e.g. checked in under tests/golden, set PROXY_WASM_TEST_UPDATE_SNAPSHOTS to update it
+    #[track_caller]
+    pub fn assert_trace_snapshot(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        let trace = self.store.data().trace.lock().unwrap().clone();
+        match trace {
+            Some(trace) => with_log(&self.log, || trace.assert_golden(path)),
+            None => panic!("Error: no trace recorded, call record_trace() first"),
+        }
+        self
+    }
+
+    // Samples the stack of the module at every millisecond it runs from now on, wasmtime only
+    // (see flamegraph)
+    pub fn record_stack_samples(&mut self) -> &mut Self {
+        Engine::set_sampling(&mut self.store, true);
+        self
+    }
+
+    // Stacks sampled so far, sampling goes on
+    pub fn stack_samples(&self) -> StackSamples {
+        self.store
+            .data()
+            .samples
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_default()
+    }
+
+    // Stops sampling and returns the stacks sampled so far
+    pub fn take_stack_samples(&mut self) -> StackSamples {
+        let samples = self.stack_samples();
+        Engine::set_sampling(&mut self.store, false);
+        samples
+    }
+
+    // Writes every callback, returned value and host call as a JSON line to the writer from now
+    // on (see EventLog for the format), e.g. File::create("target/events.jsonl")?
+    pub fn set_event_log(&mut self, writer: impl Write + Send + 'static) -> &mut Self {
+        *self.store.data().event_log.lock().unwrap() = Some(EventLog::new(Box::new(writer)));
+        self
+    }
+
+    // Stops logging (dropping the writer)
+    pub fn reset_event_log(&mut self) -> &mut Self {
+        *self.store.data().event_log.lock().unwrap() = None;
+        self
+    }
+
+    // Exports every callback as an OpenTelemetry span, with its host calls as child spans, to the
+    // writer from now on (see otlp for the format), e.g. OtlpHttpWriter::new(jaeger_endpoint)?
+    pub fn set_otlp_export(&mut self, writer: impl Write + Send + 'static) -> &mut Self {
+        let service_name = Path::new(&self.mock_settings.wasm_path)
+            .file_stem()
+            .map_or("proxy-wasm-module".to_string(), |stem| {
+                stem.to_string_lossy().into_owned()
+            });
+        *self.store.data().otlp.lock().unwrap() =
+            Some(OtlpExport::new(Box::new(writer), &service_name));
+        self
+    }
+
+    // Stops the export (writing the spans of a callback left running)
+    pub fn reset_otlp_export(&mut self) -> &mut Self {
+        *self.store.data().otlp.lock().unwrap() = None;
+        self
+    }
+
+    pub fn reset_host_settings(&mut self) {
+        self.defaults
+            .lock()
+            .unwrap()
+            .reset(self.abi_version, self.mock_settings.quiet);
+    }
+
+    pub fn toggle_strict_mode(&mut self, on: bool) {
+        self.expect.lock().unwrap().update_stage(!on);
+    }
+
+    // On by default: stream callbacks driven out of order (e.g. the request body before its
+    // headers, or anything after proxy_on_log) fail, as do host calls the proxy would not allow
+    // at that point of the stream (e.g. modifying request headers already forwarded)
+    pub fn toggle_phase_checks(&mut self, on: bool) {
+        self.get_settings_handle().staged.set_phase_checks(on);
+    }
+
+    // On by default: the combination calls and mock upstreams set content-length to the size
+    // of the body (stripping it for chunked messages), turn off to stage the headers verbatim
+    pub fn toggle_auto_content_length(&mut self, on: bool) {
+        self.auto_content_length = on;
+    }
+
+    pub(crate) fn auto_content_length(&self) -> bool {
+        self.auto_content_length
+    }
+
+    // Path of the module, none for the Testers created from bytes or text
+    pub(crate) fn wasm_path(&self) -> Option<&str> {
+        Some(self.mock_settings.wasm_path.as_str()).filter(|path| *path != INLINE_MODULE)
+    }
+
+    pub(crate) fn vm_id(&self) -> &str {
+        &self.vm_id
+    }
+
+    // Fuel available to each callback (not counting the ones dispatched in reply to http calls,
+    // grpc calls or queue notifications), a callback running out of fuel traps and fails the
+    // execution, unlimited by default. Fuel is metered whether a limit is set or not (see
+    // runtime::Runtime::new_store), which costs CPU-bound modules some speed
+    pub fn set_fuel_limit(&mut self, fuel: Option<u64>) -> &mut Self {
+        self.fuel_limit = fuel;
+        self
+    }
+
+    // Deadline of each callback, a module still running past it (e.g. stuck in an infinite loop)
+    // is interrupted and fails the execution, none by default (wasmtime only, with wasmi use
+    // set_fuel_limit instead)
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Deadline of the whole run, counted from now: the callbacks still running past it are
+    // interrupted as with set_timeout, and the following ones fail without running (checked
+    // between callbacks with wasmi)
+    pub fn set_run_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.run_timeout = timeout;
+        self.run_deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self
+    }
+
+    // Maximum size in bytes of the linear memory, growing it past the limit fails (as with
+    // Envoy's per-VM memory limit) so that tests can check how the module copes with failed
+    // allocations, unlimited by default
+    pub fn set_memory_limit(&mut self, bytes: Option<usize>) -> &mut Self {
+        self.store.data_mut().limits = match bytes {
+            Some(bytes) => StoreLimitsBuilder::new().memory_size(bytes).build(),
+            None => StoreLimits::default(),
+        };
+        self
+    }
+
+    // Current size in bytes of the linear memory exported by the module
+    pub fn memory_size(&mut self) -> usize {
+        match self.instance.get_memory(&mut self.store, "memory") {
+            Some(memory) => memory.data(&self.store).len(),
+            None => 0,
+        }
+    }
+
+    // Bytes the linear memory grew by during the last callback (it never shrinks)
+    pub fn memory_growth(&self) -> usize {
+        self.memory_growth
+    }
+
+    // Measures memory growth from now on, e.g. after warming up the module so that its one-off
+    // allocations are not mistaken for leaks (the baseline is taken at creation otherwise)
+    pub fn reset_memory_baseline(&mut self) -> &mut Self {
+        self.memory_baseline = self.memory_size();
+        self
+    }
+
+    // The linear memory grew by less than the given amount of bytes since the baseline, to catch
+    // unbounded buffering or leaks across many simulated requests
+    #[track_caller]
+    pub fn assert_memory_growth_below(&mut self, bytes: usize) -> &mut Self {
+        let growth = self.memory_size() - self.memory_baseline;
+        assert!(
+            growth < bytes,
+            "Error: linear memory grew by {} bytes (from {} to {}), expected less than {}",
+            growth,
+            self.memory_baseline,
+            self.memory_baseline + growth,
+            bytes
+        );
+        self
+    }
+
+    // Profile data (.profraw) of a module instrumented for coverage, None when it does not
+    // export proxy_wasm_test_coverage (see the coverage module). The counters are those of this
+    // instance since it was started, run without fuel or time limits.
+    pub fn coverage_data(&mut self) -> Result<Option<Vec<u8>>> {
+        let func = match self
+            .instance
+            .get_typed_func::<(), i64>(&mut self.store, COVERAGE_EXPORT)
+        {
+            Ok(func) => func,
+            Err(_) => return Ok(None),
+        };
+        self.store.set_fuel(u64::MAX)?;
+        Engine::set_deadline(&mut self.store, None);
+        let packed = func.call(&mut self.store, ())? as u64;
+        let (address, size) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let memory = match self.instance.get_memory(&mut self.store, "memory") {
+            Some(memory) => memory.data(&self.store),
+            None => anyhow::bail!("Error: the module exports no memory"),
+        };
+        match memory.get(address..address + size) {
+            Some(data) => Ok(Some(data.to_vec())),
+            None => anyhow::bail!(
+                "Error: {} returned {} bytes at {}, past the end of the memory",
+                COVERAGE_EXPORT,
+                size,
+                address
+            ),
+        }
+    }
+
+    // Fuel consumed by each callback of the current stage must not exceed the given amount, to
+    // catch performance regressions in the module
+    pub fn expect_max_fuel(&mut self, fuel: u64) -> &mut Self {
+        self.max_fuel = Some(fuel);
+        self
+    }
+
+    // Fuel consumed by the last callback
+    pub fn fuel_consumed(&self) -> u64 {
+        self.fuel_consumed
+    }
+
+    // Time and fuel spent in each callback since the Tester was created (or the profile reset),
+    // printed when the Tester is dropped if PROXY_WASM_TEST_PROFILE is set
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    // Profiles from now on, e.g. leaving out the start of the module and warm-up requests
+    pub fn reset_profile(&mut self) -> &mut Self {
+        self.profile = Profile::default();
+        self
+    }
+
+    // Calls to each host function and the time spent handling them since the Tester was created
+    // (or the report reset), to spot chatty modules, printed along with the profile
+    pub fn host_call_report(&self) -> HostCallReport {
+        self.store.data().host_calls.lock().unwrap().clone()
+    }
+
+    pub fn reset_host_call_report(&mut self) -> &mut Self {
+        *self.store.data().host_calls.lock().unwrap() = HostCallReport::default();
+        self
+    }
+
+    // Unexpected calls to the given host function are tolerated (as with --allow-unexpected)
+    // while the remaining host functions stay strict, persists across stages
+    pub fn allow_unexpected(&mut self, host_call: HostCall) -> &mut Self {
+        self.get_expect_handle()
+            .staged
+            .set_allow_unexpected_call(host_call, true);
+        self
+    }
+
+    // Same as allow_unexpected() for host functions given by name, e.g. tolerate(&["proxy_log"]),
+    // host functions without low-level expectations are never strictly checked to begin with
+    #[track_caller]
+    pub fn tolerate(&mut self, host_calls: &[&str]) -> &mut Self {
+        for name in host_calls {
+            match HostCall::from_name(name) {
+                Some(host_call) => {
+                    self.allow_unexpected(host_call);
+                }
+                None => assert!(
+                    name.starts_with("proxy_"),
+                    "Error: \"{}\" is not a proxy-wasm host function",
+                    name
+                ),
+            }
+        }
+        self
+    }
+
+    pub fn disallow_unexpected(&mut self, host_call: HostCall) -> &mut Self {
+        self.get_expect_handle()
+            .staged
+            .set_allow_unexpected_call(host_call, false);
+        self
+    }
+
+    // Header names in all header-map expectations match regardless of case (persists across
+    // stages), as different SDKs emit them lowercased or in Title-Case
+    pub fn canonicalize_header_names(&mut self, on: bool) -> &mut Self {
+        self.get_expect_handle()
+            .staged
+            .set_canonical_header_names(on);
+        self
+    }
+
+    /* ------------------------------------- Wasm Function Executation ------------------------------------- */
+
+    pub fn execute_and_expect_n(&mut self, expect_wasm: Vec<ReturnType>) -> Result<()> {
+        let mut expect_callback = expect_wasm;
+        assert_eq!(self.function_call.len(), expect_callback.len());
+        assert_eq!(self.function_call.len(), self.function_type.len());
+        assert_ne!(self.function_call.len(), 0);
+        while expect_callback.len() > 0 {
+            self.execute_and_expect(expect_callback.remove(0))?;
+        }
+        Ok(())
+    }
+
+    pub fn execute_and_expect(&mut self, expect_wasm: ReturnType) -> Result<()> {
+        let log = self.log.clone();
+        with_log(&log, || self.execute(Some(expect_wasm)))?;
+        Ok(())
+    }
+
+    // Executes every staged callback whatever they return, returning what they returned (None
+    // for the callbacks returning nothing), e.g. to observe how the module reacts to a request
+    pub fn execute_all(&mut self) -> Result<Vec<Option<i32>>> {
+        let log = self.log.clone();
+        let mut returned = Vec::new();
+        while !self.function_call.is_empty() {
+            returned.push(with_log(&log, || self.execute(None))?);
+        }
+        Ok(returned)
+    }
+
+    fn execute(&mut self, expect_wasm: Option<ReturnType>) -> Result<Option<i32>> {
+        let function_call = self.function_call.remove(0);
+        let run_left = self
+            .run_deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if run_left == Some(Duration::ZERO) {
+            let summary = self.abort_execution();
+            let message = format!(
+                "Error: {:?} not run, the test exceeded its run timeout of {:?}\n{}",
+                function_call,
+                self.run_timeout.unwrap(),
+                summary
+            );
+            return Err(anyhow::format_err!(message.trim_end().to_string()));
+        }
+        #[cfg(feature = "schema")]
+        if let (FunctionCall::ProxyOnConfigure(..), Some(schema)) =
+            (function_call, &self.config_schema)
+        {
+            let config = self
+                .get_settings_handle()
+                .staged
+                .get_buffer_bytes(BufferType::PluginConfiguration as i32);
+            if let Err(error) = schema.validate(&config) {
+                let summary = self.abort_execution();
+                let message = format!(
+                    "Error: {:?} not run, {:#}\n{}",
+                    function_call, error, summary
+                );
+                return Err(anyhow::format_err!(message.trim_end().to_string()));
+            }
+        }
+        if let Some(trace) = self.store.data().trace.lock().unwrap().as_mut() {
+            trace.begin_stage();
+        }
+        self.get_settings_handle().staged.set_vm_id(&self.vm_id);
+        if let Some(context_id) = function_call.context_id() {
+            self.get_settings_handle()
+                .staged
+                .set_effective_context(context_id);
+        }
+        let stream_phase = function_call.stream_phase();
+        if let Some((context_id, phase, end_of_stream)) = stream_phase {
+            let entered =
+                self.get_settings_handle()
+                    .staged
+                    .enter_phase(context_id, phase, end_of_stream);
+            if let Err(reason) = entered {
+                let summary = self.abort_execution();
+                let message = format!(
+                    "Error: {:?} driven out of order, {}\n{}",
+                    function_call, reason, summary
+                );
+                return Err(anyhow::format_err!(message.trim_end().to_string()));
+            }
+        }
+        let function_call = match function_call {
+            FunctionCall::ProxyOnRequestHeaders(context_id, ..) => {
+                self.get_settings_handle().staged.match_route(context_id);
+                function_call
+            }
+            FunctionCall::ProxyOnRequestBody(context_id, body_size, end_of_stream) => {
+                let body_size =
+                    self.load_body_chunk(context_id, BufferType::HttpRequestBody, body_size);
+                FunctionCall::ProxyOnRequestBody(context_id, body_size, end_of_stream)
+            }
+            FunctionCall::ProxyOnResponseBody(context_id, body_size, end_of_stream) => {
+                let body_size =
+                    self.load_body_chunk(context_id, BufferType::HttpResponseBody, body_size);
+                FunctionCall::ProxyOnResponseBody(context_id, body_size, end_of_stream)
+            }
+            FunctionCall::ProxyOnDownstreamData(context_id, data_size, end_of_stream) => {
+                let data_size =
+                    self.load_body_chunk(context_id, BufferType::DownstreamData, data_size);
+                FunctionCall::ProxyOnDownstreamData(context_id, data_size, end_of_stream)
+            }
+            FunctionCall::ProxyOnUpstreamData(context_id, data_size, end_of_stream) => {
+                let data_size =
+                    self.load_body_chunk(context_id, BufferType::UpstreamData, data_size);
+                FunctionCall::ProxyOnUpstreamData(context_id, data_size, end_of_stream)
+            }
+            _ => function_call,
+        };
+        self.store
+            .set_fuel(self.fuel_limit.unwrap_or(u64::MAX))
+            .unwrap();
+        let fuel_before = self.store.get_fuel().unwrap();
+        let memory_before = self.memory_size();
+        let timeout = match (self.timeout, run_left) {
+            (Some(timeout), Some(run_left)) => Some(timeout.min(run_left)),
+            (timeout, run_left) => timeout.or(run_left),
+        };
+        Engine::set_deadline(&mut self.store, timeout);
+        let started = Instant::now();
+        let data_buffer = match function_call {
+            FunctionCall::ProxyOnDownstreamData(context_id, ..) => {
+                Some((context_id, BufferType::DownstreamData as i32))
+            }
+            FunctionCall::ProxyOnUpstreamData(context_id, ..) => {
+                Some((context_id, BufferType::UpstreamData as i32))
+            }
+            _ => None,
+        };
+        let called = self.call_module(function_call);
+        if let Some(callback) = function_call.export_name() {
+            let fuel = fuel_before - self.store.get_fuel().unwrap();
+            self.profile.record(callback, started.elapsed(), fuel);
+        }
+        let returned = called.as_ref().ok().copied().flatten();
+        if let Some((context_id, phase, _)) = stream_phase {
+            self.get_settings_handle()
+                .staged
+                .leave_phase(context_id, phase, returned);
+        }
+        if let Some(data_buffer) = data_buffer {
+            match returned == Some(Action::Pause as i32) {
+                true => self.held_data.insert(data_buffer),
+                false => self.held_data.remove(&data_buffer),
+            };
+        }
+        let return_wasm = match called {
+            Ok(return_wasm) => return_wasm,
+            Err(error) => {
+                let summary = self.abort_execution();
+                let reason = match timeout {
+                    Some(timeout) if started.elapsed() >= timeout => {
+                        let (limit, timeout) = match self.timeout {
+                            Some(callback_timeout) if callback_timeout == timeout => {
+                                ("callback timeout", timeout)
+                            }
+                            _ => ("run timeout", self.run_timeout.unwrap()),
+                        };
+                        format!(
+                            "did not return within the {} of {:?} (runaway module?)",
+                            limit, timeout
+                        )
+                    }
+                    _ => "trapped".to_string(),
+                };
+                // traps are described with the callback, the backtrace and the expectations left
+                // pending (the trap itself stays downcastable), other errors (e.g. a missing
+                // export) are returned as they are
+                return Err(match Engine::describe_trap(&error) {
+                    Some(trap) => {
+                        let message = format!(
+                            "Error: {:?} {}\n{}\n{}",
+                            function_call, reason, trap, summary
+                        );
+                        error.context(message.trim_end().to_string())
+                    }
+                    None => error,
+                });
+            }
+        };
+
+        self.track_context(function_call, return_wasm);
+        if let (FunctionCall::ProxyOnLog(context_id), Some(format)) =
+            (function_call, &self.access_log_format)
+        {
+            let line = format.render(self, context_id);
+            self.access_log.push((context_id, line));
+        }
+        if let Some(illegal) = self.illegal_return(function_call, return_wasm) {
+            let summary = self.abort_execution();
+            let message = format!("Error: {:?} {}\n{}", function_call, illegal, summary);
+            return Err(anyhow::format_err!(message.trim_end().to_string()));
+        }
+        self.fuel_consumed = fuel_before - self.store.get_fuel().unwrap();
+        self.memory_growth = self.memory_size() - memory_before;
+        if let Some(max_fuel) = self.max_fuel {
+            assert!(
+                self.fuel_consumed <= max_fuel,
+                "Error: {:?} consumed {} fuel, expected at most {}",
+                function_call,
+                self.fuel_consumed,
+                max_fuel
+            );
+        }
+
+        let function_type = self.function_type.remove(0);
+        match expect_wasm {
+            Some(ReturnType::None) => {
+                assert_eq!(function_type, FunctionType::ReturnVoid);
+                assert_eq!(return_wasm.is_none(), true);
+            }
+            Some(ReturnType::Bool(expect_bool)) => {
+                assert_eq!(function_type, FunctionType::ReturnBool);
+                assert_eq!(expect_bool as i32, return_wasm.unwrap_or(-1));
+            }
+            Some(ReturnType::Action(expect_action)) => {
+                assert_eq!(function_type, FunctionType::ReturnAction);
+                assert_eq!(expect_action as i32, return_wasm.unwrap_or(-1));
+            }
+            None => {}
+        }
+
+        self.dispatch_http_call_responses()?;
+        self.dispatch_grpc_call_replies()?;
+        self.dispatch_queue_ready()?;
+        #[cfg(feature = "vnext")]
+        self.dispatch_redis_call_responses()?;
+
+        // including the callbacks dispatched in reply to the module's calls
+        let violations = self.get_settings_handle().staged.take_phase_violations();
+        if !violations.is_empty() {
+            if self.function_call.is_empty() {
+                self.update_expect_stage();
+            }
+            return Err(anyhow::format_err!(
+                "Error: {:?} made host calls the proxy does not allow at this point of the stream:\n  {}",
+                function_call,
+                violations.join("\n  ")
+            ));
+        }
+
+        if self.function_call.len() == 0 {
+            self.assert_expect_stage();
+            self.update_expect_stage();
+        }
+        Ok(return_wasm)
+    }
+
+    // Records a step of the interaction with the module, when tracing is on
+    fn trace(&self, event: TracedEvent) {
+        if let Some(event_log) = self.store.data().event_log.lock().unwrap().as_mut() {
+            event_log.write(&event);
+        }
+        if let Some(otlp) = self.store.data().otlp.lock().unwrap().as_mut() {
+            otlp.write(&event);
+        }
+        if let Some(trace) = self.store.data().trace.lock().unwrap().as_mut() {
+            trace.record(event);
+        }
     }
 
-    pub fn print_host_settings(&self) {
-        self.defaults.lock().unwrap().print_staged();
+    // The host clock only moves between callbacks, so it dates everything the callback does
+    fn trace_callback(&self, function_call: &FunctionCall) {
+        let time_nanos = self.get_settings_handle().staged.get_current_time_nanos();
+        if let Some(event_log) = self.store.data().event_log.lock().unwrap().as_mut() {
+            event_log.enter(function_call.context_id(), time_nanos);
+        }
+        if let Some(otlp) = self.store.data().otlp.lock().unwrap().as_mut() {
+            otlp.enter(function_call.context_id());
+        }
+        self.trace(TracedEvent::Callback(format!("{:?}", function_call)));
     }
 
-    pub fn reset_host_settings(&mut self) {
-        self.defaults
-            .lock()
-            .unwrap()
-            .reset(self.abi_version, self.mock_settings.quiet);
+    // The Tester stays usable after a trap (e.g. out of fuel or memory), so that tests can check
+    // how the module behaves afterwards, returns the summary of the expectations left pending
+    fn abort_execution(&mut self) -> String {
+        self.function_type.remove(0);
+        let summary = self.get_expect_handle().staged.summary();
+        if self.function_call.is_empty() {
+            self.update_expect_stage();
+        }
+        summary
     }
 
-    pub fn toggle_strict_mode(&mut self, on: bool) {
-        self.expect.lock().unwrap().update_stage(!on);
+    // Why the value returned by the callback is not one it may return, if it is not
+    fn illegal_return(&self, function_call: FunctionCall, returned: Option<i32>) -> Option<String> {
+        let legal = function_call.legal_returns(self.abi_version)?;
+        let returned = returned?;
+        if legal.iter().any(|(value, _)| *value == returned) {
+            return None;
+        }
+        let legal: Vec<String> = legal
+            .iter()
+            .map(|(value, name)| format!("{} ({})", value, name))
+            .collect();
+        Some(format!(
+            "returned {}, not a legal value in ABI {:?}, expected one of: {}",
+            returned,
+            self.abi_version,
+            legal.join(", ")
+        ))
     }
 
-    /* ------------------------------------- Wasm Function Executation ------------------------------------- */
-
-    pub fn execute_and_expect_n(&mut self, expect_wasm: Vec<ReturnType>) -> Result<()> {
-        let mut expect_callback = expect_wasm;
-        assert_eq!(self.function_call.len(), expect_callback.len());
-        assert_eq!(self.function_call.len(), self.function_type.len());
-        assert_ne!(self.function_call.len(), 0);
-        while expect_callback.len() > 0 {
-            self.execute_and_expect(expect_callback.remove(0))?;
+    // Keeps the registry of contexts (see contexts()) up to date with a callback that returned
+    fn track_context(&mut self, function_call: FunctionCall, returned: Option<i32>) {
+        let mut host = self.get_settings_handle();
+        match function_call {
+            FunctionCall::ProxyOnContextCreate(context_id, root_context_id) => {
+                host.staged.create_context(context_id, root_context_id)
+            }
+            FunctionCall::ProxyOnNewConnection(context_id)
+            | FunctionCall::ProxyOnDownstreamData(context_id, ..)
+            | FunctionCall::ProxyOnDownstreamConnectionClose(context_id, ..)
+            | FunctionCall::ProxyOnUpstreamData(context_id, ..)
+            | FunctionCall::ProxyOnUpstreamConnectionClose(context_id, ..) => host
+                .staged
+                .set_context_type(context_id, ContextType::Network),
+            FunctionCall::ProxyOnRequestHeaders(context_id, ..)
+            | FunctionCall::ProxyOnRequestBody(context_id, ..)
+            | FunctionCall::ProxyOnRequestTrailers(context_id, ..)
+            | FunctionCall::ProxyOnRequestMetadata(context_id, ..)
+            | FunctionCall::ProxyOnResponseHeaders(context_id, ..)
+            | FunctionCall::ProxyOnResponseBody(context_id, ..)
+            | FunctionCall::ProxyOnResponseTrailers(context_id, ..)
+            | FunctionCall::ProxyOnResponseMetadata(context_id, ..) => {
+                host.staged.set_context_type(context_id, ContextType::Http)
+            }
+            FunctionCall::ProxyOnDone(context_id) if returned == Some(1) => host
+                .staged
+                .set_context_state(context_id, ContextState::Done),
+            FunctionCall::ProxyOnDelete(context_id) => host
+                .staged
+                .set_context_state(context_id, ContextState::Deleted),
+            _ => {}
         }
-        Ok(())
     }
 
-    pub fn execute_and_expect(&mut self, expect_wasm: ReturnType) -> Result<()> {
+    // Calls into the module for the given function call, returns what the callback returned
+    fn call_module(&mut self, function_call: FunctionCall) -> Result<Option<i32>> {
         let mut return_wasm: Option<i32> = None;
-        match self.function_call.remove(0) {
+        let context_id = function_call.context_id();
+        if !matches!(function_call, FunctionCall::AdvanceTime(..)) {
+            self.trace_callback(&function_call);
+        }
+        match function_call {
             FunctionCall::Start() => {
                 let (name, func) = self
                     .instance
@@ -415,12 +2701,12 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find `_initialize`, `main` or `_start` function export"
                     )))?;
-                println!("[host->vm] {name}()");
+                debug!("[host->vm] {name}()");
                 func.call(&mut self.store, ())?;
             }
 
             FunctionCall::ProxyOnVmStart(context_id, vm_configuration_size) => {
-                println!(
+                debug!(
                     "[host->vm] proxy_on_vm_start(context_id={}, vm_configuration_size={})",
                     context_id, vm_configuration_size
                 );
@@ -431,7 +2717,7 @@ impl Tester {
                         "Error: failed to find `proxy_on_vm_start` function export"
                     )))?
                     .call(&mut self.store, (context_id, vm_configuration_size))?;
-                println!("[host<-vm] proxy_on_vm_start return: success={}", success);
+                debug!("[host<-vm] proxy_on_vm_start return: success={}", success);
                 return_wasm = Some(success);
             }
 
@@ -445,13 +2731,13 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find `proxy_validate_configuration` function export"
                     )))?;
-                println!(
+                debug!(
                     "[host->vm] proxy_validate_configuration(root_context_id={}, configuration_size={})",
                     root_context_id, configuration_size
                 );
                 let success = proxy_validate_configuration
                     .call(&mut self.store, (root_context_id, configuration_size))?;
-                println!(
+                debug!(
                     "[host<-vm] proxy_validate_configuration return: success={}",
                     success
                 );
@@ -465,13 +2751,13 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_configure' function export"
                     )))?;
-                println!(
+                debug!(
                     "[host->vm] proxy_on_configure(context_id={}, plugin_configuration_size={})",
                     context_id, plugin_configuration_size
                 );
                 let success = proxy_on_configure
                     .call(&mut self.store, (context_id, plugin_configuration_size))?;
-                println!("[host<-vm] proxy_on_configure return: success={}", success);
+                debug!("[host<-vm] proxy_on_configure return: success={}", success);
                 return_wasm = Some(success);
             }
 
@@ -482,12 +2768,12 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find `proxy_on_tick` function export"
                     )))?;
-                println!("[host->vm] proxy_on_tick(context_id={})", context_id);
+                debug!("[host->vm] proxy_on_tick(context_id={})", context_id);
                 proxy_on_tick.call(&mut self.store, context_id)?;
             }
 
             FunctionCall::ProxyOnForeignFunction(root_context_id, function_id, data_size) => {
-                assert_eq!(self.abi_version, AbiVersion::ProxyAbiVersion0_2_0);
+                assert!(self.abi_version.is_0_2());
                 let proxy_on_foreign_function = self
                     .instance
                     .get_typed_func::<(i32, i32, i32), i32>(
@@ -497,11 +2783,11 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_foreign_function' function export"
                     )))?;
-                println!("[host->vm] proxy_on_foreign_function(root_context_id={}, function_id={}, data_size={})",
+                debug!("[host->vm] proxy_on_foreign_function(root_context_id={}, function_id={}, data_size={})",
                     root_context_id, function_id, data_size);
                 let action = proxy_on_foreign_function
                     .call(&mut self.store, (root_context_id, function_id, data_size))?;
-                println!(
+                debug!(
                     "[host<-vm] proxy_on_foreign_function return: action={}",
                     action
                 );
@@ -509,17 +2795,7 @@ impl Tester {
             }
 
             FunctionCall::ProxyOnQueueReady(context_id, queue_id) => {
-                let proxy_on_queue_ready = self
-                    .instance
-                    .get_typed_func::<(i32, i32), ()>(&mut self.store, "proxy_on_queue_ready")
-                    .or(Err(anyhow::format_err!(
-                        "Error: failed to find 'proxy_on_queue_ready' function export"
-                    )))?;
-                println!(
-                    "[host->vm] proxy_on_queue_ready(context_id={}, queue_id={})",
-                    context_id, queue_id
-                );
-                proxy_on_queue_ready.call(&mut self.store, (context_id, queue_id))?;
+                self.call_queue_ready(context_id, queue_id)?;
             }
 
             // Stream calls
@@ -530,7 +2806,7 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find `proxy_on_context_create` function export"
                     )))?;
-                println!(
+                debug!(
                     "[host->vm] proxy_on_context_create(root_context_id={}, parent_context_id={})",
                     root_context_id, parent_context_id
                 );
@@ -545,12 +2821,12 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_new_connection' function export"
                     )))?;
-                println!(
+                debug!(
                     "[host->vm] proxy_on_new_connection(context_id={})",
                     context_id
                 );
                 let action = proxy_on_new_connection.call(&mut self.store, context_id)?;
-                println!(
+                debug!(
                     "[host<-vm] proxy_on_new_connection return: action={}",
                     action
                 );
@@ -567,7 +2843,7 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_downstream_data' function export"
                     )))?;
-                println!(
+                debug!(
                         "[host->vm] proxy_on_downstream_data(context_id={}, data_size={}, end_of_stream={})",
                         context_id, data_size, end_of_stream
                     );
@@ -575,7 +2851,7 @@ impl Tester {
                     &mut self.store,
                     (context_id, data_size, end_of_stream as i32),
                 )?;
-                println!(
+                debug!(
                     "[host<-vm] proxy_on_downstream_data return: action={}",
                     action
                 );
@@ -589,7 +2865,7 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_downstream_connection_close' function export"
                     )))?;
-                println!(
+                debug!(
                     "[host->vm] proxy_on_downstream_connection_close(context_id={}, peer_data={})",
                     context_id, peer_type as i32
                 );
@@ -607,7 +2883,7 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_upstream_data' function export"
                     )))?;
-                println!(
+                debug!(
                         "[host->vm] proxy_on_upstream_data(context_id={}, data_size={}, end_of_stream={})",
                         context_id, data_size, end_of_stream
                     );
@@ -615,7 +2891,7 @@ impl Tester {
                     &mut self.store,
                     (context_id, data_size, end_of_stream as i32),
                 )?;
-                println!(
+                debug!(
                     "[host<-vm] proxy_on_upstream_data return: action={}",
                     action
                 );
@@ -632,7 +2908,7 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_upstream_connection_close' function export"
                     )))?;
-                println!(
+                debug!(
                     "[host->vm] proxy_on_upstream_connection_close(context_id={}, peer_data={})",
                     context_id, peer_type as i32
                 );
@@ -641,7 +2917,7 @@ impl Tester {
             }
 
             FunctionCall::ProxyOnRequestHeaders(context_id, num_headers, end_of_stream) => {
-                println!(
+                debug!(
                     "[host->vm] proxy_on_request_headers(context_id={}, num_headers={}, end_of_stream={})",
                     context_id, num_headers, end_of_stream
                 );
@@ -658,7 +2934,7 @@ impl Tester {
                             )))?;
                         proxy_on_request_headers.call(&mut self.store, (context_id, num_headers))?
                     }
-                    AbiVersion::ProxyAbiVersion0_2_0 => {
+                    AbiVersion::ProxyAbiVersion0_2_0 | AbiVersion::ProxyAbiVersion0_2_1 => {
                         let proxy_on_request_headers = self
                             .instance
                             .get_typed_func::<(i32, i32, i32), i32>(
@@ -679,7 +2955,7 @@ impl Tester {
                     ),
                 };
 
-                println!(
+                debug!(
                     "[host<-vm] proxy_on_request_headers return: action={}",
                     action
                 );
@@ -696,7 +2972,7 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_request_body' function export"
                     )))?;
-                println!(
+                debug!(
                         "[host->vm] proxy_on_request_body(context_id={}, body_size={}, end_of_stream={})",
                         context_id, body_size, end_of_stream
                     );
@@ -704,7 +2980,7 @@ impl Tester {
                     &mut self.store,
                     (context_id, body_size, end_of_stream as i32),
                 )?;
-                println!("[host<-vm] proxy_on_request_body return: action={}", action);
+                debug!("[host<-vm] proxy_on_request_body return: action={}", action);
                 return_wasm = Some(action);
             }
 
@@ -715,13 +2991,13 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find `proxy_on_request_trailers` function export"
                     )))?;
-                println!(
+                debug!(
                     "[host->vm] proxy_on_request_trailers(context_id={}, num_trailers={})",
                     context_id, num_trailers
                 );
                 let action =
                     proxy_on_request_trailers.call(&mut self.store, (context_id, num_trailers))?;
-                println!(
+                debug!(
                     "[host<-vm] proxy_on_request_trailers return: action={}",
                     action
                 );
@@ -735,13 +3011,13 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find `proxy_on_request_metadata` function export"
                     )))?;
-                println!(
+                debug!(
                     "[host->vm] proxy_on_request_metadata(context_id={}, nelements={})",
                     context_id, nelements
                 );
                 let action =
                     proxy_on_request_metadata.call(&mut self.store, (context_id, nelements))?;
-                println!(
+                debug!(
                     "[host<-vm] proxy_on_request_metadata return: action={}",
                     action
                 );
@@ -749,7 +3025,7 @@ impl Tester {
             }
 
             FunctionCall::ProxyOnResponseHeaders(context_id, num_headers, end_of_stream) => {
-                println!(
+                debug!(
                         "[host->vm] proxy_on_response_headers(context_id={}, num_headers={}, end_of_stream={})",
                         context_id, num_headers, end_of_stream
                     );
@@ -767,7 +3043,7 @@ impl Tester {
                         proxy_on_response_headers
                             .call(&mut self.store, (context_id, num_headers))?
                     }
-                    AbiVersion::ProxyAbiVersion0_2_0 => {
+                    AbiVersion::ProxyAbiVersion0_2_0 | AbiVersion::ProxyAbiVersion0_2_1 => {
                         let proxy_on_response_headers = self
                             .instance
                             .get_typed_func::<(i32, i32, i32), i32>(
@@ -787,7 +3063,7 @@ impl Tester {
                         self.abi_version
                     ),
                 };
-                println!(
+                debug!(
                     "[host<-vm] proxy_on_response_headers return: action={}",
                     action
                 );
@@ -804,7 +3080,7 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_response_body' function export"
                     )))?;
-                println!(
+                debug!(
                         "[host->vm] proxy_on_response_body(context_id={}, body_size={}, end_of_stream={})",
                         context_id, body_size, end_of_stream
                     );
@@ -812,7 +3088,7 @@ impl Tester {
                     &mut self.store,
                     (context_id, body_size, end_of_stream as i32),
                 )?;
-                println!("[host<-vm] function return: action -> {}", action);
+                debug!("[host<-vm] function return: action -> {}", action);
                 return_wasm = Some(action);
             }
 
@@ -826,13 +3102,13 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find `proxy_on_response_trailers` function export"
                     )))?;
-                println!(
+                debug!(
                     "[host->vm] proxy_on_response_trailers(context_id={}, num_trailers={})",
                     context_id, num_trailers
                 );
                 let action =
                     proxy_on_response_trailers.call(&mut self.store, (context_id, num_trailers))?;
-                println!(
+                debug!(
                     "[host<-vm] proxy_on_response_body return: action={}",
                     action
                 );
@@ -849,13 +3125,13 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find `proxy_on_response_metadata` function export"
                     )))?;
-                println!(
+                debug!(
                     "[host->vm] call_proxy_on_response_metadata(context_id={}, nelements={})",
                     context_id, nelements
                 );
                 let action =
                     proxy_on_response_metadata.call(&mut self.store, (context_id, nelements))?;
-                println!(
+                debug!(
                     "[host<-vm] proxy_on_response_metadata return: action={}",
                     action
                 );
@@ -870,39 +3146,17 @@ impl Tester {
                 body_size,
                 num_trailers,
             ) => {
-                let proxy_on_http_call_response = self
-                    .instance
-                    .get_typed_func::<(i32, i32, i32, i32, i32), ()>(
-                        &mut self.store,
-                        "proxy_on_http_call_response",
-                    )
-                    .or(Err(anyhow::format_err!(
-                        "Error: failed to find `proxy_on_http_call_response` function export"
-                    )))?;
-                println!(
-                        "[host->vm] proxy_on_http_call_response(context_id={}, callout_id={}, num_headers={}",
-                        context_id, callout_id, num_headers
-                    );
-                println!(
-                    "                                       body_size={}, num_trailers={})",
-                    body_size, num_trailers
-                );
-                proxy_on_http_call_response.call(
-                    &mut self.store,
-                    (context_id, callout_id, num_headers, body_size, num_trailers),
+                self.call_http_call_response(
+                    context_id,
+                    callout_id,
+                    num_headers,
+                    body_size,
+                    num_trailers,
                 )?;
             }
 
             FunctionCall::ProxyOnGrpcReceiveInitialMetadata(context_id, token, headers) => {
-                let proxy_on_grpc_receive_initial_metadata = self
-                    .instance
-                    .get_typed_func::<(i32, i32, i32), ()>(&mut self.store, "proxy_on_grpc_receive_initial_metadata")
-                    .or(Err(anyhow::format_err!(
-                        "Error: failed to find 'proxy_on_grpc_receive_initial_metadata' function export"
-                    )))?;
-                println!("[host->vm] proxy_on_grpc_receive_initial_metadata(context_id={}, token={}, headers={})", context_id, token, headers);
-                proxy_on_grpc_receive_initial_metadata
-                    .call(&mut self.store, (context_id, token, headers))?;
+                self.call_grpc_initial_metadata(context_id, token, headers)?;
             }
 
             FunctionCall::ProxyOnGrpcReceiveTrailingMetadata(context_id, token, trailers) => {
@@ -915,7 +3169,7 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_grpc_trailing_metadata' function export"
                     )))?;
-                println!(
+                debug!(
                         "[host->vm] proxy_on_grpc_receive_trailing_metadata(context_id={}, token={}, trailers={})",
                         context_id, token, trailers
                     );
@@ -924,31 +3178,16 @@ impl Tester {
             }
 
             FunctionCall::ProxyOnGrpcReceive(context_id, token, response_size) => {
-                let proxy_on_grpc_receive = self
-                    .instance
-                    .get_typed_func::<(i32, i32, i32), ()>(&mut self.store, "proxy_on_grpc_receive")
-                    .or(Err(anyhow::format_err!(
-                        "Error: failed to find 'proxy_on_grpc_receive' function export"
-                    )))?;
-                println!(
-                    "[host->vm] proxy_on_grpc_receive(context_id={}, token={}, response_size={})",
-                    context_id, token, response_size
-                );
-                proxy_on_grpc_receive.call(&mut self.store, (context_id, token, response_size))?;
+                self.call_grpc_receive(context_id, token, response_size)?;
             }
 
             FunctionCall::ProxyOnGrpcClose(context_id, token, status_code) => {
-                let proxy_on_grpc_close = self
-                    .instance
-                    .get_typed_func::<(i32, i32, i32), ()>(&mut self.store, "proxy_on_grpc_close")
-                    .or(Err(anyhow::format_err!(
-                        "Error: failed to find 'proxy_on_grpc_close' function export"
-                    )))?;
-                println!(
-                    "[host->vm] proxy_on_grpc_close(context_id={}, token={}, status_code={})",
-                    context_id, token, status_code
-                );
-                proxy_on_grpc_close.call(&mut self.store, (context_id, token, status_code))?;
+                self.call_grpc_close(context_id, token, status_code)?;
+            }
+
+            #[cfg(feature = "vnext")]
+            FunctionCall::ProxyOnRedisCallResponse(context_id, token, status, response_size) => {
+                self.call_redis_call_response(context_id, token, status, response_size)?;
             }
 
             // The stream/vm has completed
@@ -959,9 +3198,9 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_done' function export"
                     )))?;
-                println!("[host->vm] proxy_on_done(context_id={})", context_id);
+                debug!("[host->vm] proxy_on_done(context_id={})", context_id);
                 let is_done = proxy_on_done.call(&mut self.store, context_id)?;
-                println!("[host<-vm] proxy_on_done return: is_done={}", is_done);
+                debug!("[host<-vm] proxy_on_done return: is_done={}", is_done);
                 return_wasm = Some(is_done);
             }
 
@@ -972,7 +3211,7 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find `proxy_on_log` function export"
                     )))?;
-                println!("[host->vm] proxy_on_log(context_id={})", context_id);
+                debug!("[host->vm] proxy_on_log(context_id={})", context_id);
                 proxy_on_log.call(&mut self.store, context_id)?;
             }
 
@@ -983,37 +3222,373 @@ impl Tester {
                     .or(Err(anyhow::format_err!(
                         "Error: failed to find 'proxy_on_delete' function export"
                     )))?;
-                println!("[host->vm] proxy_on_delete(context_id={})", context_id);
+                debug!("[host->vm] proxy_on_delete(context_id={})", context_id);
                 proxy_on_delete.call(&mut self.store, context_id)?;
             }
-        }
 
-        match expect_wasm {
-            ReturnType::None => {
-                assert_eq!(self.function_type.remove(0), FunctionType::ReturnVoid);
-                assert_eq!(return_wasm.is_none(), true);
+            FunctionCall::AdvanceTime(duration) => {
+                debug!("[host] advance time by {:?}", duration);
+                let now_nanos = self.current_time_nanos();
+                // saturated, advancing far enough flushes every pending timer
+                let target_nanos = now_nanos
+                    .saturating_add(u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX));
+                // the clock stops at each tick and mock reply on the way, so that the module
+                // sees the time they were due at
+                loop {
+                    let next_timer_nanos = self.get_settings_handle().staged.next_timer_nanos();
+                    let timer_nanos = match next_timer_nanos {
+                        Some(timer_nanos) if timer_nanos <= target_nanos => timer_nanos,
+                        _ => break,
+                    };
+                    self.get_settings_handle()
+                        .staged
+                        .set_current_time_nanos(timer_nanos.max(now_nanos));
+                    loop {
+                        let due_tick = self.get_settings_handle().staged.take_due_tick();
+                        let context_id = match due_tick {
+                            Some(context_id) => context_id,
+                            None => break,
+                        };
+                        self.get_settings_handle()
+                            .staged
+                            .set_effective_context(context_id);
+                        self.call_module(FunctionCall::ProxyOnTick(context_id))?;
+                    }
+                    self.dispatch_http_call_responses()?;
+                    self.dispatch_grpc_call_replies()?;
+                }
+                self.get_settings_handle()
+                    .staged
+                    .set_current_time_nanos(target_nanos);
             }
-            ReturnType::Bool(expect_bool) => {
-                assert_eq!(self.function_type.remove(0), FunctionType::ReturnBool);
-                assert_eq!(expect_bool as i32, return_wasm.unwrap_or(-1));
+        }
+        if let Some(returned) = return_wasm {
+            // back to the context of the callback, after those dispatched on the way
+            let time_nanos = self.get_settings_handle().staged.get_current_time_nanos();
+            if let Some(event_log) = self.store.data().event_log.lock().unwrap().as_mut() {
+                event_log.enter(context_id, time_nanos);
             }
-            ReturnType::Action(expect_action) => {
-                assert_eq!(self.function_type.remove(0), FunctionType::ReturnAction);
-                assert_eq!(expect_action as i32, return_wasm.unwrap_or(-1));
+            self.trace(TracedEvent::Return(returned));
+        }
+        if let Some(otlp) = self.store.data().otlp.lock().unwrap().as_mut() {
+            otlp.finish();
+        }
+        Ok(return_wasm)
+    }
+
+    fn call_grpc_receive(&mut self, context_id: i32, token: i32, response_size: i32) -> Result<()> {
+        let proxy_on_grpc_receive = self
+            .instance
+            .get_typed_func::<(i32, i32, i32), ()>(&mut self.store, "proxy_on_grpc_receive")
+            .or(Err(anyhow::format_err!(
+                "Error: failed to find 'proxy_on_grpc_receive' function export"
+            )))?;
+        debug!(
+            "[host->vm] proxy_on_grpc_receive(context_id={}, token={}, response_size={})",
+            context_id, token, response_size
+        );
+        self.trace_callback(&FunctionCall::ProxyOnGrpcReceive(
+            context_id,
+            token,
+            response_size,
+        ));
+        proxy_on_grpc_receive.call(&mut self.store, (context_id, token, response_size))?;
+        Ok(())
+    }
+
+    fn call_grpc_initial_metadata(
+        &mut self,
+        context_id: i32,
+        token: i32,
+        headers: i32,
+    ) -> Result<()> {
+        let proxy_on_grpc_receive_initial_metadata = self
+            .instance
+            .get_typed_func::<(i32, i32, i32), ()>(
+                &mut self.store,
+                "proxy_on_grpc_receive_initial_metadata",
+            )
+            .or(Err(anyhow::format_err!(
+                "Error: failed to find 'proxy_on_grpc_receive_initial_metadata' function export"
+            )))?;
+        debug!("[host->vm] proxy_on_grpc_receive_initial_metadata(context_id={}, token={}, headers={})", context_id, token, headers);
+        self.trace_callback(&FunctionCall::ProxyOnGrpcReceiveInitialMetadata(
+            context_id, token, headers,
+        ));
+        proxy_on_grpc_receive_initial_metadata
+            .call(&mut self.store, (context_id, token, headers))?;
+        Ok(())
+    }
+
+    fn call_grpc_close(&mut self, context_id: i32, token: i32, status_code: i32) -> Result<()> {
+        let proxy_on_grpc_close = self
+            .instance
+            .get_typed_func::<(i32, i32, i32), ()>(&mut self.store, "proxy_on_grpc_close")
+            .or(Err(anyhow::format_err!(
+                "Error: failed to find 'proxy_on_grpc_close' function export"
+            )))?;
+        debug!(
+            "[host->vm] proxy_on_grpc_close(context_id={}, token={}, status_code={})",
+            context_id, token, status_code
+        );
+        self.trace_callback(&FunctionCall::ProxyOnGrpcClose(
+            context_id,
+            token,
+            status_code,
+        ));
+        proxy_on_grpc_close.call(&mut self.store, (context_id, token, status_code))?;
+        Ok(())
+    }
+
+    #[cfg(feature = "vnext")]
+    fn call_redis_call_response(
+        &mut self,
+        context_id: i32,
+        token: i32,
+        status: i32,
+        response_size: i32,
+    ) -> Result<()> {
+        let proxy_on_redis_call_response = self
+            .instance
+            .get_typed_func::<(i32, i32, i32, i32), ()>(
+                &mut self.store,
+                "proxy_on_redis_call_response",
+            )
+            .or(Err(anyhow::format_err!(
+                "Error: failed to find 'proxy_on_redis_call_response' function export"
+            )))?;
+        debug!(
+            "[host->vm] proxy_on_redis_call_response(context_id={}, token={}, status={}, response_size={})",
+            context_id, token, status, response_size
+        );
+        self.trace_callback(&FunctionCall::ProxyOnRedisCallResponse(
+            context_id,
+            token,
+            status,
+            response_size,
+        ));
+        proxy_on_redis_call_response
+            .call(&mut self.store, (context_id, token, status, response_size))?;
+        Ok(())
+    }
+
+    // Delivers the replies of the mock Redis clusters to the redis_calls made during the last
+    // callback (and in turn to those made while handling the replies)
+    #[cfg(feature = "vnext")]
+    fn dispatch_redis_call_responses(&mut self) -> Result<()> {
+        loop {
+            let pending = self.get_settings_handle().staged.take_pending_redis_calls();
+            if pending.is_empty() {
+                return Ok(());
+            }
+            for redis_call in pending {
+                {
+                    let mut host = self.get_settings_handle();
+                    host.staged.set_buffer_data(
+                        BufferType::RedisCallResponse as i32,
+                        redis_call.reply.clone(),
+                    );
+                    host.staged.set_effective_context(redis_call.context_id);
+                }
+                self.call_redis_call_response(
+                    redis_call.context_id,
+                    redis_call.token_id as i32,
+                    Status::Ok as i32,
+                    redis_call.reply.len() as i32,
+                )?;
             }
         }
+    }
 
-        if self.function_call.len() == 0 {
-            self.assert_expect_stage();
-            self.update_expect_stage();
+    // Delivers the replies of mock gRPC services as Envoy does for unary calls: a successful
+    // reply through proxy_on_grpc_receive, a failed or trailers-only one through
+    // proxy_on_grpc_close
+    fn dispatch_grpc_call_replies(&mut self) -> Result<()> {
+        loop {
+            let pending_grpc_calls = self.get_settings_handle().staged.take_pending_grpc_calls();
+            if pending_grpc_calls.is_empty() {
+                return Ok(());
+            }
+            for grpc_call in pending_grpc_calls {
+                let reply = grpc_call.reply;
+                {
+                    let mut host = self.get_settings_handle();
+                    host.staged.set_buffer_data(
+                        BufferType::GrpcReceiveBuffer as i32,
+                        reply.message.clone(),
+                    );
+                    host.staged.set_effective_context(grpc_call.context_id);
+                }
+                if let Some(grpc_message) = &reply.trailers_only {
+                    let headers = grpc_trailers_only_headers(reply.status, grpc_message);
+                    let num_headers = headers.len() as i32;
+                    self.get_settings_handle().staged.set_header_map_pairs(
+                        MapType::GrpcReceiveInitialMetadata as i32,
+                        headers
+                            .iter()
+                            .map(|(key, value)| (key.as_str(), value.as_str()))
+                            .collect(),
+                    );
+                    self.call_grpc_initial_metadata(
+                        grpc_call.context_id,
+                        grpc_call.token_id as i32,
+                        num_headers,
+                    )?;
+                    self.call_grpc_close(
+                        grpc_call.context_id,
+                        grpc_call.token_id as i32,
+                        reply.status as i32,
+                    )?;
+                } else if reply.status == GrpcStatus::Ok {
+                    self.call_grpc_receive(
+                        grpc_call.context_id,
+                        grpc_call.token_id as i32,
+                        reply.message.len() as i32,
+                    )?;
+                } else {
+                    self.call_grpc_close(
+                        grpc_call.context_id,
+                        grpc_call.token_id as i32,
+                        reply.status as i32,
+                    )?;
+                }
+            }
         }
+    }
+
+    fn call_queue_ready(&mut self, context_id: i32, queue_id: i32) -> Result<()> {
+        let proxy_on_queue_ready = self
+            .instance
+            .get_typed_func::<(i32, i32), ()>(&mut self.store, "proxy_on_queue_ready")
+            .or(Err(anyhow::format_err!(
+                "Error: failed to find 'proxy_on_queue_ready' function export"
+            )))?;
+        debug!(
+            "[host->vm] proxy_on_queue_ready(context_id={}, queue_id={})",
+            context_id, queue_id
+        );
+        self.trace_callback(&FunctionCall::ProxyOnQueueReady(context_id, queue_id));
+        proxy_on_queue_ready.call(&mut self.store, (context_id, queue_id))?;
+        Ok(())
+    }
 
-        println!("\n");
+    fn call_http_call_response(
+        &mut self,
+        context_id: i32,
+        callout_id: i32,
+        num_headers: i32,
+        body_size: i32,
+        num_trailers: i32,
+    ) -> Result<()> {
+        let proxy_on_http_call_response = self
+            .instance
+            .get_typed_func::<(i32, i32, i32, i32, i32), ()>(
+                &mut self.store,
+                "proxy_on_http_call_response",
+            )
+            .or(Err(anyhow::format_err!(
+                "Error: failed to find `proxy_on_http_call_response` function export"
+            )))?;
+        debug!(
+            "[host->vm] proxy_on_http_call_response(context_id={}, callout_id={}, num_headers={}",
+            context_id, callout_id, num_headers
+        );
+        debug!(
+            "                                       body_size={}, num_trailers={})",
+            body_size, num_trailers
+        );
+        self.trace_callback(&FunctionCall::ProxyOnHttpCallResponse(
+            context_id,
+            callout_id,
+            num_headers,
+            body_size,
+            num_trailers,
+        ));
+        proxy_on_http_call_response.call(
+            &mut self.store,
+            (context_id, callout_id, num_headers, body_size, num_trailers),
+        )?;
         Ok(())
     }
 
+    // Notifies this Tester's contexts of messages enqueued on the queues they registered, whether
+    // by this Tester or another one (in which case delivery happens after this Tester's next call)
+    fn dispatch_queue_ready(&mut self) -> Result<()> {
+        loop {
+            let pending_ready = self.store.data().take_queue_ready(&self.vm_id);
+            if pending_ready.is_empty() {
+                return Ok(());
+            }
+            for queue_ready in pending_ready {
+                {
+                    let mut host = self.get_settings_handle();
+                    host.staged.set_vm_id(&self.vm_id);
+                    host.staged.set_effective_context(queue_ready.context_id);
+                }
+                self.call_queue_ready(queue_ready.context_id, queue_ready.queue_id as i32)?;
+            }
+        }
+    }
+
+    // Delivers the responses of mock upstreams to http_calls made during the last callback (and
+    // in turn to http_calls made while handling those responses)
+    fn dispatch_http_call_responses(&mut self) -> Result<()> {
+        loop {
+            let pending_http_calls = self.get_settings_handle().staged.take_pending_http_calls();
+            if pending_http_calls.is_empty() {
+                return Ok(());
+            }
+            for http_call in pending_http_calls {
+                let response = http_call.response;
+                {
+                    let mut host = self.get_settings_handle();
+                    host.staged.set_header_map_pairs(
+                        MapType::HttpCallResponseHeaders as i32,
+                        response
+                            .headers
+                            .iter()
+                            .map(|(k, v)| (k as &str, v as &str))
+                            .collect(),
+                    );
+                    host.staged.set_buffer_data(
+                        BufferType::HttpCallResponseBody as i32,
+                        response.body.clone(),
+                    );
+                    host.staged.set_header_map_pairs(
+                        MapType::HttpCallResponseTrailers as i32,
+                        response
+                            .trailers
+                            .iter()
+                            .map(|(k, v)| (k as &str, v as &str))
+                            .collect(),
+                    );
+                    host.staged.set_effective_context(http_call.context_id);
+                }
+                self.call_http_call_response(
+                    http_call.context_id,
+                    http_call.token_id as i32,
+                    response.headers.len() as i32,
+                    response.body.len() as i32,
+                    response.trailers.len() as i32,
+                )?;
+            }
+        }
+    }
+
     /* ------------------------------------- Calls in setting ------------------------------------- */
 
+    // Not a callback: moves the host clock forward (in order with the surrounding calls), which
+    // delivers the ticks and the mock upstream and grpc replies that become due on the way
+    pub fn advance(&mut self, duration: Duration) -> &mut Self {
+        self.function_call.push(FunctionCall::AdvanceTime(duration));
+        self.function_type.push(FunctionType::ReturnVoid);
+        self
+    }
+
+    pub fn advance_time_millis(&mut self, millis: u64) -> &mut Self {
+        self.advance(Duration::from_millis(millis))
+    }
+
     pub fn call_start(&mut self) -> &mut Self {
         self.function_call.push(FunctionCall::Start());
         self.function_type.push(FunctionType::ReturnVoid);
@@ -1297,6 +3872,25 @@ impl Tester {
         self
     }
 
+    #[cfg(feature = "vnext")]
+    pub fn call_proxy_on_redis_call_response(
+        &mut self,
+        context_id: i32,
+        token: i32,
+        status: i32,
+        response_size: i32,
+    ) -> &mut Self {
+        self.function_call
+            .push(FunctionCall::ProxyOnRedisCallResponse(
+                context_id,
+                token,
+                status,
+                response_size,
+            ));
+        self.function_type.push(FunctionType::ReturnVoid);
+        self
+    }
+
     pub fn call_proxy_on_grpc_receive_initial_metadata(
         &mut self,
         context_id: i32,
@@ -1384,6 +3978,17 @@ impl Tester {
         headers: Option<Vec<(&str, &str)>>,
         body: Option<&str>,
         trailers: Option<Vec<(&str, &str)>>,
+    ) -> Result<&mut Self> {
+        self.http_request_bytes(http_context, headers, body.map(str::as_bytes), trailers)
+    }
+
+    // Same as http_request() for a binary (non-UTF-8) body
+    pub fn http_request_bytes(
+        &mut self,
+        http_context: i32,
+        headers: Option<Vec<(&str, &str)>>,
+        body: Option<&[u8]>,
+        trailers: Option<Vec<(&str, &str)>>,
     ) -> Result<&mut Self> {
         self.toggle_strict_mode(false);
         let mut headers = headers;
@@ -1391,16 +3996,17 @@ impl Tester {
         let mut trailers = trailers;
         let end_of_stream = false;
         if let Some(header_map_pairs) = headers.take() {
+            let header_map_pairs = self.with_content_length(header_map_pairs, body);
             let num_headers = header_map_pairs.len() as i32;
             self.set_default_header_map_pairs(MapType::HttpRequestHeaders)
-                .returning(header_map_pairs)
+                .returning(header_map_pairs.into_iter().collect::<HeaderMap>())
                 .call_proxy_on_request_headers(http_context, num_headers, end_of_stream);
         }
 
         if let Some(body_data) = body.take() {
             let body_size = body_data.len() as i32;
             self.set_default_buffer_bytes(BufferType::HttpRequestBody)
-                .returning(body_data)
+                .returning_bytes(body_data)
                 .call_proxy_on_request_body(http_context, body_size, end_of_stream);
         }
 
@@ -1419,6 +4025,17 @@ impl Tester {
         headers: Option<Vec<(&str, &str)>>,
         body: Option<&str>,
         trailers: Option<Vec<(&str, &str)>>,
+    ) -> Result<&mut Self> {
+        self.http_response_bytes(http_context, headers, body.map(str::as_bytes), trailers)
+    }
+
+    // Same as http_response() for a binary (non-UTF-8) body
+    pub fn http_response_bytes(
+        &mut self,
+        http_context: i32,
+        headers: Option<Vec<(&str, &str)>>,
+        body: Option<&[u8]>,
+        trailers: Option<Vec<(&str, &str)>>,
     ) -> Result<&mut Self> {
         self.toggle_strict_mode(false);
         let mut headers = headers;
@@ -1426,16 +4043,17 @@ impl Tester {
         let mut trailers = trailers;
         let end_of_stream = false;
         if let Some(header_map_pairs) = headers.take() {
+            let header_map_pairs = self.with_content_length(header_map_pairs, body);
             let num_headers = header_map_pairs.len() as i32;
             self.set_default_header_map_pairs(MapType::HttpResponseHeaders)
-                .returning(header_map_pairs)
+                .returning(header_map_pairs.into_iter().collect::<HeaderMap>())
                 .call_proxy_on_response_headers(http_context, num_headers, end_of_stream);
         }
 
         if let Some(body_data) = body.take() {
             let body_size = body_data.len() as i32;
             self.set_default_buffer_bytes(BufferType::HttpResponseBody)
-                .returning(body_data)
+                .returning_bytes(body_data)
                 .call_proxy_on_response_body(http_context, body_size, end_of_stream);
         }
 
@@ -1447,4 +4065,239 @@ impl Tester {
         }
         Ok(self)
     }
+
+    // Drives a request built with HttpRequest (e.g. HttpRequest::get("/path").header("x", "y"))
+    // through the request callbacks, as http_request() or http_request_chunked() do
+    pub fn send_request(&mut self, http_context: i32, request: HttpRequest) -> Result<&mut Self> {
+        let headers = request.headers.pairs();
+        let trailers = request.trailers.as_ref().map(HeaderMap::pairs);
+        match &request.body {
+            Some(HttpBody::Chunked(chunks)) => {
+                self.http_request_chunked(http_context, headers, chunks.clone(), trailers)
+            }
+            Some(HttpBody::Full(body)) => {
+                self.http_request_bytes(http_context, Some(headers), Some(body), trailers)
+            }
+            None => self.http_request_bytes(http_context, Some(headers), None, trailers),
+        }
+    }
+
+    pub fn send_response(
+        &mut self,
+        http_context: i32,
+        response: HttpResponse,
+    ) -> Result<&mut Self> {
+        let headers = response.headers.pairs();
+        let trailers = response.trailers.as_ref().map(HeaderMap::pairs);
+        match &response.body {
+            Some(HttpBody::Chunked(chunks)) => {
+                self.http_response_chunked(http_context, headers, chunks.clone(), trailers)
+            }
+            Some(HttpBody::Full(body)) => {
+                self.http_response_bytes(http_context, Some(headers), Some(body), trailers)
+            }
+            None => self.http_response_bytes(http_context, Some(headers), None, trailers),
+        }
+    }
+
+    pub(crate) fn with_content_length(
+        &self,
+        header_map_pairs: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+    ) -> Vec<(String, String)> {
+        let mut header_map_pairs = header_map_pairs
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        if let (true, Some(body)) = (self.auto_content_length, body) {
+            set_content_length(&mut header_map_pairs, body.len());
+        }
+        header_map_pairs
+    }
+
+    // Delivers the body as `transfer-encoding: chunked`: headers without a content-length, one
+    // body callback per chunk and the trailers (if any) after the last chunk. end_of_stream is
+    // only set on the last chunk when there are no trailers.
+    pub fn http_request_chunked(
+        &mut self,
+        http_context: i32,
+        headers: Vec<(&str, &str)>,
+        chunks: Vec<impl AsRef<[u8]>>,
+        trailers: Option<Vec<(&str, &str)>>,
+    ) -> Result<&mut Self> {
+        self.toggle_strict_mode(false);
+        let header_map_pairs = chunked_headers(headers);
+        let num_headers = header_map_pairs.len() as i32;
+        self.set_default_header_map_pairs(MapType::HttpRequestHeaders)
+            .returning(header_map_pairs)
+            .call_proxy_on_request_headers(http_context, num_headers, false);
+
+        let chunk_sizes = self.stage_body_chunks(http_context, BufferType::HttpRequestBody, chunks);
+        let last_chunk = chunk_sizes.len() - 1;
+        for (index, body_size) in chunk_sizes.into_iter().enumerate() {
+            let end_of_stream = index == last_chunk && trailers.is_none();
+            self.call_proxy_on_request_body(http_context, body_size, end_of_stream);
+        }
+
+        if let Some(header_map_pairs) = trailers {
+            let num_trailers = header_map_pairs.len() as i32;
+            self.set_default_header_map_pairs(MapType::HttpRequestTrailers)
+                .returning(header_map_pairs)
+                .call_proxy_on_request_trailers(http_context, num_trailers);
+        }
+        Ok(self)
+    }
+
+    pub fn http_response_chunked(
+        &mut self,
+        http_context: i32,
+        headers: Vec<(&str, &str)>,
+        chunks: Vec<impl AsRef<[u8]>>,
+        trailers: Option<Vec<(&str, &str)>>,
+    ) -> Result<&mut Self> {
+        self.toggle_strict_mode(false);
+        let header_map_pairs = chunked_headers(headers);
+        let num_headers = header_map_pairs.len() as i32;
+        self.set_default_header_map_pairs(MapType::HttpResponseHeaders)
+            .returning(header_map_pairs)
+            .call_proxy_on_response_headers(http_context, num_headers, false);
+
+        let chunk_sizes =
+            self.stage_body_chunks(http_context, BufferType::HttpResponseBody, chunks);
+        let last_chunk = chunk_sizes.len() - 1;
+        for (index, body_size) in chunk_sizes.into_iter().enumerate() {
+            let end_of_stream = index == last_chunk && trailers.is_none();
+            self.call_proxy_on_response_body(http_context, body_size, end_of_stream);
+        }
+
+        if let Some(header_map_pairs) = trailers {
+            let num_trailers = header_map_pairs.len() as i32;
+            self.set_default_header_map_pairs(MapType::HttpResponseTrailers)
+                .returning(header_map_pairs)
+                .call_proxy_on_response_trailers(http_context, num_trailers);
+        }
+        Ok(self)
+    }
+
+    // Network data received by the proxy, e.g. a TCP segment, the chunks of the data callbacks of
+    // a connection delivered one per callback as the body chunks are
+    pub fn downstream_data(
+        &mut self,
+        context_id: i32,
+        data: impl AsRef<[u8]>,
+        end_of_stream: bool,
+    ) -> Result<&mut Self> {
+        self.toggle_strict_mode(false);
+        let data_sizes = self.stage_body_chunks(context_id, BufferType::DownstreamData, vec![data]);
+        Ok(self.call_proxy_on_downstream_data(context_id, data_sizes[0], end_of_stream))
+    }
+
+    pub fn upstream_data(
+        &mut self,
+        context_id: i32,
+        data: impl AsRef<[u8]>,
+        end_of_stream: bool,
+    ) -> Result<&mut Self> {
+        self.toggle_strict_mode(false);
+        let data_sizes = self.stage_body_chunks(context_id, BufferType::UpstreamData, vec![data]);
+        Ok(self.call_proxy_on_upstream_data(context_id, data_sizes[0], end_of_stream))
+    }
+
+    // Queues the chunks for delivery when their body callbacks execute, an empty chunk list
+    // still produces a single empty (terminating) chunk
+    fn stage_body_chunks(
+        &mut self,
+        context_id: i32,
+        buffer_type: BufferType,
+        chunks: Vec<impl AsRef<[u8]>>,
+    ) -> Vec<i32> {
+        let mut chunks: Vec<Bytes> = chunks.iter().map(|chunk| chunk.as_ref().to_vec()).collect();
+        if chunks.is_empty() {
+            chunks.push(vec![]);
+        }
+        let queue = self
+            .body_chunks
+            .entry((context_id, buffer_type as i32))
+            .or_default();
+        chunks
+            .into_iter()
+            .map(|chunk| {
+                let chunk_size = chunk.len() as i32;
+                queue.push_back(chunk);
+                chunk_size
+            })
+            .collect()
+    }
+
+    // Returns the size of the body buffer for the callback: while the module holds the body (or
+    // the network data), the chunks add up in the buffer, as Envoy buffers them
+    fn load_body_chunk(&mut self, context_id: i32, buffer_type: BufferType, body_size: i32) -> i32 {
+        let held = match buffer_type {
+            BufferType::HttpRequestBody | BufferType::HttpResponseBody => self
+                .get_settings_handle()
+                .staged
+                .is_body_held(context_id, buffer_type == BufferType::HttpRequestBody),
+            _ => self.held_data.contains(&(context_id, buffer_type as i32)),
+        };
+        let buffer_type = buffer_type as i32;
+        let chunk = self
+            .body_chunks
+            .get_mut(&(context_id, buffer_type))
+            .and_then(|queue| queue.pop_front());
+        let mut chunk = match chunk {
+            Some(chunk) => chunk,
+            None => return body_size,
+        };
+        let mut host = self.get_settings_handle();
+        if held {
+            let mut buffered = host.staged.get_buffer_bytes(buffer_type);
+            buffered.append(&mut chunk);
+            chunk = buffered;
+        }
+        let body_size = chunk.len() as i32;
+        host.staged.set_buffer_data(buffer_type, chunk);
+        body_size
+    }
+}
+
+// Coverage of the instance written to PROXY_WASM_TEST_COVERAGE, its stack samples to
+// PROXY_WASM_TEST_FLAMEGRAPH, and the profile of the callbacks and host calls printed with
+// PROXY_WASM_TEST_PROFILE, if set
+impl Drop for Tester {
+    fn drop(&mut self) {
+        coverage::write_profile(self);
+        flamegraph::write_samples(self);
+        if std::env::var_os("PROXY_WASM_TEST_PROFILE").is_some() && !self.profile.is_empty() {
+            let profile = self.profile.to_string();
+            let host_calls = self.host_call_report();
+            with_log(&self.log, || {
+                info!("[host] callback profile:\n{}", profile.trim_end());
+                if !host_calls.is_empty() {
+                    info!("[host] host calls:\n{}", host_calls.to_string().trim_end());
+                }
+            });
+        }
+    }
+}
+
+fn chunked_headers<'a>(headers: Vec<(&'a str, &'a str)>) -> Vec<(&'a str, &'a str)> {
+    let mut headers: Vec<(&str, &str)> = headers
+        .into_iter()
+        .filter(|(name, _)| {
+            !name.eq_ignore_ascii_case("content-length")
+                && !name.eq_ignore_ascii_case("transfer-encoding")
+        })
+        .collect();
+    headers.push(("transfer-encoding", "chunked"));
+    headers
+}
+
+fn parse_socket_address(address: &str) -> SocketAddr {
+    match address.parse() {
+        Ok(socket_address) => socket_address,
+        Err(_) => panic!(
+            "Error: \"{}\" is not a valid address (expected ip:port)",
+            address
+        ),
+    }
 }
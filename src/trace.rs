@@ -0,0 +1,1124 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::expectations::{Expect, Response};
+use crate::matchers::Exact;
+use crate::types::*;
+
+use anyhow::{bail, format_err, Result};
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+use tracing::info;
+
+type Pairs = Vec<(String, String)>;
+
+// A host call made by the module, with the arguments it passed and what the host returned
+#[derive(Debug, Clone, PartialEq)]
+pub enum TracedCall {
+    Log {
+        level: i32,
+        message: String,
+    },
+    SetTickPeriodMillis {
+        period_millis: u64,
+    },
+    GetCurrentTimeNanos {
+        time_nanos: u64,
+    },
+    GetBufferBytes {
+        buffer_type: i32,
+        buffer_data: Option<Bytes>,
+    },
+    SetBufferBytes {
+        buffer_type: i32,
+        buffer_data: Bytes,
+    },
+    GetHeaderMapPairs {
+        map_type: i32,
+        pairs: Pairs,
+    },
+    SetHeaderMapPairs {
+        map_type: i32,
+        pairs: Pairs,
+    },
+    GetHeaderMapValue {
+        map_type: i32,
+        key: String,
+        value: Option<Bytes>,
+    },
+    ReplaceHeaderMapValue {
+        map_type: i32,
+        key: String,
+        value: Bytes,
+    },
+    RemoveHeaderMapValue {
+        map_type: i32,
+        key: String,
+    },
+    AddHeaderMapValue {
+        map_type: i32,
+        key: String,
+        value: Bytes,
+    },
+    SendLocalResponse {
+        status_code: i32,
+        body: Bytes,
+        headers: Pairs,
+        grpc_status: i32,
+    },
+    HttpCall {
+        upstream: String,
+        headers: Pairs,
+        body: Bytes,
+        trailers: Pairs,
+        timeout_millis: u64,
+        token_id: u32,
+    },
+    MetricCreate {
+        metric_type: i32,
+        name: String,
+    },
+    MetricIncrement {
+        metric_id: i32,
+        offset: i64,
+    },
+    MetricRecord {
+        metric_id: i32,
+        value: u64,
+    },
+    MetricGet {
+        metric_id: i32,
+        value: u64,
+    },
+    // host call the trace names without capturing it (properties, shared data, queues, gRPC,
+    // continue/clear route cache, ...): no expectation can stage it, so replay refuses the stage
+    Untraced {
+        name: String,
+    },
+}
+
+// Step of the interaction between the host and the module
+#[derive(Debug, Clone, PartialEq)]
+pub enum TracedEvent {
+    // callback into the module (host->vm)
+    Callback(String),
+    // value returned by the last callback, for callbacks returning one
+    Return(i32),
+    // host call made by the module (vm->host)
+    HostCall(TracedCall),
+}
+
+// Interaction recorded over one expectation stage, i.e. the callbacks executed up to (and
+// including) the execute_and_expect call that asserts the stage
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TracedStage {
+    pub events: Vec<TracedEvent>,
+}
+
+// Interaction recorded while Tester::record_trace is on, saved in a line-based text format:
+//
+//   stage
+//   -> ProxyOnRequestHeaders(2, 3, false)
+//     proxy_get_header_map_value(0, ":path") -> "/admin"
+//     proxy_send_local_response(403, "", {"x-reason": "admin"}, -1)
+//   <- 1
+//
+// so that an observed run can be turned into a regression test (see Tester::replay) or
+// compared against a golden file (see Trace::assert_golden).
+//
+// Only the host calls expectations exist for are captured with their values, the other proxy_*
+// calls are written by name, e.g. `proxy_get_property(..)`, and replaying a stage that makes one
+// panics rather than silently staging less than the module did. The wasi shims (fd_write,
+// clock_time_get, random_get, ...) are not traced.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Trace {
+    pub stages: Vec<TracedStage>,
+    stage_open: bool,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Trace> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| format_err!("Error: failed to read trace {:?}: {}", path, error))?;
+        Trace::parse(&text)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_string())
+            .map_err(|error| format_err!("Error: failed to write trace {:?}: {}", path, error))
+    }
+
+    pub fn parse(text: &str) -> Result<Trace> {
+        let mut trace = Trace::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line == "stage" {
+                trace.stages.push(TracedStage::default());
+                continue;
+            }
+            let event = parse_event(line)
+                .map_err(|error| format_err!("Error: trace line {}: {}", index + 1, error))?;
+            match trace.stages.last_mut() {
+                Some(stage) => stage.events.push(event),
+                None => bail!("Error: trace line {}: event outside of a stage", index + 1),
+            }
+        }
+        Ok(trace)
+    }
+
+    pub fn calls(&self) -> impl Iterator<Item = &TracedCall> {
+        self.stages.iter().flat_map(|stage| stage.calls())
+    }
+
+    // Opens a new stage unless the current one is still pending (chained calls asserted at once)
+    pub(crate) fn begin_stage(&mut self) {
+        if !self.stage_open {
+            self.stages.push(TracedStage::default());
+            self.stage_open = true;
+        }
+    }
+
+    pub(crate) fn end_stage(&mut self) {
+        self.stage_open = false;
+    }
+
+    pub(crate) fn record(&mut self, event: TracedEvent) {
+        if let Some(stage) = self.stages.last_mut() {
+            stage.events.push(event);
+        }
+    }
+
+    // Compares the trace against a golden file, or (re)writes the file when
+    // PROXY_WASM_TEST_UPDATE_SNAPSHOTS is set, panics with a line diff on mismatch
+    #[track_caller]
+    pub fn assert_golden(&self, path: impl AsRef<Path>) {
+        assert_golden("trace", path.as_ref(), &self.to_string());
+    }
+}
+
+// Golden file check of a text (a trace, metrics) named what in the failure message
+#[track_caller]
+pub(crate) fn assert_golden(what: &str, path: &Path, actual: &str) {
+    if std::env::var_os("PROXY_WASM_TEST_UPDATE_SNAPSHOTS").is_some() {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(path, actual).unwrap();
+        info!("[host] updated golden file {:?}", path);
+        return;
+    }
+    let expected = match std::fs::read_to_string(path) {
+        Ok(expected) => expected,
+        Err(_) => panic!(
+            "Error: golden file {:?} does not exist, \
+            run with PROXY_WASM_TEST_UPDATE_SNAPSHOTS=1 to create it",
+            path
+        ),
+    };
+    if expected != actual {
+        panic!(
+            "Error: {} does not match golden file {:?} (- expected, + actual), \
+            run with PROXY_WASM_TEST_UPDATE_SNAPSHOTS=1 to update it\n{}",
+            what,
+            path,
+            diff_lines(&expected, actual)
+        );
+    }
+}
+
+// Line diff of two texts, good enough to spot where a trace diverges: unchanged lines are kept
+// around the first and last differing ones
+pub(crate) fn diff_lines(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let prefix = expected
+        .iter()
+        .zip(&actual)
+        .take_while(|(expected, actual)| expected == actual)
+        .count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(expected, actual)| expected == actual)
+        .count();
+    let context = prefix.saturating_sub(3);
+    let mut diff = vec![];
+    diff.extend(
+        expected[context..prefix]
+            .iter()
+            .map(|line| format!("  {}", line)),
+    );
+    diff.extend(
+        expected[prefix..expected.len() - suffix]
+            .iter()
+            .map(|line| format!("- {}", line)),
+    );
+    diff.extend(
+        actual[prefix..actual.len() - suffix]
+            .iter()
+            .map(|line| format!("+ {}", line)),
+    );
+    diff.extend(
+        expected[expected.len() - suffix..]
+            .iter()
+            .take(3)
+            .map(|line| format!("  {}", line)),
+    );
+    diff.join("\n")
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, stage) in self.stages.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "stage")?;
+            for event in &stage.events {
+                match event {
+                    TracedEvent::Callback(callback) => writeln!(f, "-> {}", callback)?,
+                    TracedEvent::Return(returned) => writeln!(f, "<- {}", returned)?,
+                    TracedEvent::HostCall(call) => writeln!(f, "  {}", call)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Structured log of the interaction written while Tester::set_event_log is on, one JSON object
+// per event and per line, e.g.
+//
+//   {"seq":0,"elapsed_us":41,"time_nanos":1600000000000000000,"context_id":2,"event":"callback",
+//    "name":"ProxyOnRequestHeaders","args":[2,3,false]}
+//   {"seq":1,...,"event":"host_call","name":"proxy_get_header_map_value",
+//    "args":{"map_type":0,"key":":path"},"returns":{"value":"/admin"}}
+//   {"seq":2,...,"event":"return","value":1}
+//
+// time_nanos is the host clock (see Tester::advance) and elapsed_us the wall time since the
+// log was set. Host calls and returns carry the context of the callback they happened in.
+// Bytes are written as strings, or as arrays of numbers when they are not valid UTF-8.
+pub struct EventLog {
+    writer: Box<dyn Write + Send>,
+    started: Instant,
+    sequence: u64,
+    time_nanos: u64,
+    context_id: Option<i32>,
+}
+
+impl EventLog {
+    pub(crate) fn new(writer: Box<dyn Write + Send>) -> EventLog {
+        EventLog {
+            writer,
+            started: Instant::now(),
+            sequence: 0,
+            time_nanos: 0,
+            context_id: None,
+        }
+    }
+
+    // Context and host time of the callback about to be logged (and of what follows it)
+    pub(crate) fn enter(&mut self, context_id: Option<i32>, time_nanos: u64) {
+        self.context_id = context_id;
+        self.time_nanos = time_nanos;
+    }
+
+    pub(crate) fn write(&mut self, event: &TracedEvent) {
+        let mut line = format!(
+            r#"{{"seq":{},"elapsed_us":{},"time_nanos":{},"context_id":{},"#,
+            self.sequence,
+            self.started.elapsed().as_micros(),
+            self.time_nanos,
+            self.context_id
+                .map_or("null".to_string(), |context_id| context_id.to_string())
+        );
+        match event {
+            TracedEvent::Callback(callback) => {
+                // the callback as traced, e.g. ProxyOnRequestHeaders(2, 3, false)
+                let (name, args) = callback.split_at(callback.find('(').unwrap_or(callback.len()));
+                let args = args.trim_start_matches('(').trim_end_matches(')');
+                line.push_str(r#""event":"callback","name":"#);
+                json_bytes(&mut line, name.as_bytes());
+                line.push_str(&format!(r#","args":[{}]}}"#, args));
+            }
+            TracedEvent::Return(value) => {
+                line.push_str(&format!(r#""event":"return","value":{}}}"#, value));
+            }
+            TracedEvent::HostCall(call) => {
+                let (arguments, returns) = call.values();
+                let (argument_names, return_names) = call.names();
+                line.push_str(r#""event":"host_call","name":"#);
+                json_bytes(&mut line, call.name().as_bytes());
+                line.push_str(r#","args":"#);
+                json_object(&mut line, argument_names, &arguments);
+                line.push_str(r#","returns":"#);
+                json_object(&mut line, return_names, &returns);
+                line.push('}');
+            }
+        }
+        line.push('\n');
+        self.sequence += 1;
+        if let Err(error) = self
+            .writer
+            .write_all(line.as_bytes())
+            .and_then(|_| self.writer.flush())
+        {
+            panic!("Error: cannot write to the event log: {}", error);
+        }
+    }
+}
+
+fn json_object(line: &mut String, names: &[&str], values: &[TraceValue]) {
+    line.push('{');
+    for (index, (name, value)) in names.iter().zip(values).enumerate() {
+        if index > 0 {
+            line.push(',');
+        }
+        json_bytes(line, name.as_bytes());
+        line.push(':');
+        json_value(line, value);
+    }
+    line.push('}');
+}
+
+fn json_value(line: &mut String, value: &TraceValue) {
+    match value {
+        TraceValue::Int(value) => line.push_str(&value.to_string()),
+        TraceValue::Bytes(bytes) => json_bytes(line, bytes),
+        TraceValue::Pairs(pairs) => {
+            // an array of [name, value] rather than an object, as names can repeat
+            line.push('[');
+            for (index, (name, value)) in pairs.iter().enumerate() {
+                if index > 0 {
+                    line.push(',');
+                }
+                line.push('[');
+                json_bytes(line, name.as_bytes());
+                line.push(',');
+                json_bytes(line, value.as_bytes());
+                line.push(']');
+            }
+            line.push(']');
+        }
+        TraceValue::None => line.push_str("null"),
+    }
+}
+
+pub(crate) fn json_bytes(line: &mut String, bytes: &[u8]) {
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(_) => {
+            let numbers: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+            line.push_str(&format!("[{}]", numbers.join(",")));
+            return;
+        }
+    };
+    line.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if (c as u32) < 0x20 => line.push_str(&format!("\\u{:04x}", c as u32)),
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn encode_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (index, byte)| {
+            bits | (*byte as u32) << (16 - 8 * index)
+        });
+        for index in 0..4 {
+            match index <= chunk.len() {
+                true => encoded.push(BASE64[(bits >> (18 - 6 * index) & 63) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+impl TracedStage {
+    pub fn calls(&self) -> impl Iterator<Item = &TracedCall> {
+        self.events.iter().filter_map(|event| match event {
+            TracedEvent::HostCall(call) => Some(call),
+            _ => None,
+        })
+    }
+
+    // Stages one exact expectation per recorded host call, values the host returned are handed
+    // back to the module by the expectations so that the replay does not depend on host settings
+    #[track_caller]
+    pub(crate) fn stage(&self, expect: &mut Expect) {
+        for call in self.calls() {
+            match call.clone() {
+                TracedCall::Log { level, message } => expect.set_expect_log(level, Exact(message)),
+                TracedCall::SetTickPeriodMillis { period_millis } => {
+                    expect.set_expect_set_tick_period_millis(period_millis)
+                }
+                TracedCall::GetCurrentTimeNanos { time_nanos } => {
+                    expect.set_expect_get_current_time_nanos(Some(time_nanos))
+                }
+                TracedCall::GetBufferBytes {
+                    buffer_type,
+                    buffer_data,
+                } => expect.set_expect_get_buffer_bytes(buffer_type, buffer_data.as_deref()),
+                TracedCall::SetBufferBytes {
+                    buffer_type,
+                    buffer_data,
+                } => expect.set_expect_set_buffer_bytes(buffer_type, buffer_data),
+                TracedCall::GetHeaderMapPairs { map_type, pairs } => {
+                    expect.set_expect_get_header_map_pairs(map_type, Some(pairs))
+                }
+                TracedCall::SetHeaderMapPairs { map_type, pairs } => {
+                    expect.set_expect_set_header_map_pairs(map_type, Exact(pairs))
+                }
+                TracedCall::GetHeaderMapValue {
+                    map_type,
+                    key,
+                    value,
+                } => expect.set_expect_get_header_map_value(
+                    map_type,
+                    Exact(key),
+                    Response::Fixed(value),
+                ),
+                TracedCall::ReplaceHeaderMapValue {
+                    map_type,
+                    key,
+                    value,
+                } => expect.set_expect_replace_header_map_value(map_type, Exact(key), value),
+                TracedCall::RemoveHeaderMapValue { map_type, key } => {
+                    expect.set_expect_remove_header_map_value(map_type, Exact(key))
+                }
+                TracedCall::AddHeaderMapValue {
+                    map_type,
+                    key,
+                    value,
+                } => expect.set_expect_add_header_map_value(map_type, Exact(key), value),
+                TracedCall::SendLocalResponse {
+                    status_code,
+                    body,
+                    headers,
+                    grpc_status,
+                } => expect.set_expect_send_local_response(
+                    status_code,
+                    body,
+                    Exact(headers),
+                    grpc_status,
+                ),
+                TracedCall::HttpCall {
+                    upstream,
+                    headers,
+                    body,
+                    trailers,
+                    timeout_millis,
+                    token_id,
+                } => expect.set_expect_http_call(
+                    Exact(upstream),
+                    Exact(headers),
+                    body,
+                    Exact(trailers),
+                    timeout_millis,
+                    Some(token_id),
+                ),
+                TracedCall::MetricCreate { metric_type, name } => {
+                    expect.set_expect_metric_create(metric_type, Exact(name))
+                }
+                TracedCall::MetricIncrement { metric_id, offset } => {
+                    expect.set_expect_metric_increment(metric_id, offset)
+                }
+                TracedCall::MetricRecord { metric_id, value } => {
+                    expect.set_expect_metric_record(metric_id, value)
+                }
+                TracedCall::MetricGet { metric_id, value } => {
+                    expect.set_expect_metric_get(metric_id, value)
+                }
+                TracedCall::Untraced { name } => panic!(
+                    "Error: cannot replay a stage calling {}, its values are not traced",
+                    name
+                ),
+            }
+        }
+    }
+}
+
+// Argument or returned value of a traced host call, as written in the trace
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TraceValue {
+    Int(i128),
+    Bytes(Bytes),
+    Pairs(Pairs),
+    None,
+}
+
+impl fmt::Display for TraceValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceValue::Int(value) => write!(f, "{}", value),
+            TraceValue::Bytes(bytes) => write_quoted(f, bytes),
+            TraceValue::Pairs(pairs) => {
+                write!(f, "{{")?;
+                for (index, (name, value)) in pairs.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_quoted(f, name.as_bytes())?;
+                    write!(f, ": ")?;
+                    write_quoted(f, value.as_bytes())?;
+                }
+                write!(f, "}}")
+            }
+            TraceValue::None => write!(f, "none"),
+        }
+    }
+}
+
+fn write_quoted(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    write!(f, "\"")?;
+    for &byte in bytes {
+        match byte {
+            b'"' => write!(f, "\\\"")?,
+            b'\\' => write!(f, "\\\\")?,
+            b'\n' => write!(f, "\\n")?,
+            b'\r' => write!(f, "\\r")?,
+            b'\t' => write!(f, "\\t")?,
+            0x20..=0x7e => write!(f, "{}", byte as char)?,
+            _ => write!(f, "\\x{:02x}", byte)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+fn int(value: impl Into<i128>) -> TraceValue {
+    TraceValue::Int(value.into())
+}
+
+fn bytes(value: impl AsRef<[u8]>) -> TraceValue {
+    TraceValue::Bytes(value.as_ref().to_vec())
+}
+
+fn optional_bytes(value: &Option<Bytes>) -> TraceValue {
+    value.as_ref().map_or(TraceValue::None, bytes)
+}
+
+impl TracedCall {
+    pub fn name(&self) -> &str {
+        match self {
+            TracedCall::Untraced { name } => name,
+            call => call.host_call().unwrap().name(),
+        }
+    }
+
+    // None for untraced calls
+    pub fn host_call(&self) -> Option<HostCall> {
+        Some(match self {
+            TracedCall::Log { .. } => HostCall::Log,
+            TracedCall::SetTickPeriodMillis { .. } => HostCall::SetTickPeriodMillis,
+            TracedCall::GetCurrentTimeNanos { .. } => HostCall::GetCurrentTimeNanos,
+            TracedCall::GetBufferBytes { .. } => HostCall::GetBufferBytes,
+            TracedCall::SetBufferBytes { .. } => HostCall::SetBufferBytes,
+            TracedCall::GetHeaderMapPairs { .. } => HostCall::GetHeaderMapPairs,
+            TracedCall::SetHeaderMapPairs { .. } => HostCall::SetHeaderMapPairs,
+            TracedCall::GetHeaderMapValue { .. } => HostCall::GetHeaderMapValue,
+            TracedCall::ReplaceHeaderMapValue { .. } => HostCall::ReplaceHeaderMapValue,
+            TracedCall::RemoveHeaderMapValue { .. } => HostCall::RemoveHeaderMapValue,
+            TracedCall::AddHeaderMapValue { .. } => HostCall::AddHeaderMapValue,
+            TracedCall::SendLocalResponse { .. } => HostCall::SendLocalResponse,
+            TracedCall::HttpCall { .. } => HostCall::HttpCall,
+            TracedCall::MetricCreate { .. } => HostCall::MetricCreate,
+            TracedCall::MetricIncrement { .. } => HostCall::MetricIncrement,
+            TracedCall::MetricRecord { .. } => HostCall::MetricRecord,
+            TracedCall::MetricGet { .. } => HostCall::MetricGet,
+            TracedCall::Untraced { .. } => return None,
+        })
+    }
+
+    // Names of the values() (arguments, returned values), as written in the event log
+    pub(crate) fn names(&self) -> (&'static [&'static str], &'static [&'static str]) {
+        match self {
+            TracedCall::Log { .. } => (&["level", "message"], &[]),
+            TracedCall::SetTickPeriodMillis { .. } => (&["period_millis"], &[]),
+            TracedCall::GetCurrentTimeNanos { .. } => (&[], &["time_nanos"]),
+            TracedCall::GetBufferBytes { .. } => (&["buffer_type"], &["buffer_data"]),
+            TracedCall::SetBufferBytes { .. } => (&["buffer_type", "buffer_data"], &[]),
+            TracedCall::GetHeaderMapPairs { .. } => (&["map_type"], &["pairs"]),
+            TracedCall::SetHeaderMapPairs { .. } => (&["map_type", "pairs"], &[]),
+            TracedCall::GetHeaderMapValue { .. } => (&["map_type", "key"], &["value"]),
+            TracedCall::ReplaceHeaderMapValue { .. } | TracedCall::AddHeaderMapValue { .. } => {
+                (&["map_type", "key", "value"], &[])
+            }
+            TracedCall::RemoveHeaderMapValue { .. } => (&["map_type", "key"], &[]),
+            TracedCall::SendLocalResponse { .. } => {
+                (&["status_code", "body", "headers", "grpc_status"], &[])
+            }
+            TracedCall::HttpCall { .. } => (
+                &["upstream", "headers", "body", "trailers", "timeout_millis"],
+                &["token_id"],
+            ),
+            TracedCall::MetricCreate { .. } => (&["metric_type", "name"], &[]),
+            TracedCall::MetricIncrement { .. } => (&["metric_id", "offset"], &[]),
+            TracedCall::MetricRecord { .. } | TracedCall::MetricGet { .. } => {
+                (&["metric_id", "value"], &[])
+            }
+            TracedCall::Untraced { .. } => (&[], &[]),
+        }
+    }
+
+    // (arguments, returned values) as written in the trace
+    pub(crate) fn values(&self) -> (Vec<TraceValue>, Vec<TraceValue>) {
+        match self {
+            TracedCall::Log { level, message } => (vec![int(*level), bytes(message)], vec![]),
+            TracedCall::SetTickPeriodMillis { period_millis } => {
+                (vec![int(*period_millis)], vec![])
+            }
+            TracedCall::GetCurrentTimeNanos { time_nanos } => (vec![], vec![int(*time_nanos)]),
+            TracedCall::GetBufferBytes {
+                buffer_type,
+                buffer_data,
+            } => (vec![int(*buffer_type)], vec![optional_bytes(buffer_data)]),
+            TracedCall::SetBufferBytes {
+                buffer_type,
+                buffer_data,
+            } => (vec![int(*buffer_type), bytes(buffer_data)], vec![]),
+            TracedCall::GetHeaderMapPairs { map_type, pairs } => {
+                (vec![int(*map_type)], vec![TraceValue::Pairs(pairs.clone())])
+            }
+            TracedCall::SetHeaderMapPairs { map_type, pairs } => (
+                vec![int(*map_type), TraceValue::Pairs(pairs.clone())],
+                vec![],
+            ),
+            TracedCall::GetHeaderMapValue {
+                map_type,
+                key,
+                value,
+            } => (
+                vec![int(*map_type), bytes(key)],
+                vec![optional_bytes(value)],
+            ),
+            TracedCall::ReplaceHeaderMapValue {
+                map_type,
+                key,
+                value,
+            }
+            | TracedCall::AddHeaderMapValue {
+                map_type,
+                key,
+                value,
+            } => (vec![int(*map_type), bytes(key), bytes(value)], vec![]),
+            TracedCall::RemoveHeaderMapValue { map_type, key } => {
+                (vec![int(*map_type), bytes(key)], vec![])
+            }
+            TracedCall::SendLocalResponse {
+                status_code,
+                body,
+                headers,
+                grpc_status,
+            } => (
+                vec![
+                    int(*status_code),
+                    bytes(body),
+                    TraceValue::Pairs(headers.clone()),
+                    int(*grpc_status),
+                ],
+                vec![],
+            ),
+            TracedCall::HttpCall {
+                upstream,
+                headers,
+                body,
+                trailers,
+                timeout_millis,
+                token_id,
+            } => (
+                vec![
+                    bytes(upstream),
+                    TraceValue::Pairs(headers.clone()),
+                    bytes(body),
+                    TraceValue::Pairs(trailers.clone()),
+                    int(*timeout_millis),
+                ],
+                vec![int(*token_id)],
+            ),
+            TracedCall::MetricCreate { metric_type, name } => {
+                (vec![int(*metric_type), bytes(name)], vec![])
+            }
+            TracedCall::MetricIncrement { metric_id, offset } => {
+                (vec![int(*metric_id), int(*offset)], vec![])
+            }
+            TracedCall::MetricRecord { metric_id, value }
+            | TracedCall::MetricGet { metric_id, value } => {
+                (vec![int(*metric_id), int(*value)], vec![])
+            }
+            TracedCall::Untraced { .. } => (vec![], vec![]),
+        }
+    }
+
+    fn from_values(
+        host_call: HostCall,
+        arguments: Vec<TraceValue>,
+        returns: Vec<TraceValue>,
+    ) -> Result<TracedCall> {
+        let mut arguments = Values(arguments.into_iter());
+        let mut returns = Values(returns.into_iter());
+        let call = match host_call {
+            HostCall::Log => TracedCall::Log {
+                level: arguments.int()?,
+                message: arguments.string()?,
+            },
+            HostCall::SetTickPeriodMillis => TracedCall::SetTickPeriodMillis {
+                period_millis: arguments.int()?,
+            },
+            HostCall::GetCurrentTimeNanos => TracedCall::GetCurrentTimeNanos {
+                time_nanos: returns.int()?,
+            },
+            HostCall::GetBufferBytes => TracedCall::GetBufferBytes {
+                buffer_type: arguments.int()?,
+                buffer_data: returns.optional_bytes()?,
+            },
+            HostCall::SetBufferBytes => TracedCall::SetBufferBytes {
+                buffer_type: arguments.int()?,
+                buffer_data: arguments.bytes()?,
+            },
+            HostCall::GetHeaderMapPairs => TracedCall::GetHeaderMapPairs {
+                map_type: arguments.int()?,
+                pairs: returns.pairs()?,
+            },
+            HostCall::SetHeaderMapPairs => TracedCall::SetHeaderMapPairs {
+                map_type: arguments.int()?,
+                pairs: arguments.pairs()?,
+            },
+            HostCall::GetHeaderMapValue => TracedCall::GetHeaderMapValue {
+                map_type: arguments.int()?,
+                key: arguments.string()?,
+                value: returns.optional_bytes()?,
+            },
+            HostCall::ReplaceHeaderMapValue => TracedCall::ReplaceHeaderMapValue {
+                map_type: arguments.int()?,
+                key: arguments.string()?,
+                value: arguments.bytes()?,
+            },
+            HostCall::RemoveHeaderMapValue => TracedCall::RemoveHeaderMapValue {
+                map_type: arguments.int()?,
+                key: arguments.string()?,
+            },
+            HostCall::AddHeaderMapValue => TracedCall::AddHeaderMapValue {
+                map_type: arguments.int()?,
+                key: arguments.string()?,
+                value: arguments.bytes()?,
+            },
+            HostCall::SendLocalResponse => TracedCall::SendLocalResponse {
+                status_code: arguments.int()?,
+                body: arguments.bytes()?,
+                headers: arguments.pairs()?,
+                grpc_status: arguments.int()?,
+            },
+            HostCall::HttpCall => TracedCall::HttpCall {
+                upstream: arguments.string()?,
+                headers: arguments.pairs()?,
+                body: arguments.bytes()?,
+                trailers: arguments.pairs()?,
+                timeout_millis: arguments.int()?,
+                token_id: returns.int()?,
+            },
+            HostCall::MetricCreate => TracedCall::MetricCreate {
+                metric_type: arguments.int()?,
+                name: arguments.string()?,
+            },
+            HostCall::MetricIncrement => TracedCall::MetricIncrement {
+                metric_id: arguments.int()?,
+                offset: arguments.int()?,
+            },
+            HostCall::MetricRecord => TracedCall::MetricRecord {
+                metric_id: arguments.int()?,
+                value: arguments.int()?,
+            },
+            HostCall::MetricGet => TracedCall::MetricGet {
+                metric_id: arguments.int()?,
+                value: arguments.int()?,
+            },
+        };
+        if arguments.0.next().is_some() || returns.0.next().is_some() {
+            bail!("too many values for {}", host_call.name());
+        }
+        Ok(call)
+    }
+}
+
+impl fmt::Display for TracedCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |values: Vec<TraceValue>| {
+            values
+                .iter()
+                .map(|value| value.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        if let TracedCall::Untraced { name } = self {
+            return write!(f, "{}(..)", name);
+        }
+        let (arguments, returns) = self.values();
+        write!(f, "{}({})", self.name(), join(arguments))?;
+        if !returns.is_empty() {
+            write!(f, " -> {}", join(returns))?;
+        }
+        Ok(())
+    }
+}
+
+// Values of a traced call, consumed in order while rebuilding it
+struct Values(std::vec::IntoIter<TraceValue>);
+
+impl Values {
+    fn next(&mut self) -> Result<TraceValue> {
+        self.0.next().ok_or_else(|| format_err!("missing value"))
+    }
+
+    fn int<T: std::convert::TryFrom<i128>>(&mut self) -> Result<T> {
+        match self.next()? {
+            TraceValue::Int(value) => {
+                T::try_from(value).map_err(|_| format_err!("{} is out of range", value))
+            }
+            value => bail!("expected an integer, found {}", value),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Bytes> {
+        match self.next()? {
+            TraceValue::Bytes(bytes) => Ok(bytes),
+            value => bail!("expected a string, found {}", value),
+        }
+    }
+
+    fn optional_bytes(&mut self) -> Result<Option<Bytes>> {
+        match self.next()? {
+            TraceValue::None => Ok(None),
+            TraceValue::Bytes(bytes) => Ok(Some(bytes)),
+            value => bail!("expected a string or none, found {}", value),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| format_err!("invalid utf-8 string"))
+    }
+
+    fn pairs(&mut self) -> Result<Pairs> {
+        match self.next()? {
+            TraceValue::Pairs(pairs) => Ok(pairs),
+            value => bail!("expected a map, found {}", value),
+        }
+    }
+}
+
+fn parse_event(line: &str) -> Result<TracedEvent> {
+    if let Some(callback) = line.strip_prefix("->") {
+        return Ok(TracedEvent::Callback(callback.trim().to_string()));
+    }
+    if let Some(returned) = line.strip_prefix("<-") {
+        let returned = returned.trim();
+        return Ok(TracedEvent::Return(returned.parse().map_err(|_| {
+            format_err!("invalid return value {:?}", returned)
+        })?));
+    }
+    Ok(TracedEvent::HostCall(parse_call(line)?))
+}
+
+// e.g. `proxy_get_header_map_value(0, ":path") -> "/admin"`
+fn parse_call(line: &str) -> Result<TracedCall> {
+    let open = line
+        .find('(')
+        .ok_or_else(|| format_err!("expected a host call, found {:?}", line))?;
+    let name = line[..open].trim();
+    let host_call = match HostCall::from_name(name) {
+        Some(host_call) => host_call,
+        None if name.starts_with("proxy_") && line[open..].trim() == "(..)" => {
+            return Ok(TracedCall::Untraced {
+                name: name.to_string(),
+            })
+        }
+        None => bail!("unknown host call {:?}", name),
+    };
+    let mut parser = Parser {
+        input: &line.as_bytes()[open + 1..],
+        position: 0,
+    };
+    let arguments = parser.values(b')')?;
+    let mut returns = vec![];
+    parser.skip_whitespace();
+    if parser.input[parser.position..].starts_with(b"->") {
+        parser.position += 2;
+        loop {
+            returns.push(parser.value()?);
+            parser.skip_whitespace();
+            if !parser.eat(b',') {
+                break;
+            }
+        }
+    }
+    parser.skip_whitespace();
+    if parser.position != parser.input.len() {
+        bail!("unexpected trailing input in {:?}", line);
+    }
+    TracedCall::from_values(host_call, arguments, returns)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|byte| byte.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(byte) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        if !self.eat(byte) {
+            bail!("expected {:?} at column {}", byte as char, self.position);
+        }
+        Ok(())
+    }
+
+    // Comma-separated values up to the closing delimiter
+    fn values(&mut self, close: u8) -> Result<Vec<TraceValue>> {
+        let mut values = vec![];
+        if self.eat(close) {
+            return Ok(values);
+        }
+        loop {
+            values.push(self.value()?);
+            if self.eat(close) {
+                return Ok(values);
+            }
+            self.expect(b',')?;
+        }
+    }
+
+    fn value(&mut self) -> Result<TraceValue> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'"') => Ok(TraceValue::Bytes(self.quoted()?)),
+            Some(b'{') => {
+                self.position += 1;
+                let mut pairs = vec![];
+                if self.eat(b'}') {
+                    return Ok(TraceValue::Pairs(pairs));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let name = String::from_utf8(self.quoted()?)?;
+                    self.expect(b':')?;
+                    self.skip_whitespace();
+                    let value = String::from_utf8(self.quoted()?)?;
+                    pairs.push((name, value));
+                    if self.eat(b'}') {
+                        return Ok(TraceValue::Pairs(pairs));
+                    }
+                    self.expect(b',')?;
+                }
+            }
+            Some(b'n') if self.input[self.position..].starts_with(b"none") => {
+                self.position += 4;
+                Ok(TraceValue::None)
+            }
+            Some(byte) if byte == b'-' || byte.is_ascii_digit() => {
+                let start = self.position;
+                self.position += 1;
+                while self.peek().is_some_and(|byte| byte.is_ascii_digit()) {
+                    self.position += 1;
+                }
+                let digits = std::str::from_utf8(&self.input[start..self.position])?;
+                Ok(TraceValue::Int(digits.parse().map_err(|_| {
+                    format_err!("invalid integer {:?}", digits)
+                })?))
+            }
+            _ => bail!("expected a value at column {}", self.position),
+        }
+    }
+
+    fn quoted(&mut self) -> Result<Bytes> {
+        if self.peek() != Some(b'"') {
+            bail!("expected a string at column {}", self.position);
+        }
+        self.position += 1;
+        let mut bytes = vec![];
+        loop {
+            let byte = match self.peek() {
+                Some(byte) => byte,
+                None => bail!("unterminated string"),
+            };
+            self.position += 1;
+            match byte {
+                b'"' => return Ok(bytes),
+                b'\\' => {
+                    let escaped = self
+                        .peek()
+                        .ok_or_else(|| format_err!("unterminated string"))?;
+                    self.position += 1;
+                    match escaped {
+                        b'n' => bytes.push(b'\n'),
+                        b'r' => bytes.push(b'\r'),
+                        b't' => bytes.push(b'\t'),
+                        b'x' => {
+                            let hex = self
+                                .input
+                                .get(self.position..self.position + 2)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                                .ok_or_else(|| format_err!("invalid \\x escape"))?;
+                            self.position += 2;
+                            bytes.push(hex);
+                        }
+                        other => bytes.push(other),
+                    }
+                }
+                other => bytes.push(other),
+            }
+        }
+    }
+}
@@ -13,7 +13,7 @@
 // limitations under the License.
 
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum LogLevel {
     Trace = 0,
     Debug = 1,
@@ -31,7 +31,7 @@ pub enum Action {
 }
 
 #[repr(i32)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum GrpcStatus {
     Ok = 0,
     Canceled = 1,
@@ -54,18 +54,19 @@ pub enum GrpcStatus {
 }
 
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Status {
     Ok = 0,
     NotFound = 1,
     BadArgument = 2,
+    InvalidMemoryAccess = 6,
     Empty = 7,
     CasMismatch = 8,
     InternalFailure = 10,
 }
 
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum MetricType {
     Counter = 0,
     Gauge = 1,
@@ -81,7 +82,7 @@ pub enum CloseType {
 }
 
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum BufferType {
     HttpRequestBody = 0,
     HttpResponseBody = 1,
@@ -91,15 +92,20 @@ pub enum BufferType {
     GrpcReceiveBuffer = 5,
     VmConfiguration = 6,
     PluginConfiguration = 7,
+    // reply to a vNEXT redis_call
+    #[cfg(feature = "vnext")]
+    RedisCallResponse = 8,
 }
 
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum MapType {
     HttpRequestHeaders = 0,
     HttpRequestTrailers = 1,
     HttpResponseHeaders = 2,
     HttpResponseTrailers = 3,
+    GrpcReceiveInitialMetadata = 4,
+    GrpcReceiveTrailingMetadata = 5,
     HttpCallResponseHeaders = 6,
     HttpCallResponseTrailers = 7,
 }
@@ -112,7 +118,7 @@ pub enum PeerType {
     Remote = 2,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum ReturnType {
     None,
     Bool(bool),
@@ -124,6 +130,17 @@ pub enum AbiVersion {
     UnknownAbiVersion,
     ProxyAbiVersion0_1_0,
     ProxyAbiVersion0_2_0,
+    ProxyAbiVersion0_2_1,
+}
+
+impl AbiVersion {
+    // 0.2.1 keeps the signatures of 0.2.0, adding proxy_get_log_level
+    pub fn is_0_2(&self) -> bool {
+        matches!(
+            self,
+            AbiVersion::ProxyAbiVersion0_2_0 | AbiVersion::ProxyAbiVersion0_2_1
+        )
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -133,4 +150,124 @@ pub enum ExpectStatus {
     Unexpected,
 }
 
+// Host functions over which low-level expectations can be staged
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HostCall {
+    Log,
+    SetTickPeriodMillis,
+    GetCurrentTimeNanos,
+    GetBufferBytes,
+    SetBufferBytes,
+    GetHeaderMapPairs,
+    SetHeaderMapPairs,
+    GetHeaderMapValue,
+    ReplaceHeaderMapValue,
+    RemoveHeaderMapValue,
+    AddHeaderMapValue,
+    SendLocalResponse,
+    HttpCall,
+    MetricCreate,
+    MetricIncrement,
+    MetricRecord,
+    MetricGet,
+}
+
+// Failures a mock upstream can inject into http_call responses
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UpstreamFault {
+    Reset,
+    Unavailable,
+    Timeout,
+}
+
+// Leaf value of (untyped) filter metadata, encoded the way Envoy returns protobuf Struct values
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataValue {
+    String(String),
+    Number(f64),
+    Bool(bool),
+}
+
+// Typed filter state value, encoded the way Envoy serializes the corresponding attribute types
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterStateValue {
+    Bytes(Bytes),
+    String(String),
+    Int(i64),
+    Bool(bool),
+}
+
+// Metric defined by the module (or ahead of it by expect_metric_creation), queryable by name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    pub name: String,
+    pub metric_type: i32,
+    pub value: i64,
+    pub samples: Vec<i64>,
+}
+
+// Metrics defined so far, in definition order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metrics {
+    pub entries: Vec<Metric>,
+}
+
+// Samples recorded on a histogram metric, in recording order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub samples: Vec<i64>,
+}
+
+// Message logged by the module through proxy_log
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub level: i32,
+    pub message: String,
+}
+
+// Messages logged by the module, in logging order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Logs {
+    pub entries: Vec<LogEntry>,
+}
+
+// Kind of a context as far as the host can tell: a child context becomes an HTTP or network
+// (stream) context on its first HTTP or network callback, it is Unknown until then
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContextType {
+    Root,
+    Http,
+    Network,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContextState {
+    Active,
+    // proxy_on_done returned true
+    Done,
+    Deleted,
+}
+
+// How an HTTP stream ended up, see Tester::stream_outcome
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamOutcome {
+    // request headers not received yet, or held by the module
+    Pending,
+    // request headers continued upstream
+    Forwarded,
+    // answered by the module with proxy_send_local_response (status code)
+    LocalReply(u32),
+}
+
+// Context created by the host (see Tester::contexts), deleted ones included
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextInfo {
+    pub context_id: i32,
+    pub context_type: ContextType,
+    // parent root context of a child context
+    pub root_context_id: Option<i32>,
+    pub state: ContextState,
+}
+
 pub type Bytes = Vec<u8>;
@@ -12,14 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::runtime::*;
 use anyhow::Result;
-use wasmtime::*;
 
 pub fn print_boundary(wasm_file: &str) -> Result<()> {
-    let store: Store<()> = Store::default();
-    let module = Module::from_file(store.engine(), wasm_file)?;
-    print_imports(&module);
-    print_exports(&module);
+    let loaded = Engine::load_module(wasm_file)?;
+    print_imports(&loaded.module);
+    print_exports(&loaded.module);
     return Ok(());
 }
 
@@ -42,7 +41,7 @@ pub fn print_imports(module: &Module) {
 
 pub fn print_exports(module: &Module) {
     let exports = module.exports();
-    println!("This module requires {} exports", exports.len());
+    println!("This module requires {} exports", module.exports().count());
     println!("-----------------------------------------------------------------");
     // get details of all imports (in order)
     for (c, item) in exports.enumerate() {
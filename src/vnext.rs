@@ -0,0 +1,162 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Host functions not (yet) part of a released Proxy-Wasm ABI, available with the "vnext"
+// feature. They follow the hosts implementing them ahead of the spec and may change with it:
+//
+//   proxy_redis_init(cluster, username, password, timeout_milliseconds)
+//   proxy_redis_call(cluster, query) -> token
+//
+// Replies of the mock Redis clusters (Tester::set_mock_redis) are delivered after the callback
+// through proxy_on_redis_call_response(context_id, token, status, response_size), the reply
+// itself being read from the RedisCallResponse buffer.
+
+#![cfg_attr(feature = "wasmi", allow(unused_mut))]
+
+use crate::hostcalls::HostState;
+use crate::runtime::*;
+use crate::types::*;
+
+use anyhow::Result;
+use tracing::{error, trace};
+
+// Returns whether a vNEXT implementation of the imported function exists
+pub fn link_hostfunc(linker: &mut Linker<HostState>, import: &ImportType) -> Result<bool> {
+    let (module, name) = (import.module(), import.name());
+    let linked = match name {
+        "proxy_redis_init" => Some(linker.func_wrap(
+            module,
+            name,
+            |mut caller: Caller<'_, HostState>,
+             cluster_data: i32,
+             cluster_size: i32,
+             _username_data: i32,
+             _username_size: i32,
+             _password_data: i32,
+             _password_size: i32,
+             timeout_milliseconds: i32|
+             -> i32 {
+                let state = caller.data().clone();
+                let _timer = state.time_host_call("proxy_redis_init");
+                let cluster = match read_string(&mut caller, cluster_data, cluster_size) {
+                    Some(cluster) => cluster,
+                    None => return Status::InvalidMemoryAccess as i32,
+                };
+                // only clusters with a mock can be reached
+                let status = match state.host.lock().unwrap().staged.init_redis(&cluster) {
+                    true => Status::Ok,
+                    false => Status::BadArgument,
+                };
+                trace!(
+                    "[vm->host] proxy_redis_init(cluster={:?}, timeout_milliseconds={}) status: {:?}",
+                    cluster,
+                    timeout_milliseconds,
+                    state.get_status()
+                );
+                trace!("[vm<-host] proxy_redis_init(...) return: {:?}", status);
+                status as i32
+            },
+        )),
+
+        "proxy_redis_call" => Some(linker.func_wrap(
+            module,
+            name,
+            |mut caller: Caller<'_, HostState>,
+             cluster_data: i32,
+             cluster_size: i32,
+             query_data: i32,
+             query_size: i32,
+             return_token: i32|
+             -> i32 {
+                let state = caller.data().clone();
+                let _timer = state.time_host_call("proxy_redis_call");
+                let cluster = read_string(&mut caller, cluster_data, cluster_size);
+                let query = read_bytes(&mut caller, query_data, query_size);
+                let (cluster, query) = match (cluster, query) {
+                    (Some(cluster), Some(query)) => (cluster, query),
+                    _ => return Status::InvalidMemoryAccess as i32,
+                };
+                let token_id = match state
+                    .host
+                    .lock()
+                    .unwrap()
+                    .staged
+                    .queue_redis_call(&cluster, query.clone())
+                {
+                    Some(token_id) => token_id,
+                    None => {
+                        trace!(
+                            "[vm<-host] proxy_redis_call(...) return: {:?} (redis_init not called)",
+                            Status::BadArgument
+                        );
+                        return Status::BadArgument as i32;
+                    }
+                };
+                let start = return_token as u32 as usize;
+                match memory(&mut caller).and_then(|mem| {
+                    mem.data_mut(&mut caller)
+                        .get_mut(start..start + 4)
+                        .map(|data| data.copy_from_slice(&token_id.to_le_bytes()))
+                }) {
+                    Some(()) => {}
+                    None => return Status::InvalidMemoryAccess as i32,
+                }
+                trace!(
+                    "[vm->host] proxy_redis_call(cluster={:?}, query={:?}) status: {:?}",
+                    cluster,
+                    String::from_utf8_lossy(&query),
+                    state.get_status()
+                );
+                trace!(
+                    "[vm<-host] proxy_redis_call(...) -> (return_token={}) return: {:?}",
+                    token_id,
+                    Status::Ok
+                );
+                Status::Ok as i32
+            },
+        )),
+
+        _ => None,
+    };
+    match linked {
+        Some(result) => {
+            result?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn memory(caller: &mut Caller<'_, HostState>) -> Option<Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => Some(mem),
+        _ => {
+            error!("Error: vNEXT host function cannot get export \"memory\"");
+            None
+        }
+    }
+}
+
+fn read_bytes(caller: &mut Caller<'_, HostState>, data: i32, size: i32) -> Option<Bytes> {
+    let mem = memory(caller)?;
+    let start = data as u32 as usize;
+    let bytes = mem
+        .data(&*caller)
+        .get(start..start + size as u32 as usize)?;
+    Some(bytes.to_vec())
+}
+
+fn read_string(caller: &mut Caller<'_, HostState>, data: i32, size: i32) -> Option<String> {
+    String::from_utf8(read_bytes(caller, data, size)?).ok()
+}